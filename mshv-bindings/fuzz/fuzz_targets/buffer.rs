@@ -0,0 +1,23 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mshv_bindings::AlignedBuffer;
+
+fuzz_target!(|input: (u16, u8, Vec<u8>)| {
+    let (size, align_shift, data) = input;
+    // AlignedBuffer::new() expects a valid layout, keep the inputs within it
+    let size = size as usize + 1;
+    let align = 1usize << (align_shift % 13);
+    let mut buf = AlignedBuffer::new(size, align).unwrap();
+    assert!(buf.size() == size);
+    assert!(buf.as_ptr() as usize % align == 0);
+    assert!(buf.as_slice().iter().all(|b| *b == 0));
+
+    let len = std::cmp::min(size, data.len());
+    buf.as_mut_slice()[..len].copy_from_slice(&data[..len]);
+    assert!(&buf.as_slice()[..len] == &data[..len]);
+});
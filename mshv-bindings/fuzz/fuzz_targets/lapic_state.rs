@@ -0,0 +1,51 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mshv_bindings::*;
+
+fuzz_target!(|lapic: LapicState| {
+    let vp_state = mshv_vp_state::from(lapic);
+    let round_trip = LapicState::from(vp_state);
+    // SAFETY: buf.lapic was leaked from a Box by From<LapicState>
+    drop(unsafe { Box::from_raw(vp_state.buf.lapic) });
+
+    // Every register carried by hv_local_interrupt_controller_state must
+    // survive the round trip. PPR is recomputed from the ISR.
+    let offsets = [
+        LOCAL_APIC_OFFSET_APIC_ID,
+        LOCAL_APIC_OFFSET_VERSION,
+        LOCAL_APIC_OFFSET_REMOTE_READ,
+        LOCAL_APIC_OFFSET_LDR,
+        LOCAL_APIC_OFFSET_DFR,
+        LOCAL_APIC_OFFSET_SPURIOUS,
+        LOCAL_APIC_OFFSET_ERROR,
+        LOCAL_APIC_OFFSET_ICR_LOW,
+        LOCAL_APIC_OFFSET_ICR_HIGH,
+        LOCAL_APIC_OFFSET_TIMER_LVT,
+        LOCAL_APIC_OFFSET_THERMAL_LVT,
+        LOCAL_APIC_OFFSET_PERFMON_LVT,
+        LOCAL_APIC_OFFSET_LINT0_LVT,
+        LOCAL_APIC_OFFSET_LINT1_LVT,
+        LOCAL_APIC_OFFSET_ERROR_LVT,
+        LOCAL_APIC_OFFSET_INITIAL_COUNT,
+        LOCAL_APIC_OFFSET_CURRENT_COUNT,
+        LOCAL_APIC_OFFSET_DIVIDER,
+    ]
+    .iter()
+    .copied()
+    .chain((0..8).flat_map(|i| {
+        [
+            LOCAL_APIC_OFFSET_ISR + i * 16,
+            LOCAL_APIC_OFFSET_TMR + i * 16,
+            LOCAL_APIC_OFFSET_IRR + i * 16,
+        ]
+    }));
+    for offset in offsets {
+        let offset = offset as usize;
+        assert!(lapic.regs[offset..offset + 4] == round_trip.regs[offset..offset + 4]);
+    }
+});
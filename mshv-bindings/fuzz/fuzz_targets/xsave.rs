@@ -0,0 +1,21 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mshv_bindings::*;
+
+fuzz_target!(|xsave: XSave| {
+    // vp_state.buf points into `xsave`, which outlives it
+    let vp_state = mshv_vp_state::from(xsave);
+    assert!(vp_state.buf_size == xsave.data_size());
+    let round_trip = XSave::from(vp_state);
+
+    assert!(round_trip.flags() == xsave.flags());
+    assert!(round_trip.states() == xsave.states());
+    assert!(round_trip.data_size() == xsave.data_size());
+    let len = std::cmp::min(4096, xsave.data_size() as u32) as usize;
+    assert!(round_trip.buffer[24..24 + len] == xsave.buffer[24..24 + len]);
+});
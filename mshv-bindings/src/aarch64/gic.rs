@@ -0,0 +1,185 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+use crate::bindings::{hv_partition_property_code, hv_register_name};
+#[cfg(feature = "with-serde")]
+use serde_derive::{Deserialize, Serialize};
+
+// The virtual GIC is owned by the hypervisor and is configured through
+// partition properties rather than through an emulated device. The codes
+// are the arm64 hv_partition_property_code values of the Linux MSHV
+// driver headers (include/hyperv/hvgdk_mini.h).
+pub const HV_PARTITION_PROPERTY_GICD_BASE_ADDRESS: hv_partition_property_code = 0x00070000;
+pub const HV_PARTITION_PROPERTY_GITS_TRANSLATER_BASE_ADDRESS: hv_partition_property_code =
+    0x00070001;
+pub const HV_PARTITION_PROPERTY_GIC_LPI_INT_ID_BITS: hv_partition_property_code = 0x00070002;
+pub const HV_PARTITION_PROPERTY_GIC_PPI_OVERFLOW_INTERRUPT_FROM_CNTV: hv_partition_property_code =
+    0x00070003;
+pub const HV_PARTITION_PROPERTY_GIC_PPI_PERFORMANCE_MONITORS_INTERRUPT: hv_partition_property_code =
+    0x00070005;
+
+/// Per vCPU register holding the guest physical address of its redistributor
+pub const HV_ARM64_REGISTER_GICR_BASE_GPA: hv_register_name = 0x00063000;
+
+/// Size of the GICD register frame
+pub const GIC_V3_DIST_SIZE: u64 = 0x10000;
+/// Size of the redistributor frames (RD_base + SGI_base) of a single vCPU
+pub const GIC_V3_REDIST_SIZE: u64 = 0x20000;
+/// Size of the GITS register frames
+pub const GIC_V3_ITS_SIZE: u64 = 0x20000;
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+/// Layout and interrupt assignment of the virtual GICv3 of a partition
+pub struct GicConfig {
+    /// Guest physical address of the distributor
+    pub gicd_base: u64,
+    /// Guest physical address of the redistributor of vCPU 0. The
+    /// redistributors of the other vCPUs follow contiguously.
+    pub gicr_base: u64,
+    /// Guest physical address of the ITS translater, if any
+    pub gits_translater_base: Option<u64>,
+    /// Number of LPI interrupt ID bits, only meaningful with an ITS
+    pub lpi_int_id_bits: u32,
+    /// PPI raised by the virtual timer
+    pub vtimer_ppi: u32,
+    /// PPI raised by the performance monitors
+    pub pmu_ppi: u32,
+}
+
+impl GicConfig {
+    /// Guest physical address of the redistributor of vCPU `vp_index`
+    pub fn gicr_base_for_vcpu(&self, vp_index: u32) -> u64 {
+        self.gicr_base + vp_index as u64 * GIC_V3_REDIST_SIZE
+    }
+
+    /// Partition properties to program for this configuration
+    pub fn partition_properties(&self) -> Vec<(hv_partition_property_code, u64)> {
+        let mut props = vec![
+            (HV_PARTITION_PROPERTY_GICD_BASE_ADDRESS, self.gicd_base),
+            (
+                HV_PARTITION_PROPERTY_GIC_PPI_OVERFLOW_INTERRUPT_FROM_CNTV,
+                self.vtimer_ppi as u64,
+            ),
+            (
+                HV_PARTITION_PROPERTY_GIC_PPI_PERFORMANCE_MONITORS_INTERRUPT,
+                self.pmu_ppi as u64,
+            ),
+        ];
+        if let Some(gits) = self.gits_translater_base {
+            props.push((HV_PARTITION_PROPERTY_GITS_TRANSLATER_BASE_ADDRESS, gits));
+            props.push((
+                HV_PARTITION_PROPERTY_GIC_LPI_INT_ID_BITS,
+                self.lpi_int_id_bits as u64,
+            ));
+        }
+        props
+    }
+
+    /// Configuration read back from the values of the properties
+    /// `partition_properties` returns, as fetched one by one from the
+    /// partition. An ITS translater base of 0 means no ITS. `gicr_base` is
+    /// per vCPU and not a partition property, it is left 0.
+    pub fn from_partition_properties(props: &[(hv_partition_property_code, u64)]) -> Self {
+        let mut config = GicConfig::default();
+        for &(code, value) in props {
+            match code {
+                HV_PARTITION_PROPERTY_GICD_BASE_ADDRESS => config.gicd_base = value,
+                HV_PARTITION_PROPERTY_GITS_TRANSLATER_BASE_ADDRESS if value != 0 => {
+                    config.gits_translater_base = Some(value)
+                }
+                HV_PARTITION_PROPERTY_GIC_LPI_INT_ID_BITS => config.lpi_int_id_bits = value as u32,
+                HV_PARTITION_PROPERTY_GIC_PPI_OVERFLOW_INTERRUPT_FROM_CNTV => {
+                    config.vtimer_ppi = value as u32
+                }
+                HV_PARTITION_PROPERTY_GIC_PPI_PERFORMANCE_MONITORS_INTERRUPT => {
+                    config.pmu_ppi = value as u32
+                }
+                _ => {}
+            }
+        }
+        config
+    }
+}
+
+/// Partition properties read back by `GicConfig::from_partition_properties`
+pub const GIC_PARTITION_PROPERTIES: [hv_partition_property_code; 5] = [
+    HV_PARTITION_PROPERTY_GICD_BASE_ADDRESS,
+    HV_PARTITION_PROPERTY_GITS_TRANSLATER_BASE_ADDRESS,
+    HV_PARTITION_PROPERTY_GIC_LPI_INT_ID_BITS,
+    HV_PARTITION_PROPERTY_GIC_PPI_OVERFLOW_INTERRUPT_FROM_CNTV,
+    HV_PARTITION_PROPERTY_GIC_PPI_PERFORMANCE_MONITORS_INTERRUPT,
+];
+
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+/// Saved virtual GIC of a partition: its configuration and the
+/// redistributor address of each vCPU.
+///
+/// The distributor and redistributor registers themselves, with the
+/// enabled, pending and active state of each interrupt, live in the
+/// hypervisor and the MSHV ABI has no call to read or write them, so they
+/// are not part of the state.
+pub struct GicState {
+    /// Partition-wide configuration
+    pub config: GicConfig,
+    /// Redistributor base of each vCPU, in vCPU order
+    pub redistributors: Vec<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gic_config() {
+        let mut config = GicConfig {
+            gicd_base: 0x0800_0000,
+            gicr_base: 0x080a_0000,
+            vtimer_ppi: 27,
+            pmu_ppi: 23,
+            ..Default::default()
+        };
+        assert_eq!(config.gicr_base_for_vcpu(0), 0x080a_0000);
+        assert_eq!(config.gicr_base_for_vcpu(2), 0x080e_0000);
+        assert_eq!(config.partition_properties().len(), 3);
+
+        config.gits_translater_base = Some(0x0808_0000);
+        config.lpi_int_id_bits = 16;
+        let props = config.partition_properties();
+        assert_eq!(props.len(), 5);
+        assert!(props.contains(&(
+            HV_PARTITION_PROPERTY_GITS_TRANSLATER_BASE_ADDRESS,
+            0x0808_0000
+        )));
+        config.gicr_base = 0;
+        assert_eq!(GicConfig::from_partition_properties(&props), config);
+    }
+
+    #[test]
+    fn test_gic_property_codes() {
+        // From include/hyperv/hvgdk_mini.h
+        assert_eq!(HV_PARTITION_PROPERTY_GICD_BASE_ADDRESS, 0x0007_0000);
+        assert_eq!(
+            HV_PARTITION_PROPERTY_GITS_TRANSLATER_BASE_ADDRESS,
+            0x0007_0001
+        );
+        assert_eq!(HV_PARTITION_PROPERTY_GIC_LPI_INT_ID_BITS, 0x0007_0002);
+        assert_eq!(
+            HV_PARTITION_PROPERTY_GIC_PPI_OVERFLOW_INTERRUPT_FROM_CNTV,
+            0x0007_0003
+        );
+        assert_eq!(
+            HV_PARTITION_PROPERTY_GIC_PPI_PERFORMANCE_MONITORS_INTERRUPT,
+            0x0007_0005
+        );
+        assert_eq!(HV_ARM64_REGISTER_GICR_BASE_GPA, 0x0006_3000);
+        let config = GicConfig::from_partition_properties(&[(
+            HV_PARTITION_PROPERTY_GITS_TRANSLATER_BASE_ADDRESS,
+            0,
+        )]);
+        assert!(config.gits_translater_base.is_none());
+    }
+}
@@ -0,0 +1,116 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+use super::gic::GicConfig;
+use std::collections::BTreeMap;
+
+/// First LPI interrupt ID
+pub const GIC_LPI_BASE: u32 = 8192;
+/// Offset of GITS_TRANSLATER from the base of the ITS frames
+pub const GITS_TRANSLATER_OFFSET: u64 = 0x1_0040;
+
+/// ITS device ID of a PCI function: its requester ID, with the segment
+/// above it
+pub fn pci_its_device_id(segment: u16, bus: u8, devfn: u8) -> u32 {
+    (segment as u32) << 16 | (bus as u32) << 8 | devfn as u32
+}
+
+/// Translation of the (device ID, event ID) pairs of MSIs written to the
+/// ITS into LPIs, as set up by the guest with MAPTI and torn down with
+/// DISCARD and MAPD.
+///
+/// MSI routes and device interrupts target the LPI: the routing entry of
+/// an MSI holds the translater address and the LPI interrupt ID, see
+/// `msi_route`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ItsTranslationTable {
+    translater: u64,
+    lpi_int_id_bits: u32,
+    // LPI of each (device ID, event ID)
+    lpis: BTreeMap<(u32, u32), u32>,
+}
+
+impl ItsTranslationTable {
+    /// Creates an empty table for the ITS of `config`
+    pub fn new(config: &GicConfig) -> Result<Self, &'static str> {
+        let translater = config
+            .gits_translater_base
+            .ok_or("GIC configuration without ITS")?;
+        if !(14..=32).contains(&config.lpi_int_id_bits) {
+            return Err("Invalid number of LPI interrupt ID bits");
+        }
+        Ok(ItsTranslationTable {
+            translater,
+            lpi_int_id_bits: config.lpi_int_id_bits,
+            lpis: BTreeMap::new(),
+        })
+    }
+
+    /// Maps `event_id` of `device_id` to LPI `intid`, replacing any
+    /// previous mapping
+    pub fn map(&mut self, device_id: u32, event_id: u32, intid: u32) -> Result<(), &'static str> {
+        if intid < GIC_LPI_BASE || (intid as u64) >> self.lpi_int_id_bits != 0 {
+            return Err("Interrupt ID is not a valid LPI");
+        }
+        self.lpis.insert((device_id, event_id), intid);
+        Ok(())
+    }
+
+    /// Removes the mapping of `event_id` of `device_id`, returning its LPI
+    pub fn unmap(&mut self, device_id: u32, event_id: u32) -> Option<u32> {
+        self.lpis.remove(&(device_id, event_id))
+    }
+
+    /// Removes every mapping of `device_id`
+    pub fn unmap_device(&mut self, device_id: u32) {
+        self.lpis.retain(|(device, _), _| *device != device_id);
+    }
+
+    /// LPI raised by `event_id` of `device_id`
+    pub fn translate(&self, device_id: u32, event_id: u32) -> Option<u32> {
+        self.lpis.get(&(device_id, event_id)).copied()
+    }
+
+    /// MSI address and data of the routing entry for `event_id` of
+    /// `device_id`
+    pub fn msi_route(&self, device_id: u32, event_id: u32) -> Option<(u64, u32)> {
+        self.translate(device_id, event_id)
+            .map(|intid| (self.translater, intid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_its_translation_table() {
+        let mut config = GicConfig {
+            gicd_base: 0x0800_0000,
+            gicr_base: 0x080a_0000,
+            ..Default::default()
+        };
+        assert!(ItsTranslationTable::new(&config).is_err());
+        config.gits_translater_base = Some(0x0808_0000 + GITS_TRANSLATER_OFFSET);
+        assert!(ItsTranslationTable::new(&config).is_err());
+        config.lpi_int_id_bits = 16;
+        let mut its = ItsTranslationTable::new(&config).unwrap();
+
+        let device = pci_its_device_id(0, 1, 0x08);
+        assert_eq!(device, 0x108);
+        its.map(device, 0, 8192).unwrap();
+        its.map(device, 1, 8193).unwrap();
+        its.map(0x200, 0, 8194).unwrap();
+        assert!(its.map(device, 2, 32).is_err());
+        assert!(its.map(device, 2, 1 << 16).is_err());
+        assert_eq!(its.msi_route(device, 1), Some((0x0809_0040, 8193)));
+
+        assert_eq!(its.unmap(device, 1), Some(8193));
+        assert_eq!(its.translate(device, 1), None);
+        its.unmap_device(device);
+        assert_eq!(its.translate(device, 0), None);
+        assert_eq!(its.translate(0x200, 0), Some(8194));
+    }
+}
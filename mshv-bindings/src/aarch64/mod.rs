@@ -0,0 +1,14 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+pub mod regs;
+pub use regs::*;
+pub mod gic;
+pub use gic::*;
+pub mod vcpu;
+pub use vcpu::*;
+pub mod psci;
+pub use psci::*;
+pub mod its;
+pub use its::*;
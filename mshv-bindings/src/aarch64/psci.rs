@@ -0,0 +1,171 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+#[cfg(feature = "with-serde")]
+use serde_derive::{Deserialize, Serialize};
+
+// PSCI 0.2+ function IDs, SMC32 calling convention. The SMC64 variants of
+// the calls taking addresses have bit 30 set.
+pub const PSCI_VERSION: u32 = 0x8400_0000;
+pub const PSCI_CPU_SUSPEND: u32 = 0x8400_0001;
+pub const PSCI_CPU_OFF: u32 = 0x8400_0002;
+pub const PSCI_CPU_ON: u32 = 0x8400_0003;
+pub const PSCI_AFFINITY_INFO: u32 = 0x8400_0004;
+pub const PSCI_SYSTEM_OFF: u32 = 0x8400_0008;
+pub const PSCI_SYSTEM_RESET: u32 = 0x8400_0009;
+pub const PSCI_FEATURES: u32 = 0x8400_000a;
+pub const PSCI_SMC64: u32 = 0x4000_0000;
+
+// Return codes, written to X0
+pub const PSCI_RET_SUCCESS: i64 = 0;
+pub const PSCI_RET_NOT_SUPPORTED: i64 = -1;
+pub const PSCI_RET_INVALID_PARAMS: i64 = -2;
+pub const PSCI_RET_DENIED: i64 = -3;
+pub const PSCI_RET_ALREADY_ON: i64 = -4;
+pub const PSCI_RET_ON_PENDING: i64 = -5;
+pub const PSCI_RET_INTERNAL_FAILURE: i64 = -6;
+
+/// AFFINITY_INFO result of a vCPU that is running
+pub const PSCI_AFFINITY_ON: i64 = 0;
+/// AFFINITY_INFO result of a vCPU that is off
+pub const PSCI_AFFINITY_OFF: i64 = 1;
+/// AFFINITY_INFO result of a vCPU being started
+pub const PSCI_AFFINITY_ON_PENDING: i64 = 2;
+
+/// PSCI_VERSION result for PSCI 1.0
+pub const PSCI_VERSION_1_0: i64 = 0x1_0000;
+
+// Function IDs of the standard secure service calls, which PSCI uses
+const PSCI_FUNCTION_MASK: u32 = 0xbfff_ffe0;
+const PSCI_FUNCTION_BASE: u32 = 0x8400_0000;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+/// A PSCI call made by the guest, decoded from X0 - X3
+pub enum PsciCall {
+    /// PSCI_VERSION
+    Version,
+    /// CPU_SUSPEND, which may be treated as a WFI
+    CpuSuspend {
+        power_state: u32,
+        entry_point: u64,
+        context_id: u64,
+    },
+    /// CPU_OFF of the calling vCPU
+    CpuOff,
+    /// CPU_ON: the vCPU with affinity `target_mpidr` starts at
+    /// `entry_point` with `context_id` in X0, see
+    /// `StandardRegisters::boot`
+    CpuOn {
+        target_mpidr: u64,
+        entry_point: u64,
+        context_id: u64,
+    },
+    /// AFFINITY_INFO, answered with one of the PSCI_AFFINITY_* values
+    AffinityInfo {
+        target_mpidr: u64,
+        lowest_affinity_level: u32,
+    },
+    /// SYSTEM_OFF
+    SystemOff,
+    /// SYSTEM_RESET
+    SystemReset,
+    /// PSCI_FEATURES for `function`
+    Features { function: u32 },
+    /// Any other PSCI function, to be answered with
+    /// PSCI_RET_NOT_SUPPORTED
+    Unknown { function: u32 },
+}
+
+impl PsciCall {
+    /// Decodes the call in `x`, X0 to X3. Returns None if X0 is not a
+    /// PSCI function ID.
+    pub fn decode(x: [u64; 4]) -> Option<Self> {
+        let function = x[0] as u32;
+        if x[0] > u32::MAX as u64 || function & PSCI_FUNCTION_MASK != PSCI_FUNCTION_BASE {
+            return None;
+        }
+        // SMC32 calls only use the low half of the arguments
+        let arg = |n: usize| {
+            if function & PSCI_SMC64 != 0 {
+                x[n]
+            } else {
+                x[n] & 0xffff_ffff
+            }
+        };
+        let call = match function & !PSCI_SMC64 {
+            PSCI_VERSION => PsciCall::Version,
+            PSCI_CPU_SUSPEND => PsciCall::CpuSuspend {
+                power_state: x[1] as u32,
+                entry_point: arg(2),
+                context_id: arg(3),
+            },
+            PSCI_CPU_OFF => PsciCall::CpuOff,
+            PSCI_CPU_ON => PsciCall::CpuOn {
+                target_mpidr: arg(1),
+                entry_point: arg(2),
+                context_id: arg(3),
+            },
+            PSCI_AFFINITY_INFO => PsciCall::AffinityInfo {
+                target_mpidr: arg(1),
+                lowest_affinity_level: x[2] as u32,
+            },
+            PSCI_SYSTEM_OFF => PsciCall::SystemOff,
+            PSCI_SYSTEM_RESET => PsciCall::SystemReset,
+            PSCI_FEATURES => PsciCall::Features {
+                function: x[1] as u32,
+            },
+            _ => PsciCall::Unknown { function },
+        };
+        Some(call)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_psci_decode() {
+        assert_eq!(
+            PsciCall::decode([PSCI_VERSION as u64, 0, 0, 0]),
+            Some(PsciCall::Version)
+        );
+        assert_eq!(
+            PsciCall::decode([
+                (PSCI_CPU_ON | PSCI_SMC64) as u64,
+                0x101,
+                0x1_4008_0000,
+                0x42
+            ]),
+            Some(PsciCall::CpuOn {
+                target_mpidr: 0x101,
+                entry_point: 0x1_4008_0000,
+                context_id: 0x42,
+            })
+        );
+        // SMC32 arguments are truncated
+        assert_eq!(
+            PsciCall::decode([PSCI_CPU_ON as u64, 0x1_0000_0001, 0x1_4008_0000, 0]),
+            Some(PsciCall::CpuOn {
+                target_mpidr: 1,
+                entry_point: 0x4008_0000,
+                context_id: 0,
+            })
+        );
+        assert_eq!(
+            PsciCall::decode([PSCI_SYSTEM_RESET as u64, 0, 0, 0]),
+            Some(PsciCall::SystemReset)
+        );
+        assert_eq!(
+            PsciCall::decode([0x8400_001f, 0, 0, 0]),
+            Some(PsciCall::Unknown {
+                function: 0x8400_001f
+            })
+        );
+        assert_eq!(PsciCall::decode([0x8600_0000, 0, 0, 0]), None);
+        assert_eq!(PsciCall::decode([0x1_8400_0000, 0, 0, 0]), None);
+    }
+}
@@ -0,0 +1,349 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+use crate::bindings::hv_register_name;
+#[cfg(feature = "with-serde")]
+use serde_derive::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+// General purpose registers
+pub const HV_ARM64_REGISTER_X0: hv_register_name = 0x00020000;
+pub const HV_ARM64_REGISTER_X1: hv_register_name = 0x00020001;
+pub const HV_ARM64_REGISTER_X2: hv_register_name = 0x00020002;
+pub const HV_ARM64_REGISTER_X3: hv_register_name = 0x00020003;
+pub const HV_ARM64_REGISTER_X4: hv_register_name = 0x00020004;
+pub const HV_ARM64_REGISTER_X5: hv_register_name = 0x00020005;
+pub const HV_ARM64_REGISTER_X6: hv_register_name = 0x00020006;
+pub const HV_ARM64_REGISTER_X7: hv_register_name = 0x00020007;
+pub const HV_ARM64_REGISTER_X8: hv_register_name = 0x00020008;
+pub const HV_ARM64_REGISTER_X9: hv_register_name = 0x00020009;
+pub const HV_ARM64_REGISTER_X10: hv_register_name = 0x0002000A;
+pub const HV_ARM64_REGISTER_X11: hv_register_name = 0x0002000B;
+pub const HV_ARM64_REGISTER_X12: hv_register_name = 0x0002000C;
+pub const HV_ARM64_REGISTER_X13: hv_register_name = 0x0002000D;
+pub const HV_ARM64_REGISTER_X14: hv_register_name = 0x0002000E;
+pub const HV_ARM64_REGISTER_X15: hv_register_name = 0x0002000F;
+pub const HV_ARM64_REGISTER_X16: hv_register_name = 0x00020010;
+pub const HV_ARM64_REGISTER_X17: hv_register_name = 0x00020011;
+pub const HV_ARM64_REGISTER_X18: hv_register_name = 0x00020012;
+pub const HV_ARM64_REGISTER_X19: hv_register_name = 0x00020013;
+pub const HV_ARM64_REGISTER_X20: hv_register_name = 0x00020014;
+pub const HV_ARM64_REGISTER_X21: hv_register_name = 0x00020015;
+pub const HV_ARM64_REGISTER_X22: hv_register_name = 0x00020016;
+pub const HV_ARM64_REGISTER_X23: hv_register_name = 0x00020017;
+pub const HV_ARM64_REGISTER_X24: hv_register_name = 0x00020018;
+pub const HV_ARM64_REGISTER_X25: hv_register_name = 0x00020019;
+pub const HV_ARM64_REGISTER_X26: hv_register_name = 0x0002001A;
+pub const HV_ARM64_REGISTER_X27: hv_register_name = 0x0002001B;
+pub const HV_ARM64_REGISTER_X28: hv_register_name = 0x0002001C;
+pub const HV_ARM64_REGISTER_FP: hv_register_name = 0x0002001D; // X29
+pub const HV_ARM64_REGISTER_LR: hv_register_name = 0x0002001E; // X30
+pub const HV_ARM64_REGISTER_SP: hv_register_name = 0x0002001F;
+pub const HV_ARM64_REGISTER_SP_EL0: hv_register_name = 0x00020020;
+pub const HV_ARM64_REGISTER_SP_EL1: hv_register_name = 0x00020021;
+pub const HV_ARM64_REGISTER_PC: hv_register_name = 0x00020022;
+pub const HV_ARM64_REGISTER_PSTATE: hv_register_name = 0x00020023;
+
+// System registers
+pub const HV_ARM64_REGISTER_MIDR_EL1: hv_register_name = 0x00040000;
+pub const HV_ARM64_REGISTER_MPIDR_EL1: hv_register_name = 0x00040001;
+pub const HV_ARM64_REGISTER_SCTLR_EL1: hv_register_name = 0x00040002;
+pub const HV_ARM64_REGISTER_ACTLR_EL1: hv_register_name = 0x00040003;
+pub const HV_ARM64_REGISTER_CPACR_EL1: hv_register_name = 0x00040004;
+pub const HV_ARM64_REGISTER_TTBR0_EL1: hv_register_name = 0x00040005;
+pub const HV_ARM64_REGISTER_TTBR1_EL1: hv_register_name = 0x00040006;
+pub const HV_ARM64_REGISTER_TCR_EL1: hv_register_name = 0x00040007;
+pub const HV_ARM64_REGISTER_ESR_EL1: hv_register_name = 0x00040008;
+pub const HV_ARM64_REGISTER_FAR_EL1: hv_register_name = 0x00040009;
+pub const HV_ARM64_REGISTER_PAR_EL1: hv_register_name = 0x0004000A;
+pub const HV_ARM64_REGISTER_MAIR_EL1: hv_register_name = 0x0004000B;
+pub const HV_ARM64_REGISTER_VBAR_EL1: hv_register_name = 0x0004000C;
+pub const HV_ARM64_REGISTER_CONTEXTIDR_EL1: hv_register_name = 0x0004000D;
+pub const HV_ARM64_REGISTER_TPIDR_EL1: hv_register_name = 0x0004000E;
+pub const HV_ARM64_REGISTER_CNTKCTL_EL1: hv_register_name = 0x0004000F;
+pub const HV_ARM64_REGISTER_TPIDRRO_EL0: hv_register_name = 0x00040010;
+pub const HV_ARM64_REGISTER_TPIDR_EL0: hv_register_name = 0x00040011;
+pub const HV_ARM64_REGISTER_SPSR_EL1: hv_register_name = 0x00040014;
+pub const HV_ARM64_REGISTER_ELR_EL1: hv_register_name = 0x00040015;
+
+/// Number of general purpose registers X0 - X30
+pub const ARM64_GP_REGISTERS_COUNT: usize = 31;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, AsBytes, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+pub struct StandardRegisters {
+    /// X0 - X30, X29 being the frame pointer and X30 the link register
+    pub regs: [u64; ARM64_GP_REGISTERS_COUNT],
+    pub sp_el0: u64,
+    pub sp_el1: u64,
+    pub pc: u64,
+    pub pstate: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, AsBytes, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+pub struct SystemRegisters {
+    pub sctlr_el1: u64,
+    pub cpacr_el1: u64,
+    pub ttbr0_el1: u64,
+    pub ttbr1_el1: u64,
+    pub tcr_el1: u64,
+    pub esr_el1: u64,
+    pub far_el1: u64,
+    pub par_el1: u64,
+    pub mair_el1: u64,
+    pub vbar_el1: u64,
+    pub contextidr_el1: u64,
+    pub tpidr_el1: u64,
+    pub cntkctl_el1: u64,
+    pub tpidrro_el0: u64,
+    pub tpidr_el0: u64,
+    pub spsr_el1: u64,
+    pub elr_el1: u64,
+}
+
+/// Register name of the general purpose register Xn
+pub fn arm64_gp_reg_name(n: usize) -> Result<hv_register_name, &'static str> {
+    if n >= ARM64_GP_REGISTERS_COUNT {
+        return Err("Invalid general purpose register number");
+    }
+    Ok(HV_ARM64_REGISTER_X0 + n as hv_register_name)
+}
+
+/// AArch64 system register encoding as used by the MRS/MSR instructions
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+pub struct SysRegEncoding {
+    pub op0: u8,
+    pub op1: u8,
+    pub crn: u8,
+    pub crm: u8,
+    pub op2: u8,
+}
+
+impl SysRegEncoding {
+    pub const fn new(op0: u8, op1: u8, crn: u8, crm: u8, op2: u8) -> Self {
+        SysRegEncoding {
+            op0,
+            op1,
+            crn,
+            crm,
+            op2,
+        }
+    }
+}
+
+/// System registers that can be accessed by encoding.
+///
+/// This is not the architectural system register set: it only covers the
+/// EL0/EL1 registers the hypervisor has a register name for in this crate,
+/// the MMU, exception and thread ID state a guest needs brought up and
+/// migrated. ID registers other than MIDR_EL1 and MPIDR_EL1, the timer,
+/// PMU, debug and GIC CPU interface registers are not covered, and their
+/// encodings are rejected rather than mapped to a guessed name.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+pub enum SysReg {
+    MidrEl1,
+    MpidrEl1,
+    SctlrEl1,
+    ActlrEl1,
+    CpacrEl1,
+    Ttbr0El1,
+    Ttbr1El1,
+    TcrEl1,
+    SpsrEl1,
+    ElrEl1,
+    SpEl0,
+    EsrEl1,
+    FarEl1,
+    ParEl1,
+    MairEl1,
+    VbarEl1,
+    ContextidrEl1,
+    TpidrEl1,
+    CntkctlEl1,
+    TpidrEl0,
+    TpidrroEl0,
+    SpEl1,
+}
+
+// Encoding and hypervisor name of each register, the only encodings
+// `sys_reg_to_hv_reg_name` accepts
+const SYS_REGS: [(SysReg, SysRegEncoding, hv_register_name); 22] = [
+    (
+        SysReg::MidrEl1,
+        SysRegEncoding::new(3, 0, 0, 0, 0),
+        HV_ARM64_REGISTER_MIDR_EL1,
+    ),
+    (
+        SysReg::MpidrEl1,
+        SysRegEncoding::new(3, 0, 0, 0, 5),
+        HV_ARM64_REGISTER_MPIDR_EL1,
+    ),
+    (
+        SysReg::SctlrEl1,
+        SysRegEncoding::new(3, 0, 1, 0, 0),
+        HV_ARM64_REGISTER_SCTLR_EL1,
+    ),
+    (
+        SysReg::ActlrEl1,
+        SysRegEncoding::new(3, 0, 1, 0, 1),
+        HV_ARM64_REGISTER_ACTLR_EL1,
+    ),
+    (
+        SysReg::CpacrEl1,
+        SysRegEncoding::new(3, 0, 1, 0, 2),
+        HV_ARM64_REGISTER_CPACR_EL1,
+    ),
+    (
+        SysReg::Ttbr0El1,
+        SysRegEncoding::new(3, 0, 2, 0, 0),
+        HV_ARM64_REGISTER_TTBR0_EL1,
+    ),
+    (
+        SysReg::Ttbr1El1,
+        SysRegEncoding::new(3, 0, 2, 0, 1),
+        HV_ARM64_REGISTER_TTBR1_EL1,
+    ),
+    (
+        SysReg::TcrEl1,
+        SysRegEncoding::new(3, 0, 2, 0, 2),
+        HV_ARM64_REGISTER_TCR_EL1,
+    ),
+    (
+        SysReg::SpsrEl1,
+        SysRegEncoding::new(3, 0, 4, 0, 0),
+        HV_ARM64_REGISTER_SPSR_EL1,
+    ),
+    (
+        SysReg::ElrEl1,
+        SysRegEncoding::new(3, 0, 4, 0, 1),
+        HV_ARM64_REGISTER_ELR_EL1,
+    ),
+    (
+        SysReg::SpEl0,
+        SysRegEncoding::new(3, 0, 4, 1, 0),
+        HV_ARM64_REGISTER_SP_EL0,
+    ),
+    (
+        SysReg::EsrEl1,
+        SysRegEncoding::new(3, 0, 5, 2, 0),
+        HV_ARM64_REGISTER_ESR_EL1,
+    ),
+    (
+        SysReg::FarEl1,
+        SysRegEncoding::new(3, 0, 6, 0, 0),
+        HV_ARM64_REGISTER_FAR_EL1,
+    ),
+    (
+        SysReg::ParEl1,
+        SysRegEncoding::new(3, 0, 7, 4, 0),
+        HV_ARM64_REGISTER_PAR_EL1,
+    ),
+    (
+        SysReg::MairEl1,
+        SysRegEncoding::new(3, 0, 10, 2, 0),
+        HV_ARM64_REGISTER_MAIR_EL1,
+    ),
+    (
+        SysReg::VbarEl1,
+        SysRegEncoding::new(3, 0, 12, 0, 0),
+        HV_ARM64_REGISTER_VBAR_EL1,
+    ),
+    (
+        SysReg::ContextidrEl1,
+        SysRegEncoding::new(3, 0, 13, 0, 1),
+        HV_ARM64_REGISTER_CONTEXTIDR_EL1,
+    ),
+    (
+        SysReg::TpidrEl1,
+        SysRegEncoding::new(3, 0, 13, 0, 4),
+        HV_ARM64_REGISTER_TPIDR_EL1,
+    ),
+    (
+        SysReg::CntkctlEl1,
+        SysRegEncoding::new(3, 0, 14, 1, 0),
+        HV_ARM64_REGISTER_CNTKCTL_EL1,
+    ),
+    (
+        SysReg::TpidrEl0,
+        SysRegEncoding::new(3, 3, 13, 0, 2),
+        HV_ARM64_REGISTER_TPIDR_EL0,
+    ),
+    (
+        SysReg::TpidrroEl0,
+        SysRegEncoding::new(3, 3, 13, 0, 3),
+        HV_ARM64_REGISTER_TPIDRRO_EL0,
+    ),
+    (
+        SysReg::SpEl1,
+        SysRegEncoding::new(3, 4, 4, 1, 0),
+        HV_ARM64_REGISTER_SP_EL1,
+    ),
+];
+
+impl SysReg {
+    /// Architectural encoding of the register
+    pub fn encoding(&self) -> SysRegEncoding {
+        SYS_REGS.iter().find(|r| r.0 == *self).unwrap().1
+    }
+
+    /// Hypervisor name of the register
+    pub fn hv_reg_name(&self) -> hv_register_name {
+        SYS_REGS.iter().find(|r| r.0 == *self).unwrap().2
+    }
+}
+
+impl TryFrom<SysRegEncoding> for SysReg {
+    type Error = &'static str;
+
+    fn try_from(encoding: SysRegEncoding) -> Result<Self, Self::Error> {
+        SYS_REGS
+            .iter()
+            .find(|r| r.1 == encoding)
+            .map(|r| r.0)
+            .ok_or("Unsupported system register encoding")
+    }
+}
+
+/// Hypervisor register name of the system register with the given encoding.
+/// Fails for any encoding not listed in `SysReg`.
+pub fn sys_reg_to_hv_reg_name(encoding: SysRegEncoding) -> Result<hv_register_name, &'static str> {
+    SysReg::try_from(encoding).map(|r| r.hv_reg_name())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arm64_gp_reg_name() {
+        assert_eq!(arm64_gp_reg_name(0).unwrap(), HV_ARM64_REGISTER_X0);
+        assert_eq!(arm64_gp_reg_name(28).unwrap(), HV_ARM64_REGISTER_X28);
+        assert_eq!(arm64_gp_reg_name(29).unwrap(), HV_ARM64_REGISTER_FP);
+        assert_eq!(arm64_gp_reg_name(30).unwrap(), HV_ARM64_REGISTER_LR);
+        assert!(arm64_gp_reg_name(31).is_err());
+    }
+
+    #[test]
+    fn test_sys_reg_encoding() {
+        let vbar = SysRegEncoding::new(3, 0, 12, 0, 0);
+        assert_eq!(SysReg::try_from(vbar).unwrap(), SysReg::VbarEl1);
+        assert_eq!(SysReg::VbarEl1.encoding(), vbar);
+        assert_eq!(
+            sys_reg_to_hv_reg_name(vbar).unwrap(),
+            HV_ARM64_REGISTER_VBAR_EL1
+        );
+        assert!(sys_reg_to_hv_reg_name(SysRegEncoding::new(3, 7, 15, 15, 7)).is_err());
+        for (reg, encoding, name) in SYS_REGS.iter() {
+            assert_eq!(SysReg::try_from(*encoding).unwrap(), *reg);
+            assert_eq!(reg.hv_reg_name(), *name);
+        }
+    }
+}
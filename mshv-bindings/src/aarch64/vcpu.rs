@@ -0,0 +1,128 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+use super::regs::{StandardRegisters, SystemRegisters};
+#[cfg(feature = "with-serde")]
+use serde_derive::{Deserialize, Serialize};
+
+// Bit indexes in the two banks of the arm64 processor features. Unlike KVM,
+// these are set for the whole partition, at creation, by disabling them.
+pub const HV_ARM64_PROCESSOR_FEATURE_PMU_V3: u32 = 16;
+pub const HV_ARM64_PROCESSOR_FEATURE_SVE: u32 = 64 + 37;
+
+/// Bit 31 of MPIDR_EL1 is RES1
+pub const MPIDR_EL1_RES1: u64 = 1 << 31;
+/// vCPUs per cluster in `mpidr_for_vcpu`, the most a GICv3 SGI target list
+/// can address
+pub const MPIDR_VCPUS_PER_CLUSTER: u32 = 16;
+
+// PSTATE bits
+pub const PSR_MODE_EL1H: u64 = 0x5;
+pub const PSR_F_BIT: u64 = 1 << 6;
+pub const PSR_I_BIT: u64 = 1 << 7;
+pub const PSR_A_BIT: u64 = 1 << 8;
+pub const PSR_D_BIT: u64 = 1 << 9;
+/// EL1h with all exceptions masked, as vCPUs come out of reset
+pub const PSTATE_RESET: u64 = PSR_MODE_EL1H | PSR_F_BIT | PSR_I_BIT | PSR_A_BIT | PSR_D_BIT;
+/// SCTLR_EL1 with the MMU and caches off, the value KVM resets it to
+pub const SCTLR_EL1_RESET: u64 = 0x00c5_0078;
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+/// Optional processor features exposed to the vCPUs of a partition
+pub struct VcpuFeatures {
+    /// PMUv3, whose overflow interrupt is `GicConfig::pmu_ppi`
+    pub pmu: bool,
+    /// Scalable Vector Extension
+    pub sve: bool,
+}
+
+impl VcpuFeatures {
+    /// Value of `disabled_processor_features` at partition creation
+    pub fn disabled_processor_features(&self) -> [u64; 2] {
+        let mut banks = [0u64; 2];
+        for (enabled, bit) in [
+            (self.pmu, HV_ARM64_PROCESSOR_FEATURE_PMU_V3),
+            (self.sve, HV_ARM64_PROCESSOR_FEATURE_SVE),
+        ] {
+            if !enabled {
+                banks[(bit / 64) as usize] |= 1 << (bit % 64);
+            }
+        }
+        banks
+    }
+}
+
+/// MPIDR_EL1 value with the given affinity levels
+pub fn mpidr(aff3: u8, aff2: u8, aff1: u8, aff0: u8) -> u64 {
+    MPIDR_EL1_RES1 | (aff3 as u64) << 32 | (aff2 as u64) << 16 | (aff1 as u64) << 8 | aff0 as u64
+}
+
+/// MPIDR_EL1 value of vCPU `vp_index` in a flat topology: clusters of
+/// `MPIDR_VCPUS_PER_CLUSTER` vCPUs, as KVM assigns them
+pub fn mpidr_for_vcpu(vp_index: u32) -> u64 {
+    let cluster = vp_index / MPIDR_VCPUS_PER_CLUSTER;
+    mpidr(
+        0,
+        (cluster >> 8) as u8,
+        cluster as u8,
+        (vp_index % MPIDR_VCPUS_PER_CLUSTER) as u8,
+    )
+}
+
+impl StandardRegisters {
+    /// Registers of a vCPU entering a Linux kernel at `pc`, per the arm64
+    /// boot protocol: X0 holds the device tree address, the other
+    /// registers are zero and all exceptions are masked.
+    pub fn boot(pc: u64, fdt_addr: u64) -> Self {
+        let mut regs = StandardRegisters {
+            pc,
+            pstate: PSTATE_RESET,
+            ..Default::default()
+        };
+        regs.regs[0] = fdt_addr;
+        regs
+    }
+}
+
+impl SystemRegisters {
+    /// EL1 system registers of a vCPU out of reset
+    pub fn reset() -> Self {
+        SystemRegisters {
+            sctlr_el1: SCTLR_EL1_RESET,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mpidr() {
+        assert_eq!(mpidr_for_vcpu(0), 0x8000_0000);
+        assert_eq!(mpidr_for_vcpu(17), 0x8000_0101);
+        assert_eq!(mpidr_for_vcpu(16 * 256 + 3), 0x8001_0003);
+        assert_eq!(mpidr(1, 0, 0, 2), 0x1_8000_0002);
+    }
+
+    #[test]
+    fn test_vcpu_features() {
+        let features = VcpuFeatures {
+            pmu: true,
+            sve: true,
+        };
+        assert_eq!(features.disabled_processor_features(), [0, 0]);
+        assert_eq!(
+            VcpuFeatures::default().disabled_processor_features(),
+            [1 << 16, 1 << 37]
+        );
+
+        let regs = StandardRegisters::boot(0x4008_0000, 0x4000_0000);
+        assert!(regs.regs[0] == 0x4000_0000 && regs.regs[1] == 0);
+        assert_eq!(regs.pstate, 0x3c5);
+    }
+}
@@ -439,6 +439,10 @@ pub const MSHV_CAP_CORE_API_STABLE: u32 = 0;
 pub const MSHV_CAP_REGISTER_PAGE: u32 = 1;
 pub const MSHV_CAP_VTL_RETURN_ACTION: u32 = 2;
 pub const MSHV_CAP_DR6_SHARED: u32 = 3;
+pub const MSHV_VP_MMAP_OFFSET_REGISTERS: u32 = 0;
+pub const MSHV_VP_MMAP_OFFSET_INTERCEPT_MESSAGE: u32 = 1;
+pub const MSHV_VP_MMAP_OFFSET_GHCB: u32 = 2;
+pub const MSHV_VP_MMAP_OFFSET_COUNT: u32 = 3;
 pub const MAX_RUN_MSG_SIZE: u32 = 256;
 pub const MSHV_VP_MAX_REGISTERS: u32 = 128;
 pub const MSHV_IRQFD_FLAG_DEASSIGN: u32 = 1;
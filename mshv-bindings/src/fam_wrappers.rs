@@ -4,6 +4,7 @@
 //
 use vmm_sys_util::fam::{FamStruct, FamStructWrapper};
 
+use crate::bindings::*;
 use crate::regs::*;
 
 pub const MAX_MSR_ENTRIES: usize = 256;
@@ -28,3 +29,17 @@ generate_fam_struct_impl!(
 generate_fam_struct_impl!(msr_list, u32, indices, u32, nmsrs, MAX_MSR_ENTRIES);
 
 pub type MsrList = FamStructWrapper<msr_list>;
+
+/// Maximum number of MSI routes the kernel accepts.
+pub const MSHV_MAX_MSI_ROUTES: usize = 4096;
+
+generate_fam_struct_impl!(
+    mshv_msi_routing,
+    mshv_msi_routing_entry,
+    entries,
+    u32,
+    nr,
+    MSHV_MAX_MSI_ROUTES
+);
+
+pub type MsiRouting = FamStructWrapper<mshv_msi_routing>;
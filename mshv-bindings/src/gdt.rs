@@ -0,0 +1,214 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+// Minimal GDT and IDT for guests started directly in 64-bit mode.
+//
+
+use crate::regs::{SegmentRegister, SpecialRegisters, TableRegister};
+use vmm_sys_util::errno;
+
+/// Selector of the 64-bit code segment in `BootGdt`
+pub const BOOT_GDT_CODE64_SELECTOR: u16 = 0x08;
+/// Selector of the data segment in `BootGdt`
+pub const BOOT_GDT_DATA_SELECTOR: u16 = 0x10;
+/// Selector of the TSS in `BootGdt`
+pub const BOOT_GDT_TSS_SELECTOR: u16 = 0x18;
+/// Size of the boot IDT, a single null gate
+pub const BOOT_IDT_SIZE: usize = 8;
+
+/// Encodes a segment descriptor. `flags` holds descriptor bits 40-55: the
+/// access byte in bits 0-7 and G, D/B, L and AVL in bits 15-12.
+pub fn gdt_entry(flags: u16, base: u32, limit: u32) -> u64 {
+    ((base as u64 & 0xff00_0000) << 32)
+        | ((flags as u64 & 0xf0ff) << 40)
+        | ((limit as u64 & 0xf_0000) << 32)
+        | ((base as u64 & 0x00ff_ffff) << 16)
+        | (limit as u64 & 0xffff)
+}
+
+/// Decodes the descriptor `entry` as loaded through `selector`. The limit
+/// is scaled to bytes when the descriptor is page granular.
+pub fn segment_from_gdt_entry(entry: u64, selector: u16) -> SegmentRegister {
+    let bit = |n: u32| ((entry >> n) & 1) as u8;
+    let g = bit(55);
+    let mut limit = ((entry & 0xffff) | ((entry >> 32) & 0xf_0000)) as u32;
+    if g != 0 {
+        limit = (limit << 12) | 0xfff;
+    }
+    SegmentRegister {
+        base: ((entry >> 16) & 0x00ff_ffff) | ((entry >> 32) & 0xff00_0000),
+        limit,
+        selector,
+        type_: ((entry >> 40) & 0xf) as u8,
+        present: bit(47),
+        dpl: ((entry >> 45) & 0x3) as u8,
+        db: bit(54),
+        s: bit(44),
+        l: bit(53),
+        g,
+        avl: bit(52),
+        unusable: bit(47) ^ 1,
+        padding: 0,
+    }
+}
+
+fn write_guest(mem: &mut [u8], gpa: u64, data: &[u8]) -> Result<(), errno::Error> {
+    let start = gpa as usize;
+    let dst = start
+        .checked_add(data.len())
+        .and_then(|end| mem.get_mut(start..end))
+        .ok_or_else(|| errno::Error::new(libc::EINVAL))?;
+    dst.copy_from_slice(data);
+    Ok(())
+}
+
+/// The GDT a 64-bit guest is started with: a null descriptor, a 64-bit code
+/// segment, a flat data segment and a 64-bit TSS, at the
+/// `BOOT_GDT_*_SELECTOR` selectors.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BootGdt {
+    // The TSS descriptor takes two entries
+    entries: [u64; 5],
+}
+
+impl BootGdt {
+    /// Builds the GDT for a TSS of `tss_limit + 1` bytes at `tss_base`.
+    /// The guest is expected to load its own TSS before relying on it.
+    pub fn new(tss_base: u64, tss_limit: u32) -> Self {
+        BootGdt {
+            entries: [
+                0,
+                gdt_entry(0xa09b, 0, 0xfffff),
+                gdt_entry(0xc093, 0, 0xfffff),
+                gdt_entry(0x008b, tss_base as u32, tss_limit),
+                tss_base >> 32,
+            ],
+        }
+    }
+
+    pub fn entries(&self) -> &[u64] {
+        &self.entries
+    }
+
+    /// Size of the GDT in bytes
+    pub fn size(&self) -> usize {
+        self.entries.len() * 8
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.entries
+            .iter()
+            .flat_map(|entry| entry.to_le_bytes().to_vec())
+            .collect()
+    }
+
+    /// Writes the GDT at `gpa` of `mem`, the guest memory starting at GPA 0.
+    /// Fails with EINVAL if it does not fit.
+    pub fn write_to(&self, mem: &mut [u8], gpa: u64) -> Result<(), errno::Error> {
+        write_guest(mem, gpa, &self.to_bytes())
+    }
+
+    /// GDTR value for the GDT written at `gpa`
+    pub fn table_register(&self, gpa: u64) -> TableRegister {
+        TableRegister {
+            base: gpa,
+            limit: self.size() as u16 - 1,
+        }
+    }
+
+    pub fn code_segment(&self) -> SegmentRegister {
+        segment_from_gdt_entry(self.entries[1], BOOT_GDT_CODE64_SELECTOR)
+    }
+
+    pub fn data_segment(&self) -> SegmentRegister {
+        segment_from_gdt_entry(self.entries[2], BOOT_GDT_DATA_SELECTOR)
+    }
+
+    pub fn tss_segment(&self) -> SegmentRegister {
+        let mut tss = segment_from_gdt_entry(self.entries[3], BOOT_GDT_TSS_SELECTOR);
+        tss.base |= self.entries[4] << 32;
+        tss
+    }
+
+    /// Loads the GDT written at `gpa` into `sregs`: GDTR, CS, the data
+    /// segment registers and TR. The LDT is marked unusable.
+    pub fn apply(&self, sregs: &mut SpecialRegisters, gpa: u64) {
+        let data = self.data_segment();
+        sregs.gdt = self.table_register(gpa);
+        sregs.cs = self.code_segment();
+        sregs.ds = data;
+        sregs.es = data;
+        sregs.fs = data;
+        sregs.gs = data;
+        sregs.ss = data;
+        sregs.tr = self.tss_segment();
+        sregs.ldt = SegmentRegister {
+            type_: 2,
+            unusable: 1,
+            ..Default::default()
+        };
+    }
+}
+
+/// Writes an IDT holding a single null gate at `gpa` of `mem`, the guest
+/// memory starting at GPA 0. Fails with EINVAL if it does not fit.
+pub fn write_boot_idt(mem: &mut [u8], gpa: u64) -> Result<(), errno::Error> {
+    write_guest(mem, gpa, &[0; BOOT_IDT_SIZE])
+}
+
+/// IDTR value for the IDT written by `write_boot_idt` at `gpa`
+pub fn boot_idt_register(gpa: u64) -> TableRegister {
+    TableRegister {
+        base: gpa,
+        limit: BOOT_IDT_SIZE as u16 - 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gdt_entry() {
+        assert_eq!(gdt_entry(0xa09b, 0, 0xfffff), 0x00af_9b00_0000_ffff);
+        assert_eq!(gdt_entry(0xc093, 0, 0xfffff), 0x00cf_9300_0000_ffff);
+        assert_eq!(gdt_entry(0x008b, 0x1234_5678, 0x67), 0x1200_8b34_5678_0067);
+
+        let seg = segment_from_gdt_entry(gdt_entry(0x008b, 0x1234_5678, 0x67), 0x18);
+        assert_eq!(seg.base, 0x1234_5678);
+        assert_eq!(seg.limit, 0x67);
+        assert_eq!((seg.type_, seg.s, seg.present, seg.g), (11, 0, 1, 0));
+        assert_eq!(segment_from_gdt_entry(0, 0).unusable, 1);
+    }
+
+    #[test]
+    fn test_boot_gdt() {
+        let gdt = BootGdt::new(0x1_0000_2000, 0x67);
+        let code = gdt.code_segment();
+        assert_eq!(code.selector, BOOT_GDT_CODE64_SELECTOR);
+        assert_eq!(code.limit, 0xffff_ffff);
+        assert_eq!((code.type_, code.l, code.db, code.g), (11, 1, 0, 1));
+        let data = gdt.data_segment();
+        assert_eq!((data.type_, data.l, data.db, data.dpl), (3, 0, 1, 0));
+        assert_eq!(gdt.tss_segment().base, 0x1_0000_2000);
+
+        let mut sregs = SpecialRegisters::default();
+        gdt.apply(&mut sregs, 0x500);
+        assert_eq!((sregs.gdt.base, sregs.gdt.limit), (0x500, 39));
+        assert_eq!(sregs.ss.selector, BOOT_GDT_DATA_SELECTOR);
+        assert_eq!(sregs.tr.selector, BOOT_GDT_TSS_SELECTOR);
+
+        let mut mem = vec![0xffu8; 0x1000];
+        gdt.write_to(&mut mem, 0x500).unwrap();
+        assert_eq!(mem[0x500..0x508], [0; 8]);
+        assert_eq!(mem[0x508..0x510], 0x00af_9b00_0000_ffffu64.to_le_bytes());
+        assert_eq!(mem[0x520..0x528], 1u64.to_le_bytes());
+        assert!(gdt.write_to(&mut mem, 0xff0).is_err());
+
+        write_boot_idt(&mut mem, 0x520).unwrap();
+        assert_eq!(mem[0x520..0x528], [0; 8]);
+        assert_eq!(boot_idt_register(0x520).limit, 7);
+        assert!(write_boot_idt(&mut mem, u64::MAX).is_err());
+    }
+}
@@ -0,0 +1,232 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+//! Golden files pinning the serialized formats of the state structs.
+//!
+//! Every test serializes a fixed value, compares the result with the file of
+//! the same name in `tests/golden` and reads the file back. A mismatch means
+//! that state saved by an earlier release no longer loads, or loads
+//! differently.
+//!
+//! Files of the current formats are regenerated by running the tests with
+//! `MSHV_BLESS_GOLDEN=1`, which is only right after bumping the version of
+//! the format that changed. The versioned files are named after their
+//! version, so bumping it leaves the file of the previous version, written
+//! by the code of that version, in place. Those are never rewritten: they
+//! must keep loading through `VcpuSnapshot::upgrade` and
+//! `VpStateBlob::from_bytes`.
+
+use crate::aarch64;
+use crate::regs::*;
+use crate::snapshot::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::env;
+use std::fs;
+use std::mem::size_of;
+use std::path::PathBuf;
+use zerocopy::{AsBytes, FromBytes};
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(name)
+}
+
+fn blessing() -> bool {
+    env::var_os("MSHV_BLESS_GOLDEN").is_some()
+}
+
+fn read_golden(name: &str) -> Vec<u8> {
+    fs::read(golden_path(name)).unwrap_or_else(|e| {
+        panic!(
+            "{}: {}, write it by running the test with MSHV_BLESS_GOLDEN=1",
+            name, e
+        )
+    })
+}
+
+/// Checks that golden file `name` holds `data`, after rewriting it when
+/// blessing
+fn check_current(name: &str, data: &[u8]) -> Vec<u8> {
+    if blessing() {
+        fs::write(golden_path(name), data).unwrap();
+    }
+    let golden = read_golden(name);
+    assert!(
+        golden == data,
+        "{} changed, state saved by earlier releases will not load the same",
+        name
+    );
+    golden
+}
+
+fn check_json<T: Serialize + DeserializeOwned>(name: &str, value: &T) {
+    let json = serde_json::to_string(value).unwrap();
+    let golden = check_current(&format!("{}.json", name), json.as_bytes());
+    let read: T = serde_json::from_slice(&golden).unwrap();
+    assert!(
+        serde_json::to_string(&read).unwrap() == json,
+        "{}.json does not round-trip",
+        name
+    );
+}
+
+fn check_bytes<T: AsBytes + FromBytes>(name: &str, value: &T) {
+    let golden = check_current(&format!("{}.bin", name), value.as_bytes());
+    let read = T::read_from(&golden[..]).unwrap();
+    assert!(read.as_bytes() == value.as_bytes());
+}
+
+/// Value of `T` with every byte set, different for each `seed`
+fn pattern<T: FromBytes>(seed: u8) -> T {
+    let bytes: Vec<u8> = (0..size_of::<T>())
+        .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+        .collect();
+    T::read_from(&bytes[..]).unwrap()
+}
+
+fn vcpu_snapshot() -> VcpuSnapshot {
+    let msrs = vec![msr_entry {
+        index: IA32_MSR_EFER,
+        data: 0xd01,
+        ..Default::default()
+    }];
+    let mut snapshot = VcpuSnapshot {
+        regs: pattern(1),
+        sregs: pattern(2),
+        fpu: pattern(3),
+        debug_regs: pattern(4),
+        msrs,
+        vcpu_events: pattern(5),
+        xcrs: pattern(6),
+        misc: pattern(7),
+        // What the 2 -> 3 upgrade moves out of the MSRs
+        mtrrs: pattern(10),
+        ..Default::default()
+    };
+    for (i, offset) in [0, 0x1000, 0x4000, 0x5000, 0x6000].iter().enumerate() {
+        snapshot.vp_states.buffer[*offset] = 0xa0 + i as u8;
+    }
+    snapshot
+}
+
+#[test]
+fn test_golden_registers() {
+    check_json("standard_registers", &pattern::<StandardRegisters>(1));
+    check_bytes("standard_registers", &pattern::<StandardRegisters>(1));
+    check_json("special_registers", &pattern::<SpecialRegisters>(2));
+    check_json("floating_point_unit", &pattern::<FloatingPointUnit>(3));
+    check_bytes("floating_point_unit", &pattern::<FloatingPointUnit>(3));
+    check_json("debug_registers", &pattern::<DebugRegisters>(4));
+    check_bytes("debug_registers", &pattern::<DebugRegisters>(4));
+    check_json("msr_entry", &pattern::<msr_entry>(5));
+    check_json("vcpu_events", &pattern::<VcpuEvents>(6));
+    check_bytes("vcpu_events", &pattern::<VcpuEvents>(6));
+    check_json("xcrs", &pattern::<Xcrs>(7));
+    check_json("misc_regs", &pattern::<MiscRegs>(8));
+    check_json("suspend_registers", &pattern::<SuspendRegisters>(9));
+    check_json("mtrr_state", &pattern::<MtrrState>(10));
+    check_bytes("mtrr_state", &pattern::<MtrrState>(10));
+    check_json("lapic_state", &pattern::<LapicState>(11));
+    check_json("xsave", &pattern::<XSave>(12));
+    check_json(
+        "aarch64_standard_registers",
+        &pattern::<aarch64::StandardRegisters>(13),
+    );
+    check_bytes(
+        "aarch64_standard_registers",
+        &pattern::<aarch64::StandardRegisters>(13),
+    );
+    check_json(
+        "aarch64_system_registers",
+        &pattern::<aarch64::SystemRegisters>(14),
+    );
+}
+
+#[test]
+fn test_golden_vp_state() {
+    check_json(
+        "tsc_state",
+        &TscState {
+            frequency: 2_400_000_000,
+            tsc: 0x1234_5678_9abc,
+            tsc_adjust: 0x100,
+        },
+    );
+    let mut timers = SyntheticTimersState::default();
+    for (i, timer) in timers.timers.iter_mut().enumerate() {
+        *timer = SyntheticTimerState {
+            config: 0x10001 | (i as u64) << 16,
+            count: 10_000 * (i as u64 + 1),
+            adjustment: i as u64,
+            undelivered_exp_time: 0x1000 + i as u64,
+            undelivered_msg_pending: i % 2 == 1,
+        };
+    }
+    check_json("synthetic_timers_state", &timers);
+    check_json(
+        "guest_crash",
+        &GuestCrash {
+            params: [1, 2, 3, 4, 5],
+            ctl: 1 << 63,
+        },
+    );
+    check_json(
+        "vp_state_component",
+        &VpStateComponent {
+            type_: 7,
+            data: vec![0x5a; 16],
+        },
+    );
+}
+
+#[test]
+fn test_golden_vcpu_snapshot() {
+    let snapshot = vcpu_snapshot();
+    check_json(
+        &format!("vcpu_snapshot_v{}", VCPU_SNAPSHOT_VERSION),
+        &snapshot,
+    );
+    let json = serde_json::to_string(&snapshot).unwrap();
+    for version in 1..VCPU_SNAPSHOT_VERSION {
+        let name = format!("vcpu_snapshot_v{}.json", version);
+        let golden = read_golden(&name);
+        let mut old: VcpuSnapshot = serde_json::from_slice(&golden).unwrap();
+        assert!(old.version == version);
+        old.upgrade().unwrap();
+        assert!(
+            serde_json::to_string(&old).unwrap() == json,
+            "{} upgrades to a different snapshot",
+            name
+        );
+    }
+}
+
+#[test]
+fn test_golden_vm_snapshot() {
+    check_json(
+        &format!("vm_snapshot_v{}", VM_SNAPSHOT_VERSION),
+        &VmSnapshot::default(),
+    );
+}
+
+#[test]
+fn test_golden_vp_state_blob() {
+    let snapshot = vcpu_snapshot();
+    let name = format!("vp_state_blob_v{}", VP_STATE_BLOB_VERSION);
+    check_bytes(&name, &VpStateBlob::new(&snapshot).unwrap());
+    let json = serde_json::to_string(&snapshot).unwrap();
+    for version in 1..=VP_STATE_BLOB_VERSION {
+        let name = format!("vp_state_blob_v{}.bin", version);
+        let read = VpStateBlob::from_bytes(&read_golden(&name))
+            .unwrap()
+            .to_snapshot();
+        assert!(
+            serde_json::to_string(&read).unwrap() == json,
+            "{} reads as a different snapshot",
+            name
+        );
+    }
+}
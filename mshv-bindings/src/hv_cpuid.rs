@@ -0,0 +1,280 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use crate::bindings::*;
+use crate::hvdef::*;
+use crate::regs::hv_cpuid_entry;
+
+/// "Hv#1", the Hyper-V interface signature
+pub const HV_CPUID_INTERFACE_SIGNATURE_HV1: u32 = 0x31237648;
+
+/// Default spinlock retries, meaning the guest should never notify
+pub const HV_SPINLOCK_RETRIES_NEVER_NOTIFY: u32 = 0xffffffff;
+
+// Synthetic processor feature bits in HV_PARTITION_PRIVILEGE_* order
+const PRIVILEGE_FEATURES: [(u64, u64); 14] = [
+    (2, HV_PARTITION_PRIVILEGE_ACCESS_VP_RUNTIME_MSR),
+    (3, HV_PARTITION_PRIVILEGE_PARTITION_REFERENCE_COUNTER),
+    (4, HV_PARTITION_PRIVILEGE_SYNIC_MSRS),
+    (5, HV_PARTITION_PRIVILEGE_ACCESS_SYNTHETIC_TIMER_MSRS),
+    (6, HV_PARTITION_PRIVILEGE_ACCESS_APIC_MSRS),
+    (7, HV_PARTITION_PRIVILEGE_ACCESS_HYPERCALL_MSRS),
+    (8, HV_PARTITION_PRIVILEGE_ACCESS_VP_INDEX),
+    (9, HV_PARTITION_PRIVILEGE_ACCESS_PARTITION_REFERENCE_TSC),
+    (10, HV_PARTITION_PRIVILEGE_ACCESS_GUEST_IDLE_MSR),
+    (11, HV_PARTITION_PRIVILEGE_ACCESS_FREQUENCY_MSRS),
+    (
+        15,
+        HV_PARTITION_PRIVILEGE_ENABLE_EXTENDED_GVA_RANGES_FLUSH_VA_LIST,
+    ),
+    (18, HV_PARTITION_PRIVILEGE_FAST_HYPERCALL_OUTPUT),
+    (20, HV_PARTITION_PRIVILEGE_START_VIRTUAL_PROCESSOR),
+    (29, HV_PARTITION_PRIVILEGE_SIGNAL_EVENTS),
+];
+
+// Synthetic processor feature bits advertised in CPUID 0x40000003 EDX
+const FEATURE_FLAGS: [(u64, u32); 4] = [
+    (11, HV_FEATURE_FREQUENCY_REGS_AVAILABLE),
+    (
+        15,
+        HV_FEATURE_EXTENDED_GVA_RANGES_FOR_FLUSH_VIRTUAL_ADDRESS_LIST_AVAILABLE,
+    ),
+    (18, HV_FEATURE_FAST_HYPERCALL_OUTPUT_AVAILABLE),
+    (22, HV_FEATURE_DIRECT_SYNTHETIC_TIMERS),
+];
+
+// Recommendations only make sense if the partition has the feature behind
+// them, keyed by synthetic processor feature bit
+const RECOMMENDATION_FEATURES: [(u32, u64); 7] = [
+    (HV_ENLIGHTENMENT_USE_APIC_MSRS, 6),
+    (HV_ENLIGHTENMENT_USE_X2_APIC_MSRS, 6),
+    (HV_ENLIGHTENMENT_USE_HYPERCALL_FOR_LOCAL_FLUSH, 25),
+    (
+        HV_ENLIGHTENMENT_USE_HYPERCALL_FOR_REMOTE_FLUSH_AND_LOCAL_FLUSH_ENTIRE,
+        25,
+    ),
+    (HV_ENLIGHTENMENT_USE_EX_PROCESSOR_MASKS, 24),
+    (HV_ENLIGHTENMENT_USE_SYNTHETIC_CLUSTER_IPI, 26),
+    (HV_ENLIGHTENMENT_USE_VMCS_ENLIGHTENMENTS, 32),
+];
+
+/// Builder for the Hyper-V CPUID leaves 0x40000000 to 0x4000000A.
+///
+/// The privileges and feature flags come from the partition's synthetic
+/// processor features, the rest from the VMM. Recommendations that depend on
+/// a feature the partition lacks are dropped, so the guest is never told to
+/// use an interface that would fault.
+#[derive(Clone, Debug)]
+pub struct HvCpuidBuilder {
+    features: u64,
+    build_number: u32,
+    major_version: u16,
+    minor_version: u16,
+    recommendations: u32,
+    spinlock_retries: u32,
+    max_virtual_processors: u32,
+    max_logical_processors: u32,
+    nested: Option<hv_cpuid_entry>,
+}
+
+impl HvCpuidBuilder {
+    /// Creates a builder for a partition with the given synthetic processor
+    /// features.
+    pub fn new(features: hv_partition_synthetic_processor_features) -> HvCpuidBuilder {
+        HvCpuidBuilder {
+            // SAFETY: access union fields
+            features: unsafe { features.as_uint64[0] },
+            build_number: 0,
+            major_version: 0,
+            minor_version: 0,
+            recommendations: 0,
+            spinlock_retries: HV_SPINLOCK_RETRIES_NEVER_NOTIFY,
+            max_virtual_processors: 0,
+            max_logical_processors: 0,
+            nested: None,
+        }
+    }
+    fn has_feature(&self, bit: u64) -> bool {
+        self.features & (1 << bit) != 0
+    }
+    /// Sets the hypervisor version reported in 0x40000002
+    pub fn set_version(mut self, build_number: u32, major: u16, minor: u16) -> HvCpuidBuilder {
+        self.build_number = build_number;
+        self.major_version = major;
+        self.minor_version = minor;
+        self
+    }
+    /// Sets the HV_ENLIGHTENMENT_* recommendations reported in 0x40000004
+    pub fn set_recommendations(mut self, recommendations: u32) -> HvCpuidBuilder {
+        self.recommendations = recommendations;
+        self
+    }
+    /// Sets the number of spinlock retries before the guest should notify
+    /// the hypervisor
+    pub fn set_spinlock_retries(mut self, retries: u32) -> HvCpuidBuilder {
+        self.spinlock_retries = retries;
+        self
+    }
+    /// Sets the processor limits reported in 0x40000005
+    pub fn set_limits(mut self, max_virtual: u32, max_logical: u32) -> HvCpuidBuilder {
+        self.max_virtual_processors = max_virtual;
+        self.max_logical_processors = max_logical;
+        self
+    }
+    /// Adds the nested features leaf 0x4000000A
+    pub fn set_nested(mut self, leaf: hv_cpuid_entry) -> HvCpuidBuilder {
+        self.nested = Some(leaf);
+        self
+    }
+    /// Returns the partition privilege mask reported in 0x40000003
+    pub fn privileges(&self) -> u64 {
+        PRIVILEGE_FEATURES
+            .iter()
+            .filter(|(bit, _)| self.has_feature(*bit))
+            .fold(0, |mask, (_, privilege)| mask | privilege)
+    }
+    /// Returns the recommendations the partition can honor
+    pub fn recommendations(&self) -> u32 {
+        let mut ret = self.recommendations;
+        for (recommendation, bit) in RECOMMENDATION_FEATURES {
+            if !self.has_feature(bit) {
+                ret &= !recommendation;
+            }
+        }
+        ret
+    }
+    /// Builds the leaves, or nothing if the partition does not report a
+    /// hypervisor as present
+    pub fn build(&self) -> Vec<hv_cpuid_entry> {
+        if !self.has_feature(0) {
+            return Vec::new();
+        }
+        let leaf = |function, eax, ebx, ecx, edx| hv_cpuid_entry {
+            function,
+            eax,
+            ebx,
+            ecx,
+            edx,
+            ..Default::default()
+        };
+        let max_function = match self.nested {
+            Some(_) => HV_CPUID_NESTED_FEATURES,
+            None => HV_CPUID_FUNCTION_MS_HV_HARDWARE_FEATURES,
+        };
+        let interface = if self.has_feature(1) {
+            HV_CPUID_INTERFACE_SIGNATURE_HV1
+        } else {
+            0
+        };
+        let privileges = self.privileges();
+        let features = FEATURE_FLAGS
+            .iter()
+            .filter(|(bit, _)| self.has_feature(*bit))
+            .fold(0, |flags, (_, flag)| flags | flag);
+
+        let mut ret = vec![
+            leaf(
+                HV_CPUID_FUNCTION_HV_VENDOR_AND_MAX_FUNCTION,
+                max_function,
+                u32::from_le_bytes(*b"Micr"),
+                u32::from_le_bytes(*b"osof"),
+                u32::from_le_bytes(*b"t Hv"),
+            ),
+            leaf(HV_CPUID_FUNCTION_HV_INTERFACE, interface, 0, 0, 0),
+            leaf(
+                HV_CPUID_FUNCTION_MS_HV_VERSION,
+                self.build_number,
+                (self.major_version as u32) << 16 | self.minor_version as u32,
+                0,
+                0,
+            ),
+            leaf(
+                HV_CPUID_FUNCTION_MS_HV_FEATURES,
+                privileges as u32,
+                (privileges >> 32) as u32,
+                0,
+                features,
+            ),
+            leaf(
+                HV_CPUID_FUNCTION_MS_HV_ENLIGHTENMENT_INFORMATION,
+                self.recommendations(),
+                self.spinlock_retries,
+                0,
+                0,
+            ),
+            leaf(
+                HV_CPUID_FUNCTION_MS_HV_IMPLEMENTATION_LIMITS,
+                self.max_virtual_processors,
+                self.max_logical_processors,
+                0,
+                0,
+            ),
+            leaf(HV_CPUID_FUNCTION_MS_HV_HARDWARE_FEATURES, 0, 0, 0, 0),
+        ];
+        if let Some(nested) = self.nested {
+            ret.push(nested);
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hv_cpuid_builder() {
+        let mut features = hv_partition_synthetic_processor_features::default();
+        // SAFETY: access union fields
+        unsafe {
+            features.__bindgen_anon_1.set_hypervisor_present(1);
+            features.__bindgen_anon_1.set_hv1(1);
+            features.__bindgen_anon_1.set_access_synic_regs(1);
+            features.__bindgen_anon_1.set_access_intr_ctrl_regs(1);
+            features.__bindgen_anon_1.set_direct_synthetic_timers(1);
+        }
+        let leaves = HvCpuidBuilder::new(features)
+            .set_version(20348, 10, 0)
+            .set_recommendations(
+                HV_ENLIGHTENMENT_USE_APIC_MSRS | HV_ENLIGHTENMENT_USE_SYNTHETIC_CLUSTER_IPI,
+            )
+            .set_limits(64, 64)
+            .build();
+
+        assert_eq!(leaves.len(), 7);
+        assert_eq!(
+            leaves[0].function,
+            HV_CPUID_FUNCTION_HV_VENDOR_AND_MAX_FUNCTION
+        );
+        assert_eq!(leaves[0].eax, HV_CPUID_FUNCTION_MS_HV_HARDWARE_FEATURES);
+        let vendor: Vec<u8> = [leaves[0].ebx, leaves[0].ecx, leaves[0].edx]
+            .iter()
+            .flat_map(|r| r.to_le_bytes())
+            .collect();
+        assert_eq!(&vendor, b"Microsoft Hv");
+        assert_eq!(leaves[1].eax, HV_CPUID_INTERFACE_SIGNATURE_HV1);
+        assert_eq!(leaves[2].ebx, 10 << 16);
+        assert_eq!(
+            leaves[3].eax as u64,
+            HV_PARTITION_PRIVILEGE_SYNIC_MSRS | HV_PARTITION_PRIVILEGE_ACCESS_APIC_MSRS
+        );
+        assert_eq!(leaves[3].edx, HV_FEATURE_DIRECT_SYNTHETIC_TIMERS);
+        // No synthetic cluster IPI for this partition
+        assert_eq!(leaves[4].eax, HV_ENLIGHTENMENT_USE_APIC_MSRS);
+        assert_eq!(leaves[4].ebx, HV_SPINLOCK_RETRIES_NEVER_NOTIFY);
+        assert_eq!(leaves[5].eax, 64);
+
+        let nested = hv_cpuid_entry {
+            function: HV_CPUID_NESTED_FEATURES,
+            eax: HV_NESTED_EVMCS_VERSION,
+            ..Default::default()
+        };
+        let leaves = HvCpuidBuilder::new(features).set_nested(nested).build();
+        assert_eq!(leaves[0].eax, HV_CPUID_NESTED_FEATURES);
+        assert_eq!(leaves[7], nested);
+
+        let features = hv_partition_synthetic_processor_features::default();
+        assert!(HvCpuidBuilder::new(features).build().is_empty());
+    }
+}
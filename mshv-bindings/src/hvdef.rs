@@ -14,6 +14,10 @@ pub const HV_CPUID_FUNCTION_MS_HV_ENLIGHTENMENT_INFORMATION: u32 = 0x40000004;
 pub const HV_CPUID_FUNCTION_MS_HV_IMPLEMENTATION_LIMITS: u32 = 0x40000005;
 pub const HV_CPUID_FUNCTION_MS_HV_HARDWARE_FEATURES: u32 = 0x40000006;
 
+// CPUID leaf 0x80000007, advanced power management (EDX)
+pub const CPUID_FUNCTION_ADVANCED_POWER_MANAGEMENT: u32 = 0x80000007;
+pub const CPUID_APM_INVARIANT_TSC: u32 = 1 << 8;
+
 pub const HV_PARTITION_PRIVILEGE_ACCESS_VP_RUNTIME_MSR: u64 = 0x0000000000000001;
 pub const HV_PARTITION_PRIVILEGE_PARTITION_REFERENCE_COUNTER: u64 = 0x0000000000000002;
 pub const HV_PARTITION_PRIVILEGE_SYNIC_MSRS: u64 = 0x0000000000000004;
@@ -51,6 +55,18 @@ pub const HV_PARTITION_PRIVILEGE_ENABLE_EXTENDED_HYPERCALLS: u64 = 0x00100000000
 pub const HV_PARTITION_PRIVILEGE_START_VIRTUAL_PROCESSOR: u64 = 0x0020000000000000;
 pub const HV_PARTITION_PRIVILEGE_ISOLATION: u64 = 0x0040000000000000;
 
+// Partition ID of the caller, the root partition for the VMM
+pub const HV_PARTITION_ID_SELF: u64 = u64::MAX;
+
+// Extended hypercalls, serviced by the VMM rather than the hypervisor.
+// Guests need HV_PARTITION_PRIVILEGE_ENABLE_EXTENDED_HYPERCALLS.
+pub const HV_EXT_CALL_QUERY_CAPABILITIES: u16 = 0x8001;
+pub const HV_EXT_CALL_GET_BOOT_ZEROED_MEMORY: u16 = 0x8002;
+pub const HV_EXT_CALL_MEMORY_HEAT_HINT: u16 = 0x8003;
+
+// HvExtCallQueryCapabilities output
+pub const HV_EXT_CAPABILITY_MEMORY_COLD_DISCARD_HINT: u64 = 1 << 8;
+
 pub const HV_FEATURE_MWAIT_AVAILABLE_DEPRECATED: u32 = 1 << 0;
 pub const HV_FEATURE_GUEST_DEBUGGING_AVAILABLE: u32 = 1 << 1;
 pub const HV_FEATURE_PERFORMANCE_MONITORS_AVAILABLE: u32 = 1 << 2;
@@ -102,6 +118,13 @@ pub const HV_ENLIGHTENMENT_USE_DIRECT_LOCAL_FLUSH_ENTIRE: u32 = 1 << 17;
 pub const HV_ENLIGHTENMENT_NO_NON_ARCHITECTURAL_CORE_SHARING: u32 = 1 << 18;
 pub const HV_ENLIGHTENMENT_RESERVED: u32 = 13 << 19;
 
+// CPUID leaf 0x4000000A, nested hypervisor feature identification (EAX)
+pub const HV_CPUID_NESTED_FEATURES: u32 = 0x4000000a;
+pub const HV_NESTED_EVMCS_VERSION: u32 = 0x0101; // 1.0 as low version | high version << 8
+pub const HV_NESTED_EVMCS_VERSION_MASK: u32 = 0xffff;
+pub const HV_NESTED_DIRECT_FLUSH: u32 = 1 << 17;
+pub const HV_NESTED_MSR_BITMAP: u32 = 1 << 19;
+
 pub const HV_CALL_POST_MESSAGE: u64 = 0x005c;
 pub const HV_CALL_SIGNAL_EVENT: u64 = 0x005d;
 pub const HV_CALL_RETARGET_DEVICE_INTERRUPT: u64 = 0x007e;
@@ -109,69 +132,125 @@ pub const HV_CALL_RETARGET_DEVICE_INTERRUPT: u64 = 0x007e;
 pub const MSR_HYPERCALL_ACTIVE: u64 = 1;
 pub const MSR_HYPERCALL_LOCKED: u64 = 2;
 pub const MSR_HYPERCALL_ADDR_MASK: u64 = !0xfff;
+pub const MSR_HYPERCALL_RESERVED_MASK: u64 = 0xffc;
+
+pub const HV_X64_MSR_VP_ASSIST_PAGE_ENABLE: u64 = 1;
+pub const HV_X64_MSR_VP_ASSIST_PAGE_ADDR_MASK: u64 = !0xfff;
+
+pub const HV_CRASH_CTL_CRASH_NOTIFY_MSG: u64 = 1 << 62;
+pub const HV_CRASH_CTL_CRASH_NOTIFY: u64 = 1 << 63;
 
 pub const MSR_SIEFP_SIMP_ACTIVE: u64 = 1;
 pub const MSR_SIEFP_SIMP_ADDR_MASK: u64 = !0xfff;
 
-#[derive(Debug)]
-#[repr(u16)]
-pub enum HvError {
-    InvalidHypercallCode = 0x0002,
-    InvalidHypercallInput = 0x0003,
-    InvalidAlignment = 0x0004,
-    InvalidParameter = 0x0005,
-    AccessDenied = 0x0006,
-    InvalidPartitionState = 0x0007,
-    OperationDenied = 0x0008,
-    UnknownProperty = 0x0009,
-    PropertyValueOutOfRange = 0x000A,
-    InsufficientMemory = 0x000B,
-    PartitionTooDeep = 0x000C,
-    InvalidPartitionId = 0x000D,
-    InvalidVpIndex = 0x000E,
-    NotFound = 0x0010,
-    InvalidPortId = 0x0011,
-    InvalidConnectionId = 0x0012,
-    InsufficientBuffers = 0x0013,
-    NotAcknowledged = 0x0014,
-    InvalidVpState = 0x0015,
-    Acknowledged = 0x0016,
-    InvalidSaveRestoreState = 0x0017,
-    InvalidSynicState = 0x0018,
-    ObjectInUse = 0x0019,
-    InvalidProximityDomainInfo = 0x001A,
-    NoData = 0x001B,
-    Inactive = 0x001C,
-    NoResources = 0x001D,
-    FeatureUnavailable = 0x001E,
-    PartialPacket = 0x001F,
-    ProcessorFeatureNotSupported = 0x0020,
-    ProcessorCacheLineFlushSizeIncompatible = 0x0030,
-    InsufficientBuffer = 0x0033,
-    IncompatibleProcessor = 0x0037,
-    InsufficientDeviceDomains = 0x0038,
-    CpuidFeatureValidationError = 0x003C,
-    CpuidXsaveFeatureValidationError = 0x003D,
-    ProcessorStartupTimeout = 0x003E,
-    SmxEnabled = 0x003F,
-    InvalidLpIndex = 0x0041,
-    InvalidRegisterValue = 0x0050,
-    InvalidVtlState = 0x0051,
-    NxNotDetected = 0x0055,
-    InvalidDeviceId = 0x0057,
-    InvalidDeviceState = 0x0058,
-    PendingPageRequests = 0x0059,
-    PageRequestInvalid = 0x0060,
-    KeyAlreadyExists = 0x0065,
-    DeviceAlreadyInDomain = 0x0066,
-    InvalidCpuGroupId = 0x006F,
-    InvalidCpuGroupState = 0x0070,
-    OperationFailed = 0x0071,
-    NotAllowedWithNestedVirtActive = 0x0072,
-    InsufficientRootMemory = 0x0073,
-    EventBufferAlreadyFreed = 0x0074,
+// HV_X64_REGISTER_DELIVERABILITY_NOTIFICATIONS, the hypervisor clears a
+// notification bit when it sends HVMSG_X64_INTERRUPTION_DELIVERABLE.
+pub const HV_DELIVERABILITY_NMI_NOTIFICATION: u64 = 1 << 0;
+pub const HV_DELIVERABILITY_INTERRUPT_NOTIFICATION: u64 = 1 << 1;
+
+macro_rules! hv_errors {
+    ($($variant:ident = $status:expr => $name:expr,)*) => {
+        /// Failure statuses of hypercalls, as defined by the TLFS.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        #[repr(u16)]
+        pub enum HvError {
+            $($variant = $status,)*
+        }
+
+        impl HvError {
+            /// Every status this crate knows, in status order.
+            pub const ALL: &'static [HvError] = &[$(HvError::$variant,)*];
+
+            /// Decodes a hypercall status, None for success and statuses
+            /// this crate does not know.
+            pub fn from_status(status: u16) -> Option<Self> {
+                match status {
+                    $($status => Some(HvError::$variant),)*
+                    _ => None,
+                }
+            }
+            /// TLFS name of the status, e.g. `HV_STATUS_INVALID_PARAMETER`.
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(HvError::$variant => $name,)*
+                }
+            }
+        }
+    };
+}
+
+hv_errors! {
+    InvalidHypercallCode = 0x0002 => "HV_STATUS_INVALID_HYPERCALL_CODE",
+    InvalidHypercallInput = 0x0003 => "HV_STATUS_INVALID_HYPERCALL_INPUT",
+    InvalidAlignment = 0x0004 => "HV_STATUS_INVALID_ALIGNMENT",
+    InvalidParameter = 0x0005 => "HV_STATUS_INVALID_PARAMETER",
+    AccessDenied = 0x0006 => "HV_STATUS_ACCESS_DENIED",
+    InvalidPartitionState = 0x0007 => "HV_STATUS_INVALID_PARTITION_STATE",
+    OperationDenied = 0x0008 => "HV_STATUS_OPERATION_DENIED",
+    UnknownProperty = 0x0009 => "HV_STATUS_UNKNOWN_PROPERTY",
+    PropertyValueOutOfRange = 0x000A => "HV_STATUS_PROPERTY_VALUE_OUT_OF_RANGE",
+    InsufficientMemory = 0x000B => "HV_STATUS_INSUFFICIENT_MEMORY",
+    PartitionTooDeep = 0x000C => "HV_STATUS_PARTITION_TOO_DEEP",
+    InvalidPartitionId = 0x000D => "HV_STATUS_INVALID_PARTITION_ID",
+    InvalidVpIndex = 0x000E => "HV_STATUS_INVALID_VP_INDEX",
+    NotFound = 0x0010 => "HV_STATUS_NOT_FOUND",
+    InvalidPortId = 0x0011 => "HV_STATUS_INVALID_PORT_ID",
+    InvalidConnectionId = 0x0012 => "HV_STATUS_INVALID_CONNECTION_ID",
+    InsufficientBuffers = 0x0013 => "HV_STATUS_INSUFFICIENT_BUFFERS",
+    NotAcknowledged = 0x0014 => "HV_STATUS_NOT_ACKNOWLEDGED",
+    InvalidVpState = 0x0015 => "HV_STATUS_INVALID_VP_STATE",
+    Acknowledged = 0x0016 => "HV_STATUS_ACKNOWLEDGED",
+    InvalidSaveRestoreState = 0x0017 => "HV_STATUS_INVALID_SAVE_RESTORE_STATE",
+    InvalidSynicState = 0x0018 => "HV_STATUS_INVALID_SYNIC_STATE",
+    ObjectInUse = 0x0019 => "HV_STATUS_OBJECT_IN_USE",
+    InvalidProximityDomainInfo = 0x001A => "HV_STATUS_INVALID_PROXIMITY_DOMAIN_INFO",
+    NoData = 0x001B => "HV_STATUS_NO_DATA",
+    Inactive = 0x001C => "HV_STATUS_INACTIVE",
+    NoResources = 0x001D => "HV_STATUS_NO_RESOURCES",
+    FeatureUnavailable = 0x001E => "HV_STATUS_FEATURE_UNAVAILABLE",
+    PartialPacket = 0x001F => "HV_STATUS_PARTIAL_PACKET",
+    ProcessorFeatureNotSupported = 0x0020 => "HV_STATUS_PROCESSOR_FEATURE_NOT_SUPPORTED",
+    ProcessorCacheLineFlushSizeIncompatible = 0x0030 => "HV_STATUS_PROCESSOR_CACHE_LINE_FLUSH_SIZE_INCOMPATIBLE",
+    InsufficientBuffer = 0x0033 => "HV_STATUS_INSUFFICIENT_BUFFER",
+    IncompatibleProcessor = 0x0037 => "HV_STATUS_INCOMPATIBLE_PROCESSOR",
+    InsufficientDeviceDomains = 0x0038 => "HV_STATUS_INSUFFICIENT_DEVICE_DOMAINS",
+    CpuidFeatureValidationError = 0x003C => "HV_STATUS_CPUID_FEATURE_VALIDATION_ERROR",
+    CpuidXsaveFeatureValidationError = 0x003D => "HV_STATUS_CPUID_XSAVE_FEATURE_VALIDATION_ERROR",
+    ProcessorStartupTimeout = 0x003E => "HV_STATUS_PROCESSOR_STARTUP_TIMEOUT",
+    SmxEnabled = 0x003F => "HV_STATUS_SMX_ENABLED",
+    InvalidLpIndex = 0x0041 => "HV_STATUS_INVALID_LP_INDEX",
+    InvalidRegisterValue = 0x0050 => "HV_STATUS_INVALID_REGISTER_VALUE",
+    InvalidVtlState = 0x0051 => "HV_STATUS_INVALID_VTL_STATE",
+    NxNotDetected = 0x0055 => "HV_STATUS_NX_NOT_DETECTED",
+    InvalidDeviceId = 0x0057 => "HV_STATUS_INVALID_DEVICE_ID",
+    InvalidDeviceState = 0x0058 => "HV_STATUS_INVALID_DEVICE_STATE",
+    PendingPageRequests = 0x0059 => "HV_STATUS_PENDING_PAGE_REQUESTS",
+    PageRequestInvalid = 0x0060 => "HV_STATUS_PAGE_REQUEST_INVALID",
+    KeyAlreadyExists = 0x0065 => "HV_STATUS_KEY_ALREADY_EXISTS",
+    DeviceAlreadyInDomain = 0x0066 => "HV_STATUS_DEVICE_ALREADY_IN_DOMAIN",
+    InvalidCpuGroupId = 0x006F => "HV_STATUS_INVALID_CPU_GROUP_ID",
+    InvalidCpuGroupState = 0x0070 => "HV_STATUS_INVALID_CPU_GROUP_STATE",
+    OperationFailed = 0x0071 => "HV_STATUS_OPERATION_FAILED",
+    NotAllowedWithNestedVirtActive = 0x0072 => "HV_STATUS_NOT_ALLOWED_WITH_NESTED_VIRT_ACTIVE",
+    InsufficientRootMemory = 0x0073 => "HV_STATUS_INSUFFICIENT_ROOT_MEMORY",
+    EventBufferAlreadyFreed = 0x0074 => "HV_STATUS_EVENT_BUFFER_ALREADY_FREED",
+}
+
+impl HvError {
+    /// Returns the status code of this error.
+    pub fn status(&self) -> u16 {
+        *self as u16
+    }
+}
+
+impl std::fmt::Display for HvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
 }
 
+impl std::error::Error for HvError {}
+
 pub type HvResult<T> = Result<T, HvError>;
 
 #[repr(C)]
@@ -201,3 +280,30 @@ pub struct TimerMessagePayload {
     pub expiration_time: u64,
     pub delivery_time: u64,
 }
+
+pub const HV_INTERRUPT_SOURCE_MSI: u32 = 1;
+
+pub const HV_DEVICE_INTERRUPT_TARGET_MULTICAST: u32 = 1;
+pub const HV_DEVICE_INTERRUPT_TARGET_PROCESSOR_SET: u32 = 2;
+
+#[repr(C)]
+#[derive(Copy, Clone, AsBytes, Debug, Default, FromBytes, FromZeroes, PartialEq, Eq)]
+pub struct HvInterruptEntry {
+    pub source: u32,
+    pub reserved: u32,
+    pub address: u32,
+    pub data: u32,
+}
+
+// Input of HvCallRetargetDeviceInterrupt, up to the target processors: a
+// 64-bit VP mask, or a VP set with HV_DEVICE_INTERRUPT_TARGET_PROCESSOR_SET.
+#[repr(C)]
+#[derive(Copy, Clone, AsBytes, Debug, Default, FromBytes, FromZeroes)]
+pub struct HvRetargetDeviceInterruptHeader {
+    pub partition_id: u64,
+    pub device_id: u64,
+    pub int_entry: HvInterruptEntry,
+    pub reserved: u64,
+    pub vector: u32,
+    pub flags: u32,
+}
@@ -0,0 +1,285 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+//! Conversions between the vCPU state types of this crate and their
+//! kvm-bindings equivalents, for VMMs keeping one state model for both
+//! hypervisors.
+//!
+//! The FAM wrappers (`Msrs`, `CpuId`) are foreign types on both sides, so
+//! they are converted with functions instead of `From`.
+use crate::fam_wrappers::{CpuId, Msrs};
+use crate::regs::*;
+use kvm_bindings::{
+    kvm_cpuid_entry2, kvm_debugregs, kvm_dtable, kvm_fpu, kvm_lapic_state, kvm_msr_entry, kvm_regs,
+    kvm_segment, kvm_sregs, kvm_xcr, kvm_xcrs,
+};
+use vmm_sys_util::errno;
+use vmm_sys_util::fam::{FamStruct, FamStructWrapper};
+
+// Both directions for structs with the same fields
+macro_rules! convert {
+    ($mshv:ty, $kvm:ident, $($field:ident),*) => {
+        impl From<$mshv> for $kvm {
+            #[allow(clippy::needless_update)]
+            fn from(value: $mshv) -> Self {
+                $kvm {
+                    $($field: value.$field.into(),)*
+                    ..Default::default()
+                }
+            }
+        }
+        impl From<$kvm> for $mshv {
+            #[allow(clippy::needless_update)]
+            fn from(value: $kvm) -> Self {
+                Self {
+                    $($field: value.$field.into(),)*
+                    ..Default::default()
+                }
+            }
+        }
+    };
+}
+
+convert!(
+    StandardRegisters,
+    kvm_regs,
+    rax,
+    rbx,
+    rcx,
+    rdx,
+    rsi,
+    rdi,
+    rsp,
+    rbp,
+    r8,
+    r9,
+    r10,
+    r11,
+    r12,
+    r13,
+    r14,
+    r15,
+    rip,
+    rflags
+);
+convert!(
+    SegmentRegister,
+    kvm_segment,
+    base,
+    limit,
+    selector,
+    type_,
+    present,
+    dpl,
+    db,
+    s,
+    l,
+    g,
+    avl,
+    unusable
+);
+convert!(TableRegister, kvm_dtable, base, limit);
+convert!(
+    SpecialRegisters,
+    kvm_sregs,
+    cs,
+    ds,
+    es,
+    fs,
+    gs,
+    ss,
+    tr,
+    ldt,
+    gdt,
+    idt,
+    cr0,
+    cr2,
+    cr3,
+    cr4,
+    cr8,
+    efer,
+    apic_base,
+    interrupt_bitmap
+);
+convert!(
+    FloatingPointUnit,
+    kvm_fpu,
+    fpr,
+    fcw,
+    fsw,
+    ftwx,
+    last_opcode,
+    last_ip,
+    last_dp,
+    xmm,
+    mxcsr
+);
+convert!(msr_entry, kvm_msr_entry, index, data);
+convert!(
+    hv_cpuid_entry,
+    kvm_cpuid_entry2,
+    function,
+    index,
+    flags,
+    eax,
+    ebx,
+    ecx,
+    edx
+);
+
+impl From<DebugRegisters> for kvm_debugregs {
+    fn from(regs: DebugRegisters) -> Self {
+        kvm_debugregs {
+            db: [regs.dr0, regs.dr1, regs.dr2, regs.dr3],
+            dr6: regs.dr6,
+            dr7: regs.dr7,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<kvm_debugregs> for DebugRegisters {
+    fn from(regs: kvm_debugregs) -> Self {
+        DebugRegisters {
+            dr0: regs.db[0],
+            dr1: regs.db[1],
+            dr2: regs.db[2],
+            dr3: regs.db[3],
+            dr6: regs.dr6,
+            dr7: regs.dr7,
+        }
+    }
+}
+
+impl From<Xcrs> for kvm_xcrs {
+    fn from(xcrs: Xcrs) -> Self {
+        let mut ret = kvm_xcrs {
+            nr_xcrs: 1,
+            ..Default::default()
+        };
+        ret.xcrs[0] = kvm_xcr {
+            xcr: 0,
+            value: xcrs.xcr0,
+            ..Default::default()
+        };
+        ret
+    }
+}
+
+impl From<kvm_xcrs> for Xcrs {
+    /// Only XCR0 exists in both, the others are dropped
+    fn from(xcrs: kvm_xcrs) -> Self {
+        let xcr0 = xcrs.xcrs[..(xcrs.nr_xcrs as usize).min(xcrs.xcrs.len())]
+            .iter()
+            .find(|xcr| xcr.xcr == 0)
+            .map_or(0, |xcr| xcr.value);
+        Xcrs { xcr0 }
+    }
+}
+
+// Both use the layout of the APIC page
+impl From<LapicState> for kvm_lapic_state {
+    fn from(state: LapicState) -> Self {
+        kvm_lapic_state { regs: state.regs }
+    }
+}
+
+impl From<kvm_lapic_state> for LapicState {
+    fn from(state: kvm_lapic_state) -> Self {
+        LapicState { regs: state.regs }
+    }
+}
+
+fn convert_entries<A, B, T, U>(
+    from: &FamStructWrapper<A>,
+) -> Result<FamStructWrapper<B>, errno::Error>
+where
+    A: FamStruct<Entry = T> + Default,
+    B: FamStruct<Entry = U> + Default,
+    T: Copy + Into<U>,
+    U: Default,
+{
+    let entries: Vec<U> = from
+        .as_slice()
+        .iter()
+        .map(|entry| (*entry).into())
+        .collect();
+    FamStructWrapper::from_entries(&entries).map_err(|_| errno::Error::new(libc::E2BIG))
+}
+
+/// Converts MSRs read from KVM. Fails with E2BIG above `MAX_MSR_ENTRIES`.
+pub fn msrs_from_kvm(msrs: &kvm_bindings::Msrs) -> Result<Msrs, errno::Error> {
+    convert_entries(msrs)
+}
+
+/// Converts MSRs for KVM. Fails with E2BIG above `KVM_MAX_MSR_ENTRIES`.
+pub fn msrs_to_kvm(msrs: &Msrs) -> Result<kvm_bindings::Msrs, errno::Error> {
+    convert_entries(msrs)
+}
+
+/// Converts a CPUID table built for KVM. Fails with E2BIG above
+/// `HV_MAX_CPUID_ENTRIES`.
+pub fn cpuid_from_kvm(cpuid: &kvm_bindings::CpuId) -> Result<CpuId, errno::Error> {
+    convert_entries(cpuid)
+}
+
+/// Converts a CPUID table for KVM. Fails with E2BIG above
+/// `KVM_MAX_CPUID_ENTRIES`.
+pub fn cpuid_to_kvm(cpuid: &CpuId) -> Result<kvm_bindings::CpuId, errno::Error> {
+    convert_entries(cpuid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kvm_regs() {
+        let mut sregs = SpecialRegisters::default();
+        sregs.cs.selector = 0x10;
+        sregs.cs.l = 1;
+        sregs.gdt.limit = 0x27;
+        sregs.efer = 0x500;
+        let kvm = kvm_sregs::from(sregs);
+        assert!(kvm.cs.selector == 0x10 && kvm.cs.l == 1 && kvm.gdt.limit == 0x27);
+        let back = SpecialRegisters::from(kvm);
+        assert!(back.cs.selector == 0x10 && back.gdt.limit == 0x27 && back.efer == 0x500);
+
+        let regs = StandardRegisters {
+            rip: 0x1000,
+            rflags: 2,
+            ..Default::default()
+        };
+        assert_eq!(StandardRegisters::from(kvm_regs::from(regs)), regs);
+
+        let debug = DebugRegisters {
+            dr2: 0x3000,
+            dr7: 0x400,
+            ..Default::default()
+        };
+        assert_eq!(DebugRegisters::from(kvm_debugregs::from(debug)), debug);
+        let xcrs = Xcrs { xcr0: 7 };
+        assert_eq!(Xcrs::from(kvm_xcrs::from(xcrs)), xcrs);
+    }
+
+    #[test]
+    fn test_kvm_fam_wrappers() {
+        let msrs = Msrs::from_entries(&[msr_entry {
+            index: IA32_MSR_EFER,
+            data: 0x500,
+            ..Default::default()
+        }])
+        .unwrap();
+        let kvm = msrs_to_kvm(&msrs).unwrap();
+        assert!(kvm.as_slice()[0].index == IA32_MSR_EFER && kvm.as_slice()[0].data == 0x500);
+        assert_eq!(msrs_from_kvm(&kvm).unwrap().as_slice(), msrs.as_slice());
+
+        let mut kvm = kvm_bindings::CpuId::new(2).unwrap();
+        kvm.as_mut_slice()[1].function = 0x4000_0000;
+        kvm.as_mut_slice()[1].ebx = 0x1234;
+        let cpuid = cpuid_from_kvm(&kvm).unwrap();
+        assert!(cpuid.as_slice()[1].function == 0x4000_0000 && cpuid.as_slice()[1].ebx == 0x1234);
+        assert_eq!(cpuid_to_kvm(&cpuid).unwrap(), kvm);
+    }
+}
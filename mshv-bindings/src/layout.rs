@@ -0,0 +1,113 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+//! Build-time checks on the layout of the hand-written `#[repr(C)]` types.
+//!
+//! Some of them are copied to or from the bindgen structs they mirror, or
+//! read out of a message payload, and must keep a compatible layout when
+//! the headers are regenerated. The others are written to snapshots as raw
+//! bytes and keep the KVM layout they were modelled on, so their sizes are
+//! pinned.
+use crate::aarch64;
+use crate::bindings::*;
+use crate::hvdef::*;
+use crate::regs::*;
+use crate::snapshot::*;
+use crate::snp::*;
+use std::mem::{offset_of, size_of};
+
+macro_rules! assert_size {
+    ($t:ty, $size:expr) => {
+        const _: () = assert!(size_of::<$t>() == $size);
+    };
+}
+
+macro_rules! assert_same_size {
+    ($a:ty, $b:ty) => {
+        const _: () = assert!(size_of::<$a>() == size_of::<$b>());
+    };
+}
+
+macro_rules! assert_offset {
+    ($a:ty, $a_field:ident, $b:ty, $b_field:ident) => {
+        const _: () = assert!(offset_of!($a, $a_field) == offset_of!($b, $b_field));
+    };
+}
+
+macro_rules! assert_fits_payload {
+    ($($t:ty),*) => {
+        $(const _: () = assert!(size_of::<$t>() <= size_of::<hv_message__bindgen_ty_1>());)*
+    };
+}
+
+// Mirrors of bindgen types
+assert_same_size!(RegisterValue, hv_register_value);
+assert_same_size!(PartitionProcessorFeatures, hv_partition_processor_features);
+assert_same_size!(
+    PartitionProcessorXsaveFeatures,
+    hv_partition_processor_xsave_features
+);
+assert_same_size!(
+    PartitionSyntheticProcessorFeatures,
+    hv_partition_synthetic_processor_features
+);
+assert_same_size!(SyntheticTimerState, hv_stimer_state);
+assert_same_size!(HvMessageHeader, hv_message_header);
+assert_same_size!(HvMessage, hv_message);
+assert_offset!(HvMessageHeader, typ, hv_message_header, message_type);
+assert_offset!(HvMessageHeader, len, hv_message_header, payload_size);
+assert_offset!(HvMessageHeader, flags, hv_message_header, message_flags);
+assert_offset!(HvMessage, payload, hv_message, u);
+const _: () = assert!(size_of::<LapicState>() >= size_of::<hv_local_interrupt_controller_state>());
+
+// Typed views of hv_message payloads, see unmarshal.rs
+assert_fits_payload!(
+    TimerMessagePayload,
+    hv_x64_apic_eoi_message,
+    hv_x64_hypercall_intercept_message,
+    hv_x64_memory_intercept_message,
+    hv_x64_cpuid_intercept_message,
+    hv_x64_msr_intercept_message,
+    hv_x64_io_port_intercept_message,
+    hv_x64_exception_intercept_message,
+    hv_x64_invalid_vp_register_message,
+    hv_x64_unrecoverable_exception_message,
+    hv_x64_halt_message,
+    hv_x64_interruption_deliverable_message,
+    hv_x64_sint_deliverable_message,
+    hv_x64_gpa_attribute_intercept_message,
+    hv_x64_vmgexit_intercept_message
+);
+
+// Raw snapshot formats, matching kvm_regs, kvm_sregs, kvm_segment,
+// kvm_dtable, kvm_debugregs, kvm_fpu, kvm_msr_entry and kvm_cpuid_entry2
+assert_size!(StandardRegisters, 144);
+assert_size!(SpecialRegisters, 312);
+assert_size!(SegmentRegister, 24);
+assert_size!(TableRegister, 16);
+assert_size!(DebugRegisters, 48);
+assert_size!(FloatingPointUnit, 416);
+assert_size!(msr_entry, 16);
+assert_size!(hv_cpuid_entry, 40);
+assert_size!(VcpuEvents, 56);
+assert_size!(XSave, 4120);
+assert_size!(AllVpStateComponents, VP_STATE_COMPONENTS_BUFFER_SIZE);
+assert_size!(MtrrState, 224);
+assert_size!(VpStateBlob, 34000);
+const _: () = assert!(offset_of!(SegmentRegister, type_) == 14);
+const _: () = assert!(offset_of!(SegmentRegister, unusable) == 22);
+const _: () = assert!(offset_of!(SpecialRegisters, gdt) == 192);
+const _: () = assert!(offset_of!(FloatingPointUnit, xmm) == 152);
+assert_size!(aarch64::StandardRegisters, 280);
+assert_size!(aarch64::SystemRegisters, 136);
+
+// HvCallRetargetDeviceInterrupt input, see hvdef.rs
+assert_size!(HvInterruptEntry, 16);
+assert_size!(HvRetargetDeviceInterruptHeader, 48);
+
+// SEV-SNP launch pages, see snp.rs
+assert_size!(SnpCpuidFunction, 48);
+assert_size!(SnpCpuidPage, 4096);
+assert_size!(SnpSecretsPage, 4096);
+const _: () = assert!(offset_of!(SnpSecretsPage, os_area) == 0xa0);
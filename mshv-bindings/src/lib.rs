@@ -22,8 +22,15 @@ pub mod bindings;
 pub use bindings::*;
 pub mod regs;
 pub use regs::*;
+pub mod gdt;
+pub use gdt::*;
+pub mod synic;
+pub use synic::*;
 pub mod snp;
 pub use snp::*;
+pub mod snapshot;
+pub use snapshot::*;
+pub mod aarch64;
 
 #[cfg(feature = "with-serde")]
 extern crate serde;
@@ -32,8 +39,13 @@ extern crate serde;
 extern crate serde_derive;
 pub mod hvdef;
 pub use hvdef::*;
+pub mod hv_cpuid;
+pub use hv_cpuid::*;
+mod sha384;
 mod unmarshal;
 
+mod layout;
+
 #[cfg(feature = "fam-wrappers")]
 mod fam_wrappers;
 
@@ -42,3 +54,9 @@ pub use fam_wrappers::*;
 
 #[cfg(feature = "with-serde")]
 mod serializers;
+
+#[cfg(all(test, feature = "with-serde"))]
+mod golden;
+
+#[cfg(all(feature = "with-kvm-bindings", target_arch = "x86_64"))]
+pub mod kvm;
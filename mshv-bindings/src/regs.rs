@@ -4,6 +4,7 @@
 //
 
 use crate::bindings::*;
+use crate::hvdef::HV_CRASH_CTL_CRASH_NOTIFY_MSG;
 #[cfg(feature = "with-serde")]
 use serde_derive::{Deserialize, Serialize};
 use std::cmp;
@@ -14,6 +15,7 @@ use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, Eq, PartialEq, AsBytes, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "with-arbitrary", derive(arbitrary::Arbitrary))]
 #[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
 pub struct StandardRegisters {
     pub rax: u64,
@@ -36,8 +38,182 @@ pub struct StandardRegisters {
     pub rflags: u64,
 }
 
+/// Returns the value for register `name` in `assocs`, EINVAL if it is missing
+fn find_hv_reg(
+    assocs: &[hv_register_assoc],
+    name: hv_register_name,
+) -> Result<hv_register_value, errno::Error> {
+    assocs
+        .iter()
+        .find(|assoc| assoc.name == name)
+        .map(|assoc| assoc.value)
+        .ok_or_else(|| errno::Error::new(libc::EINVAL))
+}
+
+/// RFLAGS bit 1, which always reads as one
+pub const X86_RFLAGS_FIXED: u64 = 1 << 1;
+/// RFLAGS bits that must be zero
+pub const X86_RFLAGS_RESERVED: u64 = !0x3f_7fd7;
+pub const X86_CR0_PE: u64 = 1 << 0;
+pub const X86_CR0_ET: u64 = 1 << 4;
+pub const X86_CR0_NW: u64 = 1 << 29;
+pub const X86_CR0_CD: u64 = 1 << 30;
+pub const X86_CR0_PG: u64 = 1 << 31;
+/// CR0 bits that must be zero
+pub const X86_CR0_RESERVED: u64 = !0xffff_ffff;
+pub const X86_CR4_PAE: u64 = 1 << 5;
+/// CR4.LA57: 57 bit linear addresses with 5 level paging
+pub const X86_CR4_LA57: u64 = 1 << 12;
+/// CR4.LASS: linear address space separation
+pub const X86_CR4_LASS: u64 = 1 << 27;
+/// CR4.LAM_SUP: linear address masking for supervisor pointers
+pub const X86_CR4_LAM_SUP: u64 = 1 << 28;
+/// CR4.FRED: flexible return and event delivery
+pub const X86_CR4_FRED: u64 = 1 << 32;
+/// CR4 bits that must be zero
+pub const X86_CR4_RESERVED: u64 = !(0x3ff_7fff | X86_CR4_LASS | X86_CR4_LAM_SUP | X86_CR4_FRED);
+pub const X86_EFER_LME: u64 = 1 << 8;
+pub const X86_EFER_LMA: u64 = 1 << 10;
+/// EFER bits that must be zero
+pub const X86_EFER_RESERVED: u64 = !0x20_fd01;
+
+/// A register value the architecture does not allow, found by `validate`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct InvalidRegister {
+    /// Name of the offending register
+    pub register: &'static str,
+    /// Offending bit of the register
+    pub bit: u8,
+}
+
+impl fmt::Display for InvalidRegister {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid value for {}: bit {}", self.register, self.bit)
+    }
+}
+
+fn check_bits(register: &'static str, value: u64, mask: u64) -> Result<(), InvalidRegister> {
+    match value & mask {
+        0 => Ok(()),
+        bits => Err(InvalidRegister {
+            register,
+            bit: bits.trailing_zeros() as u8,
+        }),
+    }
+}
+
+/// Checks that `value` is canonical for the linear address width `cr4`
+/// selects, 57 bits with CR4.LA57 and 48 otherwise, reporting the lowest
+/// upper bit that differs from the top address bit.
+fn check_canonical(register: &'static str, value: u64, cr4: u64) -> Result<(), InvalidRegister> {
+    let unused = if cr4 & X86_CR4_LA57 != 0 { 7 } else { 16 };
+    let extended = ((value << unused) as i64 >> unused) as u64;
+    check_bits(register, value ^ extended, !0)
+}
+
+impl StandardRegisters {
+    /// Names of the registers making up `StandardRegisters`, in field order
+    pub const HV_REGISTER_NAMES: [hv_register_name; 18] = [
+        hv_register_name_HV_X64_REGISTER_RAX,
+        hv_register_name_HV_X64_REGISTER_RBX,
+        hv_register_name_HV_X64_REGISTER_RCX,
+        hv_register_name_HV_X64_REGISTER_RDX,
+        hv_register_name_HV_X64_REGISTER_RSI,
+        hv_register_name_HV_X64_REGISTER_RDI,
+        hv_register_name_HV_X64_REGISTER_RSP,
+        hv_register_name_HV_X64_REGISTER_RBP,
+        hv_register_name_HV_X64_REGISTER_R8,
+        hv_register_name_HV_X64_REGISTER_R9,
+        hv_register_name_HV_X64_REGISTER_R10,
+        hv_register_name_HV_X64_REGISTER_R11,
+        hv_register_name_HV_X64_REGISTER_R12,
+        hv_register_name_HV_X64_REGISTER_R13,
+        hv_register_name_HV_X64_REGISTER_R14,
+        hv_register_name_HV_X64_REGISTER_R15,
+        hv_register_name_HV_X64_REGISTER_RIP,
+        hv_register_name_HV_X64_REGISTER_RFLAGS,
+    ];
+
+    fn values(&self) -> [u64; 18] {
+        [
+            self.rax,
+            self.rbx,
+            self.rcx,
+            self.rdx,
+            self.rsi,
+            self.rdi,
+            self.rsp,
+            self.rbp,
+            self.r8,
+            self.r9,
+            self.r10,
+            self.r11,
+            self.r12,
+            self.r13,
+            self.r14,
+            self.r15,
+            self.rip,
+            self.rflags,
+        ]
+    }
+
+    /// Converts the registers to register assocs, in `HV_REGISTER_NAMES` order
+    pub fn to_hv_assocs(&self) -> [hv_register_assoc; 18] {
+        let mut assocs = [hv_register_assoc::default(); 18];
+        for ((assoc, name), value) in assocs
+            .iter_mut()
+            .zip(Self::HV_REGISTER_NAMES.iter())
+            .zip(self.values().iter())
+        {
+            assoc.name = *name;
+            assoc.value = hv_register_value { reg64: *value };
+        }
+        assocs
+    }
+
+    /// Checks the RFLAGS reserved bits and that RIP and RSP are canonical
+    /// for the linear address width of `cr4`, see `X86_CR4_LA57`.
+    pub fn validate(&self, cr4: u64) -> Result<(), InvalidRegister> {
+        check_bits("rflags", self.rflags, X86_RFLAGS_RESERVED)?;
+        check_bits("rflags", !self.rflags, X86_RFLAGS_FIXED)?;
+        check_canonical("rip", self.rip, cr4)?;
+        check_canonical("rsp", self.rsp, cr4)
+    }
+
+    /// Builds the registers from register assocs, in any order. Fails with
+    /// EINVAL if one of `HV_REGISTER_NAMES` is missing.
+    pub fn from_hv_assocs(assocs: &[hv_register_assoc]) -> Result<Self, errno::Error> {
+        let mut v = [0u64; 18];
+        for (value, name) in v.iter_mut().zip(Self::HV_REGISTER_NAMES.iter()) {
+            // SAFETY: access union fields
+            *value = unsafe { find_hv_reg(assocs, *name)?.reg64 };
+        }
+        Ok(StandardRegisters {
+            rax: v[0],
+            rbx: v[1],
+            rcx: v[2],
+            rdx: v[3],
+            rsi: v[4],
+            rdi: v[5],
+            rsp: v[6],
+            rbp: v[7],
+            r8: v[8],
+            r9: v[9],
+            r10: v[10],
+            r11: v[11],
+            r12: v[12],
+            r13: v[13],
+            r14: v[14],
+            r15: v[15],
+            rip: v[16],
+            rflags: v[17],
+        })
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "with-arbitrary", derive(arbitrary::Arbitrary))]
 #[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
 pub struct SegmentRegister {
     /* segment register + descriptor */
@@ -141,6 +317,7 @@ impl From<SegmentRegister> for hv_x64_segment_register {
 
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "with-arbitrary", derive(arbitrary::Arbitrary))]
 #[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
 pub struct TableRegister {
     pub base: u64,
@@ -166,8 +343,253 @@ impl From<TableRegister> for hv_x64_table_register {
     }
 }
 
+/// Serializable mirror of `hv_register_value`. The union carries no tag, so
+/// the full 128-bit payload is kept as is.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, AsBytes, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+pub struct RegisterValue {
+    pub low: u64,
+    pub high: u64,
+}
+
+impl From<hv_register_value> for RegisterValue {
+    fn from(value: hv_register_value) -> Self {
+        // SAFETY: every field of the union is plain data and reg128 covers all of it
+        let reg = unsafe { value.reg128 };
+        RegisterValue {
+            low: reg.low_part,
+            high: reg.high_part,
+        }
+    }
+}
+
+impl From<RegisterValue> for hv_register_value {
+    fn from(value: RegisterValue) -> Self {
+        hv_register_value {
+            reg128: hv_u128 {
+                low_part: value.low,
+                high_part: value.high,
+            },
+        }
+    }
+}
+
+impl From<u64> for hv_register_value {
+    fn from(value: u64) -> Self {
+        hv_register_value { reg64: value }
+    }
+}
+
+/// Serializable mirror of `hv_register_assoc`
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+pub struct RegisterAssoc {
+    pub name: u32,
+    pub value: RegisterValue,
+}
+
+impl From<hv_register_assoc> for RegisterAssoc {
+    fn from(assoc: hv_register_assoc) -> Self {
+        RegisterAssoc {
+            name: assoc.name,
+            value: assoc.value.into(),
+        }
+    }
+}
+
+impl From<RegisterAssoc> for hv_register_assoc {
+    fn from(assoc: RegisterAssoc) -> Self {
+        hv_register_assoc {
+            name: assoc.name,
+            value: assoc.value.into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// A fixed set of registers read or written together, kept on the stack.
+///
+/// Meant for the few registers each exit handler touches, e.g. RIP, RAX
+/// and RFLAGS after a port IO intercept. Entries are addressed by their
+/// position in the set: the `*_at::<I>` accessors check the index at
+/// compile time, the others panic if it is out of range.
+#[derive(Copy, Clone)]
+pub struct RegBundle<const N: usize> {
+    regs: [hv_register_assoc; N],
+}
+
+// Fails to build when evaluated, i.e. when an `*_at::<I>` accessor is
+// used, for an index `I` out of a bundle of `N` registers
+struct BundleIndex<const I: usize, const N: usize>;
+
+impl<const I: usize, const N: usize> BundleIndex<I, N> {
+    const CHECK: () = assert!(I < N, "register index out of the bundle");
+}
+
+impl<const N: usize> RegBundle<N> {
+    /// Creates a bundle of the registers `names`, with zero values
+    pub fn new(names: [hv_register_name; N]) -> Self {
+        RegBundle {
+            regs: names.map(|name| hv_register_assoc {
+                name,
+                ..Default::default()
+            }),
+        }
+    }
+    /// Creates a bundle of 64 bit registers set to the given values
+    pub fn with_values(regs: [(hv_register_name, u64); N]) -> Self {
+        RegBundle {
+            regs: regs.map(|(name, value)| hv_register_assoc {
+                name,
+                value: hv_register_value { reg64: value },
+                ..Default::default()
+            }),
+        }
+    }
+    /// Name of register `i`
+    pub fn name(&self, i: usize) -> hv_register_name {
+        self.regs[i].name
+    }
+    /// Value of register `i`
+    pub fn value(&self, i: usize) -> hv_register_value {
+        self.regs[i].value
+    }
+    /// Value of 64 bit register `i`
+    pub fn reg64(&self, i: usize) -> u64 {
+        // SAFETY: every field of the union is plain data
+        unsafe { self.regs[i].value.reg64 }
+    }
+    /// Sets the value of register `i`
+    pub fn set_value(&mut self, i: usize, value: hv_register_value) {
+        self.regs[i].value = value;
+    }
+    /// Sets the value of 64 bit register `i`
+    pub fn set_reg64(&mut self, i: usize, value: u64) {
+        self.regs[i].value = hv_register_value { reg64: value };
+    }
+    /// Name of register `I`, checked at compile time
+    pub fn name_at<const I: usize>(&self) -> hv_register_name {
+        let () = BundleIndex::<I, N>::CHECK;
+        self.regs[I].name
+    }
+    /// Value of register `I`, checked at compile time
+    pub fn value_at<const I: usize>(&self) -> hv_register_value {
+        let () = BundleIndex::<I, N>::CHECK;
+        self.regs[I].value
+    }
+    /// Value of 64 bit register `I`, checked at compile time
+    pub fn reg64_at<const I: usize>(&self) -> u64 {
+        let () = BundleIndex::<I, N>::CHECK;
+        // SAFETY: every field of the union is plain data
+        unsafe { self.regs[I].value.reg64 }
+    }
+    /// Sets the value of register `I`, checked at compile time
+    pub fn set_value_at<const I: usize>(&mut self, value: hv_register_value) {
+        let () = BundleIndex::<I, N>::CHECK;
+        self.regs[I].value = value;
+    }
+    /// Sets the value of 64 bit register `I`, checked at compile time
+    pub fn set_reg64_at<const I: usize>(&mut self, value: u64) {
+        self.set_value_at::<I>(hv_register_value { reg64: value });
+    }
+    /// Registers of the bundle, to pass to `get_reg` or `set_reg`
+    pub fn as_slice(&self) -> &[hv_register_assoc] {
+        &self.regs
+    }
+    /// Registers of the bundle, to pass to `get_reg`
+    pub fn as_mut_slice(&mut self) -> &mut [hv_register_assoc] {
+        &mut self.regs
+    }
+}
+
+impl<const N: usize> fmt::Debug for RegBundle<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.regs.iter().map(|r| RegisterAssoc::from(*r)))
+            .finish()
+    }
+}
+
+/// Serializable mirror of `hv_partition_processor_features`
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+pub struct PartitionProcessorFeatures {
+    pub as_uint64: [u64; 2],
+}
+
+impl From<hv_partition_processor_features> for PartitionProcessorFeatures {
+    fn from(features: hv_partition_processor_features) -> Self {
+        PartitionProcessorFeatures {
+            // SAFETY: as_uint64 covers the whole union
+            as_uint64: unsafe { features.as_uint64 },
+        }
+    }
+}
+
+impl From<PartitionProcessorFeatures> for hv_partition_processor_features {
+    fn from(features: PartitionProcessorFeatures) -> Self {
+        hv_partition_processor_features {
+            as_uint64: features.as_uint64,
+        }
+    }
+}
+
+/// Serializable mirror of `hv_partition_processor_xsave_features`
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+pub struct PartitionProcessorXsaveFeatures {
+    pub as_uint64: u64,
+}
+
+impl From<hv_partition_processor_xsave_features> for PartitionProcessorXsaveFeatures {
+    fn from(features: hv_partition_processor_xsave_features) -> Self {
+        PartitionProcessorXsaveFeatures {
+            // SAFETY: as_uint64 covers the whole union
+            as_uint64: unsafe { features.as_uint64 },
+        }
+    }
+}
+
+impl From<PartitionProcessorXsaveFeatures> for hv_partition_processor_xsave_features {
+    fn from(features: PartitionProcessorXsaveFeatures) -> Self {
+        hv_partition_processor_xsave_features {
+            as_uint64: features.as_uint64,
+        }
+    }
+}
+
+/// Serializable mirror of `hv_partition_synthetic_processor_features`
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+pub struct PartitionSyntheticProcessorFeatures {
+    pub as_uint64: [u64; 1],
+}
+
+impl From<hv_partition_synthetic_processor_features> for PartitionSyntheticProcessorFeatures {
+    fn from(features: hv_partition_synthetic_processor_features) -> Self {
+        PartitionSyntheticProcessorFeatures {
+            // SAFETY: as_uint64 covers the whole union
+            as_uint64: unsafe { features.as_uint64 },
+        }
+    }
+}
+
+impl From<PartitionSyntheticProcessorFeatures> for hv_partition_synthetic_processor_features {
+    fn from(features: PartitionSyntheticProcessorFeatures) -> Self {
+        hv_partition_synthetic_processor_features {
+            as_uint64: features.as_uint64,
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "with-arbitrary", derive(arbitrary::Arbitrary))]
 #[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
 pub struct SpecialRegisters {
     pub cs: SegmentRegister,
@@ -190,8 +612,168 @@ pub struct SpecialRegisters {
     pub interrupt_bitmap: [u64; 4usize],
 }
 
+impl SpecialRegisters {
+    /// Names of the registers making up `SpecialRegisters`, in field order.
+    /// `interrupt_bitmap` has no register of its own.
+    pub const HV_REGISTER_NAMES: [hv_register_name; 17] = [
+        hv_register_name_HV_X64_REGISTER_CS,
+        hv_register_name_HV_X64_REGISTER_DS,
+        hv_register_name_HV_X64_REGISTER_ES,
+        hv_register_name_HV_X64_REGISTER_FS,
+        hv_register_name_HV_X64_REGISTER_GS,
+        hv_register_name_HV_X64_REGISTER_SS,
+        hv_register_name_HV_X64_REGISTER_TR,
+        hv_register_name_HV_X64_REGISTER_LDTR,
+        hv_register_name_HV_X64_REGISTER_GDTR,
+        hv_register_name_HV_X64_REGISTER_IDTR,
+        hv_register_name_HV_X64_REGISTER_CR0,
+        hv_register_name_HV_X64_REGISTER_CR2,
+        hv_register_name_HV_X64_REGISTER_CR3,
+        hv_register_name_HV_X64_REGISTER_CR4,
+        hv_register_name_HV_X64_REGISTER_CR8,
+        hv_register_name_HV_X64_REGISTER_EFER,
+        hv_register_name_HV_X64_REGISTER_APIC_BASE,
+    ];
+
+    /// Converts the registers to register assocs, in `HV_REGISTER_NAMES` order.
+    /// `interrupt_bitmap` is not included.
+    pub fn to_hv_assocs(&self) -> [hv_register_assoc; 17] {
+        let values: [hv_register_value; 17] = [
+            hv_register_value {
+                segment: self.cs.into(),
+            },
+            hv_register_value {
+                segment: self.ds.into(),
+            },
+            hv_register_value {
+                segment: self.es.into(),
+            },
+            hv_register_value {
+                segment: self.fs.into(),
+            },
+            hv_register_value {
+                segment: self.gs.into(),
+            },
+            hv_register_value {
+                segment: self.ss.into(),
+            },
+            hv_register_value {
+                segment: self.tr.into(),
+            },
+            hv_register_value {
+                segment: self.ldt.into(),
+            },
+            hv_register_value {
+                table: self.gdt.into(),
+            },
+            hv_register_value {
+                table: self.idt.into(),
+            },
+            hv_register_value { reg64: self.cr0 },
+            hv_register_value { reg64: self.cr2 },
+            hv_register_value { reg64: self.cr3 },
+            hv_register_value { reg64: self.cr4 },
+            hv_register_value { reg64: self.cr8 },
+            hv_register_value { reg64: self.efer },
+            hv_register_value {
+                reg64: self.apic_base,
+            },
+        ];
+        let mut assocs = [hv_register_assoc::default(); 17];
+        for ((assoc, name), value) in assocs
+            .iter_mut()
+            .zip(Self::HV_REGISTER_NAMES.iter())
+            .zip(values.iter())
+        {
+            assoc.name = *name;
+            assoc.value = *value;
+        }
+        assocs
+    }
+
+    /// Returns the external interrupt pending injection according to
+    /// `interrupt_bitmap`, where bit `n` of word `n / 64` stands for vector
+    /// `n`. Fails with EINVAL if more than one vector is set.
+    pub fn pending_interrupt(&self) -> Result<Option<u8>, errno::Error> {
+        let mut pending = None;
+        for (i, bits) in self.interrupt_bitmap.iter().enumerate() {
+            match bits.count_ones() {
+                0 => continue,
+                1 if pending.is_none() => {
+                    pending = Some((i * 64) as u8 + bits.trailing_zeros() as u8)
+                }
+                _ => return Err(errno::Error::new(libc::EINVAL)),
+            }
+        }
+        Ok(pending)
+    }
+
+    /// Sets `interrupt_bitmap` so that only `vector`, if any, is pending
+    pub fn set_pending_interrupt(&mut self, vector: Option<u8>) {
+        self.interrupt_bitmap = [0; 4];
+        if let Some(vector) = vector {
+            self.interrupt_bitmap[vector as usize / 64] = 1 << (vector % 64);
+        }
+    }
+
+    /// Checks the CR0, CR4 and EFER reserved bits, and that paging and
+    /// long mode are enabled consistently across them.
+    pub fn validate(&self) -> Result<(), InvalidRegister> {
+        check_bits("cr0", self.cr0, X86_CR0_RESERVED)?;
+        if self.cr0 & X86_CR0_PG != 0 {
+            check_bits("cr0", !self.cr0, X86_CR0_PE)?;
+        }
+        if self.cr0 & X86_CR0_NW != 0 {
+            check_bits("cr0", !self.cr0, X86_CR0_CD)?;
+        }
+        check_bits("cr4", self.cr4, X86_CR4_RESERVED)?;
+        check_bits("efer", self.efer, X86_EFER_RESERVED)?;
+        if self.efer & X86_EFER_LME != 0 && self.cr0 & X86_CR0_PG != 0 {
+            check_bits("cr4", !self.cr4, X86_CR4_PAE)?;
+            check_bits("efer", !self.efer, X86_EFER_LMA)
+        } else {
+            check_bits("efer", self.efer, X86_EFER_LMA)
+        }
+    }
+
+    /// Builds the registers from register assocs, in any order. Fails with
+    /// EINVAL if one of `HV_REGISTER_NAMES` is missing. `interrupt_bitmap` is
+    /// left empty.
+    pub fn from_hv_assocs(assocs: &[hv_register_assoc]) -> Result<Self, errno::Error> {
+        let mut v = [hv_register_value::default(); 17];
+        for (value, name) in v.iter_mut().zip(Self::HV_REGISTER_NAMES.iter()) {
+            *value = find_hv_reg(assocs, *name)?;
+        }
+        // SAFETY: access union fields
+        let ret = unsafe {
+            SpecialRegisters {
+                cs: SegmentRegister::from(v[0].segment),
+                ds: SegmentRegister::from(v[1].segment),
+                es: SegmentRegister::from(v[2].segment),
+                fs: SegmentRegister::from(v[3].segment),
+                gs: SegmentRegister::from(v[4].segment),
+                ss: SegmentRegister::from(v[5].segment),
+                tr: SegmentRegister::from(v[6].segment),
+                ldt: SegmentRegister::from(v[7].segment),
+                gdt: TableRegister::from(v[8].table),
+                idt: TableRegister::from(v[9].table),
+                cr0: v[10].reg64,
+                cr2: v[11].reg64,
+                cr3: v[12].reg64,
+                cr4: v[13].reg64,
+                cr8: v[14].reg64,
+                efer: v[15].reg64,
+                apic_base: v[16].reg64,
+                interrupt_bitmap: [0; 4],
+            }
+        };
+        Ok(ret)
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, Eq, PartialEq, AsBytes, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "with-arbitrary", derive(arbitrary::Arbitrary))]
 #[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
 pub struct DebugRegisters {
     pub dr0: u64,
@@ -204,6 +786,7 @@ pub struct DebugRegisters {
 
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, Eq, PartialEq, AsBytes, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "with-arbitrary", derive(arbitrary::Arbitrary))]
 #[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
 pub struct FloatingPointUnit {
     pub fpr: [[u8; 16usize]; 8usize],
@@ -263,6 +846,31 @@ pub const IA32_MSR_MTRR_FIX4K_E8000: u32 = 0x026D;
 pub const IA32_MSR_MTRR_FIX4K_F0000: u32 = 0x026E;
 pub const IA32_MSR_MTRR_FIX4K_F8000: u32 = 0x026F;
 
+pub const MTRR_TYPE_UC: u8 = 0;
+pub const MTRR_TYPE_WC: u8 = 1;
+pub const MTRR_TYPE_WT: u8 = 4;
+pub const MTRR_TYPE_WP: u8 = 5;
+pub const MTRR_TYPE_WB: u8 = 6;
+pub const MTRR_DEF_TYPE_FE: u64 = 1 << 10;
+pub const MTRR_DEF_TYPE_E: u64 = 1 << 11;
+pub const MTRR_PHYSMASK_VALID: u64 = 1 << 11;
+pub const MTRR_PHYS_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+pub const MTRR_NUM_FIXED: usize = 11;
+pub const MTRR_NUM_VARIABLE: usize = 8;
+pub const MTRR_FIXED_MSRS: [u32; MTRR_NUM_FIXED] = [
+    IA32_MSR_MTRR_FIX64K_00000,
+    IA32_MSR_MTRR_FIX16K_80000,
+    IA32_MSR_MTRR_FIX16K_A0000,
+    IA32_MSR_MTRR_FIX4K_C0000,
+    IA32_MSR_MTRR_FIX4K_C8000,
+    IA32_MSR_MTRR_FIX4K_D0000,
+    IA32_MSR_MTRR_FIX4K_D8000,
+    IA32_MSR_MTRR_FIX4K_E0000,
+    IA32_MSR_MTRR_FIX4K_E8000,
+    IA32_MSR_MTRR_FIX4K_F0000,
+    IA32_MSR_MTRR_FIX4K_F8000,
+];
+
 pub const IA32_MSR_TSC_AUX: u32 = 0xC0000103;
 pub const IA32_MSR_BNDCFGS: u32 = 0x00000d90;
 pub const IA32_MSR_DEBUG_CTL: u32 = 0x1D9;
@@ -271,6 +879,10 @@ pub const IA32_MSR_TSC_ADJUST: u32 = 0x0000003b;
 
 pub const IA32_MSR_MISC_ENABLE: u32 = 0x000001a0;
 
+pub const IA32_MSR_MCG_CAP: u32 = 0x00000179;
+pub const IA32_MSR_MCG_STATUS: u32 = 0x0000017a;
+pub const IA32_MSR_MC0_CTL: u32 = 0x00000400;
+
 pub fn msr_to_hv_reg_name(msr: u32) -> Result<::std::os::raw::c_uint, &'static str> {
     match msr {
         IA32_MSR_TSC => Ok(hv_register_name_HV_X64_REGISTER_TSC),
@@ -332,6 +944,7 @@ pub fn msr_to_hv_reg_name(msr: u32) -> Result<::std::os::raw::c_uint, &'static s
 
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, Eq, PartialEq, AsBytes, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "with-arbitrary", derive(arbitrary::Arbitrary))]
 #[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
 pub struct msr_entry {
     pub index: u32,
@@ -339,6 +952,35 @@ pub struct msr_entry {
     pub data: u64,
 }
 
+/// Builds an array of register assocs from `name => value` pairs. Names
+/// are any `hv_register_name` expression and values anything that converts
+/// into a `hv_register_value`: a `u64` for 64 bit registers, or a full
+/// value for segment, table and 128 bit registers, e.g.
+/// `hv_regs![hv_register_name_HV_X64_REGISTER_RIP => 0x1000]`.
+#[macro_export]
+macro_rules! hv_regs {
+    ($($name:expr => $value:expr),* $(,)?) => {
+        [$($crate::bindings::hv_register_assoc {
+            name: $name,
+            value: $crate::bindings::hv_register_value::from($value),
+            ..Default::default()
+        }),*]
+    };
+}
+
+/// Builds an array of MSR entries from `index => data` pairs, e.g.
+/// `msr_entries![IA32_MSR_EFER => efer]`.
+#[macro_export]
+macro_rules! msr_entries {
+    ($($index:expr => $data:expr),* $(,)?) => {
+        [$($crate::regs::msr_entry {
+            index: $index,
+            data: $data,
+            ..Default::default()
+        }),*]
+    };
+}
+
 #[repr(C)]
 #[derive(Debug, Default)]
 #[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
@@ -359,6 +1001,7 @@ pub struct msr_list {
 
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, Eq, PartialEq, AsBytes, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "with-arbitrary", derive(arbitrary::Arbitrary))]
 #[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
 pub struct VcpuEvents {
     pub pending_interruption: u64,
@@ -369,6 +1012,7 @@ pub struct VcpuEvents {
 }
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, Eq, PartialEq, AsBytes, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "with-arbitrary", derive(arbitrary::Arbitrary))]
 #[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
 pub struct Xcrs {
     pub xcr0: u64,
@@ -376,6 +1020,7 @@ pub struct Xcrs {
 
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, Eq, PartialEq, AsBytes, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "with-arbitrary", derive(arbitrary::Arbitrary))]
 pub struct hv_cpuid_entry {
     pub function: __u32,
     pub index: __u32,
@@ -397,6 +1042,24 @@ pub struct hv_cpuid {
     pub entries: __IncompleteArrayField<hv_cpuid_entry>,
 }
 
+// Argument of MSHV_ROOT_HVCALL. It comes from the upstream Linux driver
+// header (include/uapi/linux/mshv.h), not from the one `bindings.rs` is
+// generated from, so it is written by hand and its layout is checked by
+// `test_mshv_root_hvcall_layout`. Drop it once the bindings are generated
+// from a header that has it.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, PartialOrd, Ord, PartialEq, Eq)]
+pub struct mshv_root_hvcall {
+    pub code: __u16,
+    pub reps: __u16,
+    pub in_sz: __u16,
+    pub out_sz: __u16,
+    pub status: __u16,
+    pub rsvd: [__u8; 6usize],
+    pub in_ptr: __u64,
+    pub out_ptr: __u64,
+}
+
 pub const LOCAL_APIC_OFFSET_APIC_ID: isize = 0x20; // APIC ID Register.
 pub const LOCAL_APIC_OFFSET_VERSION: isize = 0x30; // APIC Version Register.
 pub const LOCAL_APIC_OFFSET_TPR: isize = 0x80; // Task Priority Register
@@ -424,48 +1087,91 @@ pub const LOCAL_APIC_OFFSET_CURRENT_COUNT: isize = 0x390; // R/O Current count R
 pub const LOCAL_APIC_OFFSET_DIVIDER: isize = 0x3e0; // Divide configuration Register.
 pub const LOCAL_X2APIC_OFFSET_SELF_IPI: isize = 0x3f0; // Self IPI register, only present in x2APIC.
 
-pub struct Buffer {
-    pub layout: std::alloc::Layout,
-    pub buf: *mut u8,
+/// Zeroed heap buffer with a given alignment, such as the page aligned
+/// buffers the hypervisor reads and writes VP state through. It owns its
+/// allocation, which is freed on drop.
+pub struct AlignedBuffer {
+    layout: std::alloc::Layout,
+    buf: ptr::NonNull<u8>,
 }
 
-impl Buffer {
-    pub fn new(size: usize, align: usize) -> Result<Buffer, errno::Error> {
-        let layout = std::alloc::Layout::from_size_align(size, align).unwrap();
-        // SAFETY: layout is valid
-        let buf = unsafe { std::alloc::alloc(layout) };
-        if buf.is_null() {
-            return Err(errno::Error::new(libc::ENOMEM));
+/// Former name of `AlignedBuffer`
+#[deprecated(note = "use AlignedBuffer")]
+pub type Buffer = AlignedBuffer;
+
+impl AlignedBuffer {
+    /// Allocates `size` zeroed bytes aligned to `align`. Fails with EINVAL
+    /// if `size` is zero or `align` is not a power of two, and with ENOMEM
+    /// if the allocation fails.
+    pub fn new(size: usize, align: usize) -> Result<AlignedBuffer, errno::Error> {
+        let layout = std::alloc::Layout::from_size_align(size, align)
+            .map_err(|_| errno::Error::new(libc::EINVAL))?;
+        if size == 0 {
+            return Err(errno::Error::new(libc::EINVAL));
         }
+        // SAFETY: layout is valid and not zero sized
+        let buf = unsafe { std::alloc::alloc_zeroed(layout) };
+        let buf = ptr::NonNull::new(buf).ok_or(errno::Error::new(libc::ENOMEM))?;
+        Ok(AlignedBuffer { layout, buf })
+    }
 
-        let buf = Buffer { layout, buf };
+    /// Frees the buffer, same as dropping it
+    #[deprecated(note = "the buffer is freed when dropped")]
+    pub fn dealloc(self) {}
 
-        Ok(buf)
+    pub fn size(&self) -> usize {
+        self.layout.size()
     }
 
-    pub fn dealloc(self) {
-        // SAFETY: buf was allocated with layout
-        unsafe {
-            std::alloc::dealloc(self.buf, self.layout);
-        }
+    pub fn align(&self) -> usize {
+        self.layout.align()
     }
 
-    pub fn size(&self) -> usize {
-        self.layout.size()
+    /// Start of the buffer, to hand to the kernel for reading
+    pub fn as_ptr(&self) -> *const u8 {
+        self.buf.as_ptr()
+    }
+
+    /// Start of the buffer, to hand to the kernel for writing
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.buf.as_ptr()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        // SAFETY: buf holds size() initialized bytes, borrowed with self
+        unsafe { std::slice::from_raw_parts(self.buf.as_ptr(), self.size()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: buf holds size() initialized bytes, borrowed mutably with self
+        unsafe { std::slice::from_raw_parts_mut(self.buf.as_ptr(), self.size()) }
+    }
+}
+
+impl fmt::Debug for AlignedBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AlignedBuffer")
+            .field("size", &self.size())
+            .field("align", &self.align())
+            .finish()
     }
 }
 
-impl Drop for Buffer {
+// SAFETY: AlignedBuffer exclusively owns its allocation, so it can be moved to another thread
+unsafe impl Send for AlignedBuffer {}
+
+impl Drop for AlignedBuffer {
     fn drop(&mut self) {
         // SAFETY: buf was allocated with layout
         unsafe {
-            std::alloc::dealloc(self.buf, self.layout);
+            std::alloc::dealloc(self.buf.as_ptr(), self.layout);
         }
     }
 }
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, AsBytes, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "with-arbitrary", derive(arbitrary::Arbitrary))]
 pub struct LapicState {
     pub regs: [::std::os::raw::c_char; 1024usize],
 }
@@ -482,6 +1188,7 @@ impl Default for hv_register_value {
 } */
 #[repr(C)]
 #[derive(Copy, Clone, Debug, AsBytes, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "with-arbitrary", derive(arbitrary::Arbitrary))]
 /// This struct normalizes the actual mhsv XSave structure
 /// XSave only used in save and restore functionalities, serilization and
 /// deserialization are needed. Putting all the fields into a single buffer makes
@@ -558,45 +1265,173 @@ impl From<XSave> for mshv_vp_state {
         ret
     }
 }
-impl From<mshv_vp_state> for LapicState {
-    fn from(reg: mshv_vp_state) -> Self {
-        let mut ret: LapicState = LapicState::default();
-        let state = ret.regs.as_mut_ptr();
-        let hv_state = unsafe { *reg.buf.lapic };
-        unsafe {
-            *(state.offset(LOCAL_APIC_OFFSET_APIC_ID) as *mut u32) = hv_state.apic_id;
-            *(state.offset(LOCAL_APIC_OFFSET_VERSION) as *mut u32) = hv_state.apic_version;
-            *(state.offset(LOCAL_APIC_OFFSET_REMOTE_READ) as *mut u32) = hv_state.apic_remote_read;
-            *(state.offset(LOCAL_APIC_OFFSET_LDR) as *mut u32) = hv_state.apic_ldr;
-            *(state.offset(LOCAL_APIC_OFFSET_DFR) as *mut u32) = hv_state.apic_dfr;
-            *(state.offset(LOCAL_APIC_OFFSET_SPURIOUS) as *mut u32) = hv_state.apic_spurious;
-            *(state.offset(LOCAL_APIC_OFFSET_ERROR) as *mut u32) = hv_state.apic_esr;
-            *(state.offset(LOCAL_APIC_OFFSET_ICR_LOW) as *mut u32) = hv_state.apic_icr_low;
-            *(state.offset(LOCAL_APIC_OFFSET_ICR_HIGH) as *mut u32) = hv_state.apic_icr_high;
-            *(state.offset(LOCAL_APIC_OFFSET_TIMER_LVT) as *mut u32) = hv_state.apic_lvt_timer;
-            *(state.offset(LOCAL_APIC_OFFSET_THERMAL_LVT) as *mut u32) = hv_state.apic_lvt_thermal;
-            *(state.offset(LOCAL_APIC_OFFSET_PERFMON_LVT) as *mut u32) = hv_state.apic_lvt_perfmon;
-            *(state.offset(LOCAL_APIC_OFFSET_LINT0_LVT) as *mut u32) = hv_state.apic_lvt_lint0;
-            *(state.offset(LOCAL_APIC_OFFSET_LINT1_LVT) as *mut u32) = hv_state.apic_lvt_lint1;
-            *(state.offset(LOCAL_APIC_OFFSET_ERROR_LVT) as *mut u32) = hv_state.apic_lvt_error;
-            *(state.offset(LOCAL_APIC_OFFSET_INITIAL_COUNT) as *mut u32) =
-                hv_state.apic_initial_count;
-            *(state.offset(LOCAL_APIC_OFFSET_CURRENT_COUNT) as *mut u32) =
-                hv_state.apic_counter_value;
-            *(state.offset(LOCAL_APIC_OFFSET_DIVIDER) as *mut u32) =
-                hv_state.apic_divide_configuration;
+/// Default IA32_APIC_BASE address of the local APIC page
+pub const APIC_DEFAULT_ADDRESS: u64 = 0xfee0_0000;
+/// IA32_APIC_BASE: this is the bootstrap processor
+pub const APIC_BASE_BSP: u64 = 1 << 8;
+/// IA32_APIC_BASE: x2APIC mode, only with `APIC_BASE_ENABLE`
+pub const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+/// IA32_APIC_BASE: the local APIC is enabled
+pub const APIC_BASE_ENABLE: u64 = 1 << 11;
+/// IA32_APIC_BASE: bits holding the address of the local APIC page
+pub const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+/// Spurious interrupt vector register: the APIC is software enabled
+pub const APIC_SPURIOUS_SOFTWARE_ENABLE: u32 = 1 << 8;
+/// LVT entry: the interrupt is masked
+pub const APIC_LVT_MASKED: u32 = 1 << 16;
+
+/// Local vector table entries, masked while the APIC is software disabled
+const LOCAL_APIC_LVT_OFFSETS: [isize; 6] = [
+    LOCAL_APIC_OFFSET_TIMER_LVT,
+    LOCAL_APIC_OFFSET_THERMAL_LVT,
+    LOCAL_APIC_OFFSET_PERFMON_LVT,
+    LOCAL_APIC_OFFSET_LINT0_LVT,
+    LOCAL_APIC_OFFSET_LINT1_LVT,
+    LOCAL_APIC_OFFSET_ERROR_LVT,
+];
+
+/// Decoded IA32_APIC_BASE, the register selecting where the local APIC is
+/// mapped and which mode it runs in
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ApicBase {
+    /// GPA of the local APIC page in xAPIC mode
+    pub address: u64,
+    /// This is the bootstrap processor
+    pub bsp: bool,
+    /// The local APIC is enabled
+    pub enabled: bool,
+    /// The local APIC is in x2APIC mode, accessed through MSRs
+    pub x2apic: bool,
+}
+
+impl Default for ApicBase {
+    /// Reset value for an application processor: enabled xAPIC at the
+    /// default address
+    fn default() -> Self {
+        ApicBase {
+            address: APIC_DEFAULT_ADDRESS,
+            bsp: false,
+            enabled: true,
+            x2apic: false,
+        }
+    }
+}
+
+impl From<u64> for ApicBase {
+    fn from(value: u64) -> Self {
+        ApicBase {
+            address: value & APIC_BASE_ADDRESS_MASK,
+            bsp: value & APIC_BASE_BSP != 0,
+            enabled: value & APIC_BASE_ENABLE != 0,
+            x2apic: value & APIC_BASE_X2APIC_ENABLE != 0,
+        }
+    }
+}
+
+impl From<ApicBase> for u64 {
+    fn from(base: ApicBase) -> Self {
+        (base.address & APIC_BASE_ADDRESS_MASK)
+            | if base.bsp { APIC_BASE_BSP } else { 0 }
+            | if base.enabled { APIC_BASE_ENABLE } else { 0 }
+            | if base.x2apic {
+                APIC_BASE_X2APIC_ENABLE
+            } else {
+                0
+            }
+    }
+}
+
+/// Mode of a local APIC, the part of IA32_APIC_BASE that decides how its
+/// registers are laid out
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum ApicMode {
+    /// Hardware disabled, no local interrupt is delivered
+    Disabled,
+    /// Enabled and accessed through its page
+    #[default]
+    XApic,
+    /// Enabled and accessed through MSRs
+    X2Apic,
+}
+
+impl ApicBase {
+    /// Mode the local APIC runs in. The page address plays no part in it.
+    pub fn mode(&self) -> ApicMode {
+        match (self.enabled, self.x2apic) {
+            (false, _) => ApicMode::Disabled,
+            (true, false) => ApicMode::XApic,
+            (true, true) => ApicMode::X2Apic,
+        }
+    }
+}
+
+impl LapicState {
+    /// Register at `offset` of the APIC page, one of `LOCAL_APIC_OFFSET_*`
+    pub fn reg(&self, offset: isize) -> u32 {
+        let offset = offset as usize;
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&self.as_bytes()[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    }
+    /// Sets the register at `offset` of the APIC page
+    pub fn set_reg(&mut self, offset: isize, value: u32) {
+        let offset = offset as usize;
+        self.as_bytes_mut()[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+    /// The spurious interrupt vector register enables the APIC
+    pub fn is_software_enabled(&self) -> bool {
+        self.reg(LOCAL_APIC_OFFSET_SPURIOUS) & APIC_SPURIOUS_SOFTWARE_ENABLE != 0
+    }
+
+    /// Converts the hypervisor's LAPIC state, saved while the APIC was in
+    /// `mode`. In x2APIC mode the APIC ID keeps all 32 bits and
+    /// the logical destination is the one derived from it.
+    pub fn from_hv_state(
+        hv_state: &hv_local_interrupt_controller_state,
+        mode: ApicMode,
+    ) -> LapicState {
+        let mut ret = LapicState::default();
+        let registers = [
+            (LOCAL_APIC_OFFSET_APIC_ID, hv_state.apic_id),
+            (LOCAL_APIC_OFFSET_VERSION, hv_state.apic_version),
+            (LOCAL_APIC_OFFSET_REMOTE_READ, hv_state.apic_remote_read),
+            (LOCAL_APIC_OFFSET_LDR, hv_state.apic_ldr),
+            (LOCAL_APIC_OFFSET_DFR, hv_state.apic_dfr),
+            (LOCAL_APIC_OFFSET_SPURIOUS, hv_state.apic_spurious),
+            (LOCAL_APIC_OFFSET_ERROR, hv_state.apic_esr),
+            (LOCAL_APIC_OFFSET_ICR_LOW, hv_state.apic_icr_low),
+            (LOCAL_APIC_OFFSET_ICR_HIGH, hv_state.apic_icr_high),
+            (LOCAL_APIC_OFFSET_TIMER_LVT, hv_state.apic_lvt_timer),
+            (LOCAL_APIC_OFFSET_THERMAL_LVT, hv_state.apic_lvt_thermal),
+            (LOCAL_APIC_OFFSET_PERFMON_LVT, hv_state.apic_lvt_perfmon),
+            (LOCAL_APIC_OFFSET_LINT0_LVT, hv_state.apic_lvt_lint0),
+            (LOCAL_APIC_OFFSET_LINT1_LVT, hv_state.apic_lvt_lint1),
+            (LOCAL_APIC_OFFSET_ERROR_LVT, hv_state.apic_lvt_error),
+            (LOCAL_APIC_OFFSET_INITIAL_COUNT, hv_state.apic_initial_count),
+            (LOCAL_APIC_OFFSET_CURRENT_COUNT, hv_state.apic_counter_value),
+            (
+                LOCAL_APIC_OFFSET_DIVIDER,
+                hv_state.apic_divide_configuration,
+            ),
+        ];
+        for (offset, value) in registers {
+            ret.set_reg(offset, value);
         }
 
         /* vectors ISR TMR IRR */
         for i in 0..8 {
-            unsafe {
-                *(state.offset(LOCAL_APIC_OFFSET_ISR + i * 16) as *mut u32) =
-                    hv_state.apic_isr[i as usize];
-                *(state.offset(LOCAL_APIC_OFFSET_TMR + i * 16) as *mut u32) =
-                    hv_state.apic_tmr[i as usize];
-                *(state.offset(LOCAL_APIC_OFFSET_IRR + i * 16) as *mut u32) =
-                    hv_state.apic_irr[i as usize];
-            }
+            ret.set_reg(
+                LOCAL_APIC_OFFSET_ISR + i * 16,
+                hv_state.apic_isr[i as usize],
+            );
+            ret.set_reg(
+                LOCAL_APIC_OFFSET_TMR + i * 16,
+                hv_state.apic_tmr[i as usize],
+            );
+            ret.set_reg(
+                LOCAL_APIC_OFFSET_IRR + i * 16,
+                hv_state.apic_irr[i as usize],
+            );
         }
 
         // Highest priority interrupt (isr = in service register) this is how WHP computes it
@@ -611,59 +1446,86 @@ impl From<mshv_vp_state> for LapicState {
         }
 
         // TODO This is meant to be max(tpr, isrv), but tpr is not populated!
-        unsafe {
-            *(state.offset(LOCAL_APIC_OFFSET_PPR) as *mut u32) = isrv;
-        }
+        ret.set_reg(LOCAL_APIC_OFFSET_PPR, isrv);
+        ret.fixup(mode);
         ret
     }
-}
 
-impl From<LapicState> for mshv_vp_state {
-    fn from(reg: LapicState) -> Self {
-        let state = reg.regs.as_ptr();
-        let mut vp_state: mshv_vp_state = mshv_vp_state::default();
-        unsafe {
-            let mut lapic_state = hv_local_interrupt_controller_state {
-                apic_id: *(state.offset(LOCAL_APIC_OFFSET_APIC_ID) as *mut u32),
-                apic_version: *(state.offset(LOCAL_APIC_OFFSET_VERSION) as *mut u32),
-                apic_remote_read: *(state.offset(LOCAL_APIC_OFFSET_REMOTE_READ) as *mut u32),
-                apic_ldr: *(state.offset(LOCAL_APIC_OFFSET_LDR) as *mut u32),
-                apic_dfr: *(state.offset(LOCAL_APIC_OFFSET_DFR) as *mut u32),
-                apic_spurious: *(state.offset(LOCAL_APIC_OFFSET_SPURIOUS) as *mut u32),
-                apic_esr: *(state.offset(LOCAL_APIC_OFFSET_ERROR) as *mut u32),
-                apic_icr_low: *(state.offset(LOCAL_APIC_OFFSET_ICR_LOW) as *mut u32),
-                apic_icr_high: *(state.offset(LOCAL_APIC_OFFSET_ICR_HIGH) as *mut u32),
-                apic_lvt_timer: *(state.offset(LOCAL_APIC_OFFSET_TIMER_LVT) as *mut u32),
-                apic_lvt_thermal: *(state.offset(LOCAL_APIC_OFFSET_THERMAL_LVT) as *mut u32),
-                apic_lvt_perfmon: *(state.offset(LOCAL_APIC_OFFSET_PERFMON_LVT) as *mut u32),
-                apic_lvt_lint0: *(state.offset(LOCAL_APIC_OFFSET_LINT0_LVT) as *mut u32),
-                apic_lvt_lint1: *(state.offset(LOCAL_APIC_OFFSET_LINT1_LVT) as *mut u32),
-                apic_lvt_error: *(state.offset(LOCAL_APIC_OFFSET_ERROR_LVT) as *mut u32),
-                apic_initial_count: *(state.offset(LOCAL_APIC_OFFSET_INITIAL_COUNT) as *mut u32),
-                apic_counter_value: *(state.offset(LOCAL_APIC_OFFSET_CURRENT_COUNT) as *mut u32),
-                apic_divide_configuration: *(state.offset(LOCAL_APIC_OFFSET_DIVIDER) as *mut u32),
-                apic_error_status: 0,
-                apic_lvt_cmci: 0,
-                apic_isr: [0; 8],
-                apic_tmr: [0; 8],
-                apic_irr: [0; 8],
-            };
+    /// Converts to the hypervisor's LAPIC state, to be restored while the
+    /// APIC is in `mode`.
+    pub fn to_hv_state(&self, mode: ApicMode) -> hv_local_interrupt_controller_state {
+        let mut state = *self;
+        state.fixup(mode);
+        let mut lapic_state = hv_local_interrupt_controller_state {
+            apic_id: state.reg(LOCAL_APIC_OFFSET_APIC_ID),
+            apic_version: state.reg(LOCAL_APIC_OFFSET_VERSION),
+            apic_remote_read: state.reg(LOCAL_APIC_OFFSET_REMOTE_READ),
+            apic_ldr: state.reg(LOCAL_APIC_OFFSET_LDR),
+            apic_dfr: state.reg(LOCAL_APIC_OFFSET_DFR),
+            apic_spurious: state.reg(LOCAL_APIC_OFFSET_SPURIOUS),
+            apic_esr: state.reg(LOCAL_APIC_OFFSET_ERROR),
+            apic_icr_low: state.reg(LOCAL_APIC_OFFSET_ICR_LOW),
+            apic_icr_high: state.reg(LOCAL_APIC_OFFSET_ICR_HIGH),
+            apic_lvt_timer: state.reg(LOCAL_APIC_OFFSET_TIMER_LVT),
+            apic_lvt_thermal: state.reg(LOCAL_APIC_OFFSET_THERMAL_LVT),
+            apic_lvt_perfmon: state.reg(LOCAL_APIC_OFFSET_PERFMON_LVT),
+            apic_lvt_lint0: state.reg(LOCAL_APIC_OFFSET_LINT0_LVT),
+            apic_lvt_lint1: state.reg(LOCAL_APIC_OFFSET_LINT1_LVT),
+            apic_lvt_error: state.reg(LOCAL_APIC_OFFSET_ERROR_LVT),
+            apic_initial_count: state.reg(LOCAL_APIC_OFFSET_INITIAL_COUNT),
+            apic_counter_value: state.reg(LOCAL_APIC_OFFSET_CURRENT_COUNT),
+            apic_divide_configuration: state.reg(LOCAL_APIC_OFFSET_DIVIDER),
+            apic_error_status: 0,
+            apic_lvt_cmci: 0,
+            apic_isr: [0; 8],
+            apic_tmr: [0; 8],
+            apic_irr: [0; 8],
+        };
+
+        /* vectors ISR TMR IRR */
+        for i in 0..8 {
+            lapic_state.apic_isr[i as usize] = state.reg(LOCAL_APIC_OFFSET_ISR + i * 16);
+            lapic_state.apic_tmr[i as usize] = state.reg(LOCAL_APIC_OFFSET_TMR + i * 16);
+            lapic_state.apic_irr[i as usize] = state.reg(LOCAL_APIC_OFFSET_IRR + i * 16);
+        }
+        lapic_state
+    }
 
-            /* vectors ISR TMR IRR */
-            for i in 0..8 {
-                lapic_state.apic_isr[i as usize] =
-                    *(state.offset(LOCAL_APIC_OFFSET_ISR + i * 16) as *mut u32);
-                lapic_state.apic_tmr[i as usize] =
-                    *(state.offset(LOCAL_APIC_OFFSET_TMR + i * 16) as *mut u32);
-                lapic_state.apic_irr[i as usize] =
-                    *(state.offset(LOCAL_APIC_OFFSET_IRR + i * 16) as *mut u32);
+    // Brings the registers in line with what the APIC allows in `mode`
+    fn fixup(&mut self, mode: ApicMode) {
+        // A disabled APIC, hardware or software, delivers no local interrupts
+        if mode == ApicMode::Disabled || !self.is_software_enabled() {
+            for offset in LOCAL_APIC_LVT_OFFSETS {
+                let lvt = self.reg(offset);
+                self.set_reg(offset, lvt | APIC_LVT_MASKED);
             }
-            vp_state.type_ =
-                hv_get_set_vp_state_type_HV_GET_SET_VP_STATE_LOCAL_INTERRUPT_CONTROLLER_STATE;
-            vp_state.buf_size = 1024;
-            let boxed_obj = Box::new(lapic_state);
-            vp_state.buf.lapic = Box::into_raw(boxed_obj);
         }
+        if mode == ApicMode::X2Apic {
+            // There is no DFR in x2APIC mode and the LDR is read only
+            let id = self.reg(LOCAL_APIC_OFFSET_APIC_ID);
+            self.set_reg(LOCAL_APIC_OFFSET_DFR, 0xffff_ffff);
+            self.set_reg(LOCAL_APIC_OFFSET_LDR, ((id >> 4) << 16) | (1 << (id & 0xf)));
+        }
+    }
+}
+
+impl From<mshv_vp_state> for LapicState {
+    fn from(reg: mshv_vp_state) -> Self {
+        // SAFETY: buf points to the LAPIC state filled in by MSHV_GET_VP_STATE
+        let hv_state = unsafe { *reg.buf.lapic };
+        LapicState::from_hv_state(&hv_state, ApicMode::default())
+    }
+}
+
+impl From<LapicState> for mshv_vp_state {
+    fn from(reg: LapicState) -> Self {
+        let mut vp_state: mshv_vp_state = mshv_vp_state {
+            type_: hv_get_set_vp_state_type_HV_GET_SET_VP_STATE_LOCAL_INTERRUPT_CONTROLLER_STATE,
+            buf_size: 1024,
+            ..Default::default()
+        };
+        let boxed_obj = Box::new(reg.to_hv_state(ApicMode::default()));
+        vp_state.buf.lapic = Box::into_raw(boxed_obj);
         vp_state
     }
 }
@@ -721,16 +1583,1709 @@ impl XSave {
     }
 }
 
-#[repr(C)]
-#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, AsBytes, FromBytes, FromZeroes)]
-#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
-pub struct SuspendRegisters {
-    pub explicit_register: u64,
-    pub intercept_register: u64,
+/// Size of the XSAVE area the hypervisor hands out, a page
+pub const XSAVE_AREA_SIZE: usize = 4096;
+
+// Offsets in the XSAVE area, the legacy FXSAVE region first
+const XSAVE_OFFSET_FCW: usize = 0;
+const XSAVE_OFFSET_FSW: usize = 2;
+const XSAVE_OFFSET_FTW: usize = 4;
+const XSAVE_OFFSET_MXCSR: usize = 24;
+const XSAVE_OFFSET_ST: usize = 32;
+const XSAVE_OFFSET_XMM: usize = 160;
+const XSAVE_OFFSET_XSTATE_BV: usize = 512;
+const XSAVE_OFFSET_XCOMP_BV: usize = 520;
+
+/// Read-only view of XSave state borrowed from the buffer holding it, for
+/// callers that only look at a few fields. `XSave` owns a copy instead.
+#[derive(Debug, Copy, Clone)]
+pub struct XSaveView<'a> {
+    flags: u64,
+    states: u64,
+    data: &'a [u8],
 }
-#[repr(C)]
-#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, AsBytes, FromBytes, FromZeroes)]
-#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
-pub struct MiscRegs {
-    pub hypercall: u64,
+
+impl<'a> XSaveView<'a> {
+    /// Creates a view of the XSAVE area `data`, as returned by
+    /// MSHV_GET_VP_STATE with `flags` and `states`
+    pub fn new(flags: u64, states: u64, data: &'a [u8]) -> Self {
+        XSaveView {
+            flags,
+            states,
+            data: &data[..cmp::min(data.len(), XSAVE_AREA_SIZE)],
+        }
+    }
+    pub fn flags(&self) -> u64 {
+        self.flags
+    }
+    pub fn states(&self) -> u64 {
+        self.states
+    }
+    /// The XSAVE area
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+    fn bytes<const N: usize>(&self, offset: usize) -> [u8; N] {
+        let mut bytes = [0; N];
+        if let Some(src) = self.data.get(offset..offset + N) {
+            bytes.copy_from_slice(src);
+        }
+        bytes
+    }
+    /// x87 control word
+    pub fn fcw(&self) -> u16 {
+        u16::from_le_bytes(self.bytes(XSAVE_OFFSET_FCW))
+    }
+    /// x87 status word
+    pub fn fsw(&self) -> u16 {
+        u16::from_le_bytes(self.bytes(XSAVE_OFFSET_FSW))
+    }
+    /// Abridged x87 tag word
+    pub fn ftw(&self) -> u8 {
+        self.bytes::<1>(XSAVE_OFFSET_FTW)[0]
+    }
+    pub fn mxcsr(&self) -> u32 {
+        u32::from_le_bytes(self.bytes(XSAVE_OFFSET_MXCSR))
+    }
+    /// x87 register `i`, as the 10 byte value followed by padding
+    pub fn st(&self, i: usize) -> [u8; 16] {
+        assert!(i < 8);
+        self.bytes(XSAVE_OFFSET_ST + i * 16)
+    }
+    /// Low 128 bits of vector register `i`
+    pub fn xmm(&self, i: usize) -> u128 {
+        assert!(i < 16);
+        u128::from_le_bytes(self.bytes(XSAVE_OFFSET_XMM + i * 16))
+    }
+    /// State components present in the area, from the XSAVE header
+    pub fn xstate_bv(&self) -> u64 {
+        u64::from_le_bytes(self.bytes(XSAVE_OFFSET_XSTATE_BV))
+    }
+    /// Compaction format of the area, from the XSAVE header
+    pub fn xcomp_bv(&self) -> u64 {
+        u64::from_le_bytes(self.bytes(XSAVE_OFFSET_XCOMP_BV))
+    }
+    /// Owned copy of the state
+    pub fn to_xsave(&self) -> XSave {
+        let mut xsave = XSave::default();
+        let buffer = xsave.as_bytes_mut();
+        buffer[..8].copy_from_slice(&self.flags.to_le_bytes());
+        buffer[8..16].copy_from_slice(&self.states.to_le_bytes());
+        buffer[16..24].copy_from_slice(&(self.data.len() as u64).to_le_bytes());
+        buffer[24..24 + self.data.len()].copy_from_slice(self.data);
+        xsave
+    }
+}
+
+impl<'a> From<&'a XSave> for XSaveView<'a> {
+    fn from(xsave: &'a XSave) -> Self {
+        let size = cmp::min(xsave.data_size() as usize, XSAVE_AREA_SIZE);
+        XSaveView::new(
+            xsave.flags(),
+            xsave.states(),
+            &xsave.as_bytes()[24..24 + size],
+        )
+    }
+}
+
+/// Crash report written by the guest through the Hyper-V crash MSRs
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+pub struct GuestCrash {
+    /// HV_X64_MSR_CRASH_P0 to HV_X64_MSR_CRASH_P4
+    pub params: [u64; 5],
+    /// HV_X64_MSR_CRASH_CTL
+    pub ctl: u64,
+}
+
+impl GuestCrash {
+    /// Windows guests write the bugcheck code to P0
+    pub fn bugcheck_code(&self) -> u32 {
+        self.params[0] as u32
+    }
+
+    /// Windows guests write the four bugcheck parameters to P1-P4
+    pub fn bugcheck_params(&self) -> [u64; 4] {
+        [
+            self.params[1],
+            self.params[2],
+            self.params[3],
+            self.params[4],
+        ]
+    }
+
+    /// Returns the GPA and size of the crash message, if the guest provided
+    /// one. P3 and P4 then hold the message location instead of parameters.
+    pub fn message(&self) -> Option<(u64, u64)> {
+        if self.ctl & HV_CRASH_CTL_CRASH_NOTIFY_MSG != 0 {
+            Some((self.params[3], self.params[4]))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for GuestCrash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "guest crash: bugcheck {:#x} ({:#x}, {:#x}, {:#x}, {:#x})",
+            self.bugcheck_code(),
+            self.params[1],
+            self.params[2],
+            self.params[3],
+            self.params[4]
+        )
+    }
+}
+
+/// i8042 keyboard controller command port
+pub const I8042_COMMAND_PORT: u16 = 0x64;
+/// i8042 command pulsing the CPU reset line
+pub const I8042_CMD_PULSE_RESET: u8 = 0xfe;
+/// Reset control register of PIIX/ICH chipsets
+pub const RESET_CONTROL_PORT: u16 = 0xcf9;
+/// Reset control bit starting the reset
+pub const RESET_CONTROL_RST_CPU: u8 = 1 << 2;
+/// Reset control bit asking for a full reset, cycling power
+pub const RESET_CONTROL_FULL_RESET: u8 = 1 << 3;
+
+/// How the guest asked for a reset, see `hv_message::to_reset_exit`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ResetSource {
+    /// Pulse reset command written to the i8042 keyboard controller
+    KeyboardController,
+    /// Write to the reset control register
+    ResetControl {
+        /// The guest asked for a full reset
+        full: bool,
+    },
+    /// Write to HV_X64_MSR_RESET, the Hyper-V reset enlightenment
+    HypervMsr,
+}
+
+/// Why the guest stopped running, see `VcpuFd::shutdown_exit`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ShutdownReason {
+    /// The vCPU hit an exception it could not deliver, a triple fault
+    TripleFault,
+    /// HvCallEnterSleepState to S5, the guest powered off
+    PowerOff,
+    /// HvCallEnterSleepState to any other state, one of
+    /// hv_sleep_state_HV_SLEEP_STATE_*
+    SleepState(hv_sleep_state),
+    /// The guest reported a crash through HV_X64_MSR_CRASH_CTL
+    Crash(GuestCrash),
+}
+
+/// Exit after which the VMM resets or tears down the partition, see
+/// `VcpuFd::power_exit`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PowerExit {
+    /// The guest asked for a reset
+    Reset(ResetSource),
+    /// The guest stopped running
+    Shutdown(ShutdownReason),
+}
+
+/// What the guest became ready to take, see
+/// `hv_message::to_interrupt_window_exit`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum InterruptWindow {
+    /// An external interrupt: RFLAGS.IF is set and nothing blocks it
+    Interrupt,
+    /// A non maskable interrupt
+    Nmi,
+}
+
+/// Machine check exception vector
+pub const X86_MC_VECTOR: u8 = 18;
+
+/// MCG_STATUS: execution can restart at the saved RIP
+pub const MCG_STATUS_RIPV: u64 = 1 << 0;
+/// MCG_STATUS: the saved RIP points at the faulting instruction
+pub const MCG_STATUS_EIPV: u64 = 1 << 1;
+/// MCG_STATUS: a machine check is in progress
+pub const MCG_STATUS_MCIP: u64 = 1 << 2;
+
+/// MCi_STATUS: the register holds a valid error
+pub const MCI_STATUS_VAL: u64 = 1 << 63;
+/// MCi_STATUS: an earlier error was lost
+pub const MCI_STATUS_OVER: u64 = 1 << 62;
+/// MCi_STATUS: the error was not corrected
+pub const MCI_STATUS_UC: u64 = 1 << 61;
+/// MCi_STATUS: the error was enabled in MCi_CTL
+pub const MCI_STATUS_EN: u64 = 1 << 60;
+/// MCi_STATUS: MCi_MISC holds more information
+pub const MCI_STATUS_MISCV: u64 = 1 << 59;
+/// MCi_STATUS: MCi_ADDR holds the error address
+pub const MCI_STATUS_ADDRV: u64 = 1 << 58;
+/// MCi_STATUS: the processor context is corrupt
+pub const MCI_STATUS_PCC: u64 = 1 << 57;
+/// MCi_STATUS: the error was signaled with a machine check
+pub const MCI_STATUS_S: u64 = 1 << 56;
+/// MCi_STATUS: software has to act on the error before resuming
+pub const MCI_STATUS_AR: u64 = 1 << 55;
+
+/// A machine check to report to the guest, see
+/// `VcpuFd::inject_machine_check`.
+///
+/// The hypervisor does not virtualize the MCA banks: the VMM intercepts
+/// the guest reads of MCG_STATUS and of the MSRs of `bank`, and answers
+/// them with `msr_value`.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct MceInfo {
+    /// Bank reporting the error
+    pub bank: u8,
+    /// MCi_STATUS of the bank, MCI_STATUS_* flags and the error codes
+    pub status: u64,
+    /// MCi_ADDR of the bank
+    pub addr: u64,
+    /// MCi_MISC of the bank
+    pub misc: u64,
+    /// MCG_STATUS, MCG_STATUS_* flags
+    pub mcg_status: u64,
+}
+
+impl MceInfo {
+    /// Builds the uncorrected memory error a host reports for a poisoned
+    /// page at guest physical address `gpa`. An action required error is
+    /// for a page the vCPU was accessing, an action optional one for a
+    /// page found by scrubbing.
+    pub fn memory_poison(bank: u8, gpa: u64, action_required: bool) -> Self {
+        let mut status = MCI_STATUS_VAL
+            | MCI_STATUS_UC
+            | MCI_STATUS_EN
+            | MCI_STATUS_MISCV
+            | MCI_STATUS_ADDRV
+            | MCI_STATUS_S;
+        let mcg_status = if action_required {
+            // Data load error in the memory hierarchy
+            status |= MCI_STATUS_AR | 0x134;
+            MCG_STATUS_MCIP | MCG_STATUS_EIPV
+        } else {
+            // Memory scrubbing error
+            status |= 0xc0;
+            MCG_STATUS_MCIP | MCG_STATUS_RIPV
+        };
+        MceInfo {
+            bank,
+            status,
+            addr: gpa,
+            // Physical address, valid down to the page
+            misc: (2 << 6) | 12,
+            mcg_status,
+        }
+    }
+
+    /// MSR index of MCi_CTL of `bank`, followed by MCi_STATUS, MCi_ADDR
+    /// and MCi_MISC
+    pub fn bank_msr(bank: u8) -> u32 {
+        IA32_MSR_MC0_CTL + 4 * bank as u32
+    }
+
+    /// Returns what the guest reads from MSR `index` while the machine
+    /// check is reported, None for MSRs it does not cover.
+    pub fn msr_value(&self, index: u32) -> Option<u64> {
+        let base = Self::bank_msr(self.bank);
+        if index == IA32_MSR_MCG_STATUS {
+            Some(self.mcg_status)
+        } else if index == base + 1 {
+            Some(self.status)
+        } else if index == base + 2 {
+            Some(self.addr)
+        } else if index == base + 3 {
+            Some(self.misc)
+        } else {
+            None
+        }
+    }
+
+    /// HV_REGISTER_PENDING_INTERRUPTION value queueing the #MC
+    pub fn pending_interruption(&self) -> hv_x64_pending_interruption_register {
+        let mut pending = hv_x64_pending_interruption_register { as_uint64: 0 };
+        // SAFETY: access union fields
+        unsafe {
+            pending.__bindgen_anon_1.set_interruption_pending(1);
+            pending
+                .__bindgen_anon_1
+                .set_interruption_type(HV_X64_PENDING_EXCEPTION);
+            pending
+                .__bindgen_anon_1
+                .set_interruption_vector(X86_MC_VECTOR as u32);
+        }
+        pending
+    }
+}
+
+/// Guest TSC state of a vCPU.
+///
+/// The TSC offset is not part of it: the hypervisor exposes no register for
+/// it and derives it from the guest TSC value written, so the state carries
+/// that value and the offset against a given host is only computed, see
+/// `offset_for_host`.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "with-arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+pub struct TscState {
+    /// TSC frequency in Hz
+    pub frequency: u64,
+    /// Guest TSC value
+    pub tsc: u64,
+    /// IA32_TSC_ADJUST value
+    pub tsc_adjust: u64,
+}
+
+impl TscState {
+    /// Returns the offset between the guest TSC and a host TSC reading
+    /// `host_tsc`, for the guest to resume at `tsc`.
+    pub fn offset_for_host(&self, host_tsc: u64) -> u64 {
+        self.tsc.wrapping_sub(host_tsc)
+    }
+
+    /// Moves `tsc` forward by `elapsed_ns` nanoseconds, for VMMs that want
+    /// the guest TSC to account for the time the guest was not running.
+    pub fn advance(&mut self, elapsed_ns: u64) {
+        let ticks = elapsed_ns as u128 * self.frequency as u128 / 1_000_000_000;
+        self.tsc = self.tsc.wrapping_add(ticks as u64);
+    }
+}
+
+/// State of one synthetic timer (STIMERn_CONFIG/STIMERn_COUNT)
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "with-arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+pub struct SyntheticTimerState {
+    pub config: u64,
+    pub count: u64,
+    pub adjustment: u64,
+    pub undelivered_exp_time: u64,
+    /// An expiration message could not be delivered and is still pending
+    pub undelivered_msg_pending: bool,
+}
+
+/// State of the synthetic timers of a vCPU, as carried by the
+/// HV_GET_SET_VP_STATE_SYNTHETIC_TIMERS VP state component
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "with-arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+pub struct SyntheticTimersState {
+    pub timers: [SyntheticTimerState; HV_SYNIC_STIMER_COUNT as usize],
+}
+
+impl From<hv_synthetic_timers_state> for SyntheticTimersState {
+    fn from(state: hv_synthetic_timers_state) -> Self {
+        let mut ret = SyntheticTimersState::default();
+        for (timer, hv_timer) in ret.timers.iter_mut().zip(state.timers.iter()) {
+            *timer = SyntheticTimerState {
+                config: hv_timer.config,
+                count: hv_timer.count,
+                adjustment: hv_timer.adjustment,
+                undelivered_exp_time: hv_timer.undelivered_exp_time,
+                undelivered_msg_pending: hv_timer.flags.undelivered_msg_pending() != 0,
+            };
+        }
+        ret
+    }
+}
+
+impl From<SyntheticTimersState> for hv_synthetic_timers_state {
+    fn from(state: SyntheticTimersState) -> Self {
+        let mut ret = hv_synthetic_timers_state::default();
+        for (hv_timer, timer) in ret.timers.iter_mut().zip(state.timers.iter()) {
+            let mut flags = hv_stimer_state__bindgen_ty_1::default();
+            flags.set_undelivered_msg_pending(timer.undelivered_msg_pending as u32);
+            *hv_timer = hv_stimer_state {
+                flags,
+                resvd: 0,
+                config: timer.config,
+                count: timer.count,
+                adjustment: timer.adjustment,
+                undelivered_exp_time: timer.undelivered_exp_time,
+            };
+        }
+        ret
+    }
+}
+
+bitflags::bitflags! {
+    /// Flags for `VcpuFd::translate_gva`
+    #[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+    pub struct GvaTranslateFlags: u64 {
+        /// Fail unless the page is readable
+        const VALIDATE_READ = HV_TRANSLATE_GVA_VALIDATE_READ as u64;
+        /// Fail unless the page is writable
+        const VALIDATE_WRITE = HV_TRANSLATE_GVA_VALIDATE_WRITE as u64;
+        /// Fail unless the page is executable
+        const VALIDATE_EXECUTE = HV_TRANSLATE_GVA_VALIDATE_EXECUTE as u64;
+        /// Skip the privilege level check
+        const PRIVILEGE_EXEMPT = HV_TRANSLATE_GVA_PRIVILEGE_EXEMPT as u64;
+        /// Set the accessed and, for writes, dirty bits in the guest page tables
+        const SET_PAGE_TABLE_BITS = HV_TRANSLATE_GVA_SET_PAGE_TABLE_BITS as u64;
+        /// Keep the guest from flushing its TLB until the translation is used
+        const TLB_FLUSH_INHIBIT = HV_TRANSLATE_GVA_TLB_FLUSH_INHIBIT as u64;
+        /// Check access as supervisor mode
+        const SUPERVISOR_ACCESS = HV_TRANSLATE_GVA_SUPERVISOR_ACCESS as u64;
+        /// Check access as user mode
+        const USER_ACCESS = HV_TRANSLATE_GVA_USER_ACCESS as u64;
+        /// Apply SMAP to supervisor accesses
+        const ENFORCE_SMAP = HV_TRANSLATE_GVA_ENFORCE_SMAP as u64;
+        /// Ignore SMAP for supervisor accesses
+        const OVERRIDE_SMAP = HV_TRANSLATE_GVA_OVERRIDE_SMAP as u64;
+        /// Translate as a shadow stack access
+        const SHADOW_STACK = HV_TRANSLATE_GVA_SHADOW_STACK as u64;
+    }
+}
+
+bitflags::bitflags! {
+    /// What to do with the page access state bits after reading them, for
+    /// `VmFd::get_dirty_log` and the other access state queries. With no
+    /// flag the bits are only read.
+    #[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+    pub struct GpaAccessFlags: u64 {
+        /// Clear the accessed bits
+        const CLEAR_ACCESSED = 1 << 0;
+        /// Set the accessed bits
+        const SET_ACCESSED = 1 << 1;
+        /// Clear the dirty bits, to track writes from now on
+        const CLEAR_DIRTY = 1 << 2;
+        /// Set the dirty bits, which dirty page tracking needs before it
+        /// is disabled
+        const SET_DIRTY = 1 << 3;
+    }
+}
+
+impl GpaAccessFlags {
+    /// The flags as the hypercall input takes them
+    pub fn to_hv(self) -> hv_gpa_page_access_state_flags {
+        hv_gpa_page_access_state_flags {
+            as_uint64: self.bits(),
+        }
+    }
+}
+
+bitflags::bitflags! {
+    /// Guest access permissions of mapped memory, see
+    /// `VmFd::protect_user_memory`. The other HV_MAP_GPA_* flags of a
+    /// region are kept.
+    #[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+    pub struct GpaProtection: u32 {
+        /// The guest may read
+        const READ = HV_MAP_GPA_READABLE;
+        /// The guest may write
+        const WRITE = HV_MAP_GPA_WRITABLE;
+        /// The guest may execute, in kernel and user mode
+        const EXECUTE = HV_MAP_GPA_EXECUTABLE;
+    }
+}
+
+/// Kind of guest access behind an intercept
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum GpaAccessType {
+    /// Read
+    Read,
+    /// Write
+    Write,
+    /// Instruction fetch
+    Execute,
+}
+
+impl GpaAccessType {
+    /// Decodes an HV_INTERCEPT_ACCESS_* value
+    pub fn from_intercept(access_type: u8) -> Option<GpaAccessType> {
+        match access_type as u32 {
+            HV_INTERCEPT_ACCESS_READ => Some(GpaAccessType::Read),
+            HV_INTERCEPT_ACCESS_WRITE => Some(GpaAccessType::Write),
+            HV_INTERCEPT_ACCESS_EXECUTE => Some(GpaAccessType::Execute),
+            _ => None,
+        }
+    }
+    /// The protection that allows the access
+    pub fn protection(&self) -> GpaProtection {
+        match self {
+            GpaAccessType::Read => GpaProtection::READ,
+            GpaAccessType::Write => GpaProtection::WRITE,
+            GpaAccessType::Execute => GpaProtection::EXECUTE,
+        }
+    }
+}
+
+/// Guest access to mapped memory its protection denies, decoded by
+/// `hv_message::to_protection_violation`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct GpaProtectionViolation {
+    /// Guest physical address accessed
+    pub gpa: u64,
+    /// Guest virtual address accessed, if the hypervisor reports it
+    pub gva: Option<u64>,
+    /// What the guest tried
+    pub access: GpaAccessType,
+    /// Address of the faulting instruction
+    pub rip: u64,
+    /// Length of the faulting instruction, to skip it
+    pub instruction_length: u8,
+}
+
+/// HLT exit, decoded by `hv_message::to_halt_exit`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct HaltExit {
+    /// VP that halted
+    pub vp_index: u32,
+    /// Address of the HLT instruction
+    pub rip: u64,
+    /// RFLAGS at the HLT, IF tells whether an interrupt can wake the vCPU
+    pub rflags: u64,
+    /// Length of the HLT instruction, to skip it
+    pub instruction_length: u8,
+    /// Privilege level the guest halted at
+    pub cpl: u8,
+    /// An interrupt shadow (STI or MOV SS) covers the HLT
+    pub interrupt_shadow: bool,
+    /// An event is pending delivery to the vCPU
+    pub interruption_pending: bool,
+}
+
+/// Outcome of a GVA translation
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum GvaResult {
+    /// The GVA was translated
+    Success,
+    /// A page table entry on the walk is not present
+    PageNotPresent,
+    /// The access is not allowed at the current privilege level
+    PrivilegeViolation,
+    /// A page table entry on the walk has reserved bits set
+    InvalidPageTableFlags,
+    /// The resulting GPA is not mapped
+    GpaUnmapped,
+    /// The resulting GPA is not readable
+    GpaNoReadAccess,
+    /// The resulting GPA is not writable
+    GpaNoWriteAccess,
+    /// The resulting GPA is in an overlay page that may not be accessed
+    GpaIllegalOverlayAccess,
+    /// The walk hit an intercepted page
+    Intercept,
+    /// The resulting GPA has not been accepted by the isolated guest
+    GpaUnaccepted,
+    /// Result code not known to this crate
+    Unknown(u32),
+}
+
+impl GvaResult {
+    /// Returns true if the translation succeeded
+    pub fn is_success(&self) -> bool {
+        *self == GvaResult::Success
+    }
+}
+
+impl From<u32> for GvaResult {
+    #[allow(non_upper_case_globals)]
+    fn from(code: u32) -> Self {
+        match code {
+            hv_translate_gva_result_code_HV_TRANSLATE_GVA_SUCCESS => GvaResult::Success,
+            hv_translate_gva_result_code_HV_TRANSLATE_GVA_PAGE_NOT_PRESENT => {
+                GvaResult::PageNotPresent
+            }
+            hv_translate_gva_result_code_HV_TRANSLATE_GVA_PRIVILEGE_VIOLATION => {
+                GvaResult::PrivilegeViolation
+            }
+            hv_translate_gva_result_code_HV_TRANSLATE_GVA_INVALIDE_PAGE_TABLE_FLAGS => {
+                GvaResult::InvalidPageTableFlags
+            }
+            hv_translate_gva_result_code_HV_TRANSLATE_GVA_GPA_UNMAPPED => GvaResult::GpaUnmapped,
+            hv_translate_gva_result_code_HV_TRANSLATE_GVA_GPA_NO_READ_ACCESS => {
+                GvaResult::GpaNoReadAccess
+            }
+            hv_translate_gva_result_code_HV_TRANSLATE_GVA_GPA_NO_WRITE_ACCESS => {
+                GvaResult::GpaNoWriteAccess
+            }
+            hv_translate_gva_result_code_HV_TRANSLATE_GVA_GPA_ILLEGAL_OVERLAY_ACCESS => {
+                GvaResult::GpaIllegalOverlayAccess
+            }
+            hv_translate_gva_result_code_HV_TRANSLATE_GVA_INTERCEPT => GvaResult::Intercept,
+            hv_translate_gva_result_code_HV_TRANSLATE_GVA_GPA_UNACCEPTED => {
+                GvaResult::GpaUnaccepted
+            }
+            code => GvaResult::Unknown(code),
+        }
+    }
+}
+
+impl From<hv_translate_gva_result> for GvaResult {
+    fn from(result: hv_translate_gva_result) -> Self {
+        // SAFETY: access union fields
+        let code = unsafe { result.__bindgen_anon_1.result_code };
+        GvaResult::from(code)
+    }
+}
+
+/// RFLAGS direction flag
+pub const X86_RFLAGS_DF: u64 = 1 << 10;
+
+/// String I/O (INS/OUTS) access decoded from an I/O port intercept
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct StringIo {
+    /// I/O port
+    pub port: u16,
+    /// Bytes per element: 1, 2 or 4
+    pub access_size: u8,
+    /// True for OUTS, false for INS
+    pub is_write: bool,
+    /// True if the instruction has a REP prefix
+    pub rep: bool,
+    /// Number of elements to transfer, RCX for REP, 1 otherwise
+    pub count: u64,
+    /// Segment base, DS for OUTS and ES for INS
+    pub segment_base: u64,
+    /// Index register, RSI for OUTS and RDI for INS
+    pub index: u64,
+    /// RFLAGS.DF is set, the index moves down after each element
+    pub reverse: bool,
+}
+
+impl StringIo {
+    /// Decodes `msg`, returns `None` if it is not a string I/O access.
+    ///
+    /// Segment override prefixes are not taken into account, OUTS always
+    /// reads from DS.
+    pub fn from_intercept(msg: &hv_x64_io_port_intercept_message) -> Option<StringIo> {
+        let access_info = msg.access_info;
+        // SAFETY: access union fields
+        let (string_op, rep, access_size) = unsafe {
+            (
+                access_info.__bindgen_anon_1.string_op(),
+                access_info.__bindgen_anon_1.rep_prefix(),
+                access_info.__bindgen_anon_1.access_size(),
+            )
+        };
+        if string_op == 0 {
+            return None;
+        }
+        let header = msg.header;
+        let is_write = header.intercept_access_type == HV_INTERCEPT_ACCESS_WRITE as u8;
+        let (segment, index) = if is_write {
+            (msg.ds_segment, msg.rsi)
+        } else {
+            (msg.es_segment, msg.rdi)
+        };
+        Some(StringIo {
+            port: msg.port_number,
+            access_size,
+            is_write,
+            rep: rep != 0,
+            count: if rep != 0 { msg.rcx } else { 1 },
+            segment_base: segment.base,
+            index,
+            reverse: header.rflags & X86_RFLAGS_DF != 0,
+        })
+    }
+    /// Guest virtual address of element `n`
+    pub fn gva(&self, n: u64) -> u64 {
+        let offset = n.wrapping_mul(self.access_size as u64);
+        let index = if self.reverse {
+            self.index.wrapping_sub(offset)
+        } else {
+            self.index.wrapping_add(offset)
+        };
+        self.segment_base.wrapping_add(index)
+    }
+    /// Index register value once all elements are transferred
+    pub fn final_index(&self) -> u64 {
+        self.gva(self.count).wrapping_sub(self.segment_base)
+    }
+}
+
+/// Hypercall input value: fast hypercall, parameters passed in registers
+pub const HV_HYPERCALL_FAST_BIT: u64 = 1 << 16;
+/// Hypercall input value: shift of the 12-bit rep count, also the shift of
+/// the reps completed in the result value
+pub const HV_HYPERCALL_REP_COMP_SHIFT: u32 = 32;
+/// Hypercall input value: shift of the 12-bit rep start index
+pub const HV_HYPERCALL_REP_START_SHIFT: u32 = 48;
+/// Mask of the rep count and rep start index fields
+pub const HV_HYPERCALL_REP_MASK: u64 = 0xfff;
+
+/// Hypercall forwarded to user space, decoded from a hypercall intercept
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct HypercallExit {
+    /// Call code, HvExtCall* codes have bit 15 set
+    pub code: u16,
+    /// Parameters are in registers rather than in guest memory
+    pub fast: bool,
+    /// Number of reps for rep hypercalls, 0 otherwise
+    pub rep_count: u16,
+    /// First rep to process, non zero when a rep hypercall is restarted
+    pub rep_start: u16,
+    /// Input parameter GPA, or the first input value for fast hypercalls
+    pub input: u64,
+    /// Output parameter GPA, or the second input value for fast hypercalls
+    pub output: u64,
+    /// The guest issued the hypercall in long mode
+    pub long_mode: bool,
+    /// RIP of the hypercall instruction
+    pub rip: u64,
+    /// Length of the hypercall instruction
+    pub instruction_length: u8,
+}
+
+impl HypercallExit {
+    /// Decodes `msg`. The input value is in RCX and the parameters in RDX
+    /// and R8 for 64-bit guests, in EDX:EAX, EBX:ECX and EDI:ESI otherwise.
+    pub fn from_intercept(msg: &hv_x64_hypercall_intercept_message) -> HypercallExit {
+        let header = msg.header;
+        let execution_state = header.execution_state;
+        // SAFETY: access union fields
+        let long_mode = unsafe { execution_state.__bindgen_anon_1.efer_lma() } != 0;
+        let pair = |hi: u64, lo: u64| (hi & 0xffff_ffff) << 32 | lo & 0xffff_ffff;
+        let (control, input, output) = if long_mode {
+            (msg.rcx, msg.rdx, msg.r8)
+        } else {
+            (
+                pair(msg.rdx, msg.rax),
+                pair(msg.rbx, msg.rcx),
+                pair(msg.rdi, msg.rsi),
+            )
+        };
+        HypercallExit {
+            code: control as u16,
+            fast: control & HV_HYPERCALL_FAST_BIT != 0,
+            rep_count: (control >> HV_HYPERCALL_REP_COMP_SHIFT & HV_HYPERCALL_REP_MASK) as u16,
+            rep_start: (control >> HV_HYPERCALL_REP_START_SHIFT & HV_HYPERCALL_REP_MASK) as u16,
+            input,
+            output,
+            long_mode,
+            rip: header.rip,
+            instruction_length: header.instruction_length(),
+        }
+    }
+    /// Returns true for HvExtCall* hypercalls
+    pub fn is_extended(&self) -> bool {
+        self.code & 0x8000 != 0
+    }
+    /// Hypercall result value for `status` with `reps_completed` reps done
+    pub fn result(status: u16, reps_completed: u16) -> u64 {
+        status as u64
+            | (reps_completed as u64 & HV_HYPERCALL_REP_MASK) << HV_HYPERCALL_REP_COMP_SHIFT
+    }
+}
+
+/// Synthetic timer configuration, the value of HV_REGISTER_STIMERx_CONFIG
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct StimerConfig {
+    /// The timer is armed
+    pub enabled: bool,
+    /// Rearm the timer with the same count when it expires
+    pub periodic: bool,
+    /// Lazy expiration, only for the reference counter based timers
+    pub lazy: bool,
+    /// Setting a non zero count enables the timer
+    pub auto_enable: bool,
+    /// APIC vector raised on expiration in direct mode
+    pub vector: u8,
+    /// Raise `vector` instead of posting a message to `sint`
+    pub direct_mode: bool,
+    /// SINT the expiration message is posted to, when not in direct mode
+    pub sint: u8,
+}
+
+impl StimerConfig {
+    /// Direct mode timer raising `vector`, one-shot unless `periodic`
+    pub fn direct(vector: u8, periodic: bool) -> StimerConfig {
+        StimerConfig {
+            enabled: true,
+            periodic,
+            vector,
+            direct_mode: true,
+            ..Default::default()
+        }
+    }
+    /// Timer posting HVMSG_TIMER_EXPIRED to `sint`, one-shot unless
+    /// `periodic`
+    pub fn message(sint: u8, periodic: bool) -> StimerConfig {
+        StimerConfig {
+            enabled: true,
+            periodic,
+            sint,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<u64> for StimerConfig {
+    fn from(value: u64) -> Self {
+        StimerConfig {
+            enabled: value & 1 != 0,
+            periodic: value & (1 << 1) != 0,
+            lazy: value & (1 << 2) != 0,
+            auto_enable: value & (1 << 3) != 0,
+            vector: (value >> 4) as u8,
+            direct_mode: value & (1 << 12) != 0,
+            sint: (value >> 16 & 0xf) as u8,
+        }
+    }
+}
+
+impl From<StimerConfig> for u64 {
+    fn from(config: StimerConfig) -> Self {
+        config.enabled as u64
+            | (config.periodic as u64) << 1
+            | (config.lazy as u64) << 2
+            | (config.auto_enable as u64) << 3
+            | (config.vector as u64) << 4
+            | (config.direct_mode as u64) << 12
+            | (config.sint as u64 & 0xf) << 16
+    }
+}
+
+/// Number of VP state components that can be retrieved through MSHV_GET_VP_STATE
+pub const VP_STATE_COMPONENTS_COUNT: usize = 5;
+
+/// VP state component types, in the order they are laid out in `AllVpStateComponents`
+pub const VP_STATE_COMP_TYPES: [hv_get_set_vp_state_type; VP_STATE_COMPONENTS_COUNT] = [
+    hv_get_set_vp_state_type_HV_GET_SET_VP_STATE_LOCAL_INTERRUPT_CONTROLLER_STATE,
+    hv_get_set_vp_state_type_HV_GET_SET_VP_STATE_XSAVE,
+    hv_get_set_vp_state_type_HV_GET_SET_VP_STATE_SIM_PAGE,
+    hv_get_set_vp_state_type_HV_GET_SET_VP_STATE_SIEF_PAGE,
+    hv_get_set_vp_state_type_HV_GET_SET_VP_STATE_SYNTHETIC_TIMERS,
+];
+
+/// Size in bytes reserved for each VP state component. The hypervisor
+/// works on whole pages, so every component is page sized.
+pub const VP_STATE_COMP_SIZES: [usize; VP_STATE_COMPONENTS_COUNT] =
+    [0x1000, 0x3000, 0x1000, 0x1000, 0x1000];
+
+pub const VP_STATE_COMPONENTS_BUFFER_SIZE: usize = VP_STATE_COMP_SIZES[0]
+    + VP_STATE_COMP_SIZES[1]
+    + VP_STATE_COMP_SIZES[2]
+    + VP_STATE_COMP_SIZES[3]
+    + VP_STATE_COMP_SIZES[4];
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, AsBytes, FromBytes, FromZeroes)]
+/// Raw contents of every VP state component (LAPIC, XSave, SIM page,
+/// SIEF page and synthetic timers) stored back to back in a single buffer,
+/// in the order given by `VP_STATE_COMP_TYPES`.
+pub struct AllVpStateComponents {
+    pub buffer: [u8; VP_STATE_COMPONENTS_BUFFER_SIZE],
+}
+
+impl Default for AllVpStateComponents {
+    fn default() -> Self {
+        AllVpStateComponents::new_zeroed()
+    }
+}
+
+impl AllVpStateComponents {
+    /// Offset of the component at `index` within the buffer
+    pub fn component_offset(index: usize) -> usize {
+        VP_STATE_COMP_SIZES[..index].iter().sum()
+    }
+
+    /// Copy the component at `index` into `buffer` if `to_buffer` is true,
+    /// otherwise fill the component from `buffer`. Fails with EINVAL if
+    /// there is no such component or it does not fit in `buffer`.
+    pub fn copy_to_or_from_buffer(
+        &mut self,
+        index: usize,
+        buffer: &mut AlignedBuffer,
+        to_buffer: bool,
+    ) -> Result<(), errno::Error> {
+        let len = match VP_STATE_COMP_SIZES.get(index) {
+            Some(len) if *len <= buffer.size() => *len,
+            _ => return Err(errno::Error::new(libc::EINVAL)),
+        };
+        let start = Self::component_offset(index);
+        let end = start + len;
+
+        if to_buffer {
+            buffer.as_mut_slice()[..len].copy_from_slice(&self.buffer[start..end]);
+        } else {
+            self.buffer[start..end].copy_from_slice(&buffer.as_slice()[..len]);
+        }
+        Ok(())
+    }
+
+    /// Raw contents of the component at `index`, see `VP_STATE_COMP_TYPES`
+    pub fn component(&self, index: usize) -> &[u8] {
+        let start = Self::component_offset(index);
+        &self.buffer[start..start + VP_STATE_COMP_SIZES[index]]
+    }
+
+    fn read_component<T: Copy>(&self, index: usize) -> T {
+        let data = self.component(index);
+        assert!(std::mem::size_of::<T>() <= data.len());
+        // SAFETY: data holds at least a T, which is plain data
+        unsafe { ptr::read_unaligned(data.as_ptr() as *const T) }
+    }
+
+    /// Local interrupt controller state, as laid out for the APIC mode the
+    /// vCPU was in. `LapicState::from_hv_state` converts it.
+    pub fn lapic(&self) -> hv_local_interrupt_controller_state {
+        self.read_component(0)
+    }
+
+    /// XSAVE area. Its flags and states are not saved with the components
+    /// and read as zero.
+    pub fn xsave(&self) -> XSaveView<'_> {
+        XSaveView::new(0, 0, self.component(1))
+    }
+
+    /// Synthetic interrupt message page
+    pub fn simp_page(&self) -> hv_message_page {
+        self.read_component(2)
+    }
+
+    /// Synthetic interrupt event flags page
+    pub fn siefp_page(&self) -> hv_synic_event_flags_page {
+        self.read_component(3)
+    }
+
+    pub fn synthetic_timers(&self) -> SyntheticTimersState {
+        SyntheticTimersState::from(self.read_component::<hv_synthetic_timers_state>(4))
+    }
+}
+
+/// A VP state component the kernel reports besides those in
+/// `VP_STATE_COMP_TYPES`, kept as opaque bytes so that state added by newer
+/// kernels survives a save and restore.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+pub struct VpStateComponent {
+    pub type_: hv_get_set_vp_state_type,
+    pub data: Vec<u8>,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, AsBytes, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+pub struct SuspendRegisters {
+    pub explicit_register: u64,
+    pub intercept_register: u64,
+}
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, AsBytes, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "with-arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+pub struct MiscRegs {
+    pub hypercall: u64,
+}
+
+fn is_mtrr_type(mem_type: u8) -> bool {
+    matches!(
+        mem_type,
+        MTRR_TYPE_UC | MTRR_TYPE_WC | MTRR_TYPE_WT | MTRR_TYPE_WP | MTRR_TYPE_WB
+    )
+}
+
+/// Variable range MTRR, an IA32_MTRR_PHYSBASEn/IA32_MTRR_PHYSMASKn pair
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, AsBytes, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "with-arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+pub struct MtrrRange {
+    pub base: u64,
+    pub mask: u64,
+}
+
+impl MtrrRange {
+    /// Returns true if the valid bit of the mask is set
+    pub fn is_enabled(&self) -> bool {
+        self.mask & MTRR_PHYSMASK_VALID != 0
+    }
+
+    /// Memory type of the range, one of MTRR_TYPE_*
+    pub fn mem_type(&self) -> u8 {
+        self.base as u8
+    }
+
+    /// Returns true if some address matches both ranges
+    pub fn overlaps(&self, other: &MtrrRange) -> bool {
+        (self.base ^ other.base) & self.mask & other.mask & MTRR_PHYS_ADDR_MASK == 0
+    }
+}
+
+/// MTRRs of a vCPU: IA32_MTRR_DEF_TYPE, the fixed range MTRRs in
+/// `MTRR_FIXED_MSRS` order and the variable range MTRRs
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, AsBytes, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "with-arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+pub struct MtrrState {
+    pub def_type: u64,
+    pub fixed: [u64; MTRR_NUM_FIXED],
+    pub variable: [MtrrRange; MTRR_NUM_VARIABLE],
+}
+
+impl MtrrState {
+    /// Indices of the MSRs holding the state, in the order `to_msrs` uses
+    pub fn msr_indices() -> Vec<u32> {
+        let mut indices = vec![IA32_MSR_MTRR_DEF_TYPE];
+        indices.extend_from_slice(&MTRR_FIXED_MSRS);
+        for i in 0..MTRR_NUM_VARIABLE as u32 {
+            indices.push(IA32_MSR_MTRR_PHYSBASE0 + 2 * i);
+            indices.push(IA32_MSR_MTRR_PHYSMASK0 + 2 * i);
+        }
+        indices
+    }
+
+    /// The state as MSR entries
+    pub fn to_msrs(&self) -> Vec<msr_entry> {
+        let mut values = vec![self.def_type];
+        values.extend_from_slice(&self.fixed);
+        for range in self.variable.iter() {
+            values.push(range.base);
+            values.push(range.mask);
+        }
+        Self::msr_indices()
+            .into_iter()
+            .zip(values)
+            .map(|(index, data)| msr_entry {
+                index,
+                data,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    /// Returns true for the MSRs holding the state
+    pub fn is_mtrr_msr(index: u32) -> bool {
+        index == IA32_MSR_MTRR_DEF_TYPE
+            || (IA32_MSR_MTRR_PHYSBASE0..=IA32_MSR_MTRR_PHYSMASK7).contains(&index)
+            || MTRR_FIXED_MSRS.contains(&index)
+    }
+
+    /// Gathers the state from MSR entries, MTRRs missing from `msrs` are 0
+    pub fn from_msrs(msrs: &[msr_entry]) -> Self {
+        let mut ret = MtrrState::default();
+        for msr in msrs {
+            match msr.index {
+                IA32_MSR_MTRR_DEF_TYPE => ret.def_type = msr.data,
+                IA32_MSR_MTRR_PHYSBASE0..=IA32_MSR_MTRR_PHYSMASK7 => {
+                    let range =
+                        &mut ret.variable[(msr.index - IA32_MSR_MTRR_PHYSBASE0) as usize / 2];
+                    if msr.index & 1 == 0 {
+                        range.base = msr.data;
+                    } else {
+                        range.mask = msr.data;
+                    }
+                }
+                index => {
+                    if let Some(i) = MTRR_FIXED_MSRS.iter().position(|m| *m == index) {
+                        ret.fixed[i] = msr.data;
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    /// Checks the memory types and reserved bits, and that overlapping
+    /// variable ranges combine into a defined type: the same type, UC over
+    /// anything, or WT over WB. Fails with EINVAL otherwise.
+    pub fn validate(&self) -> Result<(), errno::Error> {
+        let einval = Err(errno::Error::new(libc::EINVAL));
+        if self.def_type & !(0xff | MTRR_DEF_TYPE_FE | MTRR_DEF_TYPE_E) != 0
+            || !is_mtrr_type(self.def_type as u8)
+        {
+            return einval;
+        }
+        for fixed in self.fixed.iter() {
+            if !fixed.to_le_bytes().iter().all(|t| is_mtrr_type(*t)) {
+                return einval;
+            }
+        }
+        let enabled: Vec<&MtrrRange> = self.variable.iter().filter(|r| r.is_enabled()).collect();
+        for (i, range) in enabled.iter().enumerate() {
+            if range.base & !(MTRR_PHYS_ADDR_MASK | 0xff) != 0
+                || range.mask & !(MTRR_PHYS_ADDR_MASK | MTRR_PHYSMASK_VALID) != 0
+                || !is_mtrr_type(range.mem_type())
+            {
+                return einval;
+            }
+            for other in enabled[i + 1..].iter().filter(|o| range.overlaps(o)) {
+                let mut types = [range.mem_type(), other.mem_type()];
+                types.sort_unstable();
+                if types[0] != types[1]
+                    && types[0] != MTRR_TYPE_UC
+                    && types != [MTRR_TYPE_WT, MTRR_TYPE_WB]
+                {
+                    return einval;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hvdef::*;
+    use std::mem::{align_of, size_of};
+
+    #[test]
+    fn test_mshv_root_hvcall_layout() {
+        assert_eq!(size_of::<mshv_root_hvcall>(), 32);
+        assert_eq!(align_of::<mshv_root_hvcall>(), 8);
+        let args = mshv_root_hvcall::default();
+        let base = ptr::addr_of!(args) as usize;
+        assert_eq!(ptr::addr_of!(args.status) as usize - base, 8);
+        assert_eq!(ptr::addr_of!(args.in_ptr) as usize - base, 16);
+        assert_eq!(ptr::addr_of!(args.out_ptr) as usize - base, 24);
+    }
+
+    #[test]
+    fn test_mtrr_state() {
+        let mut mtrrs = MtrrState {
+            def_type: MTRR_DEF_TYPE_E | MTRR_DEF_TYPE_FE | MTRR_TYPE_WB as u64,
+            ..Default::default()
+        };
+        mtrrs.fixed[0] = 0x0606_0606_0606_0606;
+        // 3 GiB - 4 GiB uncached, on top of WB for the first 4 GiB
+        mtrrs.variable[0] = MtrrRange {
+            base: MTRR_TYPE_WB as u64,
+            mask: 0xf_0000_0000 | MTRR_PHYSMASK_VALID,
+        };
+        mtrrs.variable[1] = MtrrRange {
+            base: 0xc000_0000 | MTRR_TYPE_UC as u64,
+            mask: 0xf_c000_0000 | MTRR_PHYSMASK_VALID,
+        };
+        mtrrs.validate().unwrap();
+
+        let msrs = mtrrs.to_msrs();
+        assert!(msrs.len() == 1 + MTRR_NUM_FIXED + 2 * MTRR_NUM_VARIABLE);
+        assert!(msrs[0].index == IA32_MSR_MTRR_DEF_TYPE);
+        assert!(msrs[1 + MTRR_NUM_FIXED + 3].index == IA32_MSR_MTRR_PHYSMASK1);
+        assert!(MtrrState::from_msrs(&msrs) == mtrrs);
+
+        // WC over WB is undefined, but fine on disjoint ranges
+        mtrrs.variable[2] = MtrrRange {
+            base: 0x8000_0000 | MTRR_TYPE_WC as u64,
+            mask: 0xf_f000_0000 | MTRR_PHYSMASK_VALID,
+        };
+        assert!(mtrrs.validate().unwrap_err().errno() == libc::EINVAL);
+        mtrrs.variable[2].base |= 0x1_0000_0000;
+        mtrrs.validate().unwrap();
+        mtrrs.variable[2].mask &= !MTRR_PHYSMASK_VALID;
+        mtrrs.variable[2].base = 0x8000_0000 | MTRR_TYPE_WC as u64;
+        mtrrs.validate().unwrap();
+
+        mtrrs.fixed[3] = 2;
+        assert!(mtrrs.validate().is_err());
+        mtrrs.fixed[3] = 0;
+        mtrrs.def_type |= 1 << 12;
+        assert!(mtrrs.validate().is_err());
+    }
+
+    #[test]
+    fn test_lapic_apic_base() {
+        let base = ApicBase::from(0xfebf_f000 | APIC_BASE_ENABLE | APIC_BASE_BSP);
+        assert!(base.address == 0xfebf_f000 && base.enabled && base.bsp && !base.x2apic);
+        assert!(u64::from(base) == 0xfebf_f000 | APIC_BASE_ENABLE | APIC_BASE_BSP);
+
+        let mut lapic = LapicState::default();
+        lapic.set_reg(LOCAL_APIC_OFFSET_APIC_ID, 0x23);
+        lapic.set_reg(LOCAL_APIC_OFFSET_LINT0_LVT, 0x700);
+        lapic.set_reg(
+            LOCAL_APIC_OFFSET_SPURIOUS,
+            APIC_SPURIOUS_SOFTWARE_ENABLE | 0xff,
+        );
+        assert!(base.mode() == ApicMode::XApic);
+        let hv_state = lapic.to_hv_state(base.mode());
+        assert!(hv_state.apic_lvt_lint0 == 0x700 && hv_state.apic_id == 0x23);
+
+        // x2APIC derives the logical destination from the ID
+        let x2apic = ApicBase {
+            x2apic: true,
+            ..base
+        }
+        .mode();
+        assert!(x2apic == ApicMode::X2Apic);
+        let hv_state = lapic.to_hv_state(x2apic);
+        assert!(hv_state.apic_ldr == (2 << 16) | (1 << 3));
+        assert!(LapicState::from_hv_state(&hv_state, x2apic).reg(LOCAL_APIC_OFFSET_LDR) == 0x20008);
+
+        // Software or hardware disabled APICs keep their LVTs masked
+        lapic.set_reg(LOCAL_APIC_OFFSET_SPURIOUS, 0xff);
+        assert!(lapic.to_hv_state(base.mode()).apic_lvt_lint0 == 0x700 | APIC_LVT_MASKED);
+        let disabled = ApicBase {
+            enabled: false,
+            ..base
+        }
+        .mode();
+        assert!(disabled == ApicMode::Disabled);
+        let hv_state = LapicState::default().to_hv_state(disabled);
+        assert!(hv_state.apic_lvt_timer == APIC_LVT_MASKED);
+    }
+
+    #[test]
+    fn test_string_io() {
+        let mut msg = hv_x64_io_port_intercept_message {
+            port_number: 0x3f8,
+            rcx: 3,
+            rsi: 0x1000,
+            rdi: 0x2000,
+            ..Default::default()
+        };
+        assert!(StringIo::from_intercept(&msg).is_none());
+
+        // SAFETY: access union fields
+        unsafe {
+            msg.access_info.__bindgen_anon_1.set_string_op(1);
+            msg.access_info.__bindgen_anon_1.set_rep_prefix(1);
+            msg.access_info.__bindgen_anon_1.set_access_size(2);
+        }
+        msg.header.intercept_access_type = HV_INTERCEPT_ACCESS_WRITE as u8;
+        msg.ds_segment.base = 0x10000;
+        let io = StringIo::from_intercept(&msg).unwrap();
+        assert!(io.is_write && io.rep);
+        assert_eq!(io.count, 3);
+        assert_eq!(io.gva(1), 0x11002);
+        assert_eq!(io.final_index(), 0x1006);
+
+        msg.header.intercept_access_type = HV_INTERCEPT_ACCESS_READ as u8;
+        msg.header.rflags = X86_RFLAGS_DF;
+        let io = StringIo::from_intercept(&msg).unwrap();
+        assert!(!io.is_write && io.reverse);
+        assert_eq!(io.gva(1), 0x1ffe);
+        assert_eq!(io.final_index(), 0x1ffa);
+    }
+    #[test]
+    fn test_hypercall_exit() {
+        let mut msg = hv_x64_hypercall_intercept_message {
+            rcx: HV_EXT_CALL_MEMORY_HEAT_HINT as u64 | 3 << 32 | 1 << 48,
+            rdx: 0x1000,
+            r8: 0x2000,
+            ..Default::default()
+        };
+        msg.header.rip = 0xfff0;
+        msg.header.set_instruction_length(3);
+        // SAFETY: access union fields
+        unsafe { msg.header.execution_state.__bindgen_anon_1.set_efer_lma(1) };
+        let exit = HypercallExit::from_intercept(&msg);
+        assert!(exit.is_extended() && exit.long_mode && !exit.fast);
+        assert_eq!(exit.code, HV_EXT_CALL_MEMORY_HEAT_HINT);
+        assert_eq!((exit.rep_count, exit.rep_start), (3, 1));
+        assert_eq!((exit.input, exit.output), (0x1000, 0x2000));
+        assert_eq!(exit.instruction_length, 3);
+
+        let msg = hv_x64_hypercall_intercept_message {
+            rax: HV_EXT_CALL_QUERY_CAPABILITIES as u64 | HV_HYPERCALL_FAST_BIT,
+            rbx: 0x1,
+            rcx: 0x2000,
+            ..Default::default()
+        };
+        let exit = HypercallExit::from_intercept(&msg);
+        assert!(!exit.long_mode && exit.fast);
+        assert_eq!(exit.code, HV_EXT_CALL_QUERY_CAPABILITIES);
+        assert_eq!(exit.input, 0x1_0000_2000);
+        assert_eq!(HypercallExit::result(2, 3), 0x3_0000_0002);
+    }
+    #[test]
+    fn test_stimer_config() {
+        let config = StimerConfig::direct(0x40, true);
+        assert_eq!(u64::from(config), 0x1403);
+        assert_eq!(StimerConfig::from(0x1403), config);
+        let config = StimerConfig::message(2, false);
+        assert_eq!(u64::from(config), 0x20001);
+        assert_eq!(StimerConfig::from(0x20001), config);
+    }
+    #[test]
+    fn test_gva_result() {
+        let mut result = hv_translate_gva_result { as_uint64: 0 };
+        assert!(GvaResult::from(result).is_success());
+        result.__bindgen_anon_1.result_code =
+            hv_translate_gva_result_code_HV_TRANSLATE_GVA_GPA_UNMAPPED;
+        assert_eq!(GvaResult::from(result), GvaResult::GpaUnmapped);
+        assert_eq!(GvaResult::from(0x100), GvaResult::Unknown(0x100));
+
+        let flags = GvaTranslateFlags::VALIDATE_READ | GvaTranslateFlags::VALIDATE_WRITE;
+        assert_eq!(flags.bits(), 0x3);
+    }
+
+    #[test]
+    fn test_gpa_access_flags() {
+        let flags = GpaAccessFlags::CLEAR_DIRTY | GpaAccessFlags::SET_ACCESSED;
+        // SAFETY: access union fields
+        unsafe {
+            let hv = flags.to_hv().__bindgen_anon_1;
+            assert!(hv.clear_dirty() == 1);
+            assert!(hv.set_access() == 1);
+            assert!(hv.clear_accessed() == 0);
+            assert!(hv.set_dirty() == 0);
+        }
+        // SAFETY: access union fields
+        assert!(
+            unsafe {
+                GpaAccessFlags::SET_DIRTY
+                    .to_hv()
+                    .__bindgen_anon_1
+                    .set_dirty()
+            } == 1
+        );
+        // SAFETY: access union fields
+        assert!(unsafe { GpaAccessFlags::empty().to_hv().as_uint64 } == 0);
+    }
+    use crate::hvdef::HV_CRASH_CTL_CRASH_NOTIFY;
+
+    #[test]
+    fn test_standard_registers_hv_assocs() {
+        let regs = StandardRegisters {
+            rax: 0x1,
+            rdx: 0x2,
+            r15: 0x3,
+            rip: 0x1000,
+            rflags: 0x2,
+            ..Default::default()
+        };
+        let mut assocs = regs.to_hv_assocs();
+        assert!(assocs[16].name == hv_register_name_HV_X64_REGISTER_RIP);
+        assocs.reverse();
+        assert!(StandardRegisters::from_hv_assocs(&assocs).unwrap() == regs);
+        assert!(StandardRegisters::from_hv_assocs(&assocs[1..]).is_err());
+    }
+
+    #[test]
+    fn test_special_registers_hv_assocs() {
+        let mut sregs = SpecialRegisters {
+            cr0: 0x80050033,
+            cr3: 0x1000,
+            efer: 0x500,
+            apic_base: 0xfee00900,
+            ..Default::default()
+        };
+        sregs.cs.base = 0x10000;
+        sregs.cs.selector = 0x8;
+        sregs.cs.l = 1;
+        sregs.gdt.limit = 0x27;
+        sregs.interrupt_bitmap[0] = 1;
+
+        let assocs = sregs.to_hv_assocs();
+        let round_trip = SpecialRegisters::from_hv_assocs(&assocs).unwrap();
+        assert!(round_trip.cs.base == sregs.cs.base);
+        assert!(round_trip.cs.selector == sregs.cs.selector);
+        assert!(round_trip.cs.l == sregs.cs.l);
+        assert!(round_trip.gdt.limit == sregs.gdt.limit);
+        assert!(round_trip.cr0 == sregs.cr0);
+        assert!(round_trip.cr3 == sregs.cr3);
+        assert!(round_trip.efer == sregs.efer);
+        assert!(round_trip.apic_base == sregs.apic_base);
+        assert!(round_trip.interrupt_bitmap == [0; 4]);
+        assert!(SpecialRegisters::from_hv_assocs(&assocs[..16]).is_err());
+    }
+
+    #[test]
+    fn test_reg_bundle() {
+        let mut regs = RegBundle::with_values([
+            (hv_register_name_HV_X64_REGISTER_RIP, 0x1000),
+            (hv_register_name_HV_X64_REGISTER_RAX, 0x42),
+        ]);
+        regs.set_reg64(0, 0x1002);
+        assert_eq!(regs.name(1), hv_register_name_HV_X64_REGISTER_RAX);
+        assert_eq!(regs.reg64(0), 0x1002);
+        assert_eq!(regs.reg64(1), 0x42);
+        regs.set_reg64_at::<1>(0x43);
+        assert_eq!(regs.name_at::<1>(), hv_register_name_HV_X64_REGISTER_RAX);
+        assert_eq!(regs.reg64_at::<1>(), 0x43);
+        assert_eq!(regs.as_slice().len(), 2);
+
+        let regs = RegBundle::new([hv_register_name_HV_X64_REGISTER_RFLAGS]);
+        assert_eq!(regs.reg64(0), 0);
+        assert_eq!(
+            format!("{:?}", regs),
+            format!("[{:?}]", RegisterAssoc::from(regs.as_slice()[0]))
+        );
+    }
+
+    #[test]
+    fn test_xsave_view() {
+        let mut data = [0u8; XSAVE_AREA_SIZE];
+        data[0..2].copy_from_slice(&0x37fu16.to_le_bytes());
+        data[24..28].copy_from_slice(&0x1f80u32.to_le_bytes());
+        data[160 + 16..160 + 32].copy_from_slice(&0x1234u128.to_le_bytes());
+        data[512..520].copy_from_slice(&3u64.to_le_bytes());
+        let view = XSaveView::new(1, 2, &data);
+        assert_eq!(view.fcw(), 0x37f);
+        assert_eq!(view.mxcsr(), 0x1f80);
+        assert_eq!(view.xmm(1), 0x1234);
+        assert_eq!(view.xmm(0), 0);
+        assert_eq!(view.xstate_bv(), 3);
+
+        let xsave = view.to_xsave();
+        assert_eq!(xsave.flags(), 1);
+        assert_eq!(xsave.states(), 2);
+        assert_eq!(xsave.data_size(), XSAVE_AREA_SIZE as u64);
+        let view = XSaveView::from(&xsave);
+        assert_eq!(view.data(), &data[..]);
+        assert_eq!(view.fcw(), 0x37f);
+
+        // Fields past a short area read as zero
+        assert_eq!(XSaveView::new(0, 0, &data[..8]).mxcsr(), 0);
+    }
+
+    #[test]
+    fn test_aligned_buffer() {
+        let mut buffer = AlignedBuffer::new(0x2000, 0x1000).unwrap();
+        assert_eq!(buffer.as_ptr() as usize & 0xfff, 0);
+        assert!(buffer.as_slice().iter().all(|b| *b == 0));
+        buffer.as_mut_slice()[0x1fff] = 0xff;
+        assert_eq!(buffer.as_slice()[0x1fff], 0xff);
+        assert_eq!(buffer.size(), 0x2000);
+
+        assert!(AlignedBuffer::new(0, 0x1000).is_err());
+        assert!(AlignedBuffer::new(0x1000, 3).is_err());
+
+        let mut states = AllVpStateComponents::default();
+        states.buffer[1] = 0x42;
+        states.copy_to_or_from_buffer(0, &mut buffer, true).unwrap();
+        assert_eq!(buffer.as_slice()[1], 0x42);
+        assert!(states.copy_to_or_from_buffer(1, &mut buffer, true).is_err());
+        assert!(states
+            .copy_to_or_from_buffer(VP_STATE_COMPONENTS_COUNT, &mut buffer, false)
+            .is_err());
+    }
+
+    #[test]
+    fn test_list_macros() {
+        let cs = hv_x64_segment_register {
+            selector: 0x10,
+            ..Default::default()
+        };
+        let rax = hv_register_name_HV_X64_REGISTER_RAX;
+        let regs = hv_regs![
+            hv_register_name_HV_X64_REGISTER_RIP => 0x1000,
+            rax => 0,
+            hv_register_name_HV_REGISTER_GUEST_OS_ID => 1 << 63,
+            hv_register_name_HV_X64_REGISTER_CS => hv_register_value { segment: cs },
+        ];
+        assert_eq!(regs.len(), 4);
+        assert!(regs[0].name == hv_register_name_HV_X64_REGISTER_RIP);
+        assert!(regs[1].name == hv_register_name_HV_X64_REGISTER_RAX);
+        assert!(regs[2].name == hv_register_name_HV_REGISTER_GUEST_OS_ID);
+        // SAFETY: access union fields
+        unsafe {
+            assert!(regs[0].value.reg64 == 0x1000);
+            assert!(regs[2].value.reg64 == 1 << 63);
+            assert!(regs[3].value.segment.selector == 0x10);
+        }
+        let empty: [hv_register_assoc; 0] = hv_regs![];
+        assert!(empty.is_empty());
+
+        let efer = 0xd01;
+        let msrs = msr_entries![IA32_MSR_EFER => efer, IA32_MSR_TSC => 0];
+        assert_eq!(
+            msrs[0],
+            msr_entry {
+                index: IA32_MSR_EFER,
+                reserved: 0,
+                data: 0xd01
+            }
+        );
+        assert_eq!(msrs[1].index, IA32_MSR_TSC);
+    }
+
+    #[test]
+    fn test_validate_registers() {
+        let mut regs = StandardRegisters {
+            rip: 0xffff_ffff_8100_0000,
+            rsp: 0x7fff_ffff_f000,
+            rflags: 0x246,
+            ..Default::default()
+        };
+        regs.validate(0).unwrap();
+
+        regs.rflags = 0x200;
+        let err = regs.validate(0).unwrap_err();
+        assert_eq!(
+            err,
+            InvalidRegister {
+                register: "rflags",
+                bit: 1
+            }
+        );
+        assert_eq!(err.to_string(), "invalid value for rflags: bit 1");
+        regs.rflags = 0x2 | 1 << 15;
+        assert!(regs.validate(0).unwrap_err().bit == 15);
+        regs.rflags = 0x2;
+        regs.rip = 0x0000_8000_0000_0000;
+        assert_eq!(
+            regs.validate(0).unwrap_err(),
+            InvalidRegister {
+                register: "rip",
+                bit: 48
+            }
+        );
+        // Canonical with 57 bit linear addresses
+        regs.validate(X86_CR4_LA57).unwrap();
+        regs.rip = 0x0100_0000_0000_0000;
+        assert_eq!(
+            regs.validate(X86_CR4_LA57).unwrap_err(),
+            InvalidRegister {
+                register: "rip",
+                bit: 57
+            }
+        );
+        regs.rip = 0;
+        regs.rsp = 0xfff0_0000_0000_0000;
+        assert!(regs.validate(0).unwrap_err().register == "rsp");
+        regs.validate(X86_CR4_LA57).unwrap();
+
+        let mut sregs = SpecialRegisters {
+            cr0: X86_CR0_PE | X86_CR0_PG,
+            cr4: X86_CR4_PAE,
+            efer: X86_EFER_LME | X86_EFER_LMA,
+            ..Default::default()
+        };
+        sregs.validate().unwrap();
+        sregs.efer = X86_EFER_LME;
+        assert_eq!(
+            sregs.validate().unwrap_err(),
+            InvalidRegister {
+                register: "efer",
+                bit: 10
+            }
+        );
+        sregs.efer |= X86_EFER_LMA;
+        sregs.cr4 = 1 << 15;
+        assert_eq!(
+            sregs.validate().unwrap_err(),
+            InvalidRegister {
+                register: "cr4",
+                bit: 15
+            }
+        );
+        sregs.cr4 = X86_CR4_PAE | X86_CR4_LA57 | X86_CR4_LAM_SUP | X86_CR4_FRED;
+        sregs.validate().unwrap();
+        sregs.cr4 = X86_CR4_PAE;
+        sregs.cr0 = X86_CR0_PG;
+        assert!(sregs.validate().unwrap_err().register == "cr0");
+        // Long mode active without paging
+        sregs.cr0 = X86_CR0_PE;
+        assert!(sregs.validate().unwrap_err().register == "efer");
+        sregs.efer = 0;
+        sregs.cr0 |= 1 << 32;
+        assert!(sregs.validate().unwrap_err().bit == 32);
+    }
+
+    #[test]
+    fn test_all_vp_state_components_accessors() {
+        let mut states = AllVpStateComponents::default();
+        let lapic = AllVpStateComponents::component_offset(0);
+        states.buffer[lapic..lapic + 4].copy_from_slice(&3u32.to_le_bytes());
+        let xsave = AllVpStateComponents::component_offset(1);
+        states.buffer[xsave + 24..xsave + 28].copy_from_slice(&0x1f80u32.to_le_bytes());
+        let simp = AllVpStateComponents::component_offset(2);
+        // Message type of SINT 1
+        states.buffer[simp + 256..simp + 260].copy_from_slice(&0x8000_0000u32.to_le_bytes());
+        let stimer = AllVpStateComponents::component_offset(4);
+        states.buffer[stimer + 8..stimer + 16].copy_from_slice(&1u64.to_le_bytes());
+
+        let apic_id = states.lapic().apic_id;
+        assert_eq!(apic_id, 3);
+        assert_eq!(states.xsave().mxcsr(), 0x1f80);
+        assert_eq!(states.xsave().data().len(), XSAVE_AREA_SIZE);
+        let message_type = states.simp_page().sint_message[1].header.message_type;
+        assert_eq!(message_type, 0x8000_0000);
+        assert_eq!(states.component(3).len(), 0x1000);
+        assert_eq!(states.synthetic_timers().timers[0].config, 1);
+    }
+
+    #[test]
+    fn test_guest_crash() {
+        let mut crash = GuestCrash {
+            params: [0xd1, 0x10, 0x2, 0x0, 0xfffff80012345678],
+            ctl: HV_CRASH_CTL_CRASH_NOTIFY,
+        };
+        assert!(crash.bugcheck_code() == 0xd1);
+        assert!(crash.bugcheck_params() == [0x10, 0x2, 0x0, 0xfffff80012345678]);
+        assert!(crash.message().is_none());
+        assert!(
+            format!("{}", crash)
+                == "guest crash: bugcheck 0xd1 (0x10, 0x2, 0x0, 0xfffff80012345678)"
+        );
+        crash.ctl |= HV_CRASH_CTL_CRASH_NOTIFY_MSG;
+        assert!(crash.message() == Some((0x0, 0xfffff80012345678)));
+    }
+
+    #[test]
+    fn test_mce_info() {
+        let mce = MceInfo::memory_poison(1, 0x1234_5000, true);
+        let flags = MCI_STATUS_VAL | MCI_STATUS_UC | MCI_STATUS_AR;
+        assert_eq!(mce.status & flags, flags);
+        assert_eq!(mce.status & 0xffff, 0x134);
+        assert_eq!(MceInfo::bank_msr(1), 0x404);
+        assert_eq!(
+            mce.msr_value(IA32_MSR_MCG_STATUS),
+            Some(MCG_STATUS_MCIP | MCG_STATUS_EIPV)
+        );
+        assert_eq!(mce.msr_value(0x405), Some(mce.status));
+        assert_eq!(mce.msr_value(0x406), Some(0x1234_5000));
+        assert_eq!(mce.msr_value(0x407), Some(0x8c));
+        assert_eq!(mce.msr_value(0x401), None);
+
+        let mce = MceInfo::memory_poison(0, 0x2000, false);
+        assert_eq!(mce.status & (MCI_STATUS_AR | 0xffff), 0xc0);
+        assert_eq!(mce.mcg_status & MCG_STATUS_RIPV, MCG_STATUS_RIPV);
+
+        let pending = mce.pending_interruption();
+        // SAFETY: access union fields
+        unsafe {
+            assert_eq!(pending.__bindgen_anon_1.interruption_pending(), 1);
+            assert_eq!(
+                pending.__bindgen_anon_1.interruption_type(),
+                HV_X64_PENDING_EXCEPTION
+            );
+            assert_eq!(pending.__bindgen_anon_1.interruption_vector(), 18);
+            assert_eq!(pending.__bindgen_anon_1.deliver_error_code(), 0);
+        }
+    }
+
+    #[test]
+    fn test_tsc_state() {
+        let mut state = TscState {
+            frequency: 2_000_000_000,
+            tsc: 1000,
+            tsc_adjust: 0,
+        };
+        assert!(state.offset_for_host(400) == 600);
+        assert!(state.offset_for_host(1400) == 600u64.wrapping_neg() + 200);
+        state.advance(1_500);
+        assert!(state.tsc == 4000);
+    }
+
+    #[test]
+    fn test_synthetic_timers_state() {
+        let mut state = SyntheticTimersState::default();
+        state.timers[0].config = 0x30003;
+        state.timers[0].count = 1000;
+        state.timers[3].undelivered_exp_time = 0x1234;
+        state.timers[3].undelivered_msg_pending = true;
+
+        let hv_state = hv_synthetic_timers_state::from(state);
+        assert!(hv_state.timers[3].flags.undelivered_msg_pending() == 1);
+        assert!(hv_state.timers[1].flags.undelivered_msg_pending() == 0);
+        assert!(SyntheticTimersState::from(hv_state) == state);
+    }
+
+    #[test]
+    fn test_special_registers_pending_interrupt() {
+        let mut sregs = SpecialRegisters::default();
+        assert!(sregs.pending_interrupt().unwrap().is_none());
+        for vector in [0u8, 32, 63, 64, 200, 255] {
+            sregs.set_pending_interrupt(Some(vector));
+            assert!(sregs.pending_interrupt().unwrap() == Some(vector));
+        }
+        assert!(sregs.interrupt_bitmap == [0, 0, 0, 1 << 63]);
+        sregs.interrupt_bitmap[0] = 1;
+        assert!(sregs.pending_interrupt().is_err());
+        sregs.interrupt_bitmap = [0b11, 0, 0, 0];
+        assert!(sregs.pending_interrupt().is_err());
+        sregs.set_pending_interrupt(None);
+        assert!(sregs.interrupt_bitmap == [0; 4]);
+    }
 }
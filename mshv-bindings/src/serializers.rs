@@ -4,7 +4,7 @@
 //
 #![cfg(feature = "with-serde")]
 
-use serde::de::{Deserialize, Deserializer};
+use serde::de::{Deserialize, Deserializer, Error};
 use serde::{Serialize, Serializer};
 
 use super::regs::*;
@@ -16,7 +16,12 @@ impl<'de> Deserialize<'de> for LapicState {
     {
         let regs: Vec<::std::os::raw::c_char> = Vec::deserialize(deserializer)?;
         let mut val = LapicState::default();
-        // This panics if the source and destination have different lengths.
+        if regs.len() != val.regs.len() {
+            return Err(D::Error::invalid_length(
+                regs.len(),
+                &"1024 bytes of LAPIC registers",
+            ));
+        }
         val.regs.copy_from_slice(&regs[..]);
         Ok(val)
     }
@@ -38,7 +43,12 @@ impl<'de> Deserialize<'de> for XSave {
     {
         let data_buffer: Vec<::std::os::raw::c_char> = Vec::deserialize(deserializer)?;
         let mut val = XSave::default();
-        // This panics if the source and destination have different lengths.
+        if data_buffer.len() != val.buffer.len() {
+            return Err(D::Error::invalid_length(
+                data_buffer.len(),
+                &"4120 bytes of XSave data",
+            ));
+        }
         val.buffer.copy_from_slice(&data_buffer[..]);
         Ok(val)
     }
@@ -53,9 +63,38 @@ impl Serialize for XSave {
         data_buffer.serialize(serializer)
     }
 }
+
+impl<'de> Deserialize<'de> for AllVpStateComponents {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let data_buffer: Vec<u8> = Vec::deserialize(deserializer)?;
+        let mut val = AllVpStateComponents::default();
+        if data_buffer.len() != val.buffer.len() {
+            return Err(D::Error::invalid_length(
+                data_buffer.len(),
+                &"VP_STATE_COMPONENTS_BUFFER_SIZE bytes of VP state",
+            ));
+        }
+        val.buffer.copy_from_slice(&data_buffer[..]);
+        Ok(val)
+    }
+}
+
+impl Serialize for AllVpStateComponents {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let data_buffer = &self.buffer[..];
+        data_buffer.serialize(serializer)
+    }
+}
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bindings::*;
     use random_number::random;
     use std::ptr;
 
@@ -124,4 +163,55 @@ mod tests {
             .zip(d_xsave.buffer.iter())
             .all(|(a, b)| a == b));
     }
+    #[test]
+    fn test_all_vp_state_components_serialization_deserialization() {
+        let mut states = AllVpStateComponents::default();
+        for i in 0..VP_STATE_COMPONENTS_BUFFER_SIZE {
+            states.buffer[i] = random!();
+        }
+        let serialized = serde_json::to_string(&states).expect("err ser");
+        let d_states: AllVpStateComponents = serde_json::from_str(&serialized).expect("err unser");
+        assert!(states
+            .buffer
+            .iter()
+            .zip(d_states.buffer.iter())
+            .all(|(a, b)| a == b));
+    }
+    #[test]
+    fn test_deserialization_length_mismatch() {
+        let short = serde_json::to_string(&vec![0u8; 16]).unwrap();
+        assert!(serde_json::from_str::<LapicState>(&short).is_err());
+        assert!(serde_json::from_str::<XSave>(&short).is_err());
+        assert!(serde_json::from_str::<AllVpStateComponents>(&short).is_err());
+    }
+    #[test]
+    fn test_union_mirrors_serialization_deserialization() {
+        let assoc = hv_register_assoc {
+            name: hv_register_name_HV_X64_REGISTER_RIP,
+            value: hv_register_value {
+                reg128: hv_u128 {
+                    low_part: random!(),
+                    high_part: random!(),
+                },
+            },
+            ..Default::default()
+        };
+        let mirror = RegisterAssoc::from(assoc);
+        let serialized = serde_json::to_string(&mirror).expect("err ser");
+        let d_mirror: RegisterAssoc = serde_json::from_str(&serialized).expect("err unser");
+        assert_eq!(mirror, d_mirror);
+        assert_eq!(
+            RegisterAssoc::from(hv_register_assoc::from(d_mirror)),
+            mirror
+        );
+
+        let features = PartitionProcessorFeatures {
+            as_uint64: [random!(), random!()],
+        };
+        let serialized = serde_json::to_string(&features).expect("err ser");
+        let d_features: PartitionProcessorFeatures =
+            serde_json::from_str(&serialized).expect("err unser");
+        let hv_features = hv_partition_processor_features::from(d_features);
+        assert_eq!(PartitionProcessorFeatures::from(hv_features), features);
+    }
 }
@@ -0,0 +1,534 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+use crate::regs::*;
+#[cfg(feature = "with-serde")]
+use serde_derive::{Deserialize, Serialize};
+use std::mem;
+use vmm_sys_util::errno;
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+/// Current version of the `VcpuSnapshot` layout
+pub const VCPU_SNAPSHOT_VERSION: u32 = 3;
+/// Current version of the `VmSnapshot` layout
+pub const VM_SNAPSHOT_VERSION: u32 = 1;
+
+/// Hook bringing a `VcpuSnapshot` from version `n` to version `n + 1`
+pub type VcpuSnapshotUpgrade = fn(&mut VcpuSnapshot);
+/// Hook bringing a `VmSnapshot` from version `n` to version `n + 1`
+pub type VmSnapshotUpgrade = fn(&mut VmSnapshot);
+
+/// Upgrade hooks for `VcpuSnapshot`. Entry `i` upgrades version `i + 1` to
+/// version `i + 2`, so a new entry must be appended every time
+/// `VCPU_SNAPSHOT_VERSION` is bumped. Fields added in a newer version must be
+/// marked `#[serde(default)]` so that older snapshots still deserialize and
+/// the hook can fill them in.
+const VCPU_SNAPSHOT_UPGRADES: [VcpuSnapshotUpgrade; VCPU_SNAPSHOT_VERSION as usize - 1] = [
+    // 1 -> 2: extra_vp_states, empty for snapshots from older kernels
+    |_| {},
+    // 2 -> 3: mtrrs, moved out of the MSRs the snapshot was saved with
+    |vcpu| move_mtrrs(&mut vcpu.msrs, &mut vcpu.mtrrs),
+];
+/// Upgrade hooks for `VmSnapshot`, laid out like `VCPU_SNAPSHOT_UPGRADES`
+const VM_SNAPSHOT_UPGRADES: [VmSnapshotUpgrade; VM_SNAPSHOT_VERSION as usize - 1] = [];
+
+/// Moves the MTRR MSRs in `msrs` to `mtrrs`
+fn move_mtrrs(msrs: &mut Vec<msr_entry>, mtrrs: &mut MtrrState) {
+    *mtrrs = MtrrState::from_msrs(msrs);
+    msrs.retain(|msr| !MtrrState::is_mtrr_msr(msr.index));
+}
+
+fn run_upgrades<T>(
+    version: &mut u32,
+    current: u32,
+    upgrades: &[fn(&mut T)],
+    state: &mut T,
+) -> Result<(), errno::Error> {
+    if *version == 0 || *version > current {
+        return Err(errno::Error::new(libc::EINVAL));
+    }
+    while *version < current {
+        upgrades[(*version - 1) as usize](state);
+        *version += 1;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+/// Versioned container for the complete state of a single vCPU
+pub struct VcpuSnapshot {
+    pub version: u32,
+    pub regs: StandardRegisters,
+    pub sregs: SpecialRegisters,
+    pub fpu: FloatingPointUnit,
+    pub debug_regs: DebugRegisters,
+    pub msrs: Vec<msr_entry>,
+    pub vcpu_events: VcpuEvents,
+    pub xcrs: Xcrs,
+    pub misc: MiscRegs,
+    pub vp_states: AllVpStateComponents,
+    /// VP state components not covered by `vp_states`, since version 2
+    #[cfg_attr(feature = "with-serde", serde(default))]
+    pub extra_vp_states: Vec<VpStateComponent>,
+    /// The MTRRs, which `msrs` does not hold. Since version 3
+    #[cfg_attr(feature = "with-serde", serde(default))]
+    pub mtrrs: MtrrState,
+}
+
+impl Default for VcpuSnapshot {
+    fn default() -> Self {
+        VcpuSnapshot {
+            version: VCPU_SNAPSHOT_VERSION,
+            regs: StandardRegisters::default(),
+            sregs: SpecialRegisters::default(),
+            fpu: FloatingPointUnit::default(),
+            debug_regs: DebugRegisters::default(),
+            msrs: Vec::new(),
+            vcpu_events: VcpuEvents::default(),
+            xcrs: Xcrs::default(),
+            misc: MiscRegs::default(),
+            vp_states: AllVpStateComponents::default(),
+            extra_vp_states: Vec::new(),
+            mtrrs: MtrrState::default(),
+        }
+    }
+}
+
+impl VcpuSnapshot {
+    /// Bring a snapshot saved by an older version of this crate up to
+    /// `VCPU_SNAPSHOT_VERSION`. Fails with EINVAL if the version is unknown,
+    /// including snapshots produced by a newer version of this crate.
+    pub fn upgrade(&mut self) -> Result<(), errno::Error> {
+        let mut version = self.version;
+        run_upgrades(
+            &mut version,
+            VCPU_SNAPSHOT_VERSION,
+            &VCPU_SNAPSHOT_UPGRADES,
+            self,
+        )?;
+        self.version = version;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "with-serde", derive(Deserialize, Serialize))]
+/// Versioned container for the state of a partition and all its vCPUs
+pub struct VmSnapshot {
+    pub version: u32,
+    pub vcpus: Vec<VcpuSnapshot>,
+}
+
+impl Default for VmSnapshot {
+    fn default() -> Self {
+        VmSnapshot {
+            version: VM_SNAPSHOT_VERSION,
+            vcpus: Vec::new(),
+        }
+    }
+}
+
+impl VmSnapshot {
+    /// Bring the partition snapshot and every vCPU snapshot it contains up to
+    /// the current version. Fails with EINVAL if any version is unknown.
+    pub fn upgrade(&mut self) -> Result<(), errno::Error> {
+        let mut version = self.version;
+        run_upgrades(
+            &mut version,
+            VM_SNAPSHOT_VERSION,
+            &VM_SNAPSHOT_UPGRADES,
+            self,
+        )?;
+        self.version = version;
+        for vcpu in self.vcpus.iter_mut() {
+            vcpu.upgrade()?;
+        }
+        Ok(())
+    }
+}
+
+/// Current version of the `VpStateBlob` layout
+pub const VP_STATE_BLOB_VERSION: u32 = 2;
+/// "MSVP", first bytes of every `VpStateBlob`
+pub const VP_STATE_BLOB_MAGIC: u32 = u32::from_le_bytes(*b"MSVP");
+/// Most MSRs a `VpStateBlob` holds
+pub const VP_STATE_BLOB_MAX_MSRS: usize = 256;
+/// Size of version 1 blobs, which end before `mtrrs` and hold the MTRRs in
+/// the MSR array
+const VP_STATE_BLOB_V1_SIZE: usize = mem::size_of::<VpStateBlob>() - mem::size_of::<MtrrState>();
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, AsBytes, FromBytes, FromZeroes)]
+/// Header of a `VpStateBlob`
+pub struct VpStateBlobHeader {
+    /// `VP_STATE_BLOB_MAGIC`
+    pub magic: u32,
+    /// `VP_STATE_BLOB_VERSION` of the layout that follows
+    pub version: u32,
+    /// Size of the whole blob, header included
+    pub size: u32,
+    /// Number of valid entries in the MSR array
+    pub nmsrs: u32,
+}
+
+// TableRegister ends in padding, which must not end up in the blob
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+struct BlobTableRegister {
+    base: u64,
+    limit: u16,
+    padding: [u16; 3],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+struct BlobSpecialRegisters {
+    segments: [SegmentRegister; 8],
+    gdt: BlobTableRegister,
+    idt: BlobTableRegister,
+    cr0: u64,
+    cr2: u64,
+    cr3: u64,
+    cr4: u64,
+    cr8: u64,
+    efer: u64,
+    apic_base: u64,
+    interrupt_bitmap: [u64; 4],
+}
+
+impl From<&SpecialRegisters> for BlobSpecialRegisters {
+    fn from(sregs: &SpecialRegisters) -> Self {
+        let table = |reg: TableRegister| BlobTableRegister {
+            base: reg.base,
+            limit: reg.limit,
+            ..Default::default()
+        };
+        BlobSpecialRegisters {
+            segments: [
+                sregs.cs, sregs.ds, sregs.es, sregs.fs, sregs.gs, sregs.ss, sregs.tr, sregs.ldt,
+            ],
+            gdt: table(sregs.gdt),
+            idt: table(sregs.idt),
+            cr0: sregs.cr0,
+            cr2: sregs.cr2,
+            cr3: sregs.cr3,
+            cr4: sregs.cr4,
+            cr8: sregs.cr8,
+            efer: sregs.efer,
+            apic_base: sregs.apic_base,
+            interrupt_bitmap: sregs.interrupt_bitmap,
+        }
+    }
+}
+
+impl From<&BlobSpecialRegisters> for SpecialRegisters {
+    fn from(blob: &BlobSpecialRegisters) -> Self {
+        let table = |reg: BlobTableRegister| TableRegister {
+            base: reg.base,
+            limit: reg.limit,
+        };
+        let [cs, ds, es, fs, gs, ss, tr, ldt] = blob.segments;
+        SpecialRegisters {
+            cs,
+            ds,
+            es,
+            fs,
+            gs,
+            ss,
+            tr,
+            ldt,
+            gdt: table(blob.gdt),
+            idt: table(blob.idt),
+            cr0: blob.cr0,
+            cr2: blob.cr2,
+            cr3: blob.cr3,
+            cr4: blob.cr4,
+            cr8: blob.cr8,
+            efer: blob.efer,
+            apic_base: blob.apic_base,
+            interrupt_bitmap: blob.interrupt_bitmap,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, AsBytes, FromBytes, FromZeroes)]
+/// Complete state of a single vCPU as one flat, fixed-size buffer, for
+/// embedders persisting `VcpuSnapshot` without serde. Write it out with
+/// `as_bytes()` and read it back with `VpStateBlob::from_bytes`; the
+/// layout is native-endian and covers the same state as `VcpuSnapshot`.
+pub struct VpStateBlob {
+    pub header: VpStateBlobHeader,
+    regs: StandardRegisters,
+    sregs: BlobSpecialRegisters,
+    fpu: FloatingPointUnit,
+    debug_regs: DebugRegisters,
+    vcpu_events: VcpuEvents,
+    xcrs: Xcrs,
+    misc: MiscRegs,
+    msrs: [msr_entry; VP_STATE_BLOB_MAX_MSRS],
+    vp_states: AllVpStateComponents,
+    mtrrs: MtrrState,
+}
+
+impl VpStateBlob {
+    /// Flatten `snapshot`, which must be at the current version. Fails with
+    /// EINVAL if it holds more than `VP_STATE_BLOB_MAX_MSRS` MSRs or any
+    /// `extra_vp_states`, which have no fixed size.
+    pub fn new(snapshot: &VcpuSnapshot) -> Result<Self, errno::Error> {
+        if snapshot.version != VCPU_SNAPSHOT_VERSION
+            || snapshot.msrs.len() > VP_STATE_BLOB_MAX_MSRS
+            || !snapshot.extra_vp_states.is_empty()
+        {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let mut blob = VpStateBlob {
+            header: VpStateBlobHeader {
+                magic: VP_STATE_BLOB_MAGIC,
+                version: VP_STATE_BLOB_VERSION,
+                size: mem::size_of::<VpStateBlob>() as u32,
+                nmsrs: snapshot.msrs.len() as u32,
+            },
+            regs: snapshot.regs,
+            sregs: BlobSpecialRegisters::from(&snapshot.sregs),
+            fpu: snapshot.fpu,
+            debug_regs: snapshot.debug_regs,
+            vcpu_events: snapshot.vcpu_events,
+            xcrs: snapshot.xcrs,
+            misc: snapshot.misc,
+            msrs: [msr_entry::default(); VP_STATE_BLOB_MAX_MSRS],
+            vp_states: snapshot.vp_states,
+            mtrrs: snapshot.mtrrs,
+        };
+        blob.msrs[..snapshot.msrs.len()].copy_from_slice(&snapshot.msrs);
+        Ok(blob)
+    }
+
+    /// Read a blob previously written with `as_bytes()`, by this version of
+    /// the crate or an older one. Fails with EINVAL if `bytes` is not
+    /// exactly one blob of a known version.
+    ///
+    /// Version 1 blobs are brought to the current version, their MTRRs
+    /// moved out of the MSR array as `VcpuSnapshot::upgrade` does.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, errno::Error> {
+        let einval = || errno::Error::new(libc::EINVAL);
+        let header = VpStateBlobHeader::read_from_prefix(bytes).ok_or_else(einval)?;
+        let size = match header.version {
+            1 => VP_STATE_BLOB_V1_SIZE,
+            VP_STATE_BLOB_VERSION => mem::size_of::<VpStateBlob>(),
+            _ => return Err(einval()),
+        };
+        if header.magic != VP_STATE_BLOB_MAGIC
+            || header.size as usize != size
+            || bytes.len() != size
+            || header.nmsrs as usize > VP_STATE_BLOB_MAX_MSRS
+        {
+            return Err(einval());
+        }
+        let mut blob = VpStateBlob::new_zeroed();
+        blob.as_bytes_mut()[..size].copy_from_slice(bytes);
+        if header.version == 1 {
+            let mut msrs = blob.msrs[..header.nmsrs as usize].to_vec();
+            move_mtrrs(&mut msrs, &mut blob.mtrrs);
+            blob.msrs = [msr_entry::default(); VP_STATE_BLOB_MAX_MSRS];
+            blob.msrs[..msrs.len()].copy_from_slice(&msrs);
+            blob.header = VpStateBlobHeader {
+                version: VP_STATE_BLOB_VERSION,
+                size: mem::size_of::<VpStateBlob>() as u32,
+                nmsrs: msrs.len() as u32,
+                ..header
+            };
+        }
+        Ok(blob)
+    }
+
+    /// Expand the blob back into a `VcpuSnapshot`, ready for
+    /// `VcpuFd::restore_state`.
+    pub fn to_snapshot(&self) -> VcpuSnapshot {
+        let nmsrs = (self.header.nmsrs as usize).min(VP_STATE_BLOB_MAX_MSRS);
+        VcpuSnapshot {
+            version: VCPU_SNAPSHOT_VERSION,
+            regs: self.regs,
+            sregs: SpecialRegisters::from(&self.sregs),
+            fpu: self.fpu,
+            debug_regs: self.debug_regs,
+            msrs: self.msrs[..nmsrs].to_vec(),
+            vcpu_events: self.vcpu_events,
+            xcrs: self.xcrs,
+            misc: self.misc,
+            vp_states: self.vp_states,
+            extra_vp_states: Vec::new(),
+            mtrrs: self.mtrrs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upgrade_current_version() {
+        let mut snapshot = VmSnapshot {
+            vcpus: vec![VcpuSnapshot::default(); 2],
+            ..Default::default()
+        };
+        snapshot.upgrade().unwrap();
+        assert_eq!(snapshot.version, VM_SNAPSHOT_VERSION);
+        assert!(snapshot
+            .vcpus
+            .iter()
+            .all(|v| v.version == VCPU_SNAPSHOT_VERSION));
+    }
+
+    #[test]
+    fn test_upgrade_version_1() {
+        let mut vcpu = VcpuSnapshot {
+            version: 1,
+            ..Default::default()
+        };
+        vcpu.upgrade().unwrap();
+        assert_eq!(vcpu.version, VCPU_SNAPSHOT_VERSION);
+        assert!(vcpu.extra_vp_states.is_empty());
+    }
+
+    #[test]
+    fn test_upgrade_version_2() {
+        let mut vcpu = VcpuSnapshot {
+            version: 2,
+            ..Default::default()
+        };
+        vcpu.msrs.push(msr_entry {
+            index: IA32_MSR_MTRR_DEF_TYPE,
+            data: MTRR_DEF_TYPE_E | MTRR_TYPE_WB as u64,
+            ..Default::default()
+        });
+        vcpu.upgrade().unwrap();
+        assert_eq!(vcpu.version, VCPU_SNAPSHOT_VERSION);
+        assert_eq!(vcpu.mtrrs.def_type, MTRR_DEF_TYPE_E | MTRR_TYPE_WB as u64);
+    }
+
+    #[test]
+    fn test_vp_state_blob_v1() {
+        let vcpu = VcpuSnapshot {
+            msrs: vec![
+                msr_entry {
+                    index: IA32_MSR_EFER,
+                    data: 0x500,
+                    ..Default::default()
+                },
+                msr_entry {
+                    index: IA32_MSR_MTRR_DEF_TYPE,
+                    data: MTRR_DEF_TYPE_E | MTRR_TYPE_WB as u64,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let blob = VpStateBlob::new(&vcpu).unwrap();
+        let header = VpStateBlobHeader {
+            version: 1,
+            size: VP_STATE_BLOB_V1_SIZE as u32,
+            ..blob.header
+        };
+        let mut bytes = blob.as_bytes()[..VP_STATE_BLOB_V1_SIZE].to_vec();
+        bytes[..mem::size_of::<VpStateBlobHeader>()].copy_from_slice(header.as_bytes());
+        let blob = VpStateBlob::from_bytes(&bytes).unwrap();
+        assert_eq!(blob.header.version, VP_STATE_BLOB_VERSION);
+        assert_eq!(blob.header.nmsrs, 1);
+        let restored = blob.to_snapshot();
+        assert_eq!(restored.msrs, vcpu.msrs[..1]);
+        assert_eq!(
+            restored.mtrrs.def_type,
+            MTRR_DEF_TYPE_E | MTRR_TYPE_WB as u64
+        );
+        assert!(VpStateBlob::from_bytes(&bytes[..VP_STATE_BLOB_V1_SIZE - 1]).is_err());
+    }
+
+    #[test]
+    fn test_upgrade_unknown_version() {
+        let mut vcpu = VcpuSnapshot {
+            version: VCPU_SNAPSHOT_VERSION + 1,
+            ..Default::default()
+        };
+        assert_eq!(vcpu.upgrade().unwrap_err().errno(), libc::EINVAL);
+        vcpu.version = 0;
+        assert_eq!(vcpu.upgrade().unwrap_err().errno(), libc::EINVAL);
+
+        let mut snapshot = VmSnapshot {
+            vcpus: vec![vcpu],
+            ..Default::default()
+        };
+        assert_eq!(snapshot.upgrade().unwrap_err().errno(), libc::EINVAL);
+    }
+
+    #[test]
+    fn test_vp_state_blob() {
+        let mut vcpu = VcpuSnapshot::default();
+        vcpu.regs.rip = 0x1000;
+        vcpu.sregs.gdt.limit = 0x27;
+        vcpu.sregs.ldt.selector = 0x18;
+        vcpu.msrs.push(msr_entry {
+            index: IA32_MSR_EFER,
+            data: 0x500,
+            ..Default::default()
+        });
+        vcpu.vp_states.buffer[0x1000] = 0xa5;
+        vcpu.mtrrs.def_type = MTRR_DEF_TYPE_E;
+
+        let bytes = VpStateBlob::new(&vcpu).unwrap().as_bytes().to_vec();
+        assert_eq!(bytes.len(), mem::size_of::<VpStateBlob>());
+        let restored = VpStateBlob::from_bytes(&bytes).unwrap().to_snapshot();
+        assert_eq!(restored.regs, vcpu.regs);
+        assert_eq!(restored.sregs.gdt.limit, 0x27);
+        assert_eq!(restored.sregs.ldt.selector, 0x18);
+        assert_eq!(restored.msrs, vcpu.msrs);
+        assert_eq!(restored.vp_states.buffer[0x1000], 0xa5);
+        assert_eq!(restored.mtrrs, vcpu.mtrrs);
+
+        assert!(VpStateBlob::from_bytes(&bytes[1..]).is_err());
+        let mut bad = bytes;
+        bad[0] ^= 1;
+        assert_eq!(
+            VpStateBlob::from_bytes(&bad).unwrap_err().errno(),
+            libc::EINVAL
+        );
+        vcpu.msrs = vec![msr_entry::default(); VP_STATE_BLOB_MAX_MSRS + 1];
+        assert!(VpStateBlob::new(&vcpu).is_err());
+        vcpu.msrs.clear();
+        vcpu.extra_vp_states.push(VpStateComponent {
+            type_: 5,
+            data: vec![0; 0x1000],
+        });
+        assert!(VpStateBlob::new(&vcpu).is_err());
+    }
+
+    #[cfg(feature = "with-serde")]
+    #[test]
+    fn test_snapshot_serialization_deserialization() {
+        let mut vcpu = VcpuSnapshot::default();
+        vcpu.regs.rip = 0x1000;
+        vcpu.msrs.push(msr_entry {
+            index: IA32_MSR_EFER,
+            data: 0x500,
+            ..Default::default()
+        });
+        vcpu.vp_states.buffer[0x1000] = 0xa5;
+        let snapshot = VmSnapshot {
+            vcpus: vec![vcpu],
+            ..Default::default()
+        };
+
+        let serialized = serde_json::to_string(&snapshot).expect("err ser");
+        let mut d_snapshot: VmSnapshot = serde_json::from_str(&serialized).expect("err unser");
+        d_snapshot.upgrade().unwrap();
+        assert_eq!(d_snapshot.version, VM_SNAPSHOT_VERSION);
+        assert_eq!(d_snapshot.vcpus.len(), 1);
+        assert_eq!(d_snapshot.vcpus[0].regs, snapshot.vcpus[0].regs);
+        assert_eq!(d_snapshot.vcpus[0].msrs, snapshot.vcpus[0].msrs);
+        assert_eq!(d_snapshot.vcpus[0].vp_states.buffer[0x1000], 0xa5);
+    }
+}
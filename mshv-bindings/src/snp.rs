@@ -16,7 +16,9 @@
     non_upper_case_globals
 )]
 use crate::bindings::*;
+use crate::sha384::{sha384, SHA384_DIGEST_SIZE};
 use vmm_sys_util::errno;
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 type Result<T> = std::result::Result<T, errno::Error>;
 
@@ -732,3 +734,360 @@ pub fn get_sev_control_register(vmsa_pfn: u64) -> u64 {
         sev_control.as_uint64
     }
 }
+
+/// Maximum number of leaves in the SNP CPUID page
+pub const SNP_CPUID_COUNT_MAX: usize = 64;
+
+/// A CPUID leaf of the SNP CPUID page, as laid out by the SEV-SNP firmware
+/// ABI
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, AsBytes, FromBytes, FromZeroes)]
+pub struct SnpCpuidFunction {
+    pub eax_in: u32,
+    pub ecx_in: u32,
+    pub xcr0_in: u64,
+    pub xss_in: u64,
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+    pub reserved: u64,
+}
+
+///
+/// Page imported as HV_ISOLATED_PAGE_TYPE_CPUID at launch, holding the
+/// CPUID values the guest will trust. The firmware checks them against
+/// what the hardware allows and fails the import if they do not match.
+///
+#[repr(C)]
+#[derive(Debug, Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+pub struct SnpCpuidPage {
+    count: u32,
+    reserved1: u32,
+    reserved2: u64,
+    functions: [SnpCpuidFunction; SNP_CPUID_COUNT_MAX],
+    padding: [u8; 1008],
+}
+
+impl SnpCpuidPage {
+    /// Creates a page with no leaves
+    pub fn new() -> Self {
+        Self::new_zeroed()
+    }
+    /// Appends `function`. Fails with E2BIG if the page is full.
+    pub fn push(&mut self, function: SnpCpuidFunction) -> Result<()> {
+        let count = self.count as usize;
+        if count == SNP_CPUID_COUNT_MAX {
+            return Err(errno::Error::new(libc::E2BIG));
+        }
+        self.functions[count] = function;
+        self.count += 1;
+        Ok(())
+    }
+    /// Leaves in the page
+    pub fn functions(&self) -> &[SnpCpuidFunction] {
+        &self.functions[..self.count as usize]
+    }
+}
+
+impl Default for SnpCpuidPage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+///
+/// Page imported as HV_ISOLATED_PAGE_TYPE_SECRETS at launch. It must be
+/// imported zeroed: the firmware fills it with the VM platform
+/// communication keys, which only the guest can then read.
+///
+#[repr(C)]
+#[derive(Debug, Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+pub struct SnpSecretsPage {
+    pub version: u32,
+    pub flags: u32,
+    pub fms: u32,
+    pub reserved1: u32,
+    pub gosvw: [u8; 16],
+    pub vmpck: [[u8; 32]; 4],
+    pub os_area: [u8; 96],
+    pub vmsa_tweak_bitmap: [u8; 64],
+    pub reserved2: [u8; 3776],
+}
+
+impl SnpSecretsPage {
+    /// Creates the zeroed page to import
+    pub fn new() -> Self {
+        Self::new_zeroed()
+    }
+}
+
+impl Default for SnpSecretsPage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// SEV-SNP firmware ABI page types of the PAGE_INFO structure
+const SNP_PAGE_TYPE_NORMAL: u8 = 1;
+const SNP_PAGE_TYPE_VMSA: u8 = 2;
+const SNP_PAGE_TYPE_ZERO: u8 = 3;
+const SNP_PAGE_TYPE_UNMEASURED: u8 = 4;
+const SNP_PAGE_TYPE_SECRETS: u8 = 5;
+const SNP_PAGE_TYPE_CPUID: u8 = 6;
+
+const SNP_PAGE_SIZE: usize = 4096;
+
+/// Measured for each page by SNP_LAUNCH_UPDATE, see `SnpLaunchDigest`
+#[repr(C)]
+#[derive(Debug, Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+struct SnpPageInfo {
+    digest_cur: [u8; SHA384_DIGEST_SIZE],
+    contents: [u8; SHA384_DIGEST_SIZE],
+    length: u16,
+    page_type: u8,
+    imi_page: u8,
+    vmpl3_perms: u8,
+    vmpl2_perms: u8,
+    vmpl1_perms: u8,
+    reserved: u8,
+    gpa: u64,
+}
+
+///
+/// Host-side computation of the SNP launch measurement, for checking the
+/// MEASUREMENT of attestation reports.
+///
+/// The firmware extends the digest with every page imported at launch, so
+/// pages must be fed in the order they are passed to
+/// `import_isolated_pages`, with the same page type. The digest of normal
+/// and VMSA pages covers their contents; only the GPA and type of the
+/// other pages are measured.
+///
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SnpLaunchDigest {
+    digest: [u8; SHA384_DIGEST_SIZE],
+}
+
+impl Default for SnpLaunchDigest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SnpLaunchDigest {
+    /// Creates the digest of a guest with no pages imported yet
+    pub fn new() -> Self {
+        SnpLaunchDigest {
+            digest: [0; SHA384_DIGEST_SIZE],
+        }
+    }
+    /// Extends the digest with the page of `page_type` imported at `gpa`.
+    /// `page` holds its 4K contents, which are only read for normal and
+    /// VMSA pages.
+    pub fn update(
+        &mut self,
+        page_type: hv_isolated_page_type,
+        gpa: u64,
+        page: &[u8],
+    ) -> Result<()> {
+        if gpa & (SNP_PAGE_SIZE as u64 - 1) != 0 {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let (snp_page_type, measured) = match page_type {
+            hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_NORMAL => (SNP_PAGE_TYPE_NORMAL, true),
+            hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_VMSA => (SNP_PAGE_TYPE_VMSA, true),
+            hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_ZERO => (SNP_PAGE_TYPE_ZERO, false),
+            hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_UNMEASURED => {
+                (SNP_PAGE_TYPE_UNMEASURED, false)
+            }
+            hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_SECRETS => (SNP_PAGE_TYPE_SECRETS, false),
+            hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_CPUID => (SNP_PAGE_TYPE_CPUID, false),
+            _ => return Err(errno::Error::new(libc::EINVAL)),
+        };
+        let mut info = SnpPageInfo::new_zeroed();
+        info.digest_cur = self.digest;
+        info.length = std::mem::size_of::<SnpPageInfo>() as u16;
+        info.page_type = snp_page_type;
+        info.gpa = gpa;
+        if measured {
+            if page.len() != SNP_PAGE_SIZE {
+                return Err(errno::Error::new(libc::EINVAL));
+            }
+            info.contents = sha384(page);
+        }
+        self.digest = sha384(info.as_bytes());
+        Ok(())
+    }
+    /// Extends the digest with the pages of `page_type` imported from
+    /// `gpa` on, holding `data` padded with zeroes to a page boundary
+    pub fn update_range(
+        &mut self,
+        page_type: hv_isolated_page_type,
+        gpa: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        for (i, chunk) in data.chunks(SNP_PAGE_SIZE).enumerate() {
+            let mut page = [0; SNP_PAGE_SIZE];
+            page[..chunk.len()].copy_from_slice(chunk);
+            self.update(page_type, gpa + (i * SNP_PAGE_SIZE) as u64, &page)?;
+        }
+        Ok(())
+    }
+    /// Current value of the measurement
+    pub fn digest(&self) -> [u8; SHA384_DIGEST_SIZE] {
+        self.digest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snp_cpuid_page() {
+        let mut page = SnpCpuidPage::new();
+        let function = SnpCpuidFunction {
+            eax_in: 0xd,
+            ecx_in: 1,
+            xcr0_in: 7,
+            eax: 0xf,
+            ..Default::default()
+        };
+        page.push(function).unwrap();
+        assert_eq!(page.functions(), &[function]);
+        let bytes = page.as_bytes();
+        assert_eq!(&bytes[..4], &1u32.to_le_bytes());
+        assert_eq!(&bytes[16..20], &0xdu32.to_le_bytes());
+
+        for _ in 1..SNP_CPUID_COUNT_MAX {
+            page.push(function).unwrap();
+        }
+        assert!(page.push(function).is_err());
+        assert!(SnpSecretsPage::new().as_bytes().iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_snp_launch_digest_vector() {
+        // Known answer computed outside this crate, with Python's hashlib,
+        // by chaining PAGE_INFO structures as the SNP firmware ABI lays
+        // them out for one page of each type
+        let mut digest = SnpLaunchDigest::new();
+        digest
+            .update(
+                hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_NORMAL,
+                0xffff_f000,
+                &[0x90; 4096],
+            )
+            .unwrap();
+        for (page_type, gpa) in [
+            (hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_ZERO, 0x1000),
+            (
+                hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_UNMEASURED,
+                0x2000,
+            ),
+            (hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_SECRETS, 0x3000),
+            (hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_CPUID, 0x4000),
+        ] {
+            digest.update(page_type, gpa, &[]).unwrap();
+        }
+        let vmsa: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+        digest
+            .update(
+                hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_VMSA,
+                0xffff_ffff_f000,
+                &vmsa,
+            )
+            .unwrap();
+        let hex: String = digest
+            .digest()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(
+            hex,
+            "b763a5b7fffd031b1aa3dff5a65ceacf43965721a9ae4677637959db1faf0bf4\
+             70dfb574e64085dcb944aea521eccc59"
+        );
+    }
+
+    #[test]
+    fn test_snp_launch_digest() {
+        let mut digest = SnpLaunchDigest::new();
+        let page = [0x90u8; 4096];
+        digest
+            .update(
+                hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_NORMAL,
+                0xffff_f000,
+                &page,
+            )
+            .unwrap();
+
+        // First PAGE_INFO, built by hand
+        let mut info = [0u8; 0x70];
+        info[0x30..0x60].copy_from_slice(&sha384(&page));
+        info[0x60] = 0x70;
+        info[0x62] = 1;
+        info[0x68..].copy_from_slice(&0xffff_f000u64.to_le_bytes());
+        assert_eq!(digest.digest(), sha384(&info));
+
+        // Contents of unmeasured pages do not matter
+        let mut a = digest;
+        let mut b = digest;
+        a.update(
+            hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_ZERO,
+            0x1000,
+            &[],
+        )
+        .unwrap();
+        b.update(
+            hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_ZERO,
+            0x1000,
+            &page,
+        )
+        .unwrap();
+        assert_eq!(a, b);
+        assert_ne!(a, digest);
+
+        // A partial page is padded with zeroes
+        let mut a = SnpLaunchDigest::new();
+        let mut b = SnpLaunchDigest::new();
+        let mut data = page.to_vec();
+        data.extend_from_slice(&[0x90; 16]);
+        a.update_range(hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_NORMAL, 0, &data)
+            .unwrap();
+        let mut partial = [0u8; 4096];
+        partial[..16].copy_from_slice(&[0x90; 16]);
+        b.update(hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_NORMAL, 0, &page)
+            .unwrap();
+        b.update(
+            hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_NORMAL,
+            0x1000,
+            &partial,
+        )
+        .unwrap();
+        assert_eq!(a, b);
+
+        assert!(digest
+            .update(
+                hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_NORMAL,
+                0x10,
+                &page
+            )
+            .is_err());
+        assert!(digest
+            .update(
+                hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_VMSA,
+                0x1000,
+                &page[..16]
+            )
+            .is_err());
+        assert!(digest
+            .update(
+                hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_COUNT,
+                0x1000,
+                &page
+            )
+            .is_err());
+    }
+}
@@ -0,0 +1,259 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+// Parsing of the messages the SynIC delivers through the SIMP page.
+//
+
+use crate::bindings::*;
+use crate::hvdef::TimerMessagePayload;
+use std::fmt;
+use std::mem;
+use std::ptr;
+use vmm_sys_util::errno;
+
+type Result<T> = std::result::Result<T, errno::Error>;
+
+/// Message types below this one are defined by the guest, the others by
+/// the hypervisor.
+const HVMSG_HYPERVISOR_BASE: hv_message_type = 0x8000_0000;
+
+/// A message taken from a SIMP slot
+#[derive(Clone)]
+pub enum SynicMessage {
+    /// A synthetic timer configured for message delivery expired
+    TimerExpired(TimerMessagePayload),
+    /// Any other hypervisor message, decoded with the `hv_message::to_*`
+    /// methods
+    Intercept(Box<hv_message>),
+    /// A message posted to a port, the way VMBus talks to the guest
+    VmBus {
+        /// Guest defined message type
+        message_type: u32,
+        /// Port the message was posted to
+        port: u32,
+        /// `payload_size` bytes of payload
+        payload: Vec<u8>,
+    },
+}
+
+impl fmt::Debug for SynicMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SynicMessage::TimerExpired(payload) => {
+                f.debug_tuple("TimerExpired").field(payload).finish()
+            }
+            SynicMessage::Intercept(msg) => {
+                let message_type = msg.header.message_type;
+                f.debug_struct("Intercept")
+                    .field("message_type", &format_args!("{:#x}", message_type))
+                    .finish()
+            }
+            SynicMessage::VmBus {
+                message_type,
+                port,
+                payload,
+            } => f
+                .debug_struct("VmBus")
+                .field("message_type", message_type)
+                .field("port", port)
+                .field("payload", payload)
+                .finish(),
+        }
+    }
+}
+
+impl SynicMessage {
+    /// Parses `msg`. Returns None for an empty slot, EINVAL if the payload
+    /// size is out of range.
+    pub fn parse(msg: &hv_message) -> Result<Option<Self>> {
+        let header = msg.header;
+        let message_type = header.message_type;
+        let payload_size = header.payload_size as usize;
+        if payload_size > HV_MESSAGE_PAYLOAD_BYTE_COUNT as usize {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let message = if message_type == hv_message_type_HVMSG_NONE {
+            return Ok(None);
+        } else if message_type == hv_message_type_HVMSG_TIMER_EXPIRED {
+            SynicMessage::TimerExpired(msg.to_timer_expired_info()?)
+        } else if message_type >= HVMSG_HYPERVISOR_BASE {
+            SynicMessage::Intercept(Box::new(*msg))
+        } else {
+            // SAFETY: the payload is plain bytes and the header a port for
+            // posted messages
+            let (payload, port) = unsafe {
+                (
+                    ptr::read_unaligned(ptr::addr_of!(msg.u.payload)
+                        as *const [u8; HV_MESSAGE_PAYLOAD_BYTE_COUNT as usize]),
+                    header.__bindgen_anon_1.port.asu32,
+                )
+            };
+            SynicMessage::VmBus {
+                message_type,
+                port,
+                payload: payload[..payload_size].to_vec(),
+            }
+        };
+        Ok(Some(message))
+    }
+}
+
+/// The SIMP page of a vCPU, a message slot for each SINT, read in place.
+///
+/// The hypervisor only fills a slot the guest has emptied. Consuming a
+/// message empties its slot; if the hypervisor flagged that another one is
+/// queued behind it, the guest signals end of message by writing
+/// `HV_X64_MSR_EOM`, which `eom_required` tells.
+#[derive(Debug)]
+pub struct SimpPage<'a> {
+    page: &'a mut [u8],
+    eom_required: bool,
+}
+
+impl<'a> SimpPage<'a> {
+    /// Fails with EINVAL if `page` is smaller than a SIMP page.
+    pub fn new(page: &'a mut [u8]) -> Result<Self> {
+        if page.len() < mem::size_of::<hv_message_page>() {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        Ok(SimpPage {
+            page,
+            eom_required: false,
+        })
+    }
+
+    fn slot(&self, sint: usize) -> Result<hv_message> {
+        if sint >= HV_SYNIC_SINT_COUNT as usize {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let offset = sint * HV_MESSAGE_SIZE as usize;
+        // SAFETY: the page holds HV_SYNIC_SINT_COUNT messages, which are
+        // plain data with no alignment requirement
+        Ok(unsafe { ptr::read_unaligned(self.page[offset..].as_ptr() as *const hv_message) })
+    }
+
+    /// Bitmap of the SINTs whose slot holds a message
+    pub fn pending_sints(&self) -> u16 {
+        (0..HV_SYNIC_SINT_COUNT as usize)
+            .filter(|sint| {
+                let offset = sint * HV_MESSAGE_SIZE as usize;
+                self.page[offset..offset + 4] != [0; 4]
+            })
+            .fold(0, |bits, sint| bits | 1 << sint)
+    }
+
+    /// Parses the message in the slot of `sint`, leaving it in place.
+    /// Fails with EINVAL if there is no such SINT.
+    pub fn read(&self, sint: usize) -> Result<Option<SynicMessage>> {
+        SynicMessage::parse(&self.slot(sint)?)
+    }
+
+    /// Parses the message in the slot of `sint` and empties the slot.
+    pub fn consume(&mut self, sint: usize) -> Result<Option<SynicMessage>> {
+        let msg = self.slot(sint)?;
+        let message = SynicMessage::parse(&msg)?;
+        if message.is_some() {
+            let offset = sint * HV_MESSAGE_SIZE as usize;
+            self.page[offset..offset + 4]
+                .copy_from_slice(&hv_message_type_HVMSG_NONE.to_le_bytes());
+            // SAFETY: access union fields
+            if unsafe { msg.header.message_flags.__bindgen_anon_1.msg_pending() } != 0 {
+                self.eom_required = true;
+            }
+        }
+        Ok(message)
+    }
+
+    /// Consumes the messages of all SINTs, in SINT order
+    pub fn drain(&mut self) -> Result<Vec<(usize, SynicMessage)>> {
+        let mut messages = Vec::new();
+        for sint in 0..HV_SYNIC_SINT_COUNT as usize {
+            if let Some(message) = self.consume(sint)? {
+                messages.push((sint, message));
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Returns true if a consumed message had another queued behind it,
+    /// so the guest has to write `HV_X64_MSR_EOM`.
+    pub fn eom_required(&self) -> bool {
+        self.eom_required
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put_message(page: &mut [u8], sint: usize, message_type: u32, flags: u8, payload: &[u8]) {
+        let slot = &mut page[sint * 256..(sint + 1) * 256];
+        slot[..4].copy_from_slice(&message_type.to_le_bytes());
+        slot[4] = payload.len() as u8;
+        slot[5] = flags;
+        slot[8..12].copy_from_slice(&7u32.to_le_bytes());
+        slot[16..16 + payload.len()].copy_from_slice(payload);
+    }
+
+    #[test]
+    fn test_simp_page() {
+        let mut page = vec![0u8; 0x1000];
+        let mut timer = [0u8; 24];
+        timer[..4].copy_from_slice(&3u32.to_le_bytes());
+        timer[8..16].copy_from_slice(&0x1234u64.to_le_bytes());
+        put_message(&mut page, 0, hv_message_type_HVMSG_TIMER_EXPIRED, 0, &timer);
+        put_message(&mut page, 2, 1, 1, b"vmbus");
+        put_message(&mut page, 5, hv_message_type_HVMSG_X64_HALT, 0, &[]);
+
+        assert!(SimpPage::new(&mut page[..0x800]).is_err());
+        let mut simp = SimpPage::new(&mut page).unwrap();
+        assert_eq!(simp.pending_sints(), 0b10_0101);
+        assert!(simp.read(16).is_err());
+        assert!(simp.read(1).unwrap().is_none());
+
+        match simp.read(0).unwrap() {
+            Some(SynicMessage::TimerExpired(payload)) => {
+                assert_eq!(payload.timer_index, 3);
+                assert_eq!(payload.expiration_time, 0x1234);
+            }
+            m => panic!("unexpected message {:?}", m),
+        }
+        assert_eq!(simp.pending_sints(), 0b10_0101);
+        assert!(!simp.eom_required());
+
+        match simp.consume(2).unwrap() {
+            Some(SynicMessage::VmBus {
+                message_type,
+                port,
+                payload,
+            }) => {
+                assert_eq!((message_type, port), (1, 7));
+                assert_eq!(payload, b"vmbus");
+            }
+            m => panic!("unexpected message {:?}", m),
+        }
+        assert!(simp.eom_required());
+        assert!(simp.consume(2).unwrap().is_none());
+
+        let messages = simp.drain().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].0, 0);
+        match &messages[1] {
+            (5, SynicMessage::Intercept(msg)) => {
+                assert!(msg.to_halt_info().is_ok());
+            }
+            m => panic!("unexpected message {:?}", m),
+        }
+        assert_eq!(simp.pending_sints(), 0);
+    }
+
+    #[test]
+    fn test_bad_payload_size() {
+        let mut page = vec![0u8; 0x1000];
+        put_message(&mut page, 0, 1, 0, &[]);
+        page[4] = 241;
+        let simp = SimpPage::new(&mut page).unwrap();
+        assert!(simp.read(0).is_err());
+    }
+}
@@ -3,6 +3,8 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 //
 use crate::bindings::*;
+use crate::hvdef::*;
+use crate::regs::*;
 use vmm_sys_util::errno;
 
 type Result<T> = std::result::Result<T, errno::Error>;
@@ -35,6 +37,31 @@ impl hv_message {
             unsafe { std::ptr::read_unaligned(std::ptr::addr_of!(self.u.payload) as *const _) };
         Ok(ret)
     }
+    /// Decodes a guest access to mapped memory that its protection denies,
+    /// see `GpaProtection`. Fails with EINVAL for other exits, including
+    /// accesses to unmapped memory.
+    pub fn to_protection_violation(&self) -> Result<GpaProtectionViolation> {
+        if self.header.message_type != hv_message_type_HVMSG_GPA_INTERCEPT {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let info = self.to_memory_info()?;
+        let header = info.header;
+        let access = GpaAccessType::from_intercept(header.intercept_access_type)
+            .ok_or_else(|| errno::Error::new(libc::EINVAL))?;
+        // SAFETY: access union fields
+        let gva_valid = unsafe { info.memory_access_info.__bindgen_anon_1.gva_valid() };
+        Ok(GpaProtectionViolation {
+            gpa: info.guest_physical_address,
+            gva: if gva_valid != 0 {
+                Some(info.guest_virtual_address)
+            } else {
+                None
+            },
+            access,
+            rip: header.rip,
+            instruction_length: header.instruction_length(),
+        })
+    }
     #[inline]
     pub fn to_gpa_attribute_info(&self) -> Result<hv_x64_gpa_attribute_intercept_message> {
         if self.header.message_type != hv_message_type_HVMSG_GPA_ATTRIBUTE_INTERCEPT {
@@ -71,6 +98,54 @@ impl hv_message {
             unsafe { std::ptr::read_unaligned(std::ptr::addr_of!(self.u.payload) as *const _) };
         Ok(ret)
     }
+    /// Returns the value the guest wrote to HV_X64_MSR_CRASH_CTL to report a
+    /// crash, EINVAL if this message is not such a write.
+    #[inline]
+    pub fn to_guest_crash_ctl(&self) -> Result<u64> {
+        let info = self.to_msr_info()?;
+        if info.header.intercept_access_type != HV_INTERCEPT_ACCESS_WRITE as u8
+            || info.msr_number != HV_X64_MSR_CRASH_CTL
+        {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let ctl = (info.rdx << 32) | (info.rax & 0xffff_ffff);
+        if ctl & HV_CRASH_CTL_CRASH_NOTIFY == 0 {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        Ok(ctl)
+    }
+    /// Returns how the guest asked for a reset if this message is a write
+    /// resetting the partition, see `ResetSource`, EINVAL otherwise.
+    pub fn to_reset_exit(&self) -> Result<ResetSource> {
+        if self.header.message_type == hv_message_type_HVMSG_X64_MSR_INTERCEPT {
+            let info = self.to_msr_info()?;
+            if info.header.intercept_access_type == HV_INTERCEPT_ACCESS_WRITE as u8
+                && info.msr_number == HV_X64_MSR_RESET
+                && info.rax & 1 != 0
+            {
+                return Ok(ResetSource::HypervMsr);
+            }
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let info = self.to_ioport_info()?;
+        // SAFETY: access union fields
+        let string_op = unsafe { info.access_info.__bindgen_anon_1.string_op() };
+        if info.header.intercept_access_type != HV_INTERCEPT_ACCESS_WRITE as u8 || string_op != 0 {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let value = info.rax as u8;
+        match info.port_number {
+            I8042_COMMAND_PORT if value == I8042_CMD_PULSE_RESET => {
+                Ok(ResetSource::KeyboardController)
+            }
+            RESET_CONTROL_PORT if value & RESET_CONTROL_RST_CPU != 0 => {
+                Ok(ResetSource::ResetControl {
+                    full: value & RESET_CONTROL_FULL_RESET != 0,
+                })
+            }
+            _ => Err(errno::Error::new(libc::EINVAL)),
+        }
+    }
     #[inline]
     pub fn to_exception_info(&self) -> Result<hv_x64_exception_intercept_message> {
         if self.header.message_type != hv_message_type_HVMSG_X64_EXCEPTION_INTERCEPT {
@@ -83,6 +158,36 @@ impl hv_message {
             unsafe { std::ptr::read_unaligned(std::ptr::addr_of!(self.u.payload) as *const _) };
         Ok(ret)
     }
+    /// Returns the halt exit payload. Whether HLT exits to the VMM is decided
+    /// by the hypervisor, MSHV has no intercept type to request or suppress it.
+    #[inline]
+    pub fn to_halt_info(&self) -> Result<hv_x64_halt_message> {
+        if self.header.message_type != hv_message_type_HVMSG_X64_HALT {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        // SAFETY: We know at this point the payload is of the correct type. The payload field is
+        // unaligned. We use addr_of! to safely create a pointer, then call read_unaligned for
+        // copying its content out.
+        let ret =
+            unsafe { std::ptr::read_unaligned(std::ptr::addr_of!(self.u.payload) as *const _) };
+        Ok(ret)
+    }
+    /// Decodes the halt exit with the guest state a VMM needs for its idle
+    /// policy, see `to_halt_info` for the raw payload.
+    pub fn to_halt_exit(&self) -> Result<HaltExit> {
+        let header = self.to_halt_info()?.header;
+        // SAFETY: access union fields
+        let state = unsafe { header.execution_state.__bindgen_anon_1 };
+        Ok(HaltExit {
+            vp_index: header.vp_index,
+            rip: header.rip,
+            rflags: header.rflags,
+            instruction_length: header.instruction_length(),
+            cpl: state.cpl() as u8,
+            interrupt_shadow: state.interrupt_shadow() != 0,
+            interruption_pending: state.interruption_pending() != 0,
+        })
+    }
     #[inline]
     pub fn to_invalid_vp_register_info(&self) -> Result<hv_x64_invalid_vp_register_message> {
         if self.header.message_type != hv_message_type_HVMSG_INVALID_VP_REGISTER_VALUE {
@@ -123,6 +228,18 @@ impl hv_message {
             unsafe { std::ptr::read_unaligned(std::ptr::addr_of!(self.u.payload) as *const _) };
         Ok(ret)
     }
+    /// Returns what the guest can take now if this message is the exit
+    /// requested by `VmFd::request_interrupt_window`, EINVAL otherwise.
+    pub fn to_interrupt_window_exit(&self) -> Result<InterruptWindow> {
+        let info = self.to_interruption_deliverable_info()?;
+        if info.deliverable_type == HV_X64_PENDING_INTERRUPT {
+            Ok(InterruptWindow::Interrupt)
+        } else if info.deliverable_type == HV_X64_PENDING_NMI {
+            Ok(InterruptWindow::Nmi)
+        } else {
+            Err(errno::Error::new(libc::EINVAL))
+        }
+    }
     #[inline]
     pub fn to_apic_eoi_info(&self) -> Result<hv_x64_apic_eoi_message> {
         if self.header.message_type != hv_message_type_HVMSG_X64_APIC_EOI {
@@ -160,6 +277,18 @@ impl hv_message {
         Ok(ret)
     }
     #[inline]
+    pub fn to_timer_expired_info(&self) -> Result<TimerMessagePayload> {
+        if self.header.message_type != hv_message_type_HVMSG_TIMER_EXPIRED {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        // SAFETY: We know at this point the payload is of the correct type. The payload field is
+        // unaligned. We use addr_of! to safely create a pointer, then call read_unaligned for
+        // copying its content out.
+        let ret =
+            unsafe { std::ptr::read_unaligned(std::ptr::addr_of!(self.u.payload) as *const _) };
+        Ok(ret)
+    }
+    #[inline]
     pub fn to_vmg_intercept_info(&self) -> Result<hv_x64_vmgexit_intercept_message> {
         if self.header.message_type != hv_message_type_HVMSG_X64_SEV_VMGEXIT_INTERCEPT {
             return Err(errno::Error::new(libc::EINVAL));
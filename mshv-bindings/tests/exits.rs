@@ -0,0 +1,116 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+#![cfg(target_arch = "x86_64")]
+
+//! Decoding of exit messages, built with `mshv_test::exit_message`.
+use mshv_bindings::*;
+use mshv_test::exit_message;
+
+#[test]
+fn test_reset_exit() {
+    let io_write = |port: u16, value: u64| {
+        let mut info = hv_x64_io_port_intercept_message {
+            port_number: port,
+            rax: value,
+            ..Default::default()
+        };
+        info.header.intercept_access_type = HV_INTERCEPT_ACCESS_WRITE as u8;
+        exit_message(hv_message_type_HVMSG_X64_IO_PORT_INTERCEPT, info)
+    };
+    assert!(
+        io_write(I8042_COMMAND_PORT, 0xfe).to_reset_exit() == Ok(ResetSource::KeyboardController)
+    );
+    assert!(io_write(I8042_COMMAND_PORT, 0xd1).to_reset_exit().is_err());
+    assert!(
+        io_write(RESET_CONTROL_PORT, 0x0e).to_reset_exit()
+            == Ok(ResetSource::ResetControl { full: true })
+    );
+    assert!(
+        io_write(RESET_CONTROL_PORT, 0x06).to_reset_exit()
+            == Ok(ResetSource::ResetControl { full: false })
+    );
+    assert!(io_write(RESET_CONTROL_PORT, 0x02).to_reset_exit().is_err());
+    assert!(io_write(0x80, 0xfe).to_reset_exit().is_err());
+
+    let mut info = hv_x64_msr_intercept_message {
+        msr_number: HV_X64_MSR_RESET,
+        rax: 1,
+        ..Default::default()
+    };
+    info.header.intercept_access_type = HV_INTERCEPT_ACCESS_WRITE as u8;
+    let msg = exit_message(hv_message_type_HVMSG_X64_MSR_INTERCEPT, info);
+    assert!(msg.to_reset_exit() == Ok(ResetSource::HypervMsr));
+    assert!(hv_message::default().to_reset_exit().is_err());
+}
+
+#[test]
+fn test_interrupt_window_exit() {
+    let deliverable = |deliverable_type: u32| {
+        exit_message(
+            hv_message_type_HVMSG_X64_INTERRUPTION_DELIVERABLE,
+            hv_x64_interruption_deliverable_message {
+                deliverable_type,
+                ..Default::default()
+            },
+        )
+    };
+    assert!(
+        deliverable(HV_X64_PENDING_INTERRUPT).to_interrupt_window_exit()
+            == Ok(InterruptWindow::Interrupt)
+    );
+    assert!(deliverable(HV_X64_PENDING_NMI).to_interrupt_window_exit() == Ok(InterruptWindow::Nmi));
+    assert!(deliverable(HV_X64_PENDING_EXCEPTION)
+        .to_interrupt_window_exit()
+        .is_err());
+    assert!(hv_message::default().to_interrupt_window_exit().is_err());
+}
+
+#[test]
+fn test_protection_violation() {
+    let memory_exit = |message_type, access: u32| {
+        let mut info = hv_x64_memory_intercept_message {
+            guest_physical_address: 0xf_0010,
+            guest_virtual_address: 0xffff_f000_000f_0010,
+            ..Default::default()
+        };
+        info.header.intercept_access_type = access as u8;
+        info.header.rip = 0x1000;
+        info.header.set_instruction_length(3);
+        exit_message(message_type, info)
+    };
+    let violation = memory_exit(
+        hv_message_type_HVMSG_GPA_INTERCEPT,
+        HV_INTERCEPT_ACCESS_WRITE,
+    )
+    .to_protection_violation()
+    .unwrap();
+    assert!(
+        violation
+            == GpaProtectionViolation {
+                gpa: 0xf_0010,
+                gva: None,
+                access: GpaAccessType::Write,
+                rip: 0x1000,
+                instruction_length: 3,
+            }
+    );
+    assert!(!GpaProtection::READ.contains(violation.access.protection()));
+    assert!(memory_exit(
+        hv_message_type_HVMSG_UNMAPPED_GPA,
+        HV_INTERCEPT_ACCESS_WRITE
+    )
+    .to_protection_violation()
+    .is_err());
+    assert!(memory_exit(hv_message_type_HVMSG_GPA_INTERCEPT, 7)
+        .to_protection_violation()
+        .is_err());
+    assert!(
+        GpaAccessType::from_intercept(HV_INTERCEPT_ACCESS_EXECUTE as u8)
+            .unwrap()
+            .protection()
+            .bits()
+            == HV_MAP_GPA_EXECUTABLE
+    );
+}
@@ -0,0 +1,206 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use crate::ioctls::vcpu::VcpuFd;
+use crate::ioctls::{MshvError, Result};
+use mshv_bindings::hv_message;
+use std::os::raw::{c_int, c_void};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use vmm_sys_util::errno;
+use vmm_sys_util::signal::register_signal_handler;
+
+extern "C" fn handle_kick_signal(_: c_int, _: *mut libc::siginfo_t, _: *mut c_void) {}
+
+// A signal sent just before the pool thread enters MSHV_RUN_VP is handled
+// without interrupting it, so kicks are repeated at this interval until the
+// run is over, at most KICK_RETRIES times.
+const KICK_INTERVAL: Duration = Duration::from_micros(100);
+const KICK_RETRIES: u32 = 10_000;
+
+/// Wrapper over `VcpuFd` for async VMMs.
+///
+/// `run` moves the blocking MSHV_RUN_VP ioctl to tokio's blocking thread
+/// pool. `kick` interrupts it by sending `kick_signal` to the pool thread
+/// running the vCPU, which makes `run` fail with EINTR.
+#[derive(Debug)]
+pub struct AsyncVcpuFd {
+    vcpu: Arc<VcpuFd>,
+    kick_signal: c_int,
+    running: Arc<Mutex<RunState>>,
+}
+
+#[derive(Debug, Default)]
+struct RunState {
+    // Set from the start of `run` until the pool thread is done with the vCPU
+    busy: bool,
+    // Number of the current or last `run`
+    generation: u64,
+    // Set by a kick, checked by the pool thread before entering the ioctl
+    kicked: bool,
+    // Pool thread currently inside `VcpuFd::run`
+    thread: Option<libc::pthread_t>,
+}
+
+impl RunState {
+    fn finish(&mut self) {
+        self.busy = false;
+        self.kicked = false;
+        self.thread = None;
+    }
+}
+
+/// Kicks the vCPU if the `run` future is dropped before completing.
+struct KickOnDrop<'a>(&'a AsyncVcpuFd, u64);
+
+impl Drop for KickOnDrop<'_> {
+    fn drop(&mut self) {
+        // Nothing to do once `run` has returned: the generation is done.
+        let _ = self.0.kick_run(self.1);
+    }
+}
+
+impl AsyncVcpuFd {
+    /// Wraps `vcpu`, using `kick_signal` to interrupt it.
+    ///
+    /// A no-op handler is installed for `kick_signal`, replacing any handler
+    /// already registered for it. `kick_signal` must be a valid signal number,
+    /// a real-time signal such as `SIGRTMIN()` is usually the right choice.
+    pub fn new(vcpu: VcpuFd, kick_signal: c_int) -> Result<Self> {
+        register_signal_handler(kick_signal, handle_kick_signal)?;
+        Ok(AsyncVcpuFd {
+            vcpu: Arc::new(vcpu),
+            kick_signal,
+            running: Arc::new(Mutex::new(RunState::default())),
+        })
+    }
+    /// Returns the wrapped `VcpuFd`.
+    pub fn vcpu(&self) -> &VcpuFd {
+        &self.vcpu
+    }
+    /// Runs the vCPU on tokio's blocking thread pool until it exits.
+    ///
+    /// Must be called from within a tokio runtime. Dropping the returned
+    /// future kicks the vCPU, so the pool thread is released promptly.
+    /// Fails with EBUSY if the vCPU is already running.
+    pub async fn run(&self, hv_message_input: hv_message) -> Result<hv_message> {
+        let vcpu = self.vcpu.clone();
+        let running = self.running.clone();
+        let generation = {
+            let mut running = running.lock().unwrap();
+            if running.busy {
+                return Err(errno::Error::new(libc::EBUSY).into());
+            }
+            running.busy = true;
+            running.generation += 1;
+            running.generation
+        };
+        let _kick = KickOnDrop(self, generation);
+        let task = tokio::task::spawn_blocking(move || {
+            {
+                let mut running = running.lock().unwrap();
+                if running.kicked {
+                    running.finish();
+                    return Err(errno::Error::new(libc::EINTR).into());
+                }
+                // SAFETY: FFI call without side effects
+                running.thread = Some(unsafe { libc::pthread_self() });
+            }
+            let ret = vcpu.run(hv_message_input);
+            running.lock().unwrap().finish();
+            ret
+        });
+        match task.await {
+            Ok(ret) => ret,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(_) => {
+                self.running.lock().unwrap().finish();
+                Err(errno::Error::new(libc::ECANCELED).into())
+            }
+        }
+    }
+    /// Interrupts a pending `run`, which then fails with EINTR.
+    ///
+    /// Does nothing if the vCPU is not running. The kick is not lost if the
+    /// pool thread has not entered the ioctl yet: a run that has not started
+    /// gives up before entering it, and a run about to enter it is signalled
+    /// again until it returns. This blocks the caller for as long as that
+    /// takes, and fails with ETIMEDOUT if the run is still going after
+    /// about a second, e.g. because `kick_signal` is blocked by
+    /// `VcpuFd::set_signal_mask`.
+    pub fn kick(&self) -> Result<()> {
+        let generation = self.running.lock().unwrap().generation;
+        self.kick_run(generation)
+    }
+    // Kicks run number `generation` until it is over
+    fn kick_run(&self, generation: u64) -> Result<()> {
+        for _ in 0..KICK_RETRIES {
+            {
+                let mut running = self.running.lock().unwrap();
+                if !running.busy || running.generation != generation {
+                    return Ok(());
+                }
+                running.kicked = true;
+                let thread = match running.thread {
+                    Some(thread) => thread,
+                    // The pool thread sees `kicked` before entering the ioctl
+                    None => return Ok(()),
+                };
+                // SAFETY: thread is alive while it is recorded in `running`,
+                // as it clears the entry under the same lock before
+                // finishing.
+                let ret = unsafe { libc::pthread_kill(thread, self.kick_signal) };
+                if ret != 0 {
+                    return Err(MshvError::Ioctl(errno::Error::new(ret)));
+                }
+            }
+            thread::sleep(KICK_INTERVAL);
+        }
+        Err(errno::Error::new(libc::ETIMEDOUT).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ioctls::system::Mshv;
+    use crate::ioctls::vcpu::new_vcpu;
+    use std::fs::File;
+    use vmm_sys_util::signal::SIGRTMIN;
+
+    #[test]
+    fn test_async_run() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = AsyncVcpuFd::new(vm.create_vcpu(0).unwrap(), SIGRTMIN()).unwrap();
+        vcpu.kick().unwrap();
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        // The vCPU has no memory, so it exits right away.
+        rt.block_on(vcpu.run(hv_message::default())).unwrap();
+    }
+
+    #[test]
+    fn test_kick_before_ioctl() {
+        let vcpu =
+            AsyncVcpuFd::new(new_vcpu(File::open("/dev/null").unwrap()), SIGRTMIN()).unwrap();
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        // Without a kick the ioctl is issued, and /dev/null rejects it.
+        let ret = rt.block_on(vcpu.run(hv_message::default()));
+        assert!(ret.err() == Some(MshvError::Ioctl(errno::Error::new(libc::ENOTTY))));
+        // A kick that lands before the pool thread gets to the ioctl is
+        // kept until it does.
+        vcpu.running.lock().unwrap().kicked = true;
+        let ret = rt.block_on(vcpu.run(hv_message::default()));
+        assert!(ret.err() == Some(MshvError::Ioctl(errno::Error::new(libc::EINTR))));
+        assert!(!vcpu.running.lock().unwrap().busy);
+        assert!(!vcpu.running.lock().unwrap().kicked);
+        vcpu.kick().unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use crate::ioctls::vcpu::VcpuFd;
+use crate::ioctls::vm::VmFd;
+use crate::ioctls::Result;
+use mshv_bindings::*;
+
+/// Partition operations shared by `VmFd` and its stand-ins, such as
+/// `MockVm` from the mshv-test crate. VMM code generic over `Vm` can be
+/// unit tested without `/dev/mshv`.
+pub trait Vm {
+    /// vCPU type returned by `create_vcpu`
+    type Vcpu: Vcpu;
+
+    /// Maps a region of guest physical memory
+    fn map_user_memory(&self, user_memory_region: mshv_user_mem_region) -> Result<()>;
+    /// Unmaps a region mapped with `map_user_memory`
+    fn unmap_user_memory(&self, user_memory_region: mshv_user_mem_region) -> Result<()>;
+    /// Creates the vCPU with VP index `id`
+    fn create_vcpu(&self, id: u32) -> Result<Self::Vcpu>;
+    /// Returns a partition property
+    fn get_partition_property(&self, code: u32) -> Result<u64>;
+    /// Sets a partition property
+    fn set_partition_property(&self, code: u32, value: u64) -> Result<()>;
+}
+
+/// vCPU operations shared by `VcpuFd` and its stand-ins, such as
+/// `MockVcpu` from the mshv-test crate.
+pub trait Vcpu {
+    /// Get the register values by providing an array of register names
+    fn get_reg(&self, reg_names: &mut [hv_register_assoc]) -> Result<()>;
+    /// Set vcpu register values by providing an array of register assocs
+    fn set_reg(&self, regs: &[hv_register_assoc]) -> Result<()>;
+    /// Returns the vCPU general purpose registers
+    fn get_regs(&self) -> Result<StandardRegisters>;
+    /// Sets the vCPU general purpose registers
+    fn set_regs(&self, regs: &StandardRegisters) -> Result<()>;
+    /// Returns the vCPU special registers
+    fn get_sregs(&self) -> Result<SpecialRegisters>;
+    /// Sets the vCPU special registers
+    fn set_sregs(&self, sregs: &SpecialRegisters) -> Result<()>;
+    /// Returns the floating point state
+    fn get_fpu(&self) -> Result<FloatingPointUnit>;
+    /// Sets the floating point state
+    fn set_fpu(&self, fpu: &FloatingPointUnit) -> Result<()>;
+    /// Returns the machine-specific registers (MSR) for this vCPU
+    fn get_msrs(&self, msrs: &mut Msrs) -> Result<usize>;
+    /// Setup the model-specific registers (MSR) for this vCPU
+    fn set_msrs(&self, msrs: &Msrs) -> Result<usize>;
+    /// Get the state of the LAPIC
+    fn get_lapic(&self) -> Result<LapicState>;
+    /// Sets the state of the LAPIC
+    fn set_lapic(&self, lapic_state: &LapicState) -> Result<()>;
+    /// Returns the xsave data
+    fn get_xsave(&self) -> Result<XSave>;
+    /// Set the xsave data
+    fn set_xsave(&self, data: &XSave) -> Result<()>;
+    /// Read GPA
+    fn gpa_read(&self, input: &mut mshv_read_write_gpa) -> Result<mshv_read_write_gpa>;
+    /// Write GPA
+    fn gpa_write(&self, input: &mut mshv_read_write_gpa) -> Result<mshv_read_write_gpa>;
+    /// Runs the vCPU until its next exit
+    fn run(&self, hv_message_input: hv_message) -> Result<hv_message>;
+}
+
+impl Vm for VmFd {
+    type Vcpu = VcpuFd;
+
+    fn map_user_memory(&self, user_memory_region: mshv_user_mem_region) -> Result<()> {
+        VmFd::map_user_memory(self, user_memory_region)
+    }
+    fn unmap_user_memory(&self, user_memory_region: mshv_user_mem_region) -> Result<()> {
+        VmFd::unmap_user_memory(self, user_memory_region)
+    }
+    fn create_vcpu(&self, id: u32) -> Result<VcpuFd> {
+        VmFd::create_vcpu(self, id)
+    }
+    fn get_partition_property(&self, code: u32) -> Result<u64> {
+        VmFd::get_partition_property(self, code)
+    }
+    fn set_partition_property(&self, code: u32, value: u64) -> Result<()> {
+        VmFd::set_partition_property(self, code, value)
+    }
+}
+
+impl Vcpu for VcpuFd {
+    fn get_reg(&self, reg_names: &mut [hv_register_assoc]) -> Result<()> {
+        VcpuFd::get_reg(self, reg_names)
+    }
+    fn set_reg(&self, regs: &[hv_register_assoc]) -> Result<()> {
+        VcpuFd::set_reg(self, regs)
+    }
+    fn get_regs(&self) -> Result<StandardRegisters> {
+        VcpuFd::get_regs(self)
+    }
+    fn set_regs(&self, regs: &StandardRegisters) -> Result<()> {
+        VcpuFd::set_regs(self, regs)
+    }
+    fn get_sregs(&self) -> Result<SpecialRegisters> {
+        VcpuFd::get_sregs(self)
+    }
+    fn set_sregs(&self, sregs: &SpecialRegisters) -> Result<()> {
+        VcpuFd::set_sregs(self, sregs)
+    }
+    fn get_fpu(&self) -> Result<FloatingPointUnit> {
+        VcpuFd::get_fpu(self)
+    }
+    fn set_fpu(&self, fpu: &FloatingPointUnit) -> Result<()> {
+        VcpuFd::set_fpu(self, fpu)
+    }
+    fn get_msrs(&self, msrs: &mut Msrs) -> Result<usize> {
+        VcpuFd::get_msrs(self, msrs)
+    }
+    fn set_msrs(&self, msrs: &Msrs) -> Result<usize> {
+        VcpuFd::set_msrs(self, msrs)
+    }
+    fn get_lapic(&self) -> Result<LapicState> {
+        VcpuFd::get_lapic(self)
+    }
+    fn set_lapic(&self, lapic_state: &LapicState) -> Result<()> {
+        VcpuFd::set_lapic(self, lapic_state)
+    }
+    fn get_xsave(&self) -> Result<XSave> {
+        VcpuFd::get_xsave(self)
+    }
+    fn set_xsave(&self, data: &XSave) -> Result<()> {
+        VcpuFd::set_xsave(self, data)
+    }
+    fn gpa_read(&self, input: &mut mshv_read_write_gpa) -> Result<mshv_read_write_gpa> {
+        VcpuFd::gpa_read(self, input)
+    }
+    fn gpa_write(&self, input: &mut mshv_read_write_gpa) -> Result<mshv_read_write_gpa> {
+        VcpuFd::gpa_write(self, input)
+    }
+    fn run(&self, hv_message_input: hv_message) -> Result<hv_message> {
+        VcpuFd::run(self, hv_message_input)
+    }
+}
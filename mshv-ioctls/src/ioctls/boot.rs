@@ -0,0 +1,131 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+//! Starting a Linux kernel directly in 64-bit mode, as described by the
+//! x86 64-bit boot protocol.
+use crate::ioctls::vcpu::VcpuFd;
+use crate::ioctls::Result;
+use mshv_bindings::*;
+use vmm_sys_util::errno;
+
+/// GPA the boot GDT is written at
+pub const BOOT_GDT_START: u64 = 0x500;
+/// GPA the boot IDT is written at
+pub const BOOT_IDT_START: u64 = 0x520;
+/// Initial stack pointer of the boot vCPU
+pub const BOOT_STACK_POINTER: u64 = 0x8ff0;
+/// GPA of the PML4 table, the PDPT and the page directory follow it
+pub const PML4_START: u64 = 0x9000;
+const PDPTE_START: u64 = 0xa000;
+const PDE_START: u64 = 0xb000;
+/// Size of the identity mapping set up for the kernel, 1GiB
+pub const BOOT_IDENTITY_MAP_SIZE: u64 = 1 << 30;
+
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_WRITABLE: u64 = 1 << 1;
+const PTE_PAGE_SIZE: u64 = 1 << 7;
+
+fn write_u64(mem: &mut [u8], gpa: u64, value: u64) -> Result<()> {
+    let start = gpa as usize;
+    mem.get_mut(start..start + 8)
+        .ok_or_else(|| errno::Error::new(libc::EINVAL))?
+        .copy_from_slice(&value.to_le_bytes());
+    Ok(())
+}
+
+/// Identity maps the first `BOOT_IDENTITY_MAP_SIZE` bytes with 2MiB pages
+/// and returns the CR3 value.
+fn setup_page_tables(mem: &mut [u8]) -> Result<u64> {
+    write_u64(mem, PML4_START, PDPTE_START | PTE_PRESENT | PTE_WRITABLE)?;
+    write_u64(mem, PDPTE_START, PDE_START | PTE_PRESENT | PTE_WRITABLE)?;
+    for i in 0..BOOT_IDENTITY_MAP_SIZE >> 21 {
+        write_u64(
+            mem,
+            PDE_START + i * 8,
+            (i << 21) | PTE_PRESENT | PTE_WRITABLE | PTE_PAGE_SIZE,
+        )?;
+    }
+    Ok(PML4_START)
+}
+
+/// Sets `vcpu` up to enter a 64-bit Linux kernel at `entry`, with the zero
+/// page at `boot_params_gpa`.
+///
+/// `mem` is the guest memory starting at GPA 0, which must cover the page
+/// tables at `PML4_START`. Paging identity maps the first 1GiB, the GDT and
+/// an empty IDT are written at `BOOT_GDT_START` and `BOOT_IDT_START`, and
+/// the vCPU runs with interrupts disabled on a stack at
+/// `BOOT_STACK_POINTER`. Fails with EINVAL if `mem` is too small.
+pub fn setup_long_mode(
+    vcpu: &VcpuFd,
+    mem: &mut [u8],
+    entry: u64,
+    boot_params_gpa: u64,
+) -> Result<()> {
+    let gdt = BootGdt::new(0, 0x67);
+    gdt.write_to(mem, BOOT_GDT_START)?;
+    write_boot_idt(mem, BOOT_IDT_START)?;
+    let cr3 = setup_page_tables(mem)?;
+
+    let mut sregs = vcpu.get_sregs()?;
+    gdt.apply(&mut sregs, BOOT_GDT_START);
+    sregs.idt = boot_idt_register(BOOT_IDT_START);
+    sregs.cr3 = cr3;
+    sregs.cr4 = X86_CR4_PAE;
+    sregs.cr0 = X86_CR0_PE | X86_CR0_ET | X86_CR0_PG;
+    sregs.efer = X86_EFER_LME | X86_EFER_LMA;
+    sregs.set_pending_interrupt(None);
+    vcpu.set_sregs(&sregs)?;
+
+    vcpu.set_regs(&StandardRegisters {
+        rip: entry,
+        rsp: BOOT_STACK_POINTER,
+        rbp: BOOT_STACK_POINTER,
+        rsi: boot_params_gpa,
+        rflags: X86_RFLAGS_FIXED,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ioctls::system::Mshv;
+
+    #[test]
+    fn test_setup_page_tables() {
+        let mut mem = vec![0u8; 0x1_0000];
+        assert!(setup_page_tables(&mut mem).unwrap() == PML4_START);
+        let entry = |gpa: u64| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&mem[gpa as usize..gpa as usize + 8]);
+            u64::from_le_bytes(bytes)
+        };
+        assert!(entry(PML4_START) == PDPTE_START | 0x3);
+        assert!(entry(PDPTE_START) == PDE_START | 0x3);
+        assert!(entry(PDE_START) == 0x83);
+        assert!(entry(PDE_START + 511 * 8) == (511 << 21) | 0x83);
+        assert!(entry(PDE_START + 512 * 8) == 0);
+
+        assert!(setup_page_tables(&mut mem[..0xb000]).is_err());
+    }
+
+    #[test]
+    fn test_setup_long_mode() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let mut mem = vec![0u8; 0x1_0000];
+        vcpu.set_state_validation(true);
+        setup_long_mode(&vcpu, &mut mem, 0x100_0200, 0x7000).unwrap();
+
+        let sregs = vcpu.get_sregs().unwrap();
+        assert!(sregs.cr3 == PML4_START);
+        assert!(sregs.efer & X86_EFER_LMA != 0);
+        assert!(sregs.cs.l == 1 && sregs.cs.selector == BOOT_GDT_CODE64_SELECTOR);
+        assert!(sregs.gdt.base == BOOT_GDT_START);
+        let regs = vcpu.get_regs().unwrap();
+        assert!(regs.rip == 0x100_0200 && regs.rsi == 0x7000);
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use crate::ioctls::port::as_bytes;
+use crate::ioctls::vm::VmFd;
+use crate::ioctls::{MshvError, Result};
+use mshv_bindings::*;
+use std::collections::BTreeSet;
+
+// A sparse VP set has 64 banks of 64 VPs
+const MAX_VPS: u32 = 64 * 64;
+
+/// Where a device interrupt is delivered, see
+/// `VmFd::retarget_device_interrupt`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InterruptTarget {
+    /// Vector raised on the target VPs
+    pub vector: u32,
+    /// Deliver to every VP of `vps` instead of one of them
+    pub multicast: bool,
+    /// VP indexes, below 4096
+    pub vps: BTreeSet<u32>,
+}
+
+impl InterruptTarget {
+    /// Targets `vector` of VP `vp`.
+    pub fn new(vector: u32, vp: u32) -> Self {
+        InterruptTarget {
+            vector,
+            multicast: false,
+            vps: std::iter::once(vp).collect(),
+        }
+    }
+    /// Target of an x86 MSI as programmed by the guest, assuming APIC IDs
+    /// match VP indexes. In logical destination mode the destination is
+    /// read as a flat model mask of VPs and the interrupt is multicast.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn from_msi(address: u32, data: u32) -> Self {
+        let vector = data & 0xff;
+        let destination = (address >> 12) & 0xff;
+        if address & (1 << 2) == 0 {
+            return InterruptTarget::new(vector, destination);
+        }
+        InterruptTarget {
+            vector,
+            multicast: true,
+            vps: (0..8).filter(|vp| destination & (1 << vp) != 0).collect(),
+        }
+    }
+    // Flags and the VP mask or VP set following the fixed input
+    fn encode(&self) -> Result<(u32, Vec<u64>)> {
+        match self.vps.iter().next_back() {
+            None => return Err(MshvError::InvalidArgument { field: "vps" }),
+            Some(&vp) if vp >= MAX_VPS => return Err(MshvError::InvalidArgument { field: "vps" }),
+            _ => (),
+        }
+        let mut flags = 0;
+        if self.multicast {
+            flags |= HV_DEVICE_INTERRUPT_TARGET_MULTICAST;
+        }
+        let mut banks = [0u64; 64];
+        for vp in self.vps.iter() {
+            banks[(vp / 64) as usize] |= 1 << (vp % 64);
+        }
+        if self.vps.iter().all(|vp| *vp < 64) {
+            return Ok((flags, vec![banks[0]]));
+        }
+        let mut valid_bank_mask = 0u64;
+        let mut vp_set = vec![hv_generic_set_format_HV_GENERIC_SET_SPARSE_4K as u64, 0];
+        for (bank, contents) in banks.iter().enumerate() {
+            if *contents != 0 {
+                valid_bank_mask |= 1 << bank;
+                vp_set.push(*contents);
+            }
+        }
+        vp_set[1] = valid_bank_mask;
+        Ok((flags | HV_DEVICE_INTERRUPT_TARGET_PROCESSOR_SET, vp_set))
+    }
+}
+
+/// Builds the MSI interrupt entry identifying an interrupt of a device.
+pub fn msi_interrupt_entry(address: u32, data: u32) -> HvInterruptEntry {
+    HvInterruptEntry {
+        source: HV_INTERRUPT_SOURCE_MSI,
+        address,
+        data,
+        ..Default::default()
+    }
+}
+
+impl VmFd {
+    /// Redirects the interrupt `entry` of device `device_id`, typically
+    /// an MSI-X vector of a passed-through device, to `target`. Called
+    /// again whenever the guest moves the vector to other vCPUs or changes
+    /// it.
+    ///
+    /// Goes through `hvcall_raw`, so drivers without hypercall pass-through
+    /// fail with `MshvError::NotSupported`.
+    pub fn retarget_device_interrupt(
+        &self,
+        device_id: u64,
+        entry: HvInterruptEntry,
+        target: &InterruptTarget,
+    ) -> Result<()> {
+        let (flags, vps) = target.encode()?;
+        let header = HvRetargetDeviceInterruptHeader {
+            partition_id: HV_PARTITION_ID_SELF,
+            device_id,
+            int_entry: entry,
+            vector: target.vector,
+            flags,
+            ..Default::default()
+        };
+        let mut input = as_bytes(&header).to_vec();
+        for word in vps {
+            input.extend_from_slice(&word.to_le_bytes());
+        }
+        self.hvcall_raw(HV_CALL_RETARGET_DEVICE_INTERRUPT as u16, &input, &mut [])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interrupt_target_encode() {
+        let target = InterruptTarget::new(0x30, 5);
+        assert_eq!(target.encode().unwrap(), (0, vec![1 << 5]));
+
+        let mut target = InterruptTarget::new(0x31, 1);
+        target.multicast = true;
+        target.vps.insert(130);
+        target.vps.insert(131);
+        let (flags, vp_set) = target.encode().unwrap();
+        assert_eq!(
+            flags,
+            HV_DEVICE_INTERRUPT_TARGET_MULTICAST | HV_DEVICE_INTERRUPT_TARGET_PROCESSOR_SET
+        );
+        assert_eq!(vp_set, vec![0, 0b101, 1 << 1, 0b1100]);
+
+        target.vps.insert(MAX_VPS);
+        assert!(target.encode().is_err());
+        assert!(InterruptTarget::default().encode().is_err());
+    }
+
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    #[test]
+    fn test_interrupt_target_from_msi() {
+        assert_eq!(
+            InterruptTarget::from_msi(0xfee0_3000, 0x4041),
+            InterruptTarget::new(0x41, 3)
+        );
+        let target = InterruptTarget::from_msi(0xfee0_5004, 0x22);
+        assert!(target.multicast && target.vector == 0x22);
+        assert_eq!(target.vps.into_iter().collect::<Vec<_>>(), vec![0, 2]);
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use crate::ioctls::{MshvError, Result};
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+/// GPA range emulated by a device of the VMM, see
+/// `VmFd::register_device_region`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceRegion {
+    /// First GPA of the range
+    pub gpa: u64,
+    /// Size of the range in bytes
+    pub size: u64,
+    /// Value chosen by the VMM to identify the device
+    pub cookie: u64,
+}
+
+impl DeviceRegion {
+    /// Returns true if `gpa` is in the range
+    pub fn contains(&self, gpa: u64) -> bool {
+        gpa.wrapping_sub(self.gpa) < self.size
+    }
+}
+
+/// Guest access to a device region, decoded by `VcpuFd::device_mmio_exit`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceMmioExit {
+    /// Cookie the region was registered with
+    pub cookie: u64,
+    /// Accessed GPA
+    pub gpa: u64,
+    /// Offset of `gpa` in the region
+    pub offset: u64,
+    /// True for writes, false for reads and instruction fetches
+    pub is_write: bool,
+}
+
+/// Device regions of a VM, shared with its vCPUs.
+#[derive(Debug, Default)]
+pub(crate) struct DeviceRegions {
+    // First GPA -> region
+    regions: RwLock<BTreeMap<u64, DeviceRegion>>,
+}
+
+impl DeviceRegions {
+    /// Adds `region`, which must not overlap another one
+    pub(crate) fn insert(&self, region: DeviceRegion) -> Result<()> {
+        if region.size == 0 || region.gpa.checked_add(region.size - 1).is_none() {
+            return Err(MshvError::InvalidArgument { field: "size" });
+        }
+        let mut regions = self.regions.write().unwrap();
+        let last = region.gpa + (region.size - 1);
+        if let Some((_, next)) = regions.range(region.gpa..).next() {
+            if next.gpa <= last {
+                return Err(MshvError::InvalidArgument { field: "gpa" });
+            }
+        }
+        if let Some((_, prev)) = regions.range(..region.gpa).next_back() {
+            if prev.contains(region.gpa) {
+                return Err(MshvError::InvalidArgument { field: "gpa" });
+            }
+        }
+        regions.insert(region.gpa, region);
+        Ok(())
+    }
+    /// Removes the region starting at `gpa`
+    pub(crate) fn remove(&self, gpa: u64) -> Option<DeviceRegion> {
+        self.regions.write().unwrap().remove(&gpa)
+    }
+    /// Returns the region `gpa` is in
+    pub(crate) fn lookup(&self, gpa: u64) -> Option<DeviceRegion> {
+        let regions = self.regions.read().unwrap();
+        let (_, region) = regions.range(..=gpa).next_back()?;
+        if region.contains(gpa) {
+            Some(*region)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_regions() {
+        let regions = DeviceRegions::default();
+        let uart = DeviceRegion {
+            gpa: 0xfed0_0000,
+            size: 0x1000,
+            cookie: 1,
+        };
+        regions.insert(uart).unwrap();
+        assert!(regions.lookup(0xfed0_0000) == Some(uart));
+        assert!(regions.lookup(0xfed0_0fff) == Some(uart));
+        assert!(regions.lookup(0xfed0_1000).is_none());
+        assert!(regions.lookup(0xfecf_ffff).is_none());
+
+        for (gpa, size) in [(0xfecf_f000, 0x1001), (0xfed0_0fff, 1), (0xfed0_0000, 0x10)] {
+            assert!(
+                regions.insert(DeviceRegion {
+                    gpa,
+                    size,
+                    cookie: 2
+                }) == Err(MshvError::InvalidArgument { field: "gpa" })
+            );
+        }
+        for (gpa, size) in [(0, 0), (u64::MAX, 2)] {
+            assert!(
+                regions.insert(DeviceRegion {
+                    gpa,
+                    size,
+                    cookie: 2
+                }) == Err(MshvError::InvalidArgument { field: "size" })
+            );
+        }
+        regions
+            .insert(DeviceRegion {
+                gpa: 0xfed0_1000,
+                size: 0x1000,
+                cookie: 2,
+            })
+            .unwrap();
+        assert!(regions.lookup(0xfed0_1000).unwrap().cookie == 2);
+
+        assert!(regions.remove(0xfed0_0000) == Some(uart));
+        assert!(regions.lookup(0xfed0_0000).is_none());
+        assert!(regions.remove(0xfed0_0000).is_none());
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use crate::ioctls::vcpu::VcpuFd;
+use crate::ioctls::Result;
+use mshv_bindings::*;
+use std::cmp;
+
+/// Guest access an instruction emulator needs to complete an exit MSHV
+/// could not, typically an MMIO access to unmapped GPA.
+///
+/// Adapting an x86 emulator crate comes down to implementing its platform
+/// trait on top of this one. `VcpuMmioContext` implements it for a vCPU.
+/// This crate ships no such adapter, as it does not depend on any emulator
+/// crate.
+pub trait MmioEmulator {
+    /// Copies the instruction bytes at guest virtual address `gva` into
+    /// `buf` and returns how many were copied.
+    fn fetch(&mut self, gva: u64, buf: &mut [u8]) -> Result<usize>;
+    /// Reads guest physical memory or device registers at `gpa`.
+    fn read_gpa(&mut self, gpa: u64, data: &mut [u8]) -> Result<()>;
+    /// Writes guest physical memory or device registers at `gpa`.
+    fn write_gpa(&mut self, gpa: u64, data: &[u8]) -> Result<()>;
+    /// Returns the value of register `name`.
+    fn read_register(&mut self, name: hv_register_name) -> Result<u64>;
+    /// Sets register `name` to `value`.
+    fn write_register(&mut self, name: hv_register_name, value: u64) -> Result<()>;
+}
+
+/// Instruction emulator `VcpuFd::emulate_mmio_exit` hands the memory
+/// intercepts to, usually an x86 emulator crate behind a thin wrapper.
+///
+/// `emulate` decodes the instruction at RIP with `MmioEmulator::fetch`,
+/// executes it against the context and moves RIP past it.
+pub trait InstructionEmulator {
+    /// Emulates the instruction the vCPU stopped on.
+    fn emulate(&mut self, ctx: &mut dyn MmioEmulator) -> Result<()>;
+}
+
+impl<F: FnMut(&mut dyn MmioEmulator) -> Result<()>> InstructionEmulator for F {
+    fn emulate(&mut self, ctx: &mut dyn MmioEmulator) -> Result<()> {
+        self(ctx)
+    }
+}
+
+/// `MmioEmulator` for a vCPU stopped on a memory intercept.
+///
+/// Accesses to the page of the intercepted GPA go to `mmio`, called with
+/// the GPA, the data and true for writes. Anything else goes to guest
+/// memory. Instruction bytes come from the intercept message when it has
+/// them, saving a guest page table walk.
+pub struct VcpuMmioContext<'a, F> {
+    vcpu: &'a VcpuFd,
+    msg: hv_x64_memory_intercept_message,
+    mmio: F,
+}
+
+impl<'a, F> VcpuMmioContext<'a, F>
+where
+    F: FnMut(u64, &mut [u8], bool),
+{
+    /// Creates a context for the memory intercept `msg` taken by `vcpu`.
+    /// Fails with EINVAL if `msg` is not a memory intercept.
+    pub fn new(vcpu: &'a VcpuFd, msg: &hv_message, mmio: F) -> Result<Self> {
+        Ok(VcpuMmioContext {
+            vcpu,
+            msg: msg.to_memory_info()?,
+            mmio,
+        })
+    }
+    /// Returns the decoded memory intercept.
+    pub fn intercept(&self) -> &hv_x64_memory_intercept_message {
+        &self.msg
+    }
+    fn is_mmio(&self, gpa: u64) -> bool {
+        gpa >> HV_HYP_PAGE_SHIFT == self.msg.guest_physical_address >> HV_HYP_PAGE_SHIFT
+    }
+}
+
+impl<F> MmioEmulator for VcpuMmioContext<'_, F>
+where
+    F: FnMut(u64, &mut [u8], bool),
+{
+    fn fetch(&mut self, gva: u64, buf: &mut [u8]) -> Result<usize> {
+        let header = self.msg.header;
+        let cs = header.cs_segment;
+        let insn_bytes = self.msg.instruction_bytes;
+        let mut done = 0;
+        if gva == cs.base.wrapping_add(header.rip) {
+            done = cmp::min(self.msg.instruction_byte_count as usize, buf.len());
+            buf[..done].copy_from_slice(&insn_bytes[..done]);
+        }
+        self.vcpu
+            .access_gva(gva.wrapping_add(done as u64), &mut buf[done..], false)?;
+        Ok(buf.len())
+    }
+    fn read_gpa(&mut self, gpa: u64, data: &mut [u8]) -> Result<()> {
+        if self.is_mmio(gpa) {
+            (self.mmio)(gpa, data, false);
+            return Ok(());
+        }
+        self.vcpu.access_gpa(gpa, data, false)
+    }
+    fn write_gpa(&mut self, gpa: u64, data: &[u8]) -> Result<()> {
+        let mut data = data.to_vec();
+        if self.is_mmio(gpa) {
+            (self.mmio)(gpa, &mut data, true);
+            return Ok(());
+        }
+        self.vcpu.access_gpa(gpa, &mut data, true)
+    }
+    fn read_register(&mut self, name: hv_register_name) -> Result<u64> {
+        let mut reg_assocs = [hv_register_assoc {
+            name,
+            ..Default::default()
+        }];
+        self.vcpu.get_reg(&mut reg_assocs)?;
+        // SAFETY: access union fields
+        Ok(unsafe { reg_assocs[0].value.reg64 })
+    }
+    fn write_register(&mut self, name: hv_register_name, value: u64) -> Result<()> {
+        self.vcpu.set_reg(&[hv_register_assoc {
+            name,
+            value: hv_register_value { reg64: value },
+            ..Default::default()
+        }])
+    }
+}
+
+impl VcpuFd {
+    /// Completes a memory intercept MSHV could not, by running `emulator`
+    /// on a `VcpuMmioContext` for `msg` that sends accesses to the
+    /// intercepted page to `mmio`. Returns false, without calling
+    /// `emulator`, for exits that are not memory intercepts.
+    pub fn emulate_mmio_exit<E, F>(
+        &self,
+        msg: &hv_message,
+        emulator: &mut E,
+        mmio: F,
+    ) -> Result<bool>
+    where
+        E: InstructionEmulator + ?Sized,
+        F: FnMut(u64, &mut [u8], bool),
+    {
+        let mut ctx = match VcpuMmioContext::new(self, msg, mmio) {
+            Ok(ctx) => ctx,
+            Err(_) => return Ok(false),
+        };
+        emulator.emulate(&mut ctx)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ioctls::system::Mshv;
+    use crate::ioctls::vcpu::new_vcpu;
+    use mshv_test::exit_message;
+    use std::fs::File;
+
+    #[test]
+    fn test_vcpu_mmio_context() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        let msg = hv_message::default();
+        assert!(VcpuMmioContext::new(&vcpu, &msg, |_, _, _| {}).is_err());
+
+        let mut intercept = hv_x64_memory_intercept_message {
+            guest_physical_address: 0xd000_0010,
+            instruction_byte_count: 2,
+            ..Default::default()
+        };
+        intercept.instruction_bytes[..2].copy_from_slice(&[0x8b, 0x07]);
+        let msg = exit_message(hv_message_type_HVMSG_UNMAPPED_GPA, intercept);
+
+        let mut reads = 0;
+        {
+            let mut ctx = VcpuMmioContext::new(&vcpu, &msg, |gpa, data, write| {
+                assert!(gpa == 0xd000_0010 && !write);
+                data.fill(0xab);
+                reads += 1;
+            })
+            .unwrap();
+            let mut insn = [0u8; 2];
+            assert!(ctx.fetch(0, &mut insn).unwrap() == 2);
+            assert!(insn == [0x8b, 0x07]);
+            let mut data = [0u8; 4];
+            ctx.read_gpa(0xd000_0010, &mut data).unwrap();
+            assert!(data == [0xab; 4]);
+            ctx.write_register(hv_register_name_HV_X64_REGISTER_RAX, 0x1234)
+                .unwrap();
+            assert!(
+                ctx.read_register(hv_register_name_HV_X64_REGISTER_RAX)
+                    .unwrap()
+                    == 0x1234
+            );
+        }
+        assert!(reads == 1);
+    }
+
+    #[test]
+    fn test_emulate_mmio_exit() {
+        let vcpu = new_vcpu(File::open("/dev/null").unwrap());
+        let mut emulated = 0;
+        let mut emulator = |ctx: &mut dyn MmioEmulator| {
+            let mut insn = [0u8; 2];
+            ctx.fetch(0, &mut insn)?;
+            assert!(insn == [0x89, 0x07]);
+            ctx.write_gpa(0xd000_0010, &[1, 2, 3, 4])?;
+            emulated += 1;
+            Ok(())
+        };
+        assert!(!vcpu
+            .emulate_mmio_exit(&hv_message::default(), &mut emulator, |_, _, _| {})
+            .unwrap());
+
+        let mut intercept = hv_x64_memory_intercept_message {
+            guest_physical_address: 0xd000_0010,
+            instruction_byte_count: 2,
+            ..Default::default()
+        };
+        intercept.instruction_bytes[..2].copy_from_slice(&[0x89, 0x07]);
+        let msg = exit_message(hv_message_type_HVMSG_UNMAPPED_GPA, intercept);
+        let mut writes = Vec::new();
+        assert!(vcpu
+            .emulate_mmio_exit(&msg, &mut emulator, |gpa, data, write| {
+                assert!(write);
+                writes.push((gpa, data.to_vec()));
+            })
+            .unwrap());
+        assert!(emulated == 1);
+        assert!(writes == [(0xd000_0010, vec![1, 2, 3, 4])]);
+    }
+}
@@ -0,0 +1,203 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use crate::ioctls::vcpu::VcpuFd;
+use crate::ioctls::vm::{DoorbellId, VmFd};
+use crate::ioctls::Result;
+use event_manager::{EventOps, EventSet, Events, MutEventSubscriber};
+use std::fmt;
+use std::sync::Arc;
+use vmm_sys_util::errno;
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+// What the eventfd was registered as, undone on drop
+enum Registration {
+    None,
+    Doorbell(Arc<VmFd>, DoorbellId),
+    Irqfd {
+        vm: Arc<VmFd>,
+        irqfd: EventFd,
+        gsi: u32,
+    },
+    Halt(Arc<VcpuFd>),
+}
+
+/// event-manager subscriber calling a handler each time an eventfd is
+/// signaled, with the counter read from the eventfd.
+///
+/// The constructors register the eventfd with the partition, saving IO
+/// threads the epoll plumbing: `doorbell` for guest writes to a GPA,
+/// `resample` for EOIs of a level-triggered irqfd and `vcpu_halt` for vCPU
+/// halts. Doorbells and irqfds are unregistered, and halt eventfds cleared,
+/// when the subscriber is dropped.
+pub struct EventFdSubscriber<F> {
+    fd: EventFd,
+    handler: F,
+    registration: Registration,
+}
+
+impl<F> fmt::Debug for EventFdSubscriber<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventFdSubscriber")
+            .field("fd", &self.fd)
+            .finish()
+    }
+}
+
+fn new_eventfd() -> Result<EventFd> {
+    Ok(EventFd::new(EFD_NONBLOCK).map_err(|_| errno::Error::last())?)
+}
+
+impl<F: FnMut(u64)> EventFdSubscriber<F> {
+    /// Subscriber for `fd`, registered by the caller
+    pub fn new(fd: EventFd, handler: F) -> Self {
+        EventFdSubscriber {
+            fd,
+            handler,
+            registration: Registration::None,
+        }
+    }
+    /// Registers a doorbell, see `VmFd::register_doorbell`, and subscribes
+    /// to it.
+    pub fn doorbell(
+        vm: Arc<VmFd>,
+        gpa: u64,
+        value_match: u64,
+        flags: u32,
+        handler: F,
+    ) -> Result<Self> {
+        let fd = new_eventfd()?;
+        let id = vm.register_doorbell(gpa, value_match, flags, &fd)?;
+        Ok(EventFdSubscriber {
+            fd,
+            handler,
+            registration: Registration::Doorbell(vm, id),
+        })
+    }
+    /// Registers an irqfd on `gsi` with a resample eventfd, see
+    /// `VmFd::register_irqfd_with_resample`, and subscribes to the resample
+    /// eventfd. The device raises the interrupt by writing to `irqfd`.
+    pub fn resample(vm: Arc<VmFd>, gsi: u32, handler: F) -> Result<Self> {
+        let irqfd = new_eventfd()?;
+        let fd = new_eventfd()?;
+        vm.register_irqfd_with_resample(&irqfd, &fd, gsi)?;
+        Ok(EventFdSubscriber {
+            fd,
+            handler,
+            registration: Registration::Irqfd { vm, irqfd, gsi },
+        })
+    }
+    /// Sets the halt eventfd of `vcpu`, see `VcpuFd::set_halt_eventfd`, and
+    /// subscribes to it. Dropping the subscriber clears it, unless another
+    /// eventfd was set since.
+    pub fn vcpu_halt(vcpu: Arc<VcpuFd>, handler: F) -> Result<Self> {
+        let fd = new_eventfd()?;
+        vcpu.set_halt_eventfd(Some(&fd))?;
+        Ok(EventFdSubscriber {
+            fd,
+            handler,
+            registration: Registration::Halt(vcpu),
+        })
+    }
+    /// The subscribed eventfd
+    pub fn eventfd(&self) -> &EventFd {
+        &self.fd
+    }
+    /// The irqfd of a `resample` subscriber
+    pub fn irqfd(&self) -> Option<&EventFd> {
+        match &self.registration {
+            Registration::Irqfd { irqfd, .. } => Some(irqfd),
+            _ => None,
+        }
+    }
+}
+
+impl<F: FnMut(u64)> MutEventSubscriber for EventFdSubscriber<F> {
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        if events
+            .event_set()
+            .intersects(EventSet::ERROR | EventSet::HANG_UP)
+        {
+            let _ = ops.remove(events);
+            return;
+        }
+        // Nothing to read if another reader got there first
+        if let Ok(count) = self.fd.read() {
+            (self.handler)(count);
+        }
+    }
+    fn init(&mut self, ops: &mut EventOps) {
+        // Only fails if the eventfd is already registered with the manager
+        let _ = ops.add(Events::new(&self.fd, EventSet::IN));
+    }
+}
+
+impl<F> Drop for EventFdSubscriber<F> {
+    fn drop(&mut self) {
+        match &self.registration {
+            Registration::None => (),
+            Registration::Doorbell(vm, id) => {
+                let _ = vm.unregister_doorbell(*id);
+            }
+            Registration::Irqfd { vm, irqfd, gsi } => {
+                let _ = vm.unregister_irqfd(irqfd, *gsi);
+            }
+            Registration::Halt(vcpu) => vcpu.clear_halt_eventfd(&self.fd),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ioctls::system::Mshv;
+    use crate::ioctls::vm::new_vmfd;
+    use event_manager::{EventManager, SubscriberOps};
+    use std::fs::File;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_eventfd_subscriber() {
+        let count = Arc::new(Mutex::new(0));
+        let c = count.clone();
+        let subscriber = EventFdSubscriber::new(new_eventfd().unwrap(), move |n| {
+            *c.lock().unwrap() += n;
+        });
+        let fd = subscriber.eventfd().try_clone().unwrap();
+        assert!(subscriber.irqfd().is_none());
+
+        let mut manager = EventManager::<EventFdSubscriber<_>>::new().unwrap();
+        manager.add_subscriber(subscriber);
+        fd.write(2).unwrap();
+        fd.write(1).unwrap();
+        assert!(manager.run_with_timeout(100).unwrap() == 1);
+        assert!(*count.lock().unwrap() == 3);
+        assert!(manager.run_with_timeout(0).unwrap() == 0);
+    }
+
+    #[test]
+    fn test_eventfd_subscriber_registration() {
+        let vm = Arc::new(new_vmfd(File::open("/dev/null").unwrap()));
+        assert!(EventFdSubscriber::doorbell(vm.clone(), 0x1000, 0, 0, |_| ()).is_err());
+        assert!(EventFdSubscriber::resample(vm, 5, |_| ()).is_err());
+    }
+
+    #[test]
+    fn test_doorbell_subscriber() {
+        let hv = Mshv::new().unwrap();
+        let vm = Arc::new(hv.create_vm().unwrap());
+        let subscriber = EventFdSubscriber::doorbell(
+            vm.clone(),
+            0xe000_0000,
+            0,
+            mshv_bindings::HV_DOORBELL_FLAG_TRIGGER_SIZE_ANY
+                | mshv_bindings::HV_DOORBELL_FLAG_TRIGGER_ANY_VALUE,
+            |_| (),
+        )
+        .unwrap();
+        assert!(vm.doorbells().len() == 1);
+        drop(subscriber);
+        assert!(vm.doorbells().is_empty());
+    }
+}
@@ -0,0 +1,342 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use crate::ioctls::vm::{DoorbellId, IoEventAddress, VmFd};
+use crate::ioctls::{MshvError, Result};
+use mshv_bindings::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::os::unix::io::AsRawFd;
+use vmm_sys_util::errno;
+use vmm_sys_util::eventfd::EventFd;
+
+#[derive(Debug)]
+struct IrqFd {
+    fd: EventFd,
+    resamplefd: Option<EventFd>,
+}
+
+#[derive(Debug)]
+struct IoEvent {
+    addr: IoEventAddress,
+    datamatch: Option<u64>,
+    len: u32,
+    fd: EventFd,
+}
+
+/// Tracks the irqfds, ioeventfds and doorbells of a partition so that they
+/// can be listed and torn down together, for instance on VM reset.
+///
+/// Registrations are identified by their GSI or address and by the eventfd
+/// the caller passed: registering the same eventfd again is a no-op. The
+/// registry keeps duplicates of the eventfds, and an eventfd is recognized
+/// by comparing it to them with kcmp(2), so a closed fd whose number is
+/// reused is not mistaken for the one registered. Deregistration works
+/// after the caller closed its own fd. Doorbells are kept by the `VmFd`,
+/// the registry only remembers their handles. Like `MemoryHotplug`, the
+/// `VmFd` is passed to each call.
+#[derive(Debug, Default)]
+pub struct EventFdRegistry {
+    irqfds: BTreeMap<u32, Vec<IrqFd>>,
+    ioevents: Vec<IoEvent>,
+    doorbells: BTreeSet<DoorbellId>,
+}
+
+fn dup(fd: &EventFd) -> Result<EventFd> {
+    Ok(fd.try_clone().map_err(|_| errno::Error::last())?)
+}
+
+const KCMP_FILE: libc::c_int = 0;
+
+// Whether `a` and `b` refer to the same open eventfd. False if the kernel
+// cannot tell, for instance without CONFIG_KCMP.
+pub(crate) fn same_eventfd(a: &EventFd, b: &EventFd) -> bool {
+    // SAFETY: kcmp only compares the two fds of this process, no memory
+    // is passed
+    let ret = unsafe {
+        let pid = libc::getpid();
+        libc::syscall(
+            libc::SYS_kcmp,
+            pid,
+            pid,
+            KCMP_FILE,
+            a.as_raw_fd(),
+            b.as_raw_fd(),
+        )
+    };
+    ret == 0
+}
+
+impl EventFdRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        EventFdRegistry::default()
+    }
+    /// Returns true if nothing is registered.
+    pub fn is_empty(&self) -> bool {
+        self.irqfds.is_empty() && self.ioevents.is_empty() && self.doorbells.is_empty()
+    }
+    /// See `VmFd::register_irqfd`.
+    pub fn register_irqfd(&mut self, vm: &VmFd, fd: &EventFd, gsi: u32) -> Result<()> {
+        if self.find_irqfd(fd, gsi).is_some() {
+            return Ok(());
+        }
+        let irqfd = IrqFd {
+            fd: dup(fd)?,
+            resamplefd: None,
+        };
+        vm.register_irqfd(&irqfd.fd, gsi)?;
+        self.irqfds.entry(gsi).or_default().push(irqfd);
+        Ok(())
+    }
+    /// See `VmFd::register_irqfd_with_resample`.
+    pub fn register_irqfd_with_resample(
+        &mut self,
+        vm: &VmFd,
+        fd: &EventFd,
+        resamplefd: &EventFd,
+        gsi: u32,
+    ) -> Result<()> {
+        if self.find_irqfd(fd, gsi).is_some() {
+            return Ok(());
+        }
+        let irqfd = IrqFd {
+            fd: dup(fd)?,
+            resamplefd: Some(dup(resamplefd)?),
+        };
+        vm.register_irqfd_with_resample(&irqfd.fd, irqfd.resamplefd.as_ref().unwrap(), gsi)?;
+        self.irqfds.entry(gsi).or_default().push(irqfd);
+        Ok(())
+    }
+    /// Unregisters `fd` from `gsi`. Fails with ENOENT if it is not
+    /// registered.
+    pub fn unregister_irqfd(&mut self, vm: &VmFd, fd: &EventFd, gsi: u32) -> Result<()> {
+        match self.find_irqfd(fd, gsi) {
+            Some(index) => self.remove_irqfd(vm, gsi, index),
+            None => Err(errno::Error::new(libc::ENOENT).into()),
+        }
+    }
+    fn find_irqfd(&self, fd: &EventFd, gsi: u32) -> Option<usize> {
+        self.irqfds
+            .get(&gsi)?
+            .iter()
+            .position(|irqfd| same_eventfd(&irqfd.fd, fd))
+    }
+    fn remove_irqfd(&mut self, vm: &VmFd, gsi: u32, index: usize) -> Result<()> {
+        let irqfds = self.irqfds.get_mut(&gsi).unwrap();
+        vm.unregister_irqfd(&irqfds[index].fd, gsi)?;
+        irqfds.swap_remove(index);
+        if irqfds.is_empty() {
+            self.irqfds.remove(&gsi);
+        }
+        Ok(())
+    }
+    /// See `VmFd::register_ioevent`. Fails with EEXIST if another fd is
+    /// registered for the same address and datamatch. Like the `VmFd`
+    /// call, fails with NotSupported for PIO addresses.
+    pub fn register_ioevent<T: Into<u64>>(
+        &mut self,
+        vm: &VmFd,
+        fd: &EventFd,
+        addr: &IoEventAddress,
+        datamatch: T,
+    ) -> Result<()> {
+        let len = std::mem::size_of::<T>() as u32;
+        let datamatch = if len > 0 {
+            Some(datamatch.into())
+        } else {
+            None
+        };
+        if let Some(ioevent) = self.find_ioevent(addr, datamatch, len) {
+            if same_eventfd(&self.ioevents[ioevent].fd, fd) {
+                return Ok(());
+            }
+            return Err(errno::Error::new(libc::EEXIST).into());
+        }
+        let mmio_addr = match addr {
+            IoEventAddress::Pio(_) => return Err(MshvError::NotSupported),
+            IoEventAddress::Mmio(m) => *m,
+        };
+        let ioevent = IoEvent {
+            addr: *addr,
+            datamatch,
+            len,
+            fd: dup(fd)?,
+        };
+        vm.mmio_ioeventfd(
+            &ioevent.fd,
+            mmio_addr,
+            datamatch.unwrap_or(0),
+            len,
+            ioevent_flags(datamatch),
+        )?;
+        self.ioevents.push(ioevent);
+        Ok(())
+    }
+    /// Unregisters the ioeventfd at `addr` for `datamatch`, whose size
+    /// must be the one it was registered with. Fails with ENOENT if there
+    /// is none.
+    pub fn unregister_ioevent<T: Into<u64>>(
+        &mut self,
+        vm: &VmFd,
+        addr: &IoEventAddress,
+        datamatch: T,
+    ) -> Result<()> {
+        let len = std::mem::size_of::<T>() as u32;
+        let datamatch = if len > 0 {
+            Some(datamatch.into())
+        } else {
+            None
+        };
+        match self.find_ioevent(addr, datamatch, len) {
+            Some(index) => self.remove_ioevent(vm, index),
+            None => Err(errno::Error::new(libc::ENOENT).into()),
+        }
+    }
+    fn find_ioevent(
+        &self,
+        addr: &IoEventAddress,
+        datamatch: Option<u64>,
+        len: u32,
+    ) -> Option<usize> {
+        self.ioevents
+            .iter()
+            .position(|e| e.addr == *addr && e.datamatch == datamatch && e.len == len)
+    }
+    fn remove_ioevent(&mut self, vm: &VmFd, index: usize) -> Result<()> {
+        let ioevent = &self.ioevents[index];
+        if let IoEventAddress::Mmio(addr) = ioevent.addr {
+            vm.mmio_ioeventfd(
+                &ioevent.fd,
+                addr,
+                ioevent.datamatch.unwrap_or(0),
+                ioevent.len,
+                ioevent_flags(ioevent.datamatch) | 1 << mshv_ioeventfd_flag_nr_deassign,
+            )?;
+        }
+        self.ioevents.swap_remove(index);
+        Ok(())
+    }
+    /// See `VmFd::register_doorbell`. Registering the same doorbell with
+    /// the same eventfd again returns the existing handle.
+    pub fn register_doorbell(
+        &mut self,
+        vm: &VmFd,
+        gpa: u64,
+        value_match: u64,
+        flags: u32,
+        eventfd: &EventFd,
+    ) -> Result<DoorbellId> {
+        if let Some(id) = vm.find_doorbell(gpa, value_match, flags, eventfd) {
+            if self.doorbells.contains(&id) {
+                return Ok(id);
+            }
+        }
+        let id = vm.register_doorbell(gpa, value_match, flags, eventfd)?;
+        self.doorbells.insert(id);
+        Ok(id)
+    }
+    /// See `VmFd::unregister_doorbell`.
+    pub fn unregister_doorbell(&mut self, vm: &VmFd, id: DoorbellId) -> Result<()> {
+        vm.unregister_doorbell(id)?;
+        self.doorbells.remove(&id);
+        Ok(())
+    }
+    /// Registered irqfds as (GSI, eventfd).
+    pub fn irqfds(&self) -> impl Iterator<Item = (u32, &EventFd)> {
+        self.irqfds
+            .iter()
+            .flat_map(|(gsi, irqfds)| irqfds.iter().map(move |irqfd| (*gsi, &irqfd.fd)))
+    }
+    /// Registered ioeventfds as (address, datamatch, eventfd).
+    pub fn ioevents(&self) -> impl Iterator<Item = (IoEventAddress, Option<u64>, &EventFd)> {
+        self.ioevents
+            .iter()
+            .map(|ioevent| (ioevent.addr, ioevent.datamatch, &ioevent.fd))
+    }
+    /// Handles of the registered doorbells, see `VmFd::doorbells` for
+    /// their GPA.
+    pub fn doorbells(&self) -> impl Iterator<Item = DoorbellId> + '_ {
+        self.doorbells.iter().copied()
+    }
+    /// Unregisters everything. Entries that fail to unregister are kept
+    /// and the first error is returned after trying all of them.
+    pub fn unregister_all(&mut self, vm: &VmFd) -> Result<()> {
+        let mut result = Ok(());
+        let gsis: Vec<u32> = self.irqfds.keys().copied().collect();
+        for gsi in gsis {
+            for index in (0..self.irqfds[&gsi].len()).rev() {
+                result = result.and(self.remove_irqfd(vm, gsi, index));
+            }
+        }
+        for index in (0..self.ioevents.len()).rev() {
+            result = result.and(self.remove_ioevent(vm, index));
+        }
+        let doorbells: Vec<DoorbellId> = self.doorbells.iter().copied().collect();
+        for id in doorbells {
+            result = result.and(self.unregister_doorbell(vm, id));
+        }
+        result
+    }
+}
+
+fn ioevent_flags(datamatch: Option<u64>) -> u32 {
+    match datamatch {
+        Some(_) => 1 << mshv_ioeventfd_flag_nr_datamatch,
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ioctls::system::Mshv;
+    use crate::ioctls::vm::NoDatamatch;
+    use std::mem;
+    use std::os::unix::io::FromRawFd;
+
+    #[test]
+    fn test_same_eventfd() {
+        let efd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let clone = efd.try_clone().unwrap();
+        assert!(same_eventfd(&efd, &efd) && same_eventfd(&efd, &clone));
+        // A new eventfd given the fd number of `efd` is a different one
+        let new = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let number = efd.as_raw_fd();
+        // SAFETY: both fds are valid. dup2 closes the eventfd `number`
+        // refers to, so `efd` must not close it again.
+        assert!(unsafe { libc::dup2(new.as_raw_fd(), number) } == number);
+        mem::forget(efd);
+        // SAFETY: `number` was duplicated above and is owned by nothing else
+        let other = unsafe { EventFd::from_raw_fd(number) };
+        assert!(!same_eventfd(&other, &clone));
+    }
+    #[test]
+    fn test_eventfd_registry() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let mut registry = EventFdRegistry::new();
+        let irqfd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let ioeventfd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let other = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let addr = IoEventAddress::Mmio(0x1000);
+
+        registry.register_irqfd(&vm, &irqfd, 30).unwrap();
+        registry.register_irqfd(&vm, &irqfd, 30).unwrap();
+        assert_eq!(registry.irqfds().count(), 1);
+        registry
+            .register_ioevent(&vm, &ioeventfd, &addr, 0x42u32)
+            .unwrap();
+        registry
+            .register_ioevent(&vm, &ioeventfd, &addr, NoDatamatch)
+            .unwrap();
+        assert!(registry
+            .register_ioevent(&vm, &other, &addr, 0x42u32)
+            .is_err());
+        assert_eq!(registry.ioevents().count(), 2);
+
+        registry.unregister_all(&vm).unwrap();
+        assert!(registry.is_empty());
+        assert!(registry.unregister_irqfd(&vm, &irqfd, 30).is_err());
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use crate::ioctls::vm::{VmFd, PAGE_ACCESS_STATES_BATCH_SIZE};
+use crate::ioctls::{MshvError, Result};
+use mshv_bindings::{hv_gpa_page_access_state, GpaAccessFlags};
+use std::cmp;
+use std::fmt;
+use std::ops::Range;
+
+/// Page access state bit reported by `GpaAccessRanges`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GpaAccessKind {
+    /// Pages read or written since the accessed bit was last cleared
+    Accessed,
+    /// Pages written since the dirty bit was last cleared
+    Dirty,
+}
+
+impl GpaAccessKind {
+    fn is_set(self, state: &hv_gpa_page_access_state) -> bool {
+        // SAFETY: access union fields
+        let bit = unsafe {
+            match self {
+                GpaAccessKind::Accessed => state.__bindgen_anon_1.accessed(),
+                GpaAccessKind::Dirty => state.__bindgen_anon_1.dirty(),
+            }
+        };
+        bit != 0
+    }
+}
+
+/// Start and end of the first run of `states` from `pos` with the `kind`
+/// bit set, both `states.len()` if there is none
+fn next_run(states: &[hv_gpa_page_access_state], pos: usize, kind: GpaAccessKind) -> Range<usize> {
+    let find = |from: usize, set: bool| {
+        states[from..]
+            .iter()
+            .position(|state| kind.is_set(state) == set)
+            .map_or(states.len(), |i| from + i)
+    };
+    let start = find(pos, true);
+    start..find(start, false)
+}
+
+/// Iterator over the runs of accessed or dirty pages of a GPA range, as
+/// page frame number ranges, created by `VmFd::gpa_access_ranges`.
+///
+/// The access states are fetched lazily, in batches of up to
+/// `PAGE_ACCESS_STATES_BATCH_SIZE` pages, and the flags apply to each batch
+/// as it is fetched: with a clear flag, pages the iterator has not reached
+/// yet keep their bits until it does. Runs spanning batches are merged. A
+/// failed fetch is yielded once, after the run found up to the failed
+/// batch, and ends the iteration.
+pub struct GpaAccessRanges<'a> {
+    vm: &'a VmFd,
+    kind: GpaAccessKind,
+    flags: GpaAccessFlags,
+    // Next page to fetch and end of the walk
+    next_pfn: u64,
+    end_pfn: u64,
+    // Fetched batch, starting at batch_pfn, scanned up to pos
+    states: Vec<hv_gpa_page_access_state>,
+    batch_pfn: u64,
+    pos: usize,
+    // Run reaching the end of the batch, which may go on in the next one
+    pending: Option<Range<u64>>,
+    // Failed fetch, yielded after pending
+    error: Option<MshvError>,
+}
+
+impl<'a> fmt::Debug for GpaAccessRanges<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GpaAccessRanges")
+            .field("kind", &self.kind)
+            .field("flags", &self.flags)
+            .field("next_pfn", &self.next_pfn)
+            .field("end_pfn", &self.end_pfn)
+            .finish()
+    }
+}
+
+impl<'a> GpaAccessRanges<'a> {
+    pub(crate) fn new(
+        vm: &'a VmFd,
+        base_pfn: u64,
+        nr_pfns: u64,
+        flags: GpaAccessFlags,
+        kind: GpaAccessKind,
+    ) -> Self {
+        GpaAccessRanges {
+            vm,
+            kind,
+            flags,
+            next_pfn: base_pfn,
+            end_pfn: base_pfn.saturating_add(nr_pfns),
+            states: Vec::new(),
+            batch_pfn: base_pfn,
+            pos: 0,
+            pending: None,
+            error: None,
+        }
+    }
+    fn fetch(&mut self) -> Result<()> {
+        let count = cmp::min(
+            self.end_pfn - self.next_pfn,
+            PAGE_ACCESS_STATES_BATCH_SIZE as u64,
+        ) as u32;
+        self.states = self
+            .vm
+            .gpa_access_states(self.next_pfn, count, self.flags)?;
+        self.batch_pfn = self.next_pfn;
+        self.pos = 0;
+        // The driver may return fewer states than asked for
+        self.next_pfn += self.states.len() as u64;
+        if self.states.is_empty() {
+            self.next_pfn = self.end_pfn;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for GpaAccessRanges<'a> {
+    type Item = Result<Range<u64>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        loop {
+            if self.pos == self.states.len() {
+                if self.next_pfn == self.end_pfn {
+                    return self.pending.take().map(Ok);
+                }
+                if let Err(e) = self.fetch() {
+                    self.next_pfn = self.end_pfn;
+                    self.states.clear();
+                    self.pos = 0;
+                    // The pages of the pending run had their flags applied
+                    // already, yield it before the error
+                    if let Some(pending) = self.pending.take() {
+                        self.error = Some(e);
+                        return Some(Ok(pending));
+                    }
+                    return Some(Err(e));
+                }
+                continue;
+            }
+            let run = next_run(&self.states, self.pos, self.kind);
+            self.pos = run.end;
+            let run = self.batch_pfn + run.start as u64..self.batch_pfn + run.end as u64;
+            let pending = match self.pending.take() {
+                Some(pending) if pending.end == run.start => pending.start..run.end,
+                Some(pending) => {
+                    // The pending run ended at the batch boundary
+                    if !run.is_empty() {
+                        self.pending = Some(run);
+                    }
+                    return Some(Ok(pending));
+                }
+                None if run.is_empty() => continue,
+                None => run,
+            };
+            if self.pos == self.states.len() {
+                self.pending = Some(pending);
+            } else {
+                return Some(Ok(pending));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    use crate::ioctls::test_utils::TestVm;
+    use crate::ioctls::vm::new_vmfd;
+    use crate::MshvFeature;
+    use std::fs::File;
+
+    fn states(bits: &[u8]) -> Vec<hv_gpa_page_access_state> {
+        bits.iter()
+            .map(|&bits| hv_gpa_page_access_state { as_uint8: bits })
+            .collect()
+    }
+
+    #[test]
+    fn test_next_run() {
+        // Bit 0 is accessed, bit 1 dirty
+        let states = states(&[0, 3, 3, 1, 0, 2]);
+        assert!(next_run(&states, 0, GpaAccessKind::Accessed) == (1..4));
+        assert!(next_run(&states, 0, GpaAccessKind::Dirty) == (1..3));
+        assert!(next_run(&states, 3, GpaAccessKind::Dirty) == (5..6));
+        assert!(next_run(&states, 4, GpaAccessKind::Accessed) == (6..6));
+        assert!(next_run(&states, 6, GpaAccessKind::Dirty) == (6..6));
+    }
+
+    #[test]
+    fn test_gpa_access_ranges_error() {
+        let vm = new_vmfd(File::open("/dev/null").unwrap());
+        let mut ranges =
+            vm.gpa_access_ranges(0, 0x10, GpaAccessFlags::empty(), GpaAccessKind::Dirty);
+        assert!(
+            ranges.next().unwrap().unwrap_err()
+                == MshvError::MissingFeature(MshvFeature::GpaAccessStates)
+        );
+        assert!(ranges.next().is_none());
+        assert!(vm
+            .gpa_access_ranges(0, 0, GpaAccessFlags::empty(), GpaAccessKind::Dirty)
+            .next()
+            .is_none());
+
+        // A run reaching the end of a batch comes out before the error of
+        // the next fetch
+        let mut ranges =
+            GpaAccessRanges::new(&vm, 0, 0x10, GpaAccessFlags::empty(), GpaAccessKind::Dirty);
+        ranges.states = states(&[2, 0, 2, 2]);
+        ranges.next_pfn = 4;
+        assert!(ranges.next().unwrap().unwrap() == (0..1));
+        assert!(ranges.next().unwrap().unwrap() == (2..4));
+        assert!(
+            ranges.next().unwrap().unwrap_err()
+                == MshvError::MissingFeature(MshvFeature::GpaAccessStates)
+        );
+        assert!(ranges.next().is_none());
+    }
+
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    #[test]
+    fn test_gpa_access_ranges() {
+        // More pages than one batch
+        let nr_pfns = PAGE_ACCESS_STATES_BATCH_SIZE as u64 + 0x100;
+        let mem_size = (nr_pfns << mshv_bindings::HV_HYP_PAGE_SHIFT) as usize;
+        let test = TestVm::new(mem_size).unwrap();
+        let vm = &test.vm;
+        vm.enable_dirty_page_tracking().unwrap();
+        // Set the dirty bit of every page, then clear it while walking
+        let all: Vec<_> = vm
+            .gpa_access_ranges(0, nr_pfns, GpaAccessFlags::SET_DIRTY, GpaAccessKind::Dirty)
+            .collect::<Result<_>>()
+            .unwrap();
+        assert!(all.len() <= 1);
+        let all: Vec<_> = vm
+            .gpa_access_ranges(
+                0,
+                nr_pfns,
+                GpaAccessFlags::CLEAR_DIRTY,
+                GpaAccessKind::Dirty,
+            )
+            .collect::<Result<_>>()
+            .unwrap();
+        assert!(all.len() == 1 && all[0] == (0..nr_pfns));
+        assert!(vm
+            .gpa_access_ranges(0, nr_pfns, GpaAccessFlags::empty(), GpaAccessKind::Dirty)
+            .next()
+            .is_none());
+        vm.get_dirty_log(0, mem_size, GpaAccessFlags::SET_DIRTY)
+            .unwrap();
+        vm.disable_dirty_page_tracking().unwrap();
+    }
+}
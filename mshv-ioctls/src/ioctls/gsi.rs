@@ -0,0 +1,159 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use crate::ioctls::vm::VmFd;
+use crate::ioctls::{MshvError, Result};
+use mshv_bindings::*;
+use std::collections::{BTreeMap, BTreeSet};
+use vmm_sys_util::errno;
+
+/// Number of IOAPIC pins, the GSIs an x86 VMM usually reserves for legacy
+/// interrupts.
+pub const IOAPIC_NUM_PINS: u32 = 24;
+
+/// Hands out GSIs and keeps the MSI route of each of them.
+///
+/// GSIs below `legacy` are reserved for the interrupt controller pins and
+/// never allocated, though they can be routed. The others are allocated
+/// lowest first, freed ones being reused. Routes are only sent to the
+/// hypervisor by `commit`, which replaces the whole routing table.
+#[derive(Debug)]
+pub struct GsiAllocator {
+    legacy: u32,
+    count: u32,
+    // Lowest GSI never allocated
+    next: u32,
+    freed: BTreeSet<u32>,
+    routes: BTreeMap<u32, mshv_msi_routing_entry>,
+}
+
+impl GsiAllocator {
+    /// Creates an allocator for GSIs `0..count`, reserving `0..legacy`.
+    pub fn new(legacy: u32, count: u32) -> Result<Self> {
+        if count as usize > MSHV_MAX_MSI_ROUTES {
+            return Err(MshvError::InvalidArgument { field: "count" });
+        }
+        if legacy > count {
+            return Err(MshvError::InvalidArgument { field: "legacy" });
+        }
+        Ok(GsiAllocator {
+            legacy,
+            count,
+            next: legacy,
+            freed: BTreeSet::new(),
+            routes: BTreeMap::new(),
+        })
+    }
+    /// Allocates a GSI. Fails with ENOSPC when all are in use.
+    pub fn allocate(&mut self) -> Result<u32> {
+        if let Some(gsi) = self.freed.pop_first() {
+            return Ok(gsi);
+        }
+        if self.next == self.count {
+            return Err(errno::Error::new(libc::ENOSPC).into());
+        }
+        self.next += 1;
+        Ok(self.next - 1)
+    }
+    /// Number of GSIs reserved for the interrupt controller pins.
+    pub fn legacy(&self) -> u32 {
+        self.legacy
+    }
+    fn is_allocated(&self, gsi: u32) -> bool {
+        (self.legacy..self.next).contains(&gsi) && !self.freed.contains(&gsi)
+    }
+    /// Frees `gsi` and drops its route.
+    pub fn free(&mut self, gsi: u32) -> Result<()> {
+        if !self.is_allocated(gsi) {
+            return Err(MshvError::InvalidArgument { field: "gsi" });
+        }
+        self.routes.remove(&gsi);
+        self.freed.insert(gsi);
+        Ok(())
+    }
+    /// Routes `gsi`, legacy or allocated, to the MSI `address` and `data`.
+    pub fn set_route(&mut self, gsi: u32, address: u64, data: u32) -> Result<()> {
+        if gsi >= self.legacy && !self.is_allocated(gsi) {
+            return Err(MshvError::InvalidArgument { field: "gsi" });
+        }
+        let entry = mshv_msi_routing_entry {
+            gsi,
+            address_lo: address as u32,
+            address_hi: (address >> 32) as u32,
+            data,
+        };
+        self.routes.insert(gsi, entry);
+        Ok(())
+    }
+    /// Routes `gsi` to the LPI that `event_id` of `device_id` is mapped to
+    /// in `its`. Fails with ENOENT if there is no such mapping.
+    #[cfg(target_arch = "aarch64")]
+    pub fn set_its_route(
+        &mut self,
+        gsi: u32,
+        its: &aarch64::ItsTranslationTable,
+        device_id: u32,
+        event_id: u32,
+    ) -> Result<()> {
+        let (address, data) = its
+            .msi_route(device_id, event_id)
+            .ok_or(errno::Error::new(libc::ENOENT))?;
+        self.set_route(gsi, address, data)
+    }
+    /// Removes the route of `gsi`, if any.
+    pub fn clear_route(&mut self, gsi: u32) {
+        self.routes.remove(&gsi);
+    }
+    /// Routing table holding the routes set so far, in GSI order.
+    pub fn routing(&self) -> MsiRouting {
+        let entries: Vec<mshv_msi_routing_entry> = self.routes.values().copied().collect();
+        // Cannot fail, there are at most MSHV_MAX_MSI_ROUTES GSIs
+        MsiRouting::from_entries(&entries).unwrap()
+    }
+    /// Replaces the partition's routing table with `routing()`.
+    pub fn commit(&self, vm: &VmFd) -> Result<()> {
+        vm.set_msi_routing(self.routing().as_fam_struct_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ioctls::system::Mshv;
+
+    #[test]
+    fn test_gsi_allocator() {
+        let mut gsis = GsiAllocator::new(IOAPIC_NUM_PINS, 26).unwrap();
+        assert_eq!(gsis.allocate().unwrap(), 24);
+        assert_eq!(gsis.allocate().unwrap(), 25);
+        assert!(gsis.allocate().is_err());
+        gsis.free(24).unwrap();
+        assert!(gsis.free(24).is_err());
+        assert!(gsis.free(4).is_err());
+        assert_eq!(gsis.allocate().unwrap(), 24);
+
+        gsis.set_route(4, 0xfee0_0000, 0x30).unwrap();
+        gsis.set_route(25, 0x1_fee0_1000, 0x41).unwrap();
+        assert!(gsis.set_route(30, 0xfee0_0000, 0x30).is_err());
+        let routing = gsis.routing();
+        assert_eq!(routing.as_slice().len(), 2);
+        let entry = routing.as_slice()[1];
+        assert!(entry.gsi == 25 && entry.address_lo == 0xfee0_1000 && entry.address_hi == 1);
+        gsis.free(25).unwrap();
+        assert_eq!(gsis.routing().as_slice().len(), 1);
+
+        assert!(GsiAllocator::new(30, 26).is_err());
+        assert!(GsiAllocator::new(0, MSHV_MAX_MSI_ROUTES as u32 + 1).is_err());
+    }
+
+    #[test]
+    fn test_gsi_allocator_commit() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let mut gsis = GsiAllocator::new(IOAPIC_NUM_PINS, 64).unwrap();
+        let gsi = gsis.allocate().unwrap();
+        gsis.set_route(gsi, 0xfee0_0000, 0x30).unwrap();
+        gsis.commit(&vm).unwrap();
+    }
+}
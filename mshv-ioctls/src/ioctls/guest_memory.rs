@@ -0,0 +1,171 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use crate::ioctls::vm::VmFd;
+use crate::ioctls::Result;
+use mshv_bindings::*;
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fmt;
+use vm_memory::bitmap::Bitmap;
+use vm_memory::{GuestMemoryBackend, GuestMemoryMmap, GuestMemoryRegion};
+
+/// Called with the GPA and size of every region unmapped by
+/// `GuestMemoryRegistry::update` or `GuestMemoryRegistry::unregister`.
+pub type InvalidateCallback = Box<dyn FnMut(u64, u64) + Send>;
+
+/// Keeps the partition's memory regions in sync with a vm-memory
+/// `GuestMemoryMmap`.
+///
+/// Every region of the guest memory is mapped as one memory region,
+/// readable and writable as the host mapping allows and always executable.
+/// Like `MemoryHotplug`, the registry only keeps track of what it mapped;
+/// the `VmFd` is passed to each call and nothing is unmapped on drop.
+#[derive(Default)]
+pub struct GuestMemoryRegistry {
+    // Mapped regions, keyed by GPA
+    regions: BTreeMap<u64, mshv_user_mem_region>,
+    callbacks: Vec<InvalidateCallback>,
+}
+
+impl fmt::Debug for GuestMemoryRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GuestMemoryRegistry")
+            .field("regions", &self.regions)
+            .field("callbacks", &self.callbacks.len())
+            .finish()
+    }
+}
+
+fn memory_regions<B: Bitmap>(memory: &GuestMemoryMmap<B>) -> BTreeMap<u64, mshv_user_mem_region> {
+    memory
+        .iter()
+        .map(|region| {
+            let gpa = region.start_addr().0;
+            let mut flags = HV_MAP_GPA_EXECUTABLE;
+            if region.prot() & libc::PROT_READ != 0 {
+                flags |= HV_MAP_GPA_READABLE;
+            }
+            if region.prot() & libc::PROT_WRITE != 0 {
+                flags |= HV_MAP_GPA_WRITABLE;
+            }
+            let mshv_region = mshv_user_mem_region {
+                size: region.len(),
+                guest_pfn: gpa >> HV_HYP_PAGE_SHIFT,
+                userspace_addr: region.as_ptr() as u64,
+                flags,
+            };
+            (gpa, mshv_region)
+        })
+        .collect()
+}
+
+impl GuestMemoryRegistry {
+    /// Registers `callback`, run after each region is unmapped so that
+    /// cached translations of its GPAs can be dropped.
+    pub fn on_invalidate<F: FnMut(u64, u64) + Send + 'static>(&mut self, callback: F) {
+        self.callbacks.push(Box::new(callback));
+    }
+    /// Memory regions currently mapped, in GPA order.
+    pub fn regions(&self) -> impl Iterator<Item = &mshv_user_mem_region> {
+        self.regions.values()
+    }
+    fn unmap(&mut self, vm: &VmFd, gpa: u64) -> Result<()> {
+        let region = self.regions[&gpa];
+        vm.unmap_user_memory(region)?;
+        self.regions.remove(&gpa);
+        for callback in self.callbacks.iter_mut() {
+            callback(gpa, region.size);
+        }
+        Ok(())
+    }
+    /// Brings the mapped regions in line with `memory`: regions that are
+    /// gone or changed are unmapped, new ones mapped. Stops at the first
+    /// failure, the registry then reflects what is actually mapped.
+    pub fn update<B: Bitmap>(&mut self, vm: &VmFd, memory: &GuestMemoryMmap<B>) -> Result<()> {
+        let wanted = memory_regions(memory);
+        let stale: Vec<u64> = self
+            .regions
+            .iter()
+            .filter(|(gpa, region)| wanted.get(gpa) != Some(region))
+            .map(|(gpa, _)| *gpa)
+            .collect();
+        for gpa in stale {
+            self.unmap(vm, gpa)?;
+        }
+        for (gpa, region) in wanted {
+            if let Entry::Vacant(entry) = self.regions.entry(gpa) {
+                vm.map_user_memory(region)?;
+                entry.insert(region);
+            }
+        }
+        Ok(())
+    }
+    /// Unmaps every region. Stops at the first region that fails.
+    pub fn unregister(&mut self, vm: &VmFd) -> Result<()> {
+        while let Some(&gpa) = self.regions.keys().next() {
+            self.unmap(vm, gpa)?;
+        }
+        Ok(())
+    }
+}
+
+impl VmFd {
+    /// Maps every region of `memory`, see `GuestMemoryRegistry`.
+    pub fn register_guest_memory<B: Bitmap>(
+        &self,
+        memory: &GuestMemoryMmap<B>,
+    ) -> Result<GuestMemoryRegistry> {
+        let mut registry = GuestMemoryRegistry::default();
+        registry.update(self, memory)?;
+        Ok(registry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ioctls::system::Mshv;
+    use std::sync::{Arc, Mutex};
+    use vm_memory::GuestAddress;
+
+    #[test]
+    fn test_memory_regions() {
+        let memory: GuestMemoryMmap = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x10000), 0x2000),
+        ])
+        .unwrap();
+        let regions = memory_regions(&memory);
+        assert_eq!(regions.len(), 2);
+        let region = regions[&0x10000];
+        assert!(region.guest_pfn == 0x10 && region.size == 0x2000);
+        assert!(region.flags == HV_MAP_GPA_READABLE | HV_MAP_GPA_WRITABLE | HV_MAP_GPA_EXECUTABLE);
+    }
+
+    #[test]
+    fn test_register_guest_memory() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let memory: GuestMemoryMmap = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x10000), 0x1000),
+        ])
+        .unwrap();
+        let mut registry = vm.register_guest_memory(&memory).unwrap();
+        let invalidated = Arc::new(Mutex::new(Vec::new()));
+        let log = invalidated.clone();
+        registry.on_invalidate(move |gpa, size| log.lock().unwrap().push((gpa, size)));
+
+        let memory: GuestMemoryMmap =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0x10000), 0x2000)]).unwrap();
+        registry.update(&vm, &memory).unwrap();
+        assert_eq!(registry.regions().count(), 1);
+        assert!(invalidated.lock().unwrap().contains(&(0, 0x1000)));
+        assert!(invalidated.lock().unwrap().contains(&(0x10000, 0x1000)));
+
+        registry.unregister(&vm).unwrap();
+        assert_eq!(registry.regions().count(), 0);
+    }
+}
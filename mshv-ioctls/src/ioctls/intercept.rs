@@ -0,0 +1,234 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use mshv_bindings::*;
+use std::collections::{BTreeMap, BTreeSet};
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+use std::ops::RangeInclusive;
+
+/// Builder of the classes of guest events that exit to user space, to be
+/// installed at partition setup with `VmFd::set_intercept_policy`.
+///
+/// Intercepts on the same event are merged, their access masks combined.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InterceptPolicy {
+    // (intercept type, parameter) -> HV_INTERCEPT_ACCESS_MASK_*
+    intercepts: BTreeMap<(hv_intercept_type, u64), u32>,
+    hypercalls: BTreeSet<u16>,
+}
+
+impl InterceptPolicy {
+    /// Creates a policy without intercepts
+    pub fn new() -> InterceptPolicy {
+        InterceptPolicy::default()
+    }
+
+    fn add(mut self, intercept_type: hv_intercept_type, parameter: u64, access: u32) -> Self {
+        *self
+            .intercepts
+            .entry((intercept_type, parameter))
+            .or_insert(HV_INTERCEPT_ACCESS_MASK_NONE) |= access;
+        self
+    }
+
+    /// Makes CPUID `leaf` exit, see `VcpuFd::register_intercept_result_cpuid`
+    /// for results that do not need to
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn intercept_cpuid(self, leaf: u32) -> InterceptPolicy {
+        self.add(
+            hv_intercept_type_HV_INTERCEPT_TYPE_X64_CPUID,
+            leaf as u64,
+            HV_INTERCEPT_ACCESS_MASK_EXECUTE,
+        )
+    }
+
+    /// Makes guest accesses matching `access` to the MSRs in `msrs` exit
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn intercept_msrs(self, msrs: RangeInclusive<u32>, access: u32) -> InterceptPolicy {
+        msrs.fold(self, |policy, msr| {
+            policy.add(
+                hv_intercept_type_HV_INTERCEPT_TYPE_X64_MSR_INDEX,
+                msr as u64,
+                access,
+            )
+        })
+    }
+
+    /// Makes guest accesses matching `access` to the I/O ports in `ports`
+    /// exit
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn intercept_io_ports(self, ports: RangeInclusive<u16>, access: u32) -> InterceptPolicy {
+        ports.fold(self, |policy, port| {
+            policy.add(
+                hv_intercept_type_HV_INTERCEPT_TYPE_X64_IO_PORT,
+                port as u64,
+                access,
+            )
+        })
+    }
+
+    /// Makes guest exceptions with `vector` exit
+    pub fn intercept_exception(self, vector: u16) -> InterceptPolicy {
+        self.add(
+            hv_intercept_type_HV_INTERCEPT_TYPE_EXCEPTION,
+            vector as u64,
+            HV_INTERCEPT_ACCESS_MASK_EXECUTE,
+        )
+    }
+
+    /// Forwards the hypercalls in `codes`, see
+    /// `VmFd::install_hypercall_intercept`
+    pub fn intercept_hypercalls(mut self, codes: &[u16]) -> InterceptPolicy {
+        self.hypercalls.extend(codes);
+        self
+    }
+
+    /// Makes writes to HV_X64_MSR_CRASH_CTL exit, see
+    /// `VmFd::install_guest_crash_intercept`
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn intercept_guest_crashes(self) -> InterceptPolicy {
+        self.intercept_msrs(
+            HV_X64_MSR_CRASH_CTL..=HV_X64_MSR_CRASH_CTL,
+            HV_INTERCEPT_ACCESS_MASK_WRITE,
+        )
+    }
+
+    /// Makes the guest writes that reset the partition exit, see
+    /// `VmFd::install_reset_intercepts`
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn intercept_resets(self) -> InterceptPolicy {
+        self.intercept_io_ports(
+            I8042_COMMAND_PORT..=I8042_COMMAND_PORT,
+            HV_INTERCEPT_ACCESS_MASK_WRITE,
+        )
+        .intercept_io_ports(
+            RESET_CONTROL_PORT..=RESET_CONTROL_PORT,
+            HV_INTERCEPT_ACCESS_MASK_WRITE,
+        )
+        .intercept_msrs(
+            HV_X64_MSR_RESET..=HV_X64_MSR_RESET,
+            HV_INTERCEPT_ACCESS_MASK_WRITE,
+        )
+    }
+
+    /// Makes guest reads and writes of MCG_STATUS and of the MSRs of the
+    /// first `banks` MCA banks exit, so that the VMM answers them with
+    /// `MceInfo::msr_value` after `VcpuFd::inject_machine_check`
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn intercept_machine_checks(self, banks: u8) -> InterceptPolicy {
+        let access = HV_INTERCEPT_ACCESS_MASK_READ | HV_INTERCEPT_ACCESS_MASK_WRITE;
+        let policy = self.intercept_msrs(IA32_MSR_MCG_STATUS..=IA32_MSR_MCG_STATUS, access);
+        match banks {
+            0 => policy,
+            banks => policy.intercept_msrs(
+                MceInfo::bank_msr(0)..=MceInfo::bank_msr(banks - 1) + 3,
+                access,
+            ),
+        }
+    }
+
+    /// Makes the ways a guest stops running exit, see
+    /// `VmFd::install_shutdown_intercepts`
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn intercept_shutdowns(self) -> InterceptPolicy {
+        self.intercept_hypercalls(&[HVCALL_ENTER_SLEEP_STATE as u16])
+            .intercept_guest_crashes()
+    }
+
+    /// Returns true if the policy installs nothing
+    pub fn is_empty(&self) -> bool {
+        self.intercepts.is_empty() && self.hypercalls.is_empty()
+    }
+
+    /// Arguments of the intercepts to install, hypercalls excepted
+    pub(crate) fn intercepts(&self) -> Vec<mshv_install_intercept> {
+        self.intercepts
+            .iter()
+            .map(
+                |((intercept_type, parameter), access)| mshv_install_intercept {
+                    access_type_mask: *access,
+                    intercept_type: *intercept_type,
+                    intercept_parameter: hv_intercept_parameters {
+                        as_uint64: *parameter,
+                    },
+                },
+            )
+            .collect()
+    }
+
+    /// Hypercalls to forward
+    pub(crate) fn hypercalls(&self) -> Vec<u16> {
+        self.hypercalls.iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intercept_policy() {
+        assert!(InterceptPolicy::new().is_empty());
+        let policy = InterceptPolicy::new()
+            .intercept_exception(6)
+            .intercept_hypercalls(&[HV_EXT_CALL_QUERY_CAPABILITIES]);
+        assert!(!policy.is_empty());
+        let intercepts = policy.intercepts();
+        assert!(intercepts.len() == 1);
+        assert!(intercepts[0].intercept_type == hv_intercept_type_HV_INTERCEPT_TYPE_EXCEPTION);
+        // SAFETY: all fields of the union are integers
+        assert!(unsafe { intercepts[0].intercept_parameter.exception_vector } == 6);
+        assert!(policy.hypercalls() == vec![HV_EXT_CALL_QUERY_CAPABILITIES]);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_intercept_policy_merge() {
+        let policy = InterceptPolicy::new()
+            .intercept_io_ports(0x3f8..=0x3ff, HV_INTERCEPT_ACCESS_MASK_READ)
+            .intercept_io_ports(0x3f8..=0x3f8, HV_INTERCEPT_ACCESS_MASK_WRITE)
+            .intercept_cpuid(0x40000100)
+            .intercept_shutdowns()
+            .intercept_resets();
+        let intercepts = policy.intercepts();
+        // Serial ports, CPUID leaf, reset ports, reset and crash MSRs
+        assert!(intercepts.len() == 8 + 1 + 2 + 2);
+        let com1 = intercepts
+            .iter()
+            .find(|i| {
+                i.intercept_type == hv_intercept_type_HV_INTERCEPT_TYPE_X64_IO_PORT
+                    // SAFETY: all fields of the union are integers
+                    && unsafe { i.intercept_parameter.io_port } == 0x3f8
+            })
+            .unwrap();
+        assert!(
+            com1.access_type_mask == HV_INTERCEPT_ACCESS_MASK_READ | HV_INTERCEPT_ACCESS_MASK_WRITE
+        );
+        assert!(policy.hypercalls() == vec![HVCALL_ENTER_SLEEP_STATE as u16]);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_intercept_machine_checks() {
+        let intercepts = InterceptPolicy::new()
+            .intercept_machine_checks(2)
+            .intercepts();
+        // MCG_STATUS, then CTL, STATUS, ADDR and MISC of banks 0 and 1
+        assert!(intercepts.len() == 1 + 2 * 4);
+        let msrs: Vec<u32> = intercepts
+            .iter()
+            // SAFETY: all fields of the union are integers
+            .map(|i| unsafe { i.intercept_parameter.msr_index })
+            .collect();
+        assert!(msrs[0] == IA32_MSR_MCG_STATUS);
+        assert!(msrs[1] == 0x400 && msrs[8] == 0x407);
+        assert!(
+            InterceptPolicy::new()
+                .intercept_machine_checks(0)
+                .intercepts()
+                .len()
+                == 1
+        );
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use crate::ioctls::vm::VmFd;
+use crate::ioctls::{MshvError, Result};
+use mshv_bindings::*;
+use std::collections::BTreeMap;
+use vmm_sys_util::errno;
+
+/// Tracks a hotpluggable GPA window split into fixed-size blocks.
+///
+/// Blocks are mapped with `plug` and unmapped with `unplug`, one memory
+/// region per block, which is the granularity virtio-mem and ACPI memory
+/// hotplug work with. The manager only keeps track of what it mapped; the
+/// `VmFd` is passed to each call.
+#[derive(Debug)]
+pub struct MemoryHotplug {
+    base_gpa: u64,
+    size: u64,
+    block_size: u64,
+    // Plugged blocks, keyed by GPA
+    blocks: BTreeMap<u64, mshv_user_mem_region>,
+}
+
+impl MemoryHotplug {
+    fn new(base_gpa: u64, size: u64, block_size: u64) -> Result<Self> {
+        if block_size == 0 || !block_size.is_multiple_of(1 << HV_HYP_PAGE_SHIFT) {
+            return Err(MshvError::InvalidArgument {
+                field: "block_size",
+            });
+        }
+        if !base_gpa.is_multiple_of(block_size) {
+            return Err(MshvError::InvalidArgument { field: "base_gpa" });
+        }
+        if size == 0 || !size.is_multiple_of(block_size) || base_gpa.checked_add(size).is_none() {
+            return Err(MshvError::InvalidArgument { field: "size" });
+        }
+        Ok(MemoryHotplug {
+            base_gpa,
+            size,
+            block_size,
+            blocks: BTreeMap::new(),
+        })
+    }
+    fn check_block(&self, gpa: u64) -> Result<()> {
+        if gpa < self.base_gpa
+            || gpa - self.base_gpa >= self.size
+            || !(gpa - self.base_gpa).is_multiple_of(self.block_size)
+        {
+            return Err(MshvError::InvalidArgument { field: "gpa" });
+        }
+        Ok(())
+    }
+    /// Start of the hotpluggable window.
+    pub fn base_gpa(&self) -> u64 {
+        self.base_gpa
+    }
+    /// Size of the hotpluggable window in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+    /// Size of a block in bytes.
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+    /// Returns true if the block at `gpa` is plugged.
+    pub fn is_plugged(&self, gpa: u64) -> bool {
+        self.blocks.contains_key(&gpa)
+    }
+    /// Number of bytes currently plugged.
+    pub fn plugged_size(&self) -> u64 {
+        self.blocks.len() as u64 * self.block_size
+    }
+    /// Memory regions of the plugged blocks, in GPA order.
+    pub fn regions(&self) -> impl Iterator<Item = &mshv_user_mem_region> {
+        self.blocks.values()
+    }
+    /// Maps the block at `gpa` to `userspace_addr`, which must point to
+    /// `block_size` bytes. `flags` are the HV_MAP_GPA_* permissions.
+    ///
+    /// Fails with EEXIST if the block is already plugged.
+    pub fn plug(&mut self, vm: &VmFd, gpa: u64, userspace_addr: u64, flags: u32) -> Result<()> {
+        self.check_block(gpa)?;
+        if self.is_plugged(gpa) {
+            return Err(errno::Error::new(libc::EEXIST).into());
+        }
+        let region = mshv_user_mem_region {
+            size: self.block_size,
+            guest_pfn: gpa >> HV_HYP_PAGE_SHIFT,
+            userspace_addr,
+            flags,
+        };
+        vm.map_user_memory(region)?;
+        self.blocks.insert(gpa, region);
+        Ok(())
+    }
+    /// Unmaps the block at `gpa` and returns the region it was mapped with,
+    /// so the caller can release the backing memory.
+    ///
+    /// Fails with ENOENT if the block is not plugged.
+    pub fn unplug(&mut self, vm: &VmFd, gpa: u64) -> Result<mshv_user_mem_region> {
+        self.check_block(gpa)?;
+        let region = match self.blocks.get(&gpa) {
+            Some(region) => *region,
+            None => return Err(errno::Error::new(libc::ENOENT).into()),
+        };
+        vm.unmap_user_memory(region)?;
+        self.blocks.remove(&gpa);
+        Ok(region)
+    }
+    /// Unmaps every plugged block. Stops at the first block that fails.
+    pub fn unplug_all(&mut self, vm: &VmFd) -> Result<()> {
+        while let Some((&gpa, _)) = self.blocks.iter().next() {
+            self.unplug(vm, gpa)?;
+        }
+        Ok(())
+    }
+    /// Returns the dirty bitmap of each plugged block along with its GPA,
+    /// see `VmFd::get_dirty_log` for `flags`.
+    ///
+    /// Unplugged blocks are skipped, as the hypervisor fails the query for
+    /// GPAs that aren't mapped. Dirty page tracking must be enabled.
+    pub fn get_dirty_log(&self, vm: &VmFd, flags: GpaAccessFlags) -> Result<Vec<(u64, Vec<u64>)>> {
+        self.blocks
+            .values()
+            .map(|region| {
+                let bitmap = vm.get_dirty_log(region.guest_pfn, region.size as usize, flags)?;
+                Ok((region.guest_pfn << HV_HYP_PAGE_SHIFT, bitmap))
+            })
+            .collect()
+    }
+}
+
+impl VmFd {
+    /// Creates a `MemoryHotplug` manager for the window starting at
+    /// `base_gpa`, `size` bytes long and made of `block_size` byte blocks.
+    ///
+    /// `block_size` must be a multiple of the page size, `base_gpa` and
+    /// `size` multiples of `block_size`. No memory is mapped until blocks are
+    /// plugged.
+    pub fn memory_hotplug(
+        &self,
+        base_gpa: u64,
+        size: u64,
+        block_size: u64,
+    ) -> Result<MemoryHotplug> {
+        MemoryHotplug::new(base_gpa, size, block_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ioctls::system::Mshv;
+    use libc::c_void;
+
+    #[test]
+    fn test_memory_hotplug() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let block_size = 2 * 1024 * 1024;
+        let base_gpa = 0x1_0000_0000;
+        assert!(
+            vm.memory_hotplug(base_gpa + 0x1000, 4 * block_size, block_size)
+                .unwrap_err()
+                == MshvError::InvalidArgument { field: "base_gpa" }
+        );
+        let mut hotplug = vm
+            .memory_hotplug(base_gpa, 4 * block_size, block_size)
+            .unwrap();
+
+        // SAFETY: FFI call with valid arguments
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                block_size as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        } as u64;
+        let flags = HV_MAP_GPA_READABLE | HV_MAP_GPA_WRITABLE;
+        let gpa = base_gpa + block_size;
+        hotplug.plug(&vm, gpa, addr, flags).unwrap();
+        assert!(hotplug.is_plugged(gpa));
+        assert!(hotplug.plugged_size() == block_size);
+        assert!(
+            hotplug.plug(&vm, gpa, addr, flags).unwrap_err() == errno::Error::new(libc::EEXIST)
+        );
+        assert!(
+            hotplug.plug(&vm, gpa + 0x1000, addr, flags).unwrap_err()
+                == MshvError::InvalidArgument { field: "gpa" }
+        );
+
+        vm.enable_dirty_page_tracking().unwrap();
+        let log = hotplug
+            .get_dirty_log(&vm, GpaAccessFlags::CLEAR_DIRTY)
+            .unwrap();
+        assert!(log.len() == 1 && log[0].0 == gpa);
+        hotplug
+            .get_dirty_log(&vm, GpaAccessFlags::SET_DIRTY)
+            .unwrap();
+        vm.disable_dirty_page_tracking().unwrap();
+
+        let region = hotplug.unplug(&vm, gpa).unwrap();
+        assert!(region.userspace_addr == addr);
+        assert!(hotplug.unplug(&vm, gpa).unwrap_err() == errno::Error::new(libc::ENOENT));
+        assert!(hotplug.plugged_size() == 0);
+        // SAFETY: addr was mapped above and is no longer used by the guest
+        unsafe { libc::munmap(addr as *mut c_void, block_size as usize) };
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use std::sync::{Arc, RwLock};
+
+/// vCPU exits, labelled with the hv_message_type.
+pub const METRIC_VCPU_EXITS: &str = "mshv_vcpu_exits_total";
+/// Failed MSHV_RUN_VP calls, labelled with the errno.
+pub const METRIC_VCPU_RUN_ERRORS: &str = "mshv_vcpu_run_errors_total";
+/// Hypercalls rejected by the hypervisor, labelled with the call code and
+/// the status.
+pub const METRIC_HYPERCALL_FAILURES: &str = "mshv_hypercall_failures_total";
+/// Successful memory region maps and unmaps, labelled with the operation,
+/// 1 for map and 0 for unmap.
+pub const METRIC_MEMORY_OPERATIONS: &str = "mshv_memory_operations_total";
+/// Bytes of guest memory mapped by all partitions.
+pub const METRIC_MAPPED_MEMORY: &str = "mshv_mapped_memory_bytes";
+/// Halt exits whose eventfd, see `VcpuFd::set_halt_eventfd`, could not be
+/// signalled, labelled with the errno.
+pub const METRIC_HALT_EVENTFD_ERRORS: &str = "mshv_halt_eventfd_errors_total";
+/// Discarded guest memory whose backing pages could not be released,
+/// labelled with the errno of madvise.
+pub const METRIC_DISCARD_ERRORS: &str = "mshv_discard_errors_total";
+
+/// Receives the crate's metrics, to be forwarded to Prometheus,
+/// OpenTelemetry or similar. See the `METRIC_*` constants for the names.
+///
+/// Callbacks run inline on the thread doing the operation, including the
+/// vCPU run loop, so they should only update counters.
+pub trait Metrics: Send + Sync {
+    /// Adds `delta` to counter `name`.
+    fn counter(&self, name: &'static str, labels: &[(&'static str, u64)], delta: u64);
+    /// Adds `delta`, which may be negative, to gauge `name`.
+    fn gauge(&self, name: &'static str, labels: &[(&'static str, u64)], delta: i64);
+}
+
+static METRICS: RwLock<Option<Arc<dyn Metrics>>> = RwLock::new(None);
+
+/// Installs `metrics` as the process-wide sink, replacing any previous
+/// one. `None` turns reporting off, which is the default.
+pub fn set_metrics(metrics: Option<Arc<dyn Metrics>>) {
+    *METRICS.write().unwrap() = metrics;
+}
+
+pub(crate) fn counter(name: &'static str, labels: &[(&'static str, u64)], delta: u64) {
+    if let Some(metrics) = METRICS.read().unwrap().as_ref() {
+        metrics.counter(name, labels, delta);
+    }
+}
+
+pub(crate) fn gauge(name: &'static str, labels: &[(&'static str, u64)], delta: i64) {
+    if let Some(metrics) = METRICS.read().unwrap().as_ref() {
+        metrics.gauge(name, labels, delta);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    type Sample = (&'static str, Vec<(&'static str, u64)>, i64);
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<Sample>>);
+
+    impl Metrics for Recorder {
+        fn counter(&self, name: &'static str, labels: &[(&'static str, u64)], delta: u64) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((name, labels.to_vec(), delta as i64));
+        }
+        fn gauge(&self, name: &'static str, labels: &[(&'static str, u64)], delta: i64) {
+            self.0.lock().unwrap().push((name, labels.to_vec(), delta));
+        }
+    }
+
+    #[test]
+    fn test_set_metrics() {
+        // Other tests may report while the sink is installed, so only look
+        // for the entries this test adds
+        let recorder = Arc::new(Recorder::default());
+        set_metrics(Some(recorder.clone()));
+        counter(METRIC_HYPERCALL_FAILURES, &[("code", 0x8001)], 1);
+        gauge(METRIC_MAPPED_MEMORY, &[("test", 1)], -0x1000);
+        set_metrics(None);
+        counter(METRIC_HYPERCALL_FAILURES, &[("code", 0x8002)], 1);
+
+        let recorded = recorder.0.lock().unwrap();
+        assert!(recorded.contains(&(METRIC_HYPERCALL_FAILURES, vec![("code", 0x8001)], 1)));
+        assert!(recorded.contains(&(METRIC_MAPPED_MEMORY, vec![("test", 1)], -0x1000)));
+        assert!(!recorded.contains(&(METRIC_HYPERCALL_FAILURES, vec![("code", 0x8002)], 1)));
+    }
+}
@@ -0,0 +1,378 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use crate::ioctls::gpa_access::GpaAccessKind;
+use crate::ioctls::vcpu::VcpuFd;
+use crate::ioctls::vm::VmFd;
+use crate::ioctls::{MshvError, Result};
+use mshv_bindings::*;
+use std::cmp;
+use std::ops::Range;
+use std::ptr;
+
+/// Default most bytes passed to `ChunkWriter::write_chunk` at once
+pub const MIGRATION_CHUNK_SIZE: u64 = 0x10_0000;
+
+/// Destination of the guest memory sent by `MigrationSource`, usually a
+/// socket to the destination host.
+pub trait ChunkWriter {
+    /// Sends `data`, the guest memory at `gpa`. Later chunks of the same
+    /// GPA replace earlier ones.
+    fn write_chunk(&mut self, gpa: u64, data: &[u8]) -> Result<()>;
+}
+
+impl<F: FnMut(u64, &[u8]) -> Result<()>> ChunkWriter for F {
+    fn write_chunk(&mut self, gpa: u64, data: &[u8]) -> Result<()> {
+        self(gpa, data)
+    }
+}
+
+/// Copies the guest memory at `src` to `buf`. The guest may write it at the
+/// same time, so it is only read through volatile accesses, never through
+/// a reference; a torn copy is sent again as the write dirtied the page.
+///
+/// # Safety
+///
+/// `src` must point to `buf.len()` bytes of mapped memory.
+unsafe fn read_guest_memory(src: *const u8, buf: &mut [u8]) {
+    let mut i = 0;
+    while i < buf.len() {
+        let byte = src.add(i);
+        if byte as usize & 7 == 0 && buf.len() - i >= 8 {
+            let word = ptr::read_volatile(byte as *const u64);
+            buf[i..i + 8].copy_from_slice(&word.to_ne_bytes());
+            i += 8;
+        } else {
+            buf[i] = ptr::read_volatile(byte);
+            i += 1;
+        }
+    }
+}
+
+/// State sent after the last memory round, when the vCPUs are stopped
+#[derive(Debug, Clone)]
+pub struct MigrationState {
+    /// Memory rounds sent, including the final one
+    pub rounds: u32,
+    /// State of the vCPUs, saved with the partition time frozen
+    pub vm: VmSnapshot,
+    /// State of the VMM devices, as returned by the `save_devices` callback
+    /// of `MigrationSource::complete`
+    pub devices: Vec<u8>,
+}
+
+/// Iterative pre-copy live migration of a partition's memory.
+///
+/// `start` enables dirty page tracking. Each call to `iterate` is a round:
+/// the first one sends all the memory of the regions through a
+/// `ChunkWriter` while the guest keeps running, later ones only the pages
+/// the guest dirtied since the previous round. Once a round is small
+/// enough, the VMM stops the vCPUs and `complete` sends the pages dirtied
+/// during that round and returns the vCPU and device state to send last.
+/// `cancel` stops tracking if the migration is abandoned instead; the
+/// guest goes on running on the source.
+///
+/// The dirty bits are the page access states of `VmFd::gpa_access_ranges`.
+/// The source only keeps track of the regions and rounds; the `VmFd` is
+/// passed to each call.
+#[derive(Debug)]
+pub struct MigrationSource {
+    regions: Vec<mshv_user_mem_region>,
+    chunk_size: u64,
+    rounds: u32,
+    tracking: bool,
+}
+
+impl MigrationSource {
+    /// Creates a source sending the memory of `regions`, which must be
+    /// mapped in the partition.
+    ///
+    /// Fails with InvalidArgument if a region is empty or not a whole
+    /// number of pages.
+    ///
+    /// # Safety
+    ///
+    /// The memory at `userspace_addr` of each region is read while the
+    /// source is in use, so the caller must keep it mapped until the source
+    /// is dropped.
+    pub unsafe fn new(regions: Vec<mshv_user_mem_region>) -> Result<Self> {
+        if regions
+            .iter()
+            .any(|region| region.size == 0 || region.size & ((1 << HV_HYP_PAGE_SHIFT) - 1) != 0)
+        {
+            return Err(MshvError::InvalidArgument { field: "regions" });
+        }
+        Ok(MigrationSource {
+            regions,
+            chunk_size: MIGRATION_CHUNK_SIZE,
+            rounds: 0,
+            tracking: false,
+        })
+    }
+    /// Sets the most bytes passed to `ChunkWriter::write_chunk` at once,
+    /// `MIGRATION_CHUNK_SIZE` by default. Fails with InvalidArgument if it
+    /// is not a whole number of pages.
+    pub fn with_chunk_size(mut self, chunk_size: u64) -> Result<Self> {
+        if chunk_size == 0 || chunk_size & ((1 << HV_HYP_PAGE_SHIFT) - 1) != 0 {
+            return Err(MshvError::InvalidArgument {
+                field: "chunk_size",
+            });
+        }
+        self.chunk_size = chunk_size;
+        Ok(self)
+    }
+    /// Memory regions sent
+    pub fn regions(&self) -> &[mshv_user_mem_region] {
+        &self.regions
+    }
+    /// Memory rounds sent so far
+    pub fn rounds(&self) -> u32 {
+        self.rounds
+    }
+    /// Sends the pages `pfns` of `region`, at most `chunk_size` bytes at a
+    /// time, copied out of the guest memory first
+    fn send_pages(
+        &self,
+        region: &mshv_user_mem_region,
+        pfns: Range<u64>,
+        writer: &mut dyn ChunkWriter,
+    ) -> Result<()> {
+        let mut gpa = pfns.start << HV_HYP_PAGE_SHIFT;
+        let end = pfns.end << HV_HYP_PAGE_SHIFT;
+        let mut buf = vec![0u8; cmp::min(end - gpa, self.chunk_size) as usize];
+        while gpa < end {
+            let len = cmp::min(end - gpa, self.chunk_size) as usize;
+            let offset = gpa - (region.guest_pfn << HV_HYP_PAGE_SHIFT);
+            // SAFETY: the pages are within the region, whose memory `new`
+            // requires to stay mapped
+            unsafe {
+                read_guest_memory(
+                    (region.userspace_addr + offset) as *const u8,
+                    &mut buf[..len],
+                )
+            };
+            writer.write_chunk(gpa, &buf[..len])?;
+            gpa += len as u64;
+        }
+        Ok(())
+    }
+    /// Sends the pages dirtied since the previous round, clearing their
+    /// dirty bits, and returns how many there were
+    fn send_dirty(&mut self, vm: &VmFd, writer: &mut dyn ChunkWriter) -> Result<u64> {
+        let mut sent = 0;
+        for region in self.regions.iter() {
+            let ranges = vm.gpa_access_ranges(
+                region.guest_pfn,
+                region.size >> HV_HYP_PAGE_SHIFT,
+                GpaAccessFlags::CLEAR_DIRTY,
+                GpaAccessKind::Dirty,
+            );
+            for pfns in ranges {
+                let pfns = pfns?;
+                sent += pfns.end - pfns.start;
+                self.send_pages(region, pfns, writer)?;
+            }
+        }
+        self.rounds += 1;
+        Ok(sent)
+    }
+    /// Sets or clears the dirty bits of every page of the regions
+    fn reset_dirty(&self, vm: &VmFd, flags: GpaAccessFlags) -> Result<()> {
+        for region in self.regions.iter() {
+            vm.gpa_access_ranges(
+                region.guest_pfn,
+                region.size >> HV_HYP_PAGE_SHIFT,
+                flags,
+                GpaAccessKind::Dirty,
+            )
+            .try_for_each(|pfns| pfns.map(drop))?;
+        }
+        Ok(())
+    }
+    /// Enables dirty page tracking and clears the dirty bits of the regions,
+    /// so the first round sends all the memory.
+    pub fn start(&mut self, vm: &VmFd) -> Result<()> {
+        vm.enable_dirty_page_tracking()?;
+        self.tracking = true;
+        self.rounds = 0;
+        if let Err(e) = self.reset_dirty(vm, GpaAccessFlags::CLEAR_DIRTY) {
+            let _ = self.cancel(vm);
+            return Err(e);
+        }
+        Ok(())
+    }
+    /// Sends a round of memory while the guest runs and returns how many
+    /// pages it sent: all of them in the first round, then the pages
+    /// dirtied since the previous round. The VMM moves on to `complete`
+    /// when that is few enough to send with the vCPUs stopped.
+    pub fn iterate(&mut self, vm: &VmFd, writer: &mut dyn ChunkWriter) -> Result<u64> {
+        if !self.tracking {
+            return Err(MshvError::InvalidArgument { field: "tracking" });
+        }
+        if self.rounds > 0 {
+            return self.send_dirty(vm, writer);
+        }
+        let mut sent = 0;
+        for region in self.regions.iter() {
+            let pfns = region.size >> HV_HYP_PAGE_SHIFT;
+            self.send_pages(region, region.guest_pfn..region.guest_pfn + pfns, writer)?;
+            sent += pfns;
+        }
+        self.rounds += 1;
+        Ok(sent)
+    }
+    /// Stop-and-copy: sends the pages dirtied since the last round, saves
+    /// the state of `vcpus` as `VmFd::save_state` does, gets the device
+    /// state from `save_devices` and disables dirty page tracking. The
+    /// vCPUs must not be running. The returned state goes to the
+    /// destination after the memory.
+    pub fn complete<F>(
+        &mut self,
+        vm: &VmFd,
+        writer: &mut dyn ChunkWriter,
+        vcpus: &[VcpuFd],
+        msr_indices: &[u32],
+        save_devices: F,
+    ) -> Result<MigrationState>
+    where
+        F: FnOnce() -> Result<Vec<u8>>,
+    {
+        if !self.tracking {
+            return Err(MshvError::InvalidArgument { field: "tracking" });
+        }
+        self.iterate(vm, writer)?;
+        let state = MigrationState {
+            rounds: self.rounds,
+            vm: vm.save_state(vcpus, msr_indices)?,
+            devices: save_devices()?,
+        };
+        self.cancel(vm)?;
+        Ok(state)
+    }
+    /// Disables dirty page tracking, setting the dirty bits back first as
+    /// the hypervisor requires.
+    pub fn cancel(&mut self, vm: &VmFd) -> Result<()> {
+        if !self.tracking {
+            return Ok(());
+        }
+        self.reset_dirty(vm, GpaAccessFlags::SET_DIRTY)?;
+        vm.disable_dirty_page_tracking()?;
+        self.tracking = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ioctls::test_utils::TestVm;
+    use crate::ioctls::vm::new_vmfd;
+    use std::fs::File;
+
+    fn region(mem: &[u8], guest_pfn: u64) -> mshv_user_mem_region {
+        mshv_user_mem_region {
+            flags: HV_MAP_GPA_READABLE | HV_MAP_GPA_WRITABLE,
+            guest_pfn,
+            size: mem.len() as u64,
+            userspace_addr: mem.as_ptr() as u64,
+        }
+    }
+
+    #[test]
+    fn test_send_pages() {
+        let mem: Vec<u8> = (0..0x5000)
+            .map(|i| (i >> HV_HYP_PAGE_SHIFT) as u8)
+            .collect();
+        let region = region(&mem, 0x10);
+        // SAFETY: mem outlives the source
+        let source = unsafe { MigrationSource::new(vec![region]) }
+            .unwrap()
+            .with_chunk_size(0x2000)
+            .unwrap();
+        let mut chunks = Vec::new();
+        let mut writer = |gpa, data: &[u8]| {
+            chunks.push((gpa, data.to_vec()));
+            Ok(())
+        };
+        source.send_pages(&region, 0x11..0x15, &mut writer).unwrap();
+        assert!(chunks.len() == 2);
+        assert!(chunks[0].0 == 0x11000 && chunks[0].1 == mem[0x1000..0x3000]);
+        assert!(chunks[1].0 == 0x13000 && chunks[1].1 == mem[0x3000..0x5000]);
+    }
+
+    #[test]
+    fn test_read_guest_memory() {
+        let mem: Vec<u8> = (0..64).collect();
+        let mut buf = [0u8; 21];
+        // SAFETY: mem holds the 21 bytes from offset 3
+        unsafe { read_guest_memory(mem[3..].as_ptr(), &mut buf) };
+        assert!(buf == mem[3..24]);
+    }
+
+    #[test]
+    fn test_migration_source_errors() {
+        let mem = vec![0u8; 0x1800];
+        // SAFETY: mem outlives the source
+        unsafe {
+            assert!(
+                MigrationSource::new(vec![region(&mem, 0)]).unwrap_err()
+                    == MshvError::InvalidArgument { field: "regions" }
+            );
+            assert!(MigrationSource::new(vec![region(&mem[..0x1000], 0)])
+                .unwrap()
+                .with_chunk_size(0x800)
+                .is_err());
+        }
+        // SAFETY: mem outlives the source
+        let mut source = unsafe { MigrationSource::new(vec![region(&mem[..0x1000], 0)]) }.unwrap();
+        let vm = new_vmfd(File::open("/dev/null").unwrap());
+        let mut writer = |_, _: &[u8]| Ok(());
+        assert!(
+            source.iterate(&vm, &mut writer).unwrap_err()
+                == MshvError::InvalidArgument { field: "tracking" }
+        );
+        assert!(source.start(&vm).is_err());
+        assert!(source.cancel(&vm).is_ok());
+        assert!(source.rounds() == 0);
+    }
+
+    #[test]
+    fn test_migration_source() {
+        let mut test = TestVm::new(0x8000).unwrap();
+        #[rustfmt::skip]
+        let code = [
+            0xc6, 0x06, 0x00, 0x40, 0x01, /* movb $1, 0x4000 */
+            0xc6, 0x06, 0x00, 0x60, 0x02, /* movb $2, 0x6000 */
+            0xf4,                         /* hlt */
+        ];
+        test.load(0x1000, &code).unwrap();
+        test.setup_real_mode(0x1000).unwrap();
+        // SAFETY: the memory of test outlives the source
+        let mut source = unsafe { MigrationSource::new(vec![test.region()]) }.unwrap();
+        let mut image = vec![0u8; test.mem().len()];
+        let mut dirty = Vec::new();
+        let mut writer = |gpa, data: &[u8]| {
+            dirty.push(gpa);
+            image[gpa as usize..gpa as usize + data.len()].copy_from_slice(data);
+            Ok(())
+        };
+        source.start(&test.vm).unwrap();
+        assert!(source.iterate(&test.vm, &mut writer).unwrap() == 8);
+        test.run_until(hv_message_type_HVMSG_X64_HALT, |_, _| Ok(()))
+            .unwrap();
+        let state = source
+            .complete(
+                &test.vm,
+                &mut writer,
+                std::slice::from_ref(&test.vcpu),
+                &[],
+                || Ok(b"devices".to_vec()),
+            )
+            .unwrap();
+        assert!(state.rounds == 2 && state.vm.vcpus.len() == 1);
+        assert!(state.devices == b"devices");
+        assert!(dirty[1..].contains(&0x4000) && dirty[1..].contains(&0x6000));
+        assert!(image == test.mem());
+    }
+}
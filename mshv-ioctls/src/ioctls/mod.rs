@@ -2,13 +2,271 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 //
+use mshv_bindings::HvError;
+use std::fmt;
+use std::io;
 use vmm_sys_util::errno;
+#[cfg(feature = "async")]
+pub mod async_vcpu;
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+pub mod backend;
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+pub mod boot;
 pub mod device;
+pub mod device_interrupt;
+pub mod device_region;
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+pub mod emulator;
+#[cfg(feature = "event-subscribers")]
+pub mod event_subscriber;
+pub mod eventfd_registry;
+pub mod gpa_access;
+pub mod gsi;
+#[cfg(feature = "guest-memory")]
+pub mod guest_memory;
+pub mod intercept;
+pub mod memory_hotplug;
+pub mod metrics;
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+pub mod migration;
+pub mod port;
+pub mod seccomp;
+pub mod snapshot_stream;
 pub mod system;
+#[cfg(all(
+    any(test, feature = "test-utils"),
+    not(any(target_arch = "arm", target_arch = "aarch64"))
+))]
+pub mod test_utils;
 pub mod vcpu;
 pub mod vm;
+#[cfg(feature = "device-manager")]
+pub mod vm_device;
+pub mod vp_page;
+
+/// Errors returned by the MSHV wrappers.
+///
+/// New variants may be added as the driver grows, so matches outside this
+/// crate need a wildcard arm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MshvError {
+    /// An ioctl on an MSHV file descriptor failed.
+    Ioctl(errno::Error),
+    /// A hypercall issued on behalf of the caller failed.
+    Hypercall {
+        /// Hypercall code
+        code: u16,
+        /// Status returned by the hypervisor, see `MshvError::hv_error`
+        hv_status: u16,
+    },
+    /// An argument was rejected before reaching the driver.
+    InvalidArgument {
+        /// Name of the offending argument or field
+        field: &'static str,
+    },
+    /// The operation is not supported by MSHV.
+    NotSupported,
+    /// A register value was rejected by state validation, see
+    /// `VcpuFd::set_state_validation`.
+    InvalidRegister {
+        /// Name of the offending register
+        register: &'static str,
+        /// Offending bit of the register
+        bit: u8,
+    },
+    /// The driver lacks an ioctl the caller requires, see `Mshv::require`.
+    MissingFeature(system::MshvFeature),
+}
+
+impl MshvError {
+    /// Returns the errno equivalent of this error.
+    pub fn errno(&self) -> i32 {
+        match self {
+            MshvError::Ioctl(e) => e.errno(),
+            MshvError::Hypercall { .. } => status_errno(self.hv_error()),
+            MshvError::InvalidArgument { .. } => libc::EINVAL,
+            MshvError::InvalidRegister { .. } => libc::EINVAL,
+            MshvError::NotSupported => libc::ENOTSUP,
+            MshvError::MissingFeature(_) => libc::ENOTSUP,
+        }
+    }
+    /// Returns the decoded hypervisor status of a failed hypercall, None
+    /// for other errors and statuses unknown to `HvError`.
+    pub fn hv_error(&self) -> Option<HvError> {
+        match self {
+            MshvError::Hypercall { hv_status, .. } => HvError::from_status(*hv_status),
+            _ => None,
+        }
+    }
+    /// Returns the hypervisor statuses this error may stand for.
+    ///
+    /// The driver only reports the status of the hypercalls it passes
+    /// through, see `VmFd::hvcall_raw`, for which this is `hv_error`. The
+    /// other ioctls report a status as an errno, so a failed ioctl may stand
+    /// for any of the statuses the driver maps to its errno. It may as well
+    /// have failed before reaching the hypervisor, and EIO also stands for
+    /// statuses unknown to `HvError`.
+    pub fn hv_errors(&self) -> Vec<HvError> {
+        match self {
+            MshvError::Hypercall { .. } => self.hv_error().into_iter().collect(),
+            MshvError::Ioctl(e) => HvError::ALL
+                .iter()
+                .copied()
+                .filter(|hv_error| status_errno(Some(*hv_error)) == e.errno())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Errno the driver returns for a hypercall failing with `hv_error`, None
+/// for statuses unknown to `HvError`.
+fn status_errno(hv_error: Option<HvError>) -> i32 {
+    match hv_error {
+        Some(
+            HvError::InvalidHypercallCode
+            | HvError::InvalidHypercallInput
+            | HvError::InvalidParameter
+            | HvError::InvalidPartitionId
+            | HvError::InvalidVpIndex
+            | HvError::InvalidPortId
+            | HvError::InvalidConnectionId
+            | HvError::InvalidLpIndex
+            | HvError::InvalidRegisterValue,
+        ) => libc::EINVAL,
+        Some(HvError::InsufficientMemory | HvError::InsufficientRootMemory) => libc::ENOMEM,
+        _ => libc::EIO,
+    }
+}
+
+impl fmt::Display for MshvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MshvError::Ioctl(e) => write!(f, "MSHV ioctl failed: {}", e),
+            MshvError::Hypercall { code, hv_status } => match self.hv_error() {
+                Some(e) => write!(f, "hypercall {:#x} failed with {}", code, e),
+                None => write!(
+                    f,
+                    "hypercall {:#x} failed with status {:#x}",
+                    code, hv_status
+                ),
+            },
+            MshvError::InvalidArgument { field } => write!(f, "invalid argument: {}", field),
+            MshvError::NotSupported => write!(f, "operation not supported"),
+            MshvError::InvalidRegister { register, bit } => {
+                write!(f, "invalid value for {}: bit {}", register, bit)
+            }
+            MshvError::MissingFeature(feature) => {
+                write!(f, "the mshv driver does not support {}", feature)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MshvError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MshvError::Ioctl(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<errno::Error> for MshvError {
+    fn from(e: errno::Error) -> Self {
+        MshvError::Ioctl(e)
+    }
+}
+
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+impl From<mshv_bindings::InvalidRegister> for MshvError {
+    fn from(e: mshv_bindings::InvalidRegister) -> Self {
+        MshvError::InvalidRegister {
+            register: e.register,
+            bit: e.bit,
+        }
+    }
+}
+
+impl From<MshvError> for errno::Error {
+    fn from(e: MshvError) -> Self {
+        errno::Error::new(e.errno())
+    }
+}
+
+impl From<MshvError> for io::Error {
+    fn from(e: MshvError) -> Self {
+        io::Error::from_raw_os_error(e.errno())
+    }
+}
+
+impl PartialEq<errno::Error> for MshvError {
+    fn eq(&self, other: &errno::Error) -> bool {
+        self.errno() == other.errno()
+    }
+}
+
 /// A specialized `Result` type for MSHV ioctls.
 ///
-/// This typedef is generally used to avoid writing out errno::Error directly and
+/// This typedef is generally used to avoid writing out MshvError directly and
 /// is otherwise a direct mapping to Result.
-pub type Result<T> = std::result::Result<T, errno::Error>;
+pub type Result<T> = std::result::Result<T, MshvError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mshv_error() {
+        let e = MshvError::from(errno::Error::new(libc::EBUSY));
+        assert!(e == MshvError::Ioctl(errno::Error::new(libc::EBUSY)));
+        assert!(e == errno::Error::new(libc::EBUSY));
+        assert!(errno::Error::from(e).errno() == libc::EBUSY);
+
+        let e = MshvError::InvalidArgument { field: "msrs" };
+        assert!(e == errno::Error::new(libc::EINVAL));
+        assert!(format!("{}", e) == "invalid argument: msrs");
+        assert!(io::Error::from(e).raw_os_error() == Some(libc::EINVAL));
+
+        let e = MshvError::Hypercall {
+            code: 0x51,
+            hv_status: 0x5,
+        };
+        assert!(e.hv_error() == Some(HvError::InvalidParameter));
+        assert!(format!("{}", e) == "hypercall 0x51 failed with HV_STATUS_INVALID_PARAMETER");
+        assert!(e.errno() == libc::EINVAL);
+        let e = MshvError::Hypercall {
+            code: 0x51,
+            hv_status: 0xb,
+        };
+        assert!(e.errno() == libc::ENOMEM);
+        let e = MshvError::Hypercall {
+            code: 0x51,
+            hv_status: 0xfff,
+        };
+        assert!(e.hv_error().is_none());
+        assert!(format!("{}", e) == "hypercall 0x51 failed with status 0xfff");
+        assert!(e.errno() == libc::EIO);
+        assert!(HvError::from_status(0).is_none());
+        assert!(HvError::from_status(0x71).map(|e| e.status()) == Some(0x71));
+        assert!(MshvError::NotSupported.hv_error().is_none());
+        assert!(e.hv_errors().is_empty());
+        let e = MshvError::Hypercall {
+            code: 0x51,
+            hv_status: 0x5,
+        };
+        assert!(e.hv_errors() == vec![HvError::InvalidParameter]);
+        let e = MshvError::from(errno::Error::new(libc::ENOMEM));
+        assert!(
+            e.hv_errors() == vec![HvError::InsufficientMemory, HvError::InsufficientRootMemory]
+        );
+        let e = MshvError::from(errno::Error::new(libc::EINVAL));
+        assert!(e.hv_errors().contains(&HvError::InvalidVpIndex));
+        assert!(!e.hv_errors().contains(&HvError::AccessDenied));
+        assert!(MshvError::from(errno::Error::new(libc::ENOENT))
+            .hv_errors()
+            .is_empty());
+        assert!(MshvError::NotSupported.errno() == libc::ENOTSUP);
+    }
+}
@@ -0,0 +1,427 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use crate::ioctls::vm::VmFd;
+use crate::ioctls::{MshvError, Result};
+use mshv_bindings::*;
+use std::{mem, slice};
+
+/// What a port delivers and where, see `VmFd::create_port`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortKind {
+    /// Messages posted with HvCallPostMessage go to `target_sint` of VP
+    /// `target_vp`.
+    Message {
+        /// SINT the message is queued on
+        target_sint: u32,
+        /// VP index of the receiver
+        target_vp: u32,
+    },
+    /// HvCallSignalEvent sets a flag in the SIEF page and raises
+    /// `target_sint` of VP `target_vp`.
+    Event {
+        /// SINT raised
+        target_sint: u32,
+        /// VP index of the receiver
+        target_vp: u32,
+        /// First flag connections may set
+        base_flag_number: u16,
+        /// Number of flags starting at `base_flag_number`
+        flag_count: u16,
+    },
+    /// Monitored notification page at `monitor_address`.
+    Monitor {
+        /// GPA of the monitor page
+        monitor_address: u64,
+    },
+    /// Doorbell writes raise `target_sint` of VP `target_vp`.
+    Doorbell {
+        /// SINT raised
+        target_sint: u32,
+        /// VP index of the receiver
+        target_vp: u32,
+    },
+}
+
+impl PortKind {
+    fn port_info(self) -> hv_port_info {
+        let mut info = hv_port_info::default();
+        match self {
+            PortKind::Message {
+                target_sint,
+                target_vp,
+            } => {
+                info.port_type = hv_port_type_HV_PORT_TYPE_MESSAGE;
+                info.__bindgen_anon_1.message_port_info =
+                    hv_port_info__bindgen_ty_1__bindgen_ty_1 {
+                        target_sint,
+                        target_vp,
+                        rsvdz: 0,
+                    };
+            }
+            PortKind::Event {
+                target_sint,
+                target_vp,
+                base_flag_number,
+                flag_count,
+            } => {
+                info.port_type = hv_port_type_HV_PORT_TYPE_EVENT;
+                info.__bindgen_anon_1.event_port_info = hv_port_info__bindgen_ty_1__bindgen_ty_2 {
+                    target_sint,
+                    target_vp,
+                    base_flag_number,
+                    flag_count,
+                    rsvdz: 0,
+                };
+            }
+            PortKind::Monitor { monitor_address } => {
+                info.port_type = hv_port_type_HV_PORT_TYPE_MONITOR;
+                info.__bindgen_anon_1.monitor_port_info =
+                    hv_port_info__bindgen_ty_1__bindgen_ty_3 {
+                        monitor_address,
+                        rsvdz: 0,
+                    };
+            }
+            PortKind::Doorbell {
+                target_sint,
+                target_vp,
+            } => {
+                info.port_type = hv_port_type_HV_PORT_TYPE_DOORBELL;
+                info.__bindgen_anon_1.doorbell_port_info =
+                    hv_port_info__bindgen_ty_1__bindgen_ty_4 {
+                        target_sint,
+                        target_vp,
+                        rsvdz: 0,
+                    };
+            }
+        }
+        info
+    }
+}
+
+/// Sender side of a connection, must match the `PortKind` of the port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionKind {
+    /// Connects to a message port.
+    Message,
+    /// Connects to an event port.
+    Event,
+    /// Connects to a monitor port, sharing the page at `monitor_address`.
+    Monitor {
+        /// GPA of the monitor page
+        monitor_address: u64,
+    },
+    /// Writes of `trigger_value` to `gpa` ring a doorbell port.
+    Doorbell {
+        /// GPA of the doorbell
+        gpa: u64,
+        /// Value to match, ignored with HV_DOORBELL_FLAG_TRIGGER_ANY_VALUE
+        trigger_value: u64,
+        /// HV_DOORBELL_FLAG_* size and match flags
+        flags: u64,
+    },
+}
+
+impl ConnectionKind {
+    fn connection_info(self) -> hv_connection_info {
+        let mut info = hv_connection_info::default();
+        match self {
+            ConnectionKind::Message => info.port_type = hv_port_type_HV_PORT_TYPE_MESSAGE,
+            ConnectionKind::Event => info.port_type = hv_port_type_HV_PORT_TYPE_EVENT,
+            ConnectionKind::Monitor { monitor_address } => {
+                info.port_type = hv_port_type_HV_PORT_TYPE_MONITOR;
+                info.__bindgen_anon_1.monitor_connection_info =
+                    hv_connection_info__bindgen_ty_1__bindgen_ty_3 { monitor_address };
+            }
+            ConnectionKind::Doorbell {
+                gpa,
+                trigger_value,
+                flags,
+            } => {
+                info.port_type = hv_port_type_HV_PORT_TYPE_DOORBELL;
+                info.__bindgen_anon_1.doorbell_connection_info =
+                    hv_connection_info__bindgen_ty_1__bindgen_ty_4 {
+                        gpa,
+                        trigger_value,
+                        flags,
+                    };
+            }
+        }
+        info
+    }
+}
+
+/// A port owned by the partition, deleted when dropped.
+#[derive(Debug)]
+pub struct Port<'a> {
+    vm: &'a VmFd,
+    id: u32,
+}
+
+impl Port<'_> {
+    /// Port ID, which connections refer to.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl Drop for Port<'_> {
+    fn drop(&mut self) {
+        let mut input = hv_input_delete_port::default();
+        input.__bindgen_anon_1.port_id = hv_port_id { asu32: self.id };
+        let _ = self
+            .vm
+            .hvcall_raw(HVCALL_DELETE_PORT as u16, as_bytes(&input), &mut []);
+    }
+}
+
+/// A connection from the partition to a port, disconnected when dropped.
+#[derive(Debug)]
+pub struct Connection<'a> {
+    vm: &'a VmFd,
+    id: u32,
+}
+
+impl Connection<'_> {
+    /// Connection ID, which the guest passes to HvCallPostMessage and
+    /// HvCallSignalEvent.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        let mut input = hv_input_disconnect_port::default();
+        input.__bindgen_anon_1.connection_id = hv_connection_id { asu32: self.id };
+        let _ = self
+            .vm
+            .hvcall_raw(HVCALL_DISCONNECT_PORT as u16, as_bytes(&input), &mut []);
+    }
+}
+
+/// Event flag `flag` of SINT `sint` of VP `vp`, which a device backend
+/// signals to notify the guest.
+///
+/// Events made by `VmFd::create_guest_event` also own an event port and
+/// a connection to it, so the guest can signal the same flag with
+/// HvCallSignalEvent on `connection_id`, flag number 0. Both are removed
+/// when the event is dropped.
+#[derive(Debug)]
+pub struct GuestEvent<'a> {
+    vm: &'a VmFd,
+    vp: u32,
+    sint: u8,
+    flag: u16,
+    // Fields drop in order, disconnecting before the port is deleted
+    port: Option<(Connection<'a>, Port<'a>)>,
+}
+
+impl GuestEvent<'_> {
+    /// VP index of the receiver
+    pub fn vp(&self) -> u32 {
+        self.vp
+    }
+    /// SINT raised when the event is signaled
+    pub fn sint(&self) -> u8 {
+        self.sint
+    }
+    /// Flag set in the SIEF page
+    pub fn flag_number(&self) -> u16 {
+        self.flag
+    }
+    /// ID of the connection to the event port, if there is one
+    pub fn connection_id(&self) -> Option<u32> {
+        self.port.as_ref().map(|(connection, _)| connection.id())
+    }
+    /// ID of the event port, if there is one
+    pub fn port_id(&self) -> Option<u32> {
+        self.port.as_ref().map(|(_, port)| port.id())
+    }
+    /// Sets the flag and raises the SINT. Returns true if the flag was not
+    /// already set.
+    pub fn signal(&self) -> Result<bool> {
+        self.vm.signal_event_direct(self.vp, self.sint, self.flag)
+    }
+}
+
+fn check_event(sint: u8, flag: u16) -> Result<()> {
+    if sint as u32 >= HV_SYNIC_SINT_COUNT {
+        return Err(MshvError::InvalidArgument { field: "sint" });
+    }
+    if flag as u32 >= HV_EVENT_FLAGS_COUNT {
+        return Err(MshvError::InvalidArgument { field: "flag" });
+    }
+    Ok(())
+}
+
+pub(crate) fn as_bytes<T: Copy>(input: &T) -> &[u8] {
+    // SAFETY: the hypercall input structs are plain data
+    unsafe { slice::from_raw_parts(input as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+impl VmFd {
+    /// Creates port `port_id` in the partition. Only partition
+    /// `connection_partition_id`, HV_PARTITION_ID_SELF for the root, may
+    /// connect to it.
+    ///
+    /// Goes through `hvcall_raw`: the driver fills in the partition ID,
+    /// and drivers without hypercall pass-through fail with
+    /// `MshvError::NotSupported`.
+    pub fn create_port(
+        &self,
+        port_id: u32,
+        connection_partition_id: u64,
+        kind: PortKind,
+    ) -> Result<Port<'_>> {
+        let input = hv_input_create_port {
+            port_id: hv_port_id { asu32: port_id },
+            connection_partition_id,
+            port_info: kind.port_info(),
+            ..Default::default()
+        };
+        self.hvcall_raw(HVCALL_CREATE_PORT as u16, as_bytes(&input), &mut [])?;
+        Ok(Port {
+            vm: self,
+            id: port_id,
+        })
+    }
+    /// Connects the partition to port `port_id` of partition
+    /// `port_partition_id` as `connection_id`. Same requirements as
+    /// `create_port`.
+    pub fn connect_port(
+        &self,
+        connection_id: u32,
+        port_partition_id: u64,
+        port_id: u32,
+        kind: ConnectionKind,
+    ) -> Result<Connection<'_>> {
+        let input = hv_input_connect_port {
+            connection_id: hv_connection_id {
+                asu32: connection_id,
+            },
+            port_partition_id,
+            port_id: hv_port_id { asu32: port_id },
+            connection_info: kind.connection_info(),
+            ..Default::default()
+        };
+        self.hvcall_raw(HVCALL_CONNECT_PORT as u16, as_bytes(&input), &mut [])?;
+        Ok(Connection {
+            vm: self,
+            id: connection_id,
+        })
+    }
+    /// Returns the event for flag `flag` of SINT `sint` of VP `vp`,
+    /// signaled with `signal_event_direct`.
+    pub fn guest_event(&self, vp: u32, sint: u8, flag: u16) -> Result<GuestEvent<'_>> {
+        check_event(sint, flag)?;
+        Ok(GuestEvent {
+            vm: self,
+            vp,
+            sint,
+            flag,
+            port: None,
+        })
+    }
+    /// Like `guest_event`, and also creates event port `port_id` for the
+    /// flag with connection `connection_id` to it, on the same terms as
+    /// `create_port`.
+    pub fn create_guest_event(
+        &self,
+        connection_id: u32,
+        port_id: u32,
+        vp: u32,
+        sint: u8,
+        flag: u16,
+    ) -> Result<GuestEvent<'_>> {
+        let mut event = self.guest_event(vp, sint, flag)?;
+        let kind = PortKind::Event {
+            target_sint: sint as u32,
+            target_vp: vp,
+            base_flag_number: flag,
+            flag_count: 1,
+        };
+        let port = self.create_port(port_id, HV_PARTITION_ID_SELF, kind)?;
+        let connection = self.connect_port(
+            connection_id,
+            HV_PARTITION_ID_SELF,
+            port_id,
+            ConnectionKind::Event,
+        )?;
+        event.port = Some((connection, port));
+        Ok(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ioctls::system::Mshv;
+
+    #[test]
+    fn test_port_kind() {
+        let info = PortKind::Event {
+            target_sint: 2,
+            target_vp: 1,
+            base_flag_number: 4,
+            flag_count: 8,
+        }
+        .port_info();
+        assert!(info.port_type == hv_port_type_HV_PORT_TYPE_EVENT);
+        // SAFETY: access union fields
+        let event = unsafe { info.__bindgen_anon_1.event_port_info };
+        assert!(event.target_sint == 2 && event.target_vp == 1);
+        assert!(event.base_flag_number == 4 && event.flag_count == 8);
+
+        let info = ConnectionKind::Doorbell {
+            gpa: 0x1000,
+            trigger_value: 1,
+            flags: 0,
+        }
+        .connection_info();
+        assert!(info.port_type == hv_port_type_HV_PORT_TYPE_DOORBELL);
+        // SAFETY: access union fields
+        let doorbell = unsafe { info.__bindgen_anon_1.doorbell_connection_info };
+        assert!(doorbell.gpa == 0x1000 && doorbell.trigger_value == 1);
+    }
+    #[test]
+    fn test_create_port() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let _vcpu = vm.create_vcpu(0).unwrap();
+        let kind = PortKind::Message {
+            target_sint: 2,
+            target_vp: 0,
+        };
+        let port = vm.create_port(1, HV_PARTITION_ID_SELF, kind);
+        match port {
+            Ok(ref port) => assert!(port.id() == 1),
+            Err(ref e) => assert!(*e == MshvError::NotSupported),
+        }
+    }
+    #[test]
+    fn test_guest_event() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let _vcpu = vm.create_vcpu(0).unwrap();
+        assert!(
+            vm.guest_event(0, 16, 0).unwrap_err() == MshvError::InvalidArgument { field: "sint" }
+        );
+        assert!(
+            vm.guest_event(0, 2, 2048).unwrap_err() == MshvError::InvalidArgument { field: "flag" }
+        );
+
+        let event = vm.guest_event(0, 2, 5).unwrap();
+        assert!(event.connection_id().is_none());
+        assert!(event.flag_number() == 5 && event.sint() == 2);
+        let event = vm.create_guest_event(3, 4, 0, 2, 5);
+        match event {
+            Ok(event) => {
+                assert!(event.connection_id() == Some(3));
+                assert!(event.port_id() == Some(4));
+            }
+            Err(e) => assert!(e == MshvError::NotSupported),
+        }
+    }
+}
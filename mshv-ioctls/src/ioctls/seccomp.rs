@@ -0,0 +1,284 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use crate::mshv_ioctls::*;
+use std::os::raw::{c_long, c_ulong};
+
+/// File descriptor an ioctl is issued on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IoctlFd {
+    /// /dev/mshv, `Mshv`
+    System,
+    /// `VmFd`
+    Vm,
+    /// `VcpuFd`
+    Vcpu,
+    /// `DeviceFd`
+    Device,
+}
+
+/// Groups of ioctls, matching the parts of the API a VMM uses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IoctlGroup {
+    /// Partition and vCPU creation, intercepts, partition properties and
+    /// the vCPU run loop, register and state access.
+    Core,
+    /// Guest memory regions and GPA access states.
+    Memory,
+    /// Interrupt injection, irqfd, ioeventfd, MSI routing and SynIC.
+    Interrupts,
+    /// In-kernel devices.
+    Devices,
+    /// Isolated (SEV-SNP) partitions.
+    Isolation,
+    /// Hypercall pass-through: `VmFd::hvcall_raw`, ports and connections.
+    Hypercalls,
+}
+
+impl IoctlGroup {
+    /// Every group.
+    pub const ALL: [IoctlGroup; 6] = [
+        IoctlGroup::Core,
+        IoctlGroup::Memory,
+        IoctlGroup::Interrupts,
+        IoctlGroup::Devices,
+        IoctlGroup::Isolation,
+        IoctlGroup::Hypercalls,
+    ];
+}
+
+/// An ioctl the crate may issue. A seccomp rule allowing it matches the
+/// ioctl syscall with `request` as its second argument; the third argument
+/// is always a pointer and cannot be checked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IoctlRule {
+    /// Name of the request, for logs and generated filters
+    pub name: &'static str,
+    /// Request number, the second ioctl argument
+    pub request: c_ulong,
+    /// File descriptor the request is issued on
+    pub fd: IoctlFd,
+    /// Group the request belongs to
+    pub group: IoctlGroup,
+}
+
+macro_rules! rule {
+    ($ioctl:ident, $fd:ident, $group:ident) => {
+        IoctlRule {
+            name: stringify!($ioctl),
+            request: $ioctl(),
+            fd: IoctlFd::$fd,
+            group: IoctlGroup::$group,
+        }
+    };
+}
+
+/// A syscall other than ioctl the crate makes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SyscallRule {
+    /// Name of the libc constant, e.g. "SYS_mmap"
+    pub name: &'static str,
+    /// Syscall number on this architecture
+    pub number: c_long,
+    /// API making the syscall
+    pub callers: &'static str,
+}
+
+macro_rules! syscall {
+    ($sys:ident, $callers:expr) => {
+        SyscallRule {
+            name: stringify!($sys),
+            number: libc::$sys,
+            callers: $callers,
+        }
+    };
+}
+
+/// Returns every ioctl the crate may issue for the API in `groups`, on
+/// this architecture.
+///
+/// Only ioctls are listed, see `syscall_rules` for the other syscalls.
+pub fn ioctl_rules(groups: &[IoctlGroup]) -> Vec<IoctlRule> {
+    let all = [
+        rule!(MSHV_CREATE_PARTITION, System, Core),
+        rule!(MSHV_CREATE_VP, Vm, Core),
+        rule!(MSHV_INSTALL_INTERCEPT, Vm, Core),
+        rule!(MSHV_GET_PARTITION_PROPERTY, Vm, Core),
+        rule!(MSHV_SET_PARTITION_PROPERTY, Vm, Core),
+        rule!(MSHV_RUN_VP, Vcpu, Core),
+        rule!(MSHV_GET_VP_REGISTERS, Vcpu, Core),
+        rule!(MSHV_SET_VP_REGISTERS, Vcpu, Core),
+        rule!(MSHV_GET_VP_STATE, Vcpu, Core),
+        rule!(MSHV_SET_VP_STATE, Vcpu, Core),
+        rule!(MSHV_VP_TRANSLATE_GVA, Vcpu, Core),
+        rule!(MSHV_VP_REGISTER_INTERCEPT_RESULT, Vcpu, Core),
+        #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+        rule!(MSHV_GET_VP_CPUID_VALUES, Vcpu, Core),
+        rule!(MSHV_MAP_GUEST_MEMORY, Vm, Memory),
+        rule!(MSHV_UNMAP_GUEST_MEMORY, Vm, Memory),
+        rule!(MSHV_GET_GPA_ACCESS_STATES, Vm, Memory),
+        rule!(MSHV_READ_GPA, Vcpu, Memory),
+        rule!(MSHV_WRITE_GPA, Vcpu, Memory),
+        rule!(MSHV_ASSERT_INTERRUPT, Vm, Interrupts),
+        rule!(MSHV_IRQFD, Vm, Interrupts),
+        rule!(MSHV_IOEVENTFD, Vm, Interrupts),
+        rule!(MSHV_SET_MSI_ROUTING, Vm, Interrupts),
+        rule!(MSHV_SIGNAL_EVENT_DIRECT, Vm, Interrupts),
+        rule!(MSHV_POST_MESSAGE_DIRECT, Vm, Interrupts),
+        rule!(MSHV_REGISTER_DELIVERABILITY_NOTIFICATIONS, Vm, Interrupts),
+        rule!(MSHV_CREATE_DEVICE, Vm, Devices),
+        rule!(MSHV_SET_DEVICE_ATTR, Device, Devices),
+        rule!(MSHV_GET_DEVICE_ATTR, Device, Devices),
+        rule!(MSHV_HAS_DEVICE_ATTR, Device, Devices),
+        rule!(MSHV_MODIFY_GPA_HOST_ACCESS, Vm, Isolation),
+        rule!(MSHV_IMPORT_ISOLATED_PAGES, Vm, Isolation),
+        rule!(MSHV_COMPLETE_ISOLATED_IMPORT, Vm, Isolation),
+        rule!(MSHV_ISSUE_PSP_GUEST_REQUEST, Vm, Isolation),
+        rule!(MSHV_SEV_SNP_AP_CREATE, Vm, Isolation),
+        rule!(MSHV_ROOT_HVCALL, Vm, Hypercalls),
+    ];
+    all.iter()
+        .filter(|rule| groups.contains(&rule.group))
+        .copied()
+        .collect()
+}
+
+/// Returns the syscalls other than ioctl the crate may make, on this
+/// architecture, besides those of the Rust standard library such as
+/// close when a fd is dropped.
+pub fn syscall_rules() -> Vec<SyscallRule> {
+    vec![
+        syscall!(SYS_openat, "Mshv::new"),
+        syscall!(SYS_mmap, "VcpuFd::map_state_page"),
+        syscall!(SYS_munmap, "dropping a VpStatePage"),
+        syscall!(
+            SYS_madvise,
+            "VmFd::map_user_memory_populated, VmFd::discard_user_memory"
+        ),
+        syscall!(SYS_sched_setaffinity, "VcpuFd::set_affinity"),
+        syscall!(SYS_sched_getaffinity, "VcpuFd::set_affinity"),
+        syscall!(SYS_rt_sigprocmask, "VcpuFd::run with a signal mask set"),
+        syscall!(SYS_tgkill, "AsyncVcpuFd kicks, through pthread_kill"),
+        syscall!(
+            SYS_getpid,
+            "AsyncVcpuFd kicks, comparing eventfds in EventFdRegistry, VmFd doorbells and EventFdSubscriber"
+        ),
+        syscall!(
+            SYS_kcmp,
+            "comparing eventfds in EventFdRegistry, VmFd doorbells and EventFdSubscriber"
+        ),
+        syscall!(
+            SYS_dup,
+            "EventFd::try_clone in EventFdRegistry, VmFd::register_doorbell and VcpuFd::set_halt_eventfd"
+        ),
+        syscall!(SYS_write, "VcpuFd::run signalling the halt eventfd"),
+        syscall!(SYS_read, "EventFdSubscriber"),
+        syscall!(SYS_uname, "Mshv::get_version_info"),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+    use std::fs;
+    use std::path::Path;
+
+    /// Source of the crate, without the test modules
+    fn crate_sources(dir: &Path, sources: &mut Vec<String>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                crate_sources(&path, sources);
+            } else if path.extension().is_some_and(|ext| ext == "rs") {
+                let source = fs::read_to_string(&path).unwrap();
+                let end = source
+                    .find("#[cfg(test)]\nmod tests")
+                    .unwrap_or(source.len());
+                sources.push(source[..end].to_string());
+            }
+        }
+    }
+
+    #[test]
+    fn test_ioctl_rules() {
+        let all = ioctl_rules(&IoctlGroup::ALL);
+        let requests: HashSet<c_ulong> = all.iter().map(|rule| rule.request).collect();
+        assert_eq!(requests.len(), all.len());
+        assert!(all.contains(&rule!(MSHV_RUN_VP, Vcpu, Core)));
+
+        let memory = ioctl_rules(&[IoctlGroup::Memory]);
+        assert!(memory.iter().all(|rule| rule.group == IoctlGroup::Memory));
+        assert!(memory
+            .iter()
+            .any(|rule| rule.name == "MSHV_MAP_GUEST_MEMORY"));
+        assert!(ioctl_rules(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_syscall_rules() {
+        let rules = syscall_rules();
+        let numbers: HashSet<c_long> = rules.iter().map(|rule| rule.number).collect();
+        assert_eq!(numbers.len(), rules.len());
+
+        // libc functions the crate calls and the syscall each makes, None
+        // if it makes none. A new one has to be added here, and its
+        // syscall to `syscall_rules`.
+        let calls: HashMap<&str, Option<c_long>> = [
+            ("CPU_ISSET", None),
+            ("CPU_SET", None),
+            ("CPU_ZERO", None),
+            ("getpid", Some(libc::SYS_getpid)),
+            ("madvise", Some(libc::SYS_madvise)),
+            ("mmap", Some(libc::SYS_mmap)),
+            ("munmap", Some(libc::SYS_munmap)),
+            ("pthread_kill", Some(libc::SYS_tgkill)),
+            ("pthread_self", None),
+            ("pthread_sigmask", Some(libc::SYS_rt_sigprocmask)),
+            ("sched_getaffinity", Some(libc::SYS_sched_getaffinity)),
+            ("sched_setaffinity", Some(libc::SYS_sched_setaffinity)),
+            ("sigaddset", None),
+            ("sigemptyset", None),
+            // Checked through the SYS_ constant passed to it
+            ("syscall", None),
+            ("sysconf", None),
+            ("uname", Some(libc::SYS_uname)),
+        ]
+        .iter()
+        .copied()
+        .collect();
+
+        let mut sources = Vec::new();
+        crate_sources(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("src"),
+            &mut sources,
+        );
+        for source in &sources {
+            for line in source.lines() {
+                if line.trim_start().starts_with("//") {
+                    continue;
+                }
+                for (pos, _) in line.match_indices("libc::") {
+                    let rest = &line[pos + "libc::".len()..];
+                    let len = rest
+                        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                        .unwrap_or(rest.len());
+                    let name = &rest[..len];
+                    if name.starts_with("SYS_") {
+                        assert!(rules.iter().any(|rule| rule.name == name), "{}", name);
+                    } else if rest[len..].starts_with('(') {
+                        match calls.get(name) {
+                            Some(Some(number)) => assert!(numbers.contains(number), "{}", name),
+                            Some(None) => {}
+                            None => panic!("libc::{} is not in the list", name),
+                        }
+                    }
+                }
+                if line.contains(".try_clone()") {
+                    assert!(numbers.contains(&libc::SYS_dup));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,491 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use mshv_bindings::*;
+use std::io::{self, Read, Write};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+/// Current version of the snapshot stream layout. Version 1 streams, with
+/// run-length encoded sections, predate any release and are not read.
+pub const SNAPSHOT_STREAM_VERSION: u32 = 2;
+/// "MSSS", first bytes of every snapshot stream
+pub const SNAPSHOT_STREAM_MAGIC: u32 = u32::from_le_bytes(*b"MSSS");
+/// Most bytes of guest memory in one section, larger writes are split
+pub const SNAPSHOT_CHUNK_SIZE: usize = 0x10_0000;
+/// Most bytes in any section, bounding what a reader allocates
+pub const SNAPSHOT_MAX_SECTION_SIZE: usize = 0x400_0000;
+
+const SECTION_END: u32 = 0;
+const SECTION_MEMORY: u32 = 1;
+const SECTION_VCPU: u32 = 2;
+const SECTION_DEVICE: u32 = 3;
+
+/// Compression of the sections of a snapshot stream
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SnapshotCompression {
+    /// Sections are stored as is
+    None = 0,
+    /// Sections are LZ4 blocks, or stored as is if that does not shrink
+    /// them. Needs the `lz4` feature, also to read them back.
+    #[cfg(feature = "lz4")]
+    Lz4 = 1,
+}
+
+impl SnapshotCompression {
+    fn from_raw(raw: u32) -> io::Result<Self> {
+        match raw {
+            0 => Ok(SnapshotCompression::None),
+            #[cfg(feature = "lz4")]
+            1 => Ok(SnapshotCompression::Lz4),
+            #[cfg(not(feature = "lz4"))]
+            1 => Err(invalid_data("LZ4 section, built without the lz4 feature")),
+            _ => Err(invalid_data("unknown section compression")),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+struct StreamHeader {
+    magic: u32,
+    version: u32,
+    reserved: [u32; 2],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+struct SectionHeader {
+    kind: u32,
+    compression: u32,
+    // GPA of memory, index of vCPUs, id of devices
+    arg: u64,
+    // Size before and after compression
+    size: u32,
+    stored_size: u32,
+    // CRC32 (IEEE) of the data before compression
+    checksum: u32,
+    reserved: u32,
+}
+
+/// Compresses `data` into `buf` as `compression` asks, if that shrinks it.
+/// Returns the compression used and the bytes to store.
+#[cfg_attr(not(feature = "lz4"), allow(unused_variables, clippy::ptr_arg))]
+fn compress<'a>(
+    compression: SnapshotCompression,
+    data: &'a [u8],
+    buf: &'a mut Vec<u8>,
+) -> io::Result<(SnapshotCompression, &'a [u8])> {
+    match compression {
+        SnapshotCompression::None => Ok((SnapshotCompression::None, data)),
+        #[cfg(feature = "lz4")]
+        SnapshotCompression::Lz4 => {
+            buf.resize(lz4_flex::block::get_maximum_output_size(data.len()), 0);
+            let len = lz4_flex::block::compress_into(data, buf).map_err(io::Error::other)?;
+            if len < data.len() {
+                Ok((SnapshotCompression::Lz4, &buf[..len]))
+            } else {
+                Ok((SnapshotCompression::None, data))
+            }
+        }
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Section read back from a snapshot stream by `SnapshotReader`
+#[derive(Debug, Clone)]
+pub enum SnapshotSection {
+    /// Guest memory
+    Memory {
+        /// GPA of the first byte
+        gpa: u64,
+        /// At most `SNAPSHOT_CHUNK_SIZE` bytes
+        data: Vec<u8>,
+    },
+    /// State of a vCPU
+    Vcpu {
+        /// Index of the vCPU
+        index: u64,
+        /// Its state, brought to the current version
+        snapshot: Box<VcpuSnapshot>,
+    },
+    /// State of a device, opaque to the stream
+    Device {
+        /// Id the VMM gave the device
+        id: u64,
+        /// Its state
+        data: Vec<u8>,
+    },
+}
+
+/// Writes the state of a partition to any `Write` as a stream of sections,
+/// each with its own CRC32 and optionally compressed, so that the memory
+/// of a checkpoint never needs to be held in memory as a whole.
+///
+/// Sections can come in any order; `finish` ends the stream. The layout is
+/// native-endian, like that of `VpStateBlob`. Read it back with
+/// `SnapshotReader`.
+#[derive(Debug)]
+pub struct SnapshotWriter<W: Write> {
+    inner: W,
+    compression: SnapshotCompression,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    /// Writes the stream header to `inner`
+    pub fn new(mut inner: W, compression: SnapshotCompression) -> io::Result<Self> {
+        let header = StreamHeader {
+            magic: SNAPSHOT_STREAM_MAGIC,
+            version: SNAPSHOT_STREAM_VERSION,
+            ..Default::default()
+        };
+        inner.write_all(header.as_bytes())?;
+        Ok(SnapshotWriter {
+            inner,
+            compression,
+            buf: Vec::new(),
+        })
+    }
+
+    fn write_section(&mut self, kind: u32, arg: u64, data: &[u8]) -> io::Result<()> {
+        if data.len() > SNAPSHOT_MAX_SECTION_SIZE {
+            return Err(invalid_input(
+                "section larger than SNAPSHOT_MAX_SECTION_SIZE",
+            ));
+        }
+        let (compression, stored) = compress(self.compression, data, &mut self.buf)?;
+        let header = SectionHeader {
+            kind,
+            compression: compression as u32,
+            arg,
+            size: data.len() as u32,
+            stored_size: stored.len() as u32,
+            checksum: crc32fast::hash(data),
+            ..Default::default()
+        };
+        self.inner.write_all(header.as_bytes())?;
+        self.inner.write_all(stored)
+    }
+
+    /// Writes the guest memory at `gpa`, in sections of up to
+    /// `SNAPSHOT_CHUNK_SIZE` bytes
+    pub fn write_memory(&mut self, gpa: u64, data: &[u8]) -> io::Result<()> {
+        for (i, chunk) in data.chunks(SNAPSHOT_CHUNK_SIZE).enumerate() {
+            let offset = (i * SNAPSHOT_CHUNK_SIZE) as u64;
+            self.write_section(SECTION_MEMORY, gpa + offset, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the state of vCPU `index` as a `VpStateBlob` followed by its
+    /// `extra_vp_states`. The blob carries its own version and size, so
+    /// readers find the components after blobs of older versions too.
+    /// Fails with InvalidInput if the snapshot does not fit in a
+    /// `VpStateBlob` other than through its `extra_vp_states`.
+    pub fn write_vcpu(&mut self, index: u64, snapshot: &VcpuSnapshot) -> io::Result<()> {
+        let blob = VpStateBlob::new(&VcpuSnapshot {
+            extra_vp_states: Vec::new(),
+            ..snapshot.clone()
+        })
+        .map_err(|_| invalid_input("vCPU snapshot does not fit in a VpStateBlob"))?;
+        let mut data = blob.as_bytes().to_vec();
+        for component in snapshot.extra_vp_states.iter() {
+            data.extend_from_slice(&component.type_.to_ne_bytes());
+            data.extend_from_slice(&(component.data.len() as u32).to_ne_bytes());
+            data.extend_from_slice(&component.data);
+        }
+        self.write_section(SECTION_VCPU, index, &data)
+    }
+
+    /// Writes the state of the device `id`, opaque to the stream
+    pub fn write_device(&mut self, id: u64, data: &[u8]) -> io::Result<()> {
+        self.write_section(SECTION_DEVICE, id, data)
+    }
+
+    /// Ends the stream, flushes it and returns the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(SectionHeader::default().as_bytes())?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Reads back a stream written by `SnapshotWriter`, one section at a time
+#[derive(Debug)]
+pub struct SnapshotReader<R: Read> {
+    inner: R,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read> SnapshotReader<R> {
+    /// Reads the stream header from `inner`. Fails with InvalidData if it
+    /// is not a snapshot stream of a known version.
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut header = StreamHeader::default();
+        inner.read_exact(header.as_bytes_mut())?;
+        if header.magic != SNAPSHOT_STREAM_MAGIC || header.version != SNAPSHOT_STREAM_VERSION {
+            return Err(invalid_data("not a snapshot stream of a known version"));
+        }
+        Ok(SnapshotReader {
+            inner,
+            buf: Vec::new(),
+            done: false,
+        })
+    }
+
+    fn read_vcpu(data: &[u8]) -> io::Result<VcpuSnapshot> {
+        let invalid = || invalid_data("corrupt vCPU section");
+        // The blob carries its own version and size, older ones are smaller
+        let header = VpStateBlobHeader::read_from_prefix(data).ok_or_else(invalid)?;
+        let blob_size = header.size as usize;
+        let blob = data.get(..blob_size).ok_or_else(invalid)?;
+        let mut snapshot = VpStateBlob::from_bytes(blob)
+            .map_err(|_| invalid())?
+            .to_snapshot();
+        let mut rest = &data[blob_size..];
+        while !rest.is_empty() {
+            let field = |bytes: &[u8]| {
+                let mut value = [0u8; 4];
+                value.copy_from_slice(bytes);
+                u32::from_ne_bytes(value)
+            };
+            let header = rest.get(..8).ok_or_else(invalid)?;
+            let len = field(&header[4..]) as usize;
+            let data = rest.get(8..8 + len).ok_or_else(invalid)?;
+            snapshot.extra_vp_states.push(VpStateComponent {
+                type_: field(&header[..4]) as _,
+                data: data.to_vec(),
+            });
+            rest = &rest[8 + len..];
+        }
+        Ok(snapshot)
+    }
+
+    /// Reads the next section, or returns None at the end of the stream.
+    /// Fails with InvalidData if the section is corrupt, its checksum
+    /// included, and with UnexpectedEof if the stream ends early.
+    pub fn next_section(&mut self) -> io::Result<Option<SnapshotSection>> {
+        if self.done {
+            return Ok(None);
+        }
+        let mut header = SectionHeader::default();
+        self.inner.read_exact(header.as_bytes_mut())?;
+        if header.kind == SECTION_END {
+            self.done = true;
+            return Ok(None);
+        }
+        let size = header.size as usize;
+        let stored_size = header.stored_size as usize;
+        if size > SNAPSHOT_MAX_SECTION_SIZE || stored_size > SNAPSHOT_MAX_SECTION_SIZE {
+            return Err(invalid_data(
+                "section larger than SNAPSHOT_MAX_SECTION_SIZE",
+            ));
+        }
+        self.buf.resize(stored_size, 0);
+        self.inner.read_exact(&mut self.buf)?;
+        let data = match SnapshotCompression::from_raw(header.compression)? {
+            SnapshotCompression::None if stored_size == size => self.buf.clone(),
+            SnapshotCompression::None => return Err(invalid_data("corrupt section")),
+            #[cfg(feature = "lz4")]
+            SnapshotCompression::Lz4 => {
+                let mut data = vec![0u8; size];
+                match lz4_flex::block::decompress_into(&self.buf, &mut data) {
+                    Ok(len) if len == size => data,
+                    _ => return Err(invalid_data("corrupt compressed section")),
+                }
+            }
+        };
+        if crc32fast::hash(&data) != header.checksum {
+            return Err(invalid_data("section checksum mismatch"));
+        }
+        let section = match header.kind {
+            SECTION_MEMORY => SnapshotSection::Memory {
+                gpa: header.arg,
+                data,
+            },
+            SECTION_VCPU => SnapshotSection::Vcpu {
+                index: header.arg,
+                snapshot: Box::new(Self::read_vcpu(&data)?),
+            },
+            SECTION_DEVICE => SnapshotSection::Device {
+                id: header.arg,
+                data,
+            },
+            _ => return Err(invalid_data("unknown section kind")),
+        };
+        Ok(Some(section))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::mem;
+    use std::path::Path;
+
+    fn vcpu_snapshot() -> VcpuSnapshot {
+        let mut snapshot = VcpuSnapshot::default();
+        snapshot.regs.rip = 0xfff0;
+        snapshot.msrs.push(msr_entry {
+            index: 0x10,
+            data: 0x1234,
+            ..Default::default()
+        });
+        snapshot.extra_vp_states.push(VpStateComponent {
+            type_: 9,
+            data: vec![1, 2, 3],
+        });
+        snapshot
+    }
+
+    fn write_stream(compression: SnapshotCompression, memory: &[u8]) -> Vec<u8> {
+        let mut writer = SnapshotWriter::new(Vec::new(), compression).unwrap();
+        writer.write_memory(0x10_0000, memory).unwrap();
+        writer.write_vcpu(1, &vcpu_snapshot()).unwrap();
+        writer.write_device(7, b"device").unwrap();
+        writer.finish().unwrap()
+    }
+
+    // Reads back a stream written by write_stream
+    fn read_stream(stream: &[u8], memory: &[u8]) {
+        let mut reader = SnapshotReader::new(stream).unwrap();
+        let mut read = Vec::new();
+        while let Some(SnapshotSection::Memory { gpa, data }) = reader.next_section().unwrap() {
+            assert!(gpa == 0x10_0000 + read.len() as u64);
+            read.extend(data);
+            if read.len() == memory.len() {
+                break;
+            }
+        }
+        assert!(read == memory);
+        match reader.next_section().unwrap() {
+            Some(SnapshotSection::Vcpu { index, snapshot }) => {
+                let expected = vcpu_snapshot();
+                assert!(index == 1);
+                assert!(snapshot.regs.rip == expected.regs.rip);
+                assert!(snapshot.msrs == expected.msrs);
+                assert!(snapshot.extra_vp_states == expected.extra_vp_states);
+            }
+            section => panic!("unexpected {:?}", section),
+        }
+        match reader.next_section().unwrap() {
+            Some(SnapshotSection::Device { id, data }) => {
+                assert!(id == 7 && data == b"device")
+            }
+            section => panic!("unexpected {:?}", section),
+        }
+        assert!(reader.next_section().unwrap().is_none());
+        assert!(reader.next_section().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_snapshot_stream() {
+        // Zero pages and a chunk and a half of less compressible memory
+        let mut memory = vec![0u8; SNAPSHOT_CHUNK_SIZE / 2];
+        memory.extend((0..SNAPSHOT_CHUNK_SIZE).map(|i| (i * 7 / 3) as u8));
+        let plain = write_stream(SnapshotCompression::None, &memory);
+        read_stream(&plain, &memory);
+        #[cfg(feature = "lz4")]
+        {
+            let compressed = write_stream(SnapshotCompression::Lz4, &memory);
+            assert!(compressed.len() < plain.len() - SNAPSHOT_CHUNK_SIZE / 4);
+            read_stream(&compressed, &memory);
+        }
+    }
+
+    #[test]
+    fn test_snapshot_stream_errors() {
+        let stream = write_stream(SnapshotCompression::None, &[0xaa; 0x2000]);
+        let read_all = |stream: &[u8]| -> io::Result<()> {
+            let mut reader = SnapshotReader::new(stream)?;
+            while reader.next_section()?.is_some() {}
+            Ok(())
+        };
+        assert!(read_all(&stream).is_ok());
+        assert!(read_all(&stream[4..]).unwrap_err().kind() == io::ErrorKind::InvalidData);
+        assert!(
+            read_all(&stream[..stream.len() - 1]).unwrap_err().kind()
+                == io::ErrorKind::UnexpectedEof
+        );
+        // Flip a bit of the first section's data
+        let mut corrupt = stream.clone();
+        let offset = mem::size_of::<StreamHeader>() + mem::size_of::<SectionHeader>() + 1;
+        corrupt[offset] ^= 1;
+        assert!(read_all(&corrupt).unwrap_err().kind() == io::ErrorKind::InvalidData);
+        // Mark it LZ4 compressed, which it is not
+        let mut corrupt = stream.clone();
+        corrupt[mem::size_of::<StreamHeader>() + 4] = 1;
+        assert!(read_all(&corrupt).unwrap_err().kind() == io::ErrorKind::InvalidData);
+
+        let mut writer = SnapshotWriter::new(Vec::new(), SnapshotCompression::None).unwrap();
+        let mut snapshot = vcpu_snapshot();
+        snapshot.version = 1;
+        assert!(writer.write_vcpu(0, &snapshot).unwrap_err().kind() == io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_snapshot_stream_old_blob() {
+        // A vCPU section holding a version 1 blob, smaller than the current
+        // one, followed by an extra VP state component
+        let golden = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../mshv-bindings/tests/golden/vp_state_blob_v1.bin");
+        let mut data = fs::read(golden).unwrap();
+        assert!(data.len() < mem::size_of::<VpStateBlob>());
+        let expected = VpStateBlob::from_bytes(&data).unwrap().to_snapshot();
+        data.extend_from_slice(&9u32.to_ne_bytes());
+        data.extend_from_slice(&3u32.to_ne_bytes());
+        data.extend_from_slice(&[1, 2, 3]);
+        let mut writer = SnapshotWriter::new(Vec::new(), SnapshotCompression::None).unwrap();
+        writer.write_section(SECTION_VCPU, 0, &data).unwrap();
+        let stream = writer.finish().unwrap();
+
+        let mut reader = SnapshotReader::new(&stream[..]).unwrap();
+        match reader.next_section().unwrap() {
+            Some(SnapshotSection::Vcpu { snapshot, .. }) => {
+                assert!(snapshot.regs.rip == expected.regs.rip);
+                assert!(snapshot.msrs == expected.msrs);
+                assert!(snapshot.mtrrs == expected.mtrrs);
+                assert!(snapshot.extra_vp_states == vcpu_snapshot().extra_vp_states);
+            }
+            section => panic!("unexpected {:?}", section),
+        }
+    }
+
+    #[test]
+    fn test_golden_snapshot_stream() {
+        // Uncompressed, so that it reads the same without the lz4 feature.
+        // Bless with MSHV_BLESS_GOLDEN=1 when SNAPSHOT_STREAM_VERSION goes
+        // up, keeping the older files.
+        let mut memory = vec![0u8; 0x2000];
+        memory[0x1000..].copy_from_slice(&[0x5a; 0x1000]);
+        memory[0x10..0x20].copy_from_slice(b"guest memory....");
+        let stream = write_stream(SnapshotCompression::None, &memory);
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(format!(
+            "tests/golden/snapshot_stream_v{}.bin",
+            SNAPSHOT_STREAM_VERSION
+        ));
+        if env::var_os("MSHV_BLESS_GOLDEN").is_some() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, &stream).unwrap();
+        }
+        let golden = fs::read(&path).unwrap();
+        assert!(golden == stream, "{} changed", path.display());
+        let mut reader = SnapshotReader::new(&golden[..]).unwrap();
+        let mut sections = 0;
+        while reader.next_section().unwrap().is_some() {
+            sections += 1;
+        }
+        assert!(sections == 3);
+    }
+}
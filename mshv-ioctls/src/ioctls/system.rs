@@ -3,20 +3,199 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 //
 use crate::ioctls::vm::{new_vmfd, VmFd, VmType};
-use crate::ioctls::Result;
+use crate::ioctls::vp_page::VpStatePageKind;
+use crate::ioctls::{MshvError, Result};
 use crate::mshv_ioctls::*;
 use libc::{open, O_CLOEXEC, O_NONBLOCK};
 use mshv_bindings::*;
-use std::fs::File;
-use std::os::raw::c_char;
-use std::os::unix::io::{FromRawFd, RawFd};
+use std::fmt;
+use std::fs::{self, File};
+use std::os::raw::{c_char, c_ulong};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::Mutex;
 use vmm_sys_util::errno;
-use vmm_sys_util::ioctl::ioctl_with_ref;
+use vmm_sys_util::ioctl::{ioctl_with_ref, ioctl_with_val};
 
 /// Wrapper over MSHV system ioctls.
 #[derive(Debug)]
 pub struct Mshv {
     hv: File,
+    version_info: Mutex<Option<VersionInfo>>,
+}
+
+/// Optional driver ioctls, missing from older mshv drivers
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum MshvFeature {
+    /// MSHV_ROOT_HVCALL, hypercall pass-through
+    RootHvcall,
+    /// MSHV_GET_GPA_ACCESS_STATES, used for dirty page tracking
+    GpaAccessStates,
+    /// MSHV_CREATE_DEVICE, in-kernel devices such as VFIO
+    Devices,
+    /// MSHV_SIGNAL_EVENT_DIRECT and MSHV_POST_MESSAGE_DIRECT
+    DirectSynic,
+    /// MSHV_IOEVENTFD, backing ioevents and doorbells
+    IoEventFd,
+    /// MSHV_READ_GPA and MSHV_WRITE_GPA
+    GpaReadWrite,
+    /// MSHV_GET_VP_CPUID_VALUES
+    VpCpuidValues,
+    /// The vCPU register page, mapped from the vCPU fd
+    RegisterPage,
+}
+
+impl MshvFeature {
+    /// Every feature, in probing order
+    pub const ALL: [MshvFeature; 8] = [
+        MshvFeature::RootHvcall,
+        MshvFeature::GpaAccessStates,
+        MshvFeature::Devices,
+        MshvFeature::DirectSynic,
+        MshvFeature::IoEventFd,
+        MshvFeature::GpaReadWrite,
+        MshvFeature::VpCpuidValues,
+        MshvFeature::RegisterPage,
+    ];
+
+    /// Name of the ioctl providing the feature, or of the mmap offset for
+    /// the register page
+    pub fn ioctl_name(&self) -> &'static str {
+        match self {
+            MshvFeature::RootHvcall => "MSHV_ROOT_HVCALL",
+            MshvFeature::GpaAccessStates => "MSHV_GET_GPA_ACCESS_STATES",
+            MshvFeature::Devices => "MSHV_CREATE_DEVICE",
+            MshvFeature::DirectSynic => "MSHV_SIGNAL_EVENT_DIRECT",
+            MshvFeature::IoEventFd => "MSHV_IOEVENTFD",
+            MshvFeature::GpaReadWrite => "MSHV_READ_GPA",
+            MshvFeature::VpCpuidValues => "MSHV_GET_VP_CPUID_VALUES",
+            MshvFeature::RegisterPage => "MSHV_VP_MMAP_OFFSET_REGISTERS",
+        }
+    }
+
+    /// Returns true for features provided by vCPU ioctls, which can only be
+    /// probed on a vCPU
+    pub fn is_vcpu_feature(&self) -> bool {
+        matches!(
+            self,
+            MshvFeature::GpaReadWrite | MshvFeature::VpCpuidValues | MshvFeature::RegisterPage
+        )
+    }
+}
+
+impl fmt::Display for MshvFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.ioctl_name())
+    }
+}
+
+/// Hypervisor version, from CPUID leaf 0x40000002 of the root partition
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct HypervisorVersion {
+    /// Build number
+    pub build: u32,
+    /// Major version
+    pub major: u16,
+    /// Minor version
+    pub minor: u16,
+    /// Service pack
+    pub service_pack: u32,
+    /// Service branch
+    pub service_branch: u8,
+    /// Service number
+    pub service_number: u32,
+}
+
+impl fmt::Display for HypervisorVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}.{}-{}.{}",
+            self.major,
+            self.minor,
+            self.build,
+            self.service_pack,
+            self.service_branch,
+            self.service_number
+        )
+    }
+}
+
+/// What the VMM runs on, see `Mshv::get_version_info`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct VersionInfo {
+    /// Release of the running kernel
+    pub kernel_release: String,
+    /// Version of the mshv driver module, if it reports one
+    pub driver_version: Option<String>,
+    /// Hypervisor version, None where it cannot be queried
+    pub hypervisor: Option<HypervisorVersion>,
+    /// Optional ioctls the driver implements
+    pub features: Vec<MshvFeature>,
+}
+
+impl VersionInfo {
+    /// Returns true if the driver implements `feature`
+    pub fn supports(&self, feature: MshvFeature) -> bool {
+        self.features.contains(&feature)
+    }
+}
+
+/// Issues ioctl `nr` on `fd` with a NULL argument and returns false if the
+/// driver does not know it. The driver fails the ioctls it knows with
+/// EFAULT when copying the argument in, before acting on them, so probing
+/// has no side effect.
+pub(crate) fn probe_ioctl<F: AsRawFd>(fd: &F, nr: c_ulong) -> bool {
+    // SAFETY: the driver does not write through a NULL argument
+    let ret = unsafe { ioctl_with_val(fd, nr, 0) };
+    ret >= 0 || errno::Error::last().errno() != libc::ENOTTY
+}
+
+fn kernel_release() -> String {
+    // SAFETY: utsname is plain data
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    // SAFETY: uname fills the struct we own
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return String::new();
+    }
+    // SAFETY: uname NUL terminates the fields
+    unsafe { std::ffi::CStr::from_ptr(uts.release.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn driver_version() -> Option<String> {
+    ["mshv_root", "mshv"].iter().find_map(|module| {
+        fs::read_to_string(format!("/sys/module/{}/version", module))
+            .ok()
+            .map(|version| version.trim().to_string())
+    })
+}
+
+#[cfg(target_arch = "x86_64")]
+// __cpuid is only safe to call on recent toolchains
+#[allow(unused_unsafe)]
+fn hypervisor_version() -> Option<HypervisorVersion> {
+    use std::arch::x86_64::__cpuid;
+    // SAFETY: CPUID is available on every x86_64 processor
+    let vendor = unsafe { __cpuid(HV_CPUID_FUNCTION_HV_VENDOR_AND_MAX_FUNCTION) };
+    if vendor.eax < HV_CPUID_FUNCTION_MS_HV_VERSION {
+        return None;
+    }
+    // SAFETY: the hypervisor reports the leaf
+    let version = unsafe { __cpuid(HV_CPUID_FUNCTION_MS_HV_VERSION) };
+    Some(HypervisorVersion {
+        build: version.eax,
+        major: (version.ebx >> 16) as u16,
+        minor: version.ebx as u16,
+        service_pack: version.ecx,
+        service_branch: (version.edx >> 24) as u8,
+        service_number: version.edx & 0xff_ffff,
+    })
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn hypervisor_version() -> Option<HypervisorVersion> {
+    None
 }
 
 /// Builder for MSHV Partition
@@ -56,6 +235,8 @@ pub enum SyntheticProcessorFeature {
     TbFlushHypercalls,
     /// HvCallSendSyntheticClusterIpi is supported.
     SyntheticClusterIpi,
+    /// Enlightened VMCS is available to a nested hypervisor in the guest.
+    EnlightenedVmcs,
 }
 
 impl MshvPartitionBuilder {
@@ -86,6 +267,24 @@ impl MshvPartitionBuilder {
         self
     }
 
+    /// Makes the partition x2APIC capable, which guests with more than 255
+    /// vCPUs or APIC IDs above 0xff need
+    pub fn set_x2apic_capable(self) -> MshvPartitionBuilder {
+        self.set_partition_creation_flag(HV_PARTITION_CREATION_FLAG_X2APIC_CAPABLE as u64)
+    }
+
+    /// Exposes the optional arm64 processor features in `features` to the
+    /// vCPUs and disables the others
+    #[cfg(target_arch = "aarch64")]
+    pub fn set_vcpu_features(mut self, features: aarch64::VcpuFeatures) -> MshvPartitionBuilder {
+        self.mshv_partition
+            .partition_creation_properties
+            .disabled_processor_features = hv_partition_processor_features {
+            as_uint64: features.disabled_processor_features(),
+        };
+        self
+    }
+
     /// Set isolation type
     pub fn set_isolation_type(mut self, val: u64) -> MshvPartitionBuilder {
         // SAFETY: Setting a bunch of bitfields. Functions and unions are generated by bindgen
@@ -99,6 +298,18 @@ impl MshvPartitionBuilder {
         self
     }
 
+    /// Sets the isolation properties of partitions of `vm_type`
+    pub fn set_vm_type(self, vm_type: VmType) -> MshvPartitionBuilder {
+        let builder = self.set_isolation_type(vm_type.isolation_type());
+        if vm_type.is_hardware_isolated() {
+            // No virtual top of memory: the guest chooses which pages it
+            // shares with the host
+            builder.set_shared_gpa_boundary_page_number(0_u64)
+        } else {
+            builder
+        }
+    }
+
     /// Set shared GPA boundary page number
     pub fn set_shared_gpa_boundary_page_number(mut self, val: u64) -> MshvPartitionBuilder {
         // SAFETY: Setting a bunch of bitfields. Functions and unions are generated by bindgen
@@ -210,9 +421,43 @@ impl MshvPartitionBuilder {
                     .__bindgen_anon_1
                     .set_synthetic_cluster_ipi(1);
             },
+            SyntheticProcessorFeature::EnlightenedVmcs => unsafe {
+                self.mshv_partition
+                    .synthetic_processor_features
+                    .__bindgen_anon_1
+                    .set_enlightened_vmcs(1);
+            },
         }
         self
     }
+    /// Enables the synthetic processor features Windows needs to use the
+    /// Hyper-V reference time sources: the reference counter, the reference
+    /// TSC page, the TSC frequency registers and the synthetic timers, with
+    /// the SynIC and hypercall registers they depend on. Invariant TSC is
+    /// left enabled so the TSC page stays valid. See
+    /// `VmFd::reference_time_cpuid` for the matching CPUID.
+    pub fn set_reference_time_sources(mut self) -> MshvPartitionBuilder {
+        // SAFETY: access union fields
+        unsafe {
+            self.mshv_partition
+                .partition_creation_properties
+                .disabled_processor_features
+                .__bindgen_anon_1
+                .set_tsc_invariant_support(0);
+        }
+        self.set_synthetic_processor_feature(SyntheticProcessorFeature::HypervisorPresent)
+            .set_synthetic_processor_feature(SyntheticProcessorFeature::Hv1)
+            .set_synthetic_processor_feature(SyntheticProcessorFeature::AccessHypercallRegs)
+            .set_synthetic_processor_feature(SyntheticProcessorFeature::AccessVpIndex)
+            .set_synthetic_processor_feature(
+                SyntheticProcessorFeature::AccessPartitionReferenceCounter,
+            )
+            .set_synthetic_processor_feature(SyntheticProcessorFeature::AccessPartitionReferenceTsc)
+            .set_synthetic_processor_feature(SyntheticProcessorFeature::AccessFrequencyRegs)
+            .set_synthetic_processor_feature(SyntheticProcessorFeature::AccessSynicRegs)
+            .set_synthetic_processor_feature(SyntheticProcessorFeature::AccessSyntheticTimerRegs)
+            .set_synthetic_processor_feature(SyntheticProcessorFeature::DirectSyntheticTimers)
+    }
     /// Builds the partition
     pub fn build(&self) -> mshv_create_partition {
         self.mshv_partition
@@ -243,6 +488,7 @@ impl Mshv {
     pub unsafe fn new_with_fd_number(fd: RawFd) -> Self {
         Mshv {
             hv: File::from_raw_fd(fd),
+            version_info: Mutex::new(None),
         }
     }
 
@@ -252,7 +498,7 @@ impl Mshv {
         // SAFETY: we give a constant null-terminated string and verify the result.
         let ret = unsafe { open("/dev/mshv\0".as_ptr() as *const c_char, open_flags) };
         if ret < 0 {
-            Err(errno::Error::last())
+            Err(errno::Error::last().into())
         } else {
             Ok(ret)
         }
@@ -267,15 +513,17 @@ impl Mshv {
             let vm_file = unsafe { File::from_raw_fd(ret) };
             Ok(new_vmfd(vm_file))
         } else {
-            Err(errno::Error::last())
+            Err(errno::Error::last().into())
         }
     }
 
-    /// Helper function to creates a VM fd using the MSHV fd with provided configuration.
-    pub fn create_vm_with_type(&self, vm_type: VmType) -> Result<VmFd> {
-        let mut mshv_builder = MshvPartitionBuilder::new()
+    /// Builder holding the default configuration of partitions of
+    /// `vm_type`, to be adjusted before `create_vm_with_config` for
+    /// settings specific to the isolation type
+    pub fn partition_builder(vm_type: VmType) -> MshvPartitionBuilder {
+        MshvPartitionBuilder::new()
             .set_partition_creation_flag(HV_PARTITION_CREATION_FLAG_LAPIC_ENABLED as u64)
-            .set_partition_creation_flag(HV_PARTITION_CREATION_FLAG_X2APIC_CAPABLE as u64)
+            .set_x2apic_capable()
             .set_synthetic_processor_feature(SyntheticProcessorFeature::HypervisorPresent)
             .set_synthetic_processor_feature(SyntheticProcessorFeature::Hv1)
             .set_synthetic_processor_feature(
@@ -291,23 +539,90 @@ impl Mshv {
             .set_synthetic_processor_feature(SyntheticProcessorFeature::AccessHypercallRegs)
             .set_synthetic_processor_feature(SyntheticProcessorFeature::AccessGuestIdleReg)
             .set_synthetic_processor_feature(SyntheticProcessorFeature::TbFlushHypercalls)
-            .set_synthetic_processor_feature(SyntheticProcessorFeature::SyntheticClusterIpi);
-
-        if vm_type == VmType::Snp {
-            mshv_builder = mshv_builder
-                .set_isolation_type(HV_PARTITION_ISOLATION_TYPE_SNP as u64)
-                .set_shared_gpa_boundary_page_number(0_u64);
-        }
+            .set_synthetic_processor_feature(SyntheticProcessorFeature::SyntheticClusterIpi)
+            .set_vm_type(vm_type)
+    }
 
-        let partition_config = mshv_builder.build();
+    /// Helper function to creates a VM fd using the MSHV fd with provided configuration.
+    pub fn create_vm_with_type(&self, vm_type: VmType) -> Result<VmFd> {
+        let partition_config = Self::partition_builder(vm_type).build();
         self.create_vm_with_config(&partition_config)
     }
 
+    /// VM types the hypervisor can create partitions of on this host.
+    ///
+    /// The hypervisor does not report the isolation types it supports, so
+    /// this creates, and deletes right away, a partition of each type. A
+    /// type is unsupported if the creation fails with EINVAL or
+    /// EOPNOTSUPP; any other error, such as ENOMEM, is returned as it says
+    /// nothing about the type.
+    pub fn supported_vm_types(&self) -> Result<Vec<VmType>> {
+        let mut vm_types = Vec::new();
+        for vm_type in VmType::ALL {
+            match self.create_vm_with_type(vm_type) {
+                Ok(_) => vm_types.push(vm_type),
+                Err(MshvError::Ioctl(e))
+                    if matches!(e.errno(), libc::EINVAL | libc::EOPNOTSUPP) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(vm_types)
+    }
+
     /// Creates a VM fd using the MSHV fd.
     pub fn create_vm(&self) -> Result<VmFd> {
         self.create_vm_with_type(VmType::Normal)
     }
 
+    /// Returns the kernel, driver and hypervisor versions, and the optional
+    /// ioctls the driver implements.
+    ///
+    /// The driver has no version ioctl, so features are probed on a
+    /// partition and a vCPU created, and deleted right away, for the
+    /// purpose. The probes pass arguments the driver rejects, see
+    /// `VmFd::supports`, and the partition never runs. This is done on the
+    /// first call only, later calls return the same answer.
+    pub fn get_version_info(&self) -> Result<VersionInfo> {
+        let mut version_info = self.version_info.lock().unwrap();
+        if let Some(info) = version_info.as_ref() {
+            return Ok(info.clone());
+        }
+        let vm = self.create_vm()?;
+        let vcpu = vm.create_vcpu(0)?;
+        let features = MshvFeature::ALL
+            .iter()
+            .copied()
+            .filter(|feature| match feature {
+                MshvFeature::GpaReadWrite => probe_ioctl(&vcpu, MSHV_READ_GPA()),
+                MshvFeature::VpCpuidValues => probe_ioctl(&vcpu, MSHV_GET_VP_CPUID_VALUES()),
+                MshvFeature::RegisterPage => {
+                    vcpu.map_state_page(VpStatePageKind::Registers).is_ok()
+                }
+                _ => vm.supports(*feature).unwrap_or(false),
+            })
+            .collect();
+        let info = VersionInfo {
+            kernel_release: kernel_release(),
+            driver_version: driver_version(),
+            hypervisor: hypervisor_version(),
+            features,
+        };
+        *version_info = Some(info.clone());
+        Ok(info)
+    }
+
+    /// Fails with `MshvError::MissingFeature` for the first of `features`
+    /// the driver does not implement, so a VMM can check up front instead
+    /// of running into ENOTTY later on. The features are probed once, see
+    /// `get_version_info`.
+    pub fn require(&self, features: &[MshvFeature]) -> Result<()> {
+        let info = self.get_version_info()?;
+        match features.iter().find(|feature| !info.supports(**feature)) {
+            Some(feature) => Err(MshvError::MissingFeature(*feature)),
+            None => Ok(()),
+        }
+    }
+
     /// X86 specific call to get list of supported MSRS
     pub fn get_msr_index_list(&self) -> Result<MsrList> {
         /* return all the MSRs we currently support */
@@ -387,6 +702,71 @@ mod tests {
         assert!(vm.is_ok());
     }
     #[test]
+    fn test_supported_vm_types() {
+        let hv = Mshv::new().unwrap();
+        let vm_types = hv.supported_vm_types().unwrap();
+        assert_eq!(vm_types[0], VmType::Normal);
+    }
+    #[test]
+    fn test_get_version_info() {
+        let hv = Mshv::new().unwrap();
+        let info = hv.get_version_info().unwrap();
+        assert!(!info.kernel_release.is_empty());
+        assert!(info.supports(MshvFeature::RootHvcall));
+        hv.require(&[MshvFeature::RootHvcall]).unwrap();
+    }
+    #[test]
+    fn test_probe_ioctl() {
+        // /dev/null knows no ioctl
+        let file = File::open("/dev/null").unwrap();
+        assert!(!probe_ioctl(&file, MSHV_ROOT_HVCALL()));
+    }
+    #[test]
+    fn test_feature_names() {
+        assert_eq!(MshvFeature::RootHvcall.to_string(), "MSHV_ROOT_HVCALL");
+        let info = VersionInfo {
+            kernel_release: String::new(),
+            driver_version: None,
+            hypervisor: None,
+            features: vec![MshvFeature::Devices],
+        };
+        assert!(info.supports(MshvFeature::Devices));
+        assert!(!info.supports(MshvFeature::GpaReadWrite));
+        let version = HypervisorVersion {
+            build: 26100,
+            major: 10,
+            minor: 0,
+            ..Default::default()
+        };
+        assert_eq!(version.to_string(), "10.0.26100.0-0.0");
+    }
+    #[test]
+    fn test_set_reference_time_sources() {
+        let mut builder = MshvPartitionBuilder::new();
+        builder
+            .mshv_partition
+            .partition_creation_properties
+            .disabled_processor_features
+            .as_uint64 = [!0; 2];
+        let config = builder.set_reference_time_sources().build();
+        // SAFETY: access union fields
+        unsafe {
+            let features = config.synthetic_processor_features.__bindgen_anon_1;
+            assert!(features.hypervisor_present() == 1);
+            assert!(features.access_partition_reference_counter() == 1);
+            assert!(features.access_partition_reference_tsc() == 1);
+            assert!(features.access_frequency_regs() == 1);
+            assert!(features.access_synthetic_timer_regs() == 1);
+            assert!(features.direct_synthetic_timers() == 1);
+            assert!(features.enlightened_vmcs() == 0);
+            let disabled = config
+                .partition_creation_properties
+                .disabled_processor_features;
+            assert!(disabled.__bindgen_anon_1.tsc_invariant_support() == 0);
+            assert!(disabled.as_uint64[0] == !0);
+        }
+    }
+    #[test]
     #[ignore]
     fn test_create_vm_with_default_config() {
         let pr: mshv_create_partition = Default::default();
@@ -0,0 +1,249 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+//! Running small code blobs in a partition, for hypervisor-backed tests of
+//! this crate and of the crates built on it. They need `/dev/mshv`, and
+//! other crates the `test-utils` feature, usually from their
+//! `[dev-dependencies]`.
+//!
+//! ```no_run
+//! use mshv_bindings::*;
+//! use mshv_ioctls::test_utils::TestVm;
+//!
+//! let mut test = TestVm::new(0x4000).unwrap();
+//! // mov $0x3f8, %dx; mov $'A', %al; out %al, (%dx); hlt
+//! test.load(0x1000, &[0xba, 0xf8, 0x03, 0xb0, b'A', 0xee, 0xf4])
+//!     .unwrap();
+//! test.setup_real_mode(0x1000).unwrap();
+//! let mut out = Vec::new();
+//! test.run_until(hv_message_type_HVMSG_X64_HALT, |test, msg| {
+//!     out.push(msg.to_ioport_info()?.rax as u8);
+//!     test.skip_instruction(msg)
+//! })
+//! .unwrap();
+//! assert!(out == b"A");
+//! ```
+use crate::ioctls::boot;
+use crate::ioctls::system::Mshv;
+use crate::ioctls::vcpu::VcpuFd;
+use crate::ioctls::vm::VmFd;
+use crate::ioctls::Result;
+use mshv_bindings::*;
+use std::ptr;
+use std::slice;
+use vmm_sys_util::errno;
+
+/// Smallest memory `TestVm::setup_long_mode` needs, code goes above it
+pub const LONG_MODE_MIN_MEM_SIZE: usize = 0x1_0000;
+
+/// A partition with one vCPU and read, write and execute memory at GPA 0,
+/// unmapped when dropped.
+#[derive(Debug)]
+pub struct TestVm {
+    /// The partition
+    pub vm: VmFd,
+    /// vCPU 0
+    pub vcpu: VcpuFd,
+    addr: *mut u8,
+    region: mshv_user_mem_region,
+}
+
+impl TestVm {
+    /// Creates the partition with `mem_size` bytes of memory, rounded up
+    /// to whole pages.
+    pub fn new(mem_size: usize) -> Result<TestVm> {
+        let page_size = 1u64 << HV_HYP_PAGE_SHIFT;
+        let size = (mem_size as u64 + page_size - 1) & !(page_size - 1);
+        let vm = Mshv::new()?.create_vm()?;
+        let vcpu = vm.create_vcpu(0)?;
+        // SAFETY: FFI call, the result is checked
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(errno::Error::last().into());
+        }
+        let region = mshv_user_mem_region {
+            flags: HV_MAP_GPA_READABLE | HV_MAP_GPA_WRITABLE | HV_MAP_GPA_EXECUTABLE,
+            guest_pfn: 0,
+            size,
+            userspace_addr: addr as u64,
+        };
+        if let Err(e) = vm.map_user_memory(region) {
+            // SAFETY: addr was mapped above with size
+            unsafe { libc::munmap(addr, size as usize) };
+            return Err(e);
+        }
+        Ok(TestVm {
+            vm,
+            vcpu,
+            addr: addr as *mut u8,
+            region,
+        })
+    }
+    /// The memory region mapped at GPA 0
+    pub fn region(&self) -> mshv_user_mem_region {
+        self.region
+    }
+    /// The guest memory
+    pub fn mem(&self) -> &[u8] {
+        // SAFETY: addr maps region.size bytes for as long as self lives
+        unsafe { slice::from_raw_parts(self.addr, self.region.size as usize) }
+    }
+    /// The guest memory, mutable
+    pub fn mem_mut(&mut self) -> &mut [u8] {
+        // SAFETY: addr maps region.size bytes for as long as self lives
+        unsafe { slice::from_raw_parts_mut(self.addr, self.region.size as usize) }
+    }
+    /// Copies `bytes` to guest memory at `gpa`. Fails with EINVAL if they
+    /// do not fit.
+    pub fn load(&mut self, gpa: u64, bytes: &[u8]) -> Result<()> {
+        let start = gpa as usize;
+        self.mem_mut()
+            .get_mut(start..start.saturating_add(bytes.len()))
+            .ok_or_else(|| errno::Error::new(libc::EINVAL))?
+            .copy_from_slice(bytes);
+        Ok(())
+    }
+    /// Sets the vCPU up to run real-mode code at `entry`, below 64KiB, with
+    /// all segments based at 0 and interrupts disabled.
+    pub fn setup_real_mode(&self, entry: u64) -> Result<()> {
+        let mut sregs = self.vcpu.get_sregs()?;
+        for segment in [
+            &mut sregs.cs,
+            &mut sregs.ds,
+            &mut sregs.es,
+            &mut sregs.fs,
+            &mut sregs.gs,
+            &mut sregs.ss,
+        ] {
+            segment.base = 0;
+            segment.selector = 0;
+        }
+        self.vcpu.set_sregs(&sregs)?;
+        self.vcpu.set_regs(&StandardRegisters {
+            rip: entry,
+            rflags: X86_RFLAGS_FIXED,
+            ..Default::default()
+        })
+    }
+    /// Sets the vCPU up to run 64-bit code at `entry`, as
+    /// `boot::setup_long_mode` does: the first `LONG_MODE_MIN_MEM_SIZE`
+    /// bytes hold the page tables, GDT, IDT and stack, the rest of the
+    /// first 1GiB is identity mapped. Fails with EINVAL if the memory is
+    /// smaller than `LONG_MODE_MIN_MEM_SIZE`.
+    pub fn setup_long_mode(&mut self, entry: u64) -> Result<()> {
+        if self.mem().len() < LONG_MODE_MIN_MEM_SIZE {
+            return Err(errno::Error::new(libc::EINVAL).into());
+        }
+        // SAFETY: addr maps region.size bytes, the vCPU is borrowed
+        // separately from the memory
+        let mem = unsafe { slice::from_raw_parts_mut(self.addr, self.region.size as usize) };
+        boot::setup_long_mode(&self.vcpu, mem, entry, 0)
+    }
+    /// Runs the vCPU until it exits with `message_type` and returns that
+    /// exit. Other exits go to `on_exit`, which must resolve them, for
+    /// instance with `skip_instruction`; its errors stop the run.
+    pub fn run_until<F>(&self, message_type: hv_message_type, mut on_exit: F) -> Result<hv_message>
+    where
+        F: FnMut(&TestVm, &hv_message) -> Result<()>,
+    {
+        loop {
+            let msg = self.vcpu.run(hv_message::default())?;
+            if msg.header.message_type == message_type {
+                return Ok(msg);
+            }
+            on_exit(self, &msg)?;
+        }
+    }
+    /// Moves RIP past the instruction behind an I/O port, MSR or CPUID
+    /// intercept, ignoring its effects. Fails with EINVAL for other exits.
+    pub fn skip_instruction(&self, msg: &hv_message) -> Result<()> {
+        let header = match msg.header.message_type {
+            hv_message_type_HVMSG_X64_IO_PORT_INTERCEPT => msg.to_ioport_info()?.header,
+            hv_message_type_HVMSG_X64_MSR_INTERCEPT => msg.to_msr_info()?.header,
+            hv_message_type_HVMSG_X64_CPUID_INTERCEPT => msg.to_cpuid_info()?.header,
+            _ => return Err(errno::Error::new(libc::EINVAL).into()),
+        };
+        self.vcpu.set_reg(&[hv_register_assoc {
+            name: hv_register_name_HV_X64_REGISTER_RIP,
+            value: hv_register_value {
+                reg64: header.rip + header.instruction_length() as u64,
+            },
+            ..Default::default()
+        }])
+    }
+}
+
+impl Drop for TestVm {
+    fn drop(&mut self) {
+        let _ = self.vm.unmap_user_memory(self.region);
+        // SAFETY: addr was mapped in new with region.size
+        unsafe { libc::munmap(self.addr as *mut libc::c_void, self.region.size as usize) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Collects the bytes written to port 0x3f8 until the guest halts
+    fn serial_output(test: &TestVm) -> Vec<u8> {
+        let mut out = Vec::new();
+        test.run_until(hv_message_type_HVMSG_X64_HALT, |test, msg| {
+            let io = msg.to_ioport_info()?;
+            assert!(io.port_number == 0x3f8);
+            out.push(io.rax as u8);
+            test.skip_instruction(msg)
+        })
+        .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_run_real_mode() {
+        let mut test = TestVm::new(0x4000).unwrap();
+        #[rustfmt::skip]
+        let code = [
+            0xba, 0xf8, 0x03, /* mov $0x3f8, %dx */
+            0x00, 0xd8,       /* add %bl, %al */
+            0x04, b'0',       /* add $'0', %al */
+            0xee,             /* out %al, (%dx) */
+            0xf4,             /* hlt */
+        ];
+        test.load(0x1000, &code).unwrap();
+        assert!(test.load(0x3ffc, &code).is_err());
+        test.setup_real_mode(0x1000).unwrap();
+        let mut regs = test.vcpu.get_regs().unwrap();
+        regs.rax = 2;
+        regs.rbx = 2;
+        test.vcpu.set_regs(&regs).unwrap();
+        assert!(serial_output(&test) == b"4");
+    }
+
+    #[test]
+    fn test_run_long_mode() {
+        let mut test = TestVm::new(0x2_0000).unwrap();
+        #[rustfmt::skip]
+        let code = [
+            0x66, 0xba, 0xf8, 0x03, /* mov $0x3f8, %dx */
+            0x48, 0x89, 0xe0,       /* mov %rsp, %rax */
+            0xee,                   /* out %al, (%dx) */
+            0xf4,                   /* hlt */
+        ];
+        test.load(0x1_0000, &code).unwrap();
+        test.setup_long_mode(0x1_0000).unwrap();
+        assert!(serial_output(&test) == [boot::BOOT_STACK_POINTER as u8]);
+
+        let mut small = TestVm::new(0x4000).unwrap();
+        assert!(small.setup_long_mode(0x1000).is_err());
+    }
+}
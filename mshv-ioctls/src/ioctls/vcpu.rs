@@ -2,16 +2,28 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 //
-use crate::ioctls::Result;
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+use crate::ioctls::device_region::{DeviceMmioExit, DeviceRegions};
+#[cfg(feature = "event-subscribers")]
+use crate::ioctls::eventfd_registry::same_eventfd;
+use crate::ioctls::{metrics, MshvError, Result};
 use crate::mshv_ioctls::*;
 use mshv_bindings::*;
 use std::cmp;
+use std::fmt;
 use std::fs::File;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::ptr;
 #[cfg(test)]
 use std::slice;
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+use std::sync::Arc;
+use std::sync::{Mutex, MutexGuard};
 use vmm_sys_util::errno;
+use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref};
 
 // Macro for setting up multiple 64 bit registers together
@@ -41,10 +53,264 @@ macro_rules! set_registers_64 {
     }};
 }
 
+/// Maximum number of buffers kept in a vCPU buffer pool
+const BUFFER_POOL_MAX_BUFFERS: usize = 8;
+
+/// Page aligned buffers reused across the VP state get/set calls of a vCPU
+#[derive(Default)]
+struct BufferPool {
+    buffers: Mutex<Vec<AlignedBuffer>>,
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let len = self.buffers.lock().map(|b| b.len()).unwrap_or(0);
+        f.debug_struct("BufferPool").field("buffers", &len).finish()
+    }
+}
+
+impl BufferPool {
+    /// Returns a zeroed buffer of `size` bytes, reusing a pooled one if possible
+    fn get(&self, size: usize) -> Result<PooledBuffer<'_>> {
+        let pooled = self.buffers.lock().ok().and_then(|mut buffers| {
+            let pos = buffers.iter().position(|b| b.size() == size)?;
+            Some(buffers.swap_remove(pos))
+        });
+        let buffer = match pooled {
+            Some(mut b) => {
+                b.as_mut_slice().fill(0);
+                b
+            }
+            None => AlignedBuffer::new(size, 0x1000)?,
+        };
+        Ok(PooledBuffer {
+            pool: self,
+            buffer: Some(buffer),
+        })
+    }
+
+    fn put(&self, buffer: AlignedBuffer) {
+        if let Ok(mut buffers) = self.buffers.lock() {
+            if buffers.len() < BUFFER_POOL_MAX_BUFFERS {
+                buffers.push(buffer);
+            }
+        }
+    }
+}
+
+/// Buffer borrowed from a `BufferPool`, given back to the pool when dropped
+struct PooledBuffer<'a> {
+    pool: &'a BufferPool,
+    buffer: Option<AlignedBuffer>,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = AlignedBuffer;
+
+    fn deref(&self) -> &AlignedBuffer {
+        self.buffer.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut AlignedBuffer {
+        self.buffer.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            self.pool.put(buffer);
+        }
+    }
+}
+
+/// Page aligned buffer of a vCPU reused by its VP state calls, allocated
+/// on first use and grown to the largest size asked for. Contents are not
+/// cleared between calls.
+#[derive(Default)]
+struct ScratchBuffer {
+    buffer: Mutex<Option<AlignedBuffer>>,
+}
+
+impl fmt::Debug for ScratchBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let size = match self.buffer.try_lock() {
+            Ok(buffer) => buffer.as_ref().map_or(0, |b| b.size()),
+            Err(_) => 0,
+        };
+        f.debug_struct("ScratchBuffer")
+            .field("size", &size)
+            .finish()
+    }
+}
+
+/// `size` bytes of the scratch buffer of a vCPU, or of a pooled buffer if
+/// the scratch one is in use
+enum ScratchGuard<'a> {
+    Scratch(MutexGuard<'a, Option<AlignedBuffer>>, usize),
+    Pooled(PooledBuffer<'a>),
+}
+
+impl ScratchGuard<'_> {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            // The buffer is allocated before the guard is handed out
+            ScratchGuard::Scratch(buffer, size) => &buffer.as_ref().unwrap().as_slice()[..*size],
+            ScratchGuard::Pooled(buffer) => buffer.as_slice(),
+        }
+    }
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        match self {
+            ScratchGuard::Scratch(buffer, size) => {
+                &mut buffer.as_mut().unwrap().as_mut_slice()[..*size]
+            }
+            ScratchGuard::Pooled(buffer) => buffer.as_mut_slice(),
+        }
+    }
+    fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.as_mut_slice().as_mut_ptr()
+    }
+    fn size(&self) -> usize {
+        self.as_slice().len()
+    }
+    fn zero(&mut self) {
+        self.as_mut_slice().fill(0);
+    }
+}
+
+/// Register list of the MSR calls of a vCPU, kept to reuse its allocation
+#[derive(Default)]
+struct RegisterScratch {
+    regs: Mutex<Vec<hv_register_assoc>>,
+}
+
+impl fmt::Debug for RegisterScratch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let capacity = self.regs.lock().map(|r| r.capacity()).unwrap_or(0);
+        f.debug_struct("RegisterScratch")
+            .field("capacity", &capacity)
+            .finish()
+    }
+}
+
+/// Fills `reg_assocs` with the names of the registers backing `msrs`
+fn msr_reg_assocs(msrs: &[msr_entry], reg_assocs: &mut Vec<hv_register_assoc>) -> Result<()> {
+    reg_assocs.clear();
+    for msr in msrs {
+        let name = match msr_to_hv_reg_name(msr.index) {
+            Ok(n) => n,
+            Err(_) => return Err(MshvError::InvalidArgument { field: "msrs" }),
+        };
+        reg_assocs.push(hv_register_assoc {
+            name,
+            ..Default::default()
+        });
+    }
+    Ok(())
+}
+
+/// Value of HV_X64_MSR_HYPERCALL after the guest writes `value` to it, as
+/// the TLFS specifies: writes to a locked MSR are dropped and the page is
+/// only enabled once the guest has set its OS ID.
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+fn hypercall_msr_write(current: u64, value: u64, guest_os_id: u64) -> Result<u64> {
+    if value & MSR_HYPERCALL_RESERVED_MASK != 0 {
+        return Err(MshvError::InvalidArgument { field: "value" });
+    }
+    if current & MSR_HYPERCALL_LOCKED != 0 {
+        return Ok(current);
+    }
+    if guest_os_id == 0 {
+        return Ok(value & !MSR_HYPERCALL_ACTIVE);
+    }
+    Ok(value)
+}
+
+/// VP state of component `index` of `AllVpStateComponents`, at its offset
+/// in `buffer`. Every component starts on a page boundary, as the
+/// hypervisor requires.
+fn vp_state_component(buffer: &mut ScratchGuard<'_>, index: usize) -> mshv_vp_state {
+    let offset = AllVpStateComponents::component_offset(index);
+    assert!(offset + VP_STATE_COMP_SIZES[index] <= buffer.size());
+    let mut vp_state = mshv_vp_state::default();
+    // SAFETY: the component lies within the buffer
+    vp_state.buf.bytes = unsafe { buffer.as_mut_ptr().add(offset) };
+    vp_state.buf_size = VP_STATE_COMP_SIZES[index] as u64;
+    vp_state.type_ = VP_STATE_COMP_TYPES[index];
+    vp_state
+}
+
+// Whether a VP state component of `type_` and `size` bytes has a slot in
+// `AllVpStateComponents` large enough for it
+fn vp_state_fits(type_: hv_get_set_vp_state_type, size: usize) -> bool {
+    VP_STATE_COMP_TYPES
+        .iter()
+        .position(|known| *known == type_)
+        .is_some_and(|i| size <= VP_STATE_COMP_SIZES[i])
+}
+
+// Types of the `extra` VP state components that also have a slot in
+// `AllVpStateComponents`, which is then left unused
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+fn extra_vp_state_types(extra: &[VpStateComponent]) -> Vec<hv_get_set_vp_state_type> {
+    extra
+        .iter()
+        .map(|component| component.type_)
+        .filter(|type_| VP_STATE_COMP_TYPES.contains(type_))
+        .collect()
+}
+
+/// Merges the (GPA, length) `segments` that follow each other in guest
+/// memory, dropping empty ones. Fails with InvalidArgument if a segment
+/// wraps around the guest address space or the segments do not add up to
+/// `total` bytes.
+fn gpa_runs(segments: &[(u64, usize)], total: usize) -> Result<Vec<(u64, usize)>> {
+    let mut runs: Vec<(u64, usize)> = Vec::new();
+    let mut sum: usize = 0;
+    for &(gpa, len) in segments.iter().filter(|(_, len)| *len != 0) {
+        let end = gpa.checked_add(len as u64);
+        sum = match (end, sum.checked_add(len)) {
+            (Some(_), Some(sum)) => sum,
+            _ => return Err(MshvError::InvalidArgument { field: "segments" }),
+        };
+        match runs.last_mut() {
+            Some((start, run_len)) if *start + *run_len as u64 == gpa => *run_len += len,
+            _ => runs.push((gpa, len)),
+        }
+    }
+    if sum != total {
+        return Err(MshvError::InvalidArgument { field: "data" });
+    }
+    Ok(runs)
+}
+
+/// Replaces the signal mask of the calling thread, returning the old one
+fn set_thread_sigmask(mask: &libc::sigset_t) -> Result<libc::sigset_t> {
+    // SAFETY: sigset_t is plain data, filled in by pthread_sigmask
+    let mut old: libc::sigset_t = unsafe { std::mem::zeroed() };
+    // SAFETY: FFI call with valid sigset_t pointers
+    let ret = unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, mask, &mut old) };
+    if ret != 0 {
+        return Err(errno::Error::new(ret).into());
+    }
+    Ok(old)
+}
+
 #[derive(Debug)]
 /// Wrapper over Mshv vCPU ioctls.
 pub struct VcpuFd {
     vcpu: File,
+    buffers: BufferPool,
+    scratch: ScratchBuffer,
+    msr_regs: RegisterScratch,
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    validate_state: AtomicBool,
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    device_regions: Arc<DeviceRegions>,
+    halt_eventfd: Mutex<Option<EventFd>>,
+    signal_mask: Mutex<Option<libc::sigset_t>>,
 }
 
 /// Helper function to create a new `VcpuFd`.
@@ -53,7 +319,18 @@ pub struct VcpuFd {
 /// `create_vcpu` from `VmFd`. The function cannot be part of the `VcpuFd` implementation because
 /// then it would be exported with the public `VcpuFd` interface.
 pub fn new_vcpu(vcpu: File) -> VcpuFd {
-    VcpuFd { vcpu }
+    VcpuFd {
+        vcpu,
+        buffers: BufferPool::default(),
+        scratch: ScratchBuffer::default(),
+        msr_regs: RegisterScratch::default(),
+        #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+        validate_state: AtomicBool::new(false),
+        #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+        device_regions: Arc::default(),
+        halt_eventfd: Mutex::new(None),
+        signal_mask: Mutex::new(None),
+    }
 }
 
 impl AsRawFd for VcpuFd {
@@ -62,9 +339,36 @@ impl AsRawFd for VcpuFd {
     }
 }
 
+impl FromRawFd for VcpuFd {
+    /// This function is also unsafe as the primitives currently returned have the contract that
+    /// they are the sole owner of the file descriptor they are wrapping. Usage of this function
+    /// could accidentally allow violating this contract which can cause memory unsafety in code
+    /// that relies on it being true.
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        new_vcpu(File::from_raw_fd(fd))
+    }
+}
+
 impl VcpuFd {
+    /// Borrows `size` bytes of the scratch buffer, without clearing them.
+    /// If another call holds the scratch buffer, a zeroed pooled buffer is
+    /// used instead of waiting.
+    fn scratch(&self, size: usize) -> Result<ScratchGuard<'_>> {
+        let mut buffer = match self.scratch.buffer.try_lock() {
+            Ok(buffer) => buffer,
+            Err(_) => return Ok(ScratchGuard::Pooled(self.buffers.get(size)?)),
+        };
+        let too_small = match buffer.as_ref() {
+            Some(b) => b.size() < size,
+            None => true,
+        };
+        if too_small {
+            *buffer = None;
+            *buffer = Some(AlignedBuffer::new(size, 0x1000)?);
+        }
+        Ok(ScratchGuard::Scratch(buffer, size))
+    }
     /// Get the register values by providing an array of register names
-    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
     pub fn get_reg(&self, reg_names: &mut [hv_register_assoc]) -> Result<()> {
         //TODO: Error if input register len is zero
         let mut mshv_vp_register_args = mshv_vp_registers {
@@ -77,7 +381,7 @@ impl VcpuFd {
             ioctl_with_mut_ref(self, MSHV_GET_VP_REGISTERS(), &mut mshv_vp_register_args)
         };
         if ret != 0 {
-            return Err(errno::Error::last());
+            return Err(errno::Error::last().into());
         }
         Ok(())
     }
@@ -87,7 +391,6 @@ impl VcpuFd {
     ///
     /// * `reg_name` - general purpose register name.
     /// * `reg_value` - register value.
-    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
     pub fn set_reg(&self, regs: &[hv_register_assoc]) -> Result<()> {
         let hv_vp_register_args = mshv_vp_registers {
             count: regs.len() as i32,
@@ -96,132 +399,100 @@ impl VcpuFd {
         // SAFETY: IOCTL call with correct types.
         let ret = unsafe { ioctl_with_ref(self, MSHV_SET_VP_REGISTERS(), &hv_vp_register_args) };
         if ret != 0 {
-            return Err(errno::Error::last());
+            return Err(errno::Error::last().into());
         }
         Ok(())
     }
+    /// Reads the registers `names` into a bundle kept on the stack. Write
+    /// one back with `set_reg(bundle.as_slice())`.
+    pub fn get_reg_bundle<const N: usize>(
+        &self,
+        names: [hv_register_name; N],
+    ) -> Result<RegBundle<N>> {
+        let mut regs = RegBundle::new(names);
+        self.get_reg(regs.as_mut_slice())?;
+        Ok(regs)
+    }
+    /// Enables or disables state validation. When enabled, `set_regs` and
+    /// `set_sregs` check the registers before handing them to the hypervisor
+    /// and fail with `MshvError::InvalidRegister` naming the offending
+    /// register and bit. Disabled by default.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn set_state_validation(&self, enable: bool) {
+        self.validate_state.store(enable, Ordering::Relaxed);
+    }
     /// Sets the vCPU general purpose registers
     #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
     pub fn set_regs(&self, regs: &StandardRegisters) -> Result<()> {
-        let reg_assocs = [
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_RAX,
-                value: hv_register_value { reg64: regs.rax },
-                ..Default::default()
-            },
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_RBX,
-                value: hv_register_value { reg64: regs.rbx },
-                ..Default::default()
-            },
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_RCX,
-                value: hv_register_value { reg64: regs.rcx },
-                ..Default::default()
-            },
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_RDX,
-                value: hv_register_value { reg64: regs.rdx },
-                ..Default::default()
-            },
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_RSI,
-                value: hv_register_value { reg64: regs.rsi },
-                ..Default::default()
-            },
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_RDI,
-                value: hv_register_value { reg64: regs.rdi },
-                ..Default::default()
-            },
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_RSP,
-                value: hv_register_value { reg64: regs.rsp },
-                ..Default::default()
-            },
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_RBP,
-                value: hv_register_value { reg64: regs.rbp },
-                ..Default::default()
-            },
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_R8,
-                value: hv_register_value { reg64: regs.r8 },
-                ..Default::default()
-            },
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_R9,
-                value: hv_register_value { reg64: regs.r9 },
-                ..Default::default()
-            },
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_R10,
-                value: hv_register_value { reg64: regs.r10 },
-                ..Default::default()
-            },
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_R11,
-                value: hv_register_value { reg64: regs.r11 },
-                ..Default::default()
-            },
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_R12,
-                value: hv_register_value { reg64: regs.r12 },
-                ..Default::default()
-            },
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_R13,
-                value: hv_register_value { reg64: regs.r13 },
-                ..Default::default()
-            },
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_R14,
-                value: hv_register_value { reg64: regs.r14 },
-                ..Default::default()
-            },
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_R15,
-                value: hv_register_value { reg64: regs.r15 },
-                ..Default::default()
-            },
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_RIP,
-                value: hv_register_value { reg64: regs.rip },
-                ..Default::default()
-            },
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_RFLAGS,
-                value: hv_register_value { reg64: regs.rflags },
-                ..Default::default()
-            },
-        ];
-        self.set_reg(&reg_assocs)?;
-        Ok(())
+        if self.validate_state.load(Ordering::Relaxed) {
+            // Addresses canonical with 48 bits are canonical with 57 bits
+            // too, so CR4 is only read for those that are not
+            regs.validate(X86_CR4_LA57)?;
+            if regs.validate(0).is_err() {
+                let mut cr4 = [hv_register_assoc {
+                    name: hv_register_name_HV_X64_REGISTER_CR4,
+                    ..Default::default()
+                }];
+                self.get_reg(&mut cr4)?;
+                // SAFETY: access union fields
+                regs.validate(unsafe { cr4[0].value.reg64 })?;
+            }
+        }
+        self.set_reg(&regs.to_hv_assocs())
     }
 
     /// Returns the vCPU general purpose registers.
     #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
     pub fn get_regs(&self) -> Result<StandardRegisters> {
-        let reg_names = [
-            hv_register_name_HV_X64_REGISTER_RAX,
-            hv_register_name_HV_X64_REGISTER_RBX,
-            hv_register_name_HV_X64_REGISTER_RCX,
-            hv_register_name_HV_X64_REGISTER_RDX,
-            hv_register_name_HV_X64_REGISTER_RSI,
-            hv_register_name_HV_X64_REGISTER_RDI,
-            hv_register_name_HV_X64_REGISTER_RSP,
-            hv_register_name_HV_X64_REGISTER_RBP,
-            hv_register_name_HV_X64_REGISTER_R8,
-            hv_register_name_HV_X64_REGISTER_R9,
-            hv_register_name_HV_X64_REGISTER_R10,
-            hv_register_name_HV_X64_REGISTER_R11,
-            hv_register_name_HV_X64_REGISTER_R12,
-            hv_register_name_HV_X64_REGISTER_R13,
-            hv_register_name_HV_X64_REGISTER_R14,
-            hv_register_name_HV_X64_REGISTER_R15,
-            hv_register_name_HV_X64_REGISTER_RIP,
-            hv_register_name_HV_X64_REGISTER_RFLAGS,
-        ];
+        let mut reg_assocs: Vec<hv_register_assoc> = StandardRegisters::HV_REGISTER_NAMES
+            .iter()
+            .map(|name| hv_register_assoc {
+                name: *name,
+                ..Default::default()
+            })
+            .collect();
+        self.get_reg(&mut reg_assocs)?;
+        Ok(StandardRegisters::from_hv_assocs(&reg_assocs)?)
+    }
+    /// Sets the vCPU general purpose registers
+    #[cfg(target_arch = "aarch64")]
+    pub fn set_regs(&self, regs: &aarch64::StandardRegisters) -> Result<()> {
+        let mut reg_assocs: Vec<hv_register_assoc> = regs
+            .regs
+            .iter()
+            .enumerate()
+            .map(|(i, val)| hv_register_assoc {
+                name: aarch64::HV_ARM64_REGISTER_X0 + i as hv_register_name,
+                value: hv_register_value { reg64: *val },
+                ..Default::default()
+            })
+            .collect();
+        for (name, val) in [
+            (aarch64::HV_ARM64_REGISTER_SP_EL0, regs.sp_el0),
+            (aarch64::HV_ARM64_REGISTER_SP_EL1, regs.sp_el1),
+            (aarch64::HV_ARM64_REGISTER_PC, regs.pc),
+            (aarch64::HV_ARM64_REGISTER_PSTATE, regs.pstate),
+        ] {
+            reg_assocs.push(hv_register_assoc {
+                name,
+                value: hv_register_value { reg64: val },
+                ..Default::default()
+            });
+        }
+        self.set_reg(&reg_assocs)
+    }
+    /// Returns the vCPU general purpose registers.
+    #[cfg(target_arch = "aarch64")]
+    pub fn get_regs(&self) -> Result<aarch64::StandardRegisters> {
+        let mut reg_names: Vec<hv_register_name> = (0..aarch64::ARM64_GP_REGISTERS_COUNT)
+            .map(|i| aarch64::HV_ARM64_REGISTER_X0 + i as hv_register_name)
+            .collect();
+        reg_names.extend_from_slice(&[
+            aarch64::HV_ARM64_REGISTER_SP_EL0,
+            aarch64::HV_ARM64_REGISTER_SP_EL1,
+            aarch64::HV_ARM64_REGISTER_PC,
+            aarch64::HV_ARM64_REGISTER_PSTATE,
+        ]);
         let mut reg_assocs: Vec<hv_register_assoc> = reg_names
             .iter()
             .map(|name| hv_register_assoc {
@@ -230,54 +501,81 @@ impl VcpuFd {
             })
             .collect();
         self.get_reg(&mut reg_assocs)?;
-        let mut ret_regs = StandardRegisters::default();
+        let mut ret_regs = aarch64::StandardRegisters::default();
+        let n = aarch64::ARM64_GP_REGISTERS_COUNT;
         // SAFETY: access union fields
         unsafe {
-            ret_regs.rax = reg_assocs[0].value.reg64;
-            ret_regs.rbx = reg_assocs[1].value.reg64;
-            ret_regs.rcx = reg_assocs[2].value.reg64;
-            ret_regs.rdx = reg_assocs[3].value.reg64;
-            ret_regs.rsi = reg_assocs[4].value.reg64;
-            ret_regs.rdi = reg_assocs[5].value.reg64;
-            ret_regs.rsp = reg_assocs[6].value.reg64;
-            ret_regs.rbp = reg_assocs[7].value.reg64;
-            ret_regs.r8 = reg_assocs[8].value.reg64;
-            ret_regs.r9 = reg_assocs[9].value.reg64;
-            ret_regs.r10 = reg_assocs[10].value.reg64;
-            ret_regs.r11 = reg_assocs[11].value.reg64;
-            ret_regs.r12 = reg_assocs[12].value.reg64;
-            ret_regs.r13 = reg_assocs[13].value.reg64;
-            ret_regs.r14 = reg_assocs[14].value.reg64;
-            ret_regs.r15 = reg_assocs[15].value.reg64;
-            ret_regs.rip = reg_assocs[16].value.reg64;
-            ret_regs.rflags = reg_assocs[17].value.reg64;
+            for (i, reg) in reg_assocs.iter().take(n).enumerate() {
+                ret_regs.regs[i] = reg.value.reg64;
+            }
+            ret_regs.sp_el0 = reg_assocs[n].value.reg64;
+            ret_regs.sp_el1 = reg_assocs[n + 1].value.reg64;
+            ret_regs.pc = reg_assocs[n + 2].value.reg64;
+            ret_regs.pstate = reg_assocs[n + 3].value.reg64;
         }
 
         Ok(ret_regs)
     }
-    /// Returns the vCPU special registers.
-    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
-    pub fn get_sregs(&self) -> Result<SpecialRegisters> {
-        let reg_names: [::std::os::raw::c_uint; 18] = [
-            hv_register_name_HV_X64_REGISTER_CS,
-            hv_register_name_HV_X64_REGISTER_DS,
-            hv_register_name_HV_X64_REGISTER_ES,
-            hv_register_name_HV_X64_REGISTER_FS,
-            hv_register_name_HV_X64_REGISTER_GS,
-            hv_register_name_HV_X64_REGISTER_SS,
-            hv_register_name_HV_X64_REGISTER_TR,
-            hv_register_name_HV_X64_REGISTER_LDTR,
-            hv_register_name_HV_X64_REGISTER_GDTR,
-            hv_register_name_HV_X64_REGISTER_IDTR,
-            hv_register_name_HV_X64_REGISTER_CR0,
-            hv_register_name_HV_X64_REGISTER_CR2,
-            hv_register_name_HV_X64_REGISTER_CR3,
-            hv_register_name_HV_X64_REGISTER_CR4,
-            hv_register_name_HV_X64_REGISTER_CR8,
-            hv_register_name_HV_X64_REGISTER_EFER,
-            hv_register_name_HV_X64_REGISTER_APIC_BASE,
-            hv_register_name_HV_REGISTER_PENDING_INTERRUPTION,
+    #[cfg(target_arch = "aarch64")]
+    fn sys_registers() -> [hv_register_name; 17] {
+        [
+            aarch64::HV_ARM64_REGISTER_SCTLR_EL1,
+            aarch64::HV_ARM64_REGISTER_CPACR_EL1,
+            aarch64::HV_ARM64_REGISTER_TTBR0_EL1,
+            aarch64::HV_ARM64_REGISTER_TTBR1_EL1,
+            aarch64::HV_ARM64_REGISTER_TCR_EL1,
+            aarch64::HV_ARM64_REGISTER_ESR_EL1,
+            aarch64::HV_ARM64_REGISTER_FAR_EL1,
+            aarch64::HV_ARM64_REGISTER_PAR_EL1,
+            aarch64::HV_ARM64_REGISTER_MAIR_EL1,
+            aarch64::HV_ARM64_REGISTER_VBAR_EL1,
+            aarch64::HV_ARM64_REGISTER_CONTEXTIDR_EL1,
+            aarch64::HV_ARM64_REGISTER_TPIDR_EL1,
+            aarch64::HV_ARM64_REGISTER_CNTKCTL_EL1,
+            aarch64::HV_ARM64_REGISTER_TPIDRRO_EL0,
+            aarch64::HV_ARM64_REGISTER_TPIDR_EL0,
+            aarch64::HV_ARM64_REGISTER_SPSR_EL1,
+            aarch64::HV_ARM64_REGISTER_ELR_EL1,
+        ]
+    }
+    /// Sets the vCPU EL1 system registers
+    #[cfg(target_arch = "aarch64")]
+    pub fn set_sys_regs(&self, sys_regs: &aarch64::SystemRegisters) -> Result<()> {
+        let reg_names = Self::sys_registers();
+        let values = [
+            sys_regs.sctlr_el1,
+            sys_regs.cpacr_el1,
+            sys_regs.ttbr0_el1,
+            sys_regs.ttbr1_el1,
+            sys_regs.tcr_el1,
+            sys_regs.esr_el1,
+            sys_regs.far_el1,
+            sys_regs.par_el1,
+            sys_regs.mair_el1,
+            sys_regs.vbar_el1,
+            sys_regs.contextidr_el1,
+            sys_regs.tpidr_el1,
+            sys_regs.cntkctl_el1,
+            sys_regs.tpidrro_el0,
+            sys_regs.tpidr_el0,
+            sys_regs.spsr_el1,
+            sys_regs.elr_el1,
         ];
+        let reg_assocs: Vec<hv_register_assoc> = reg_names
+            .iter()
+            .zip(values.iter())
+            .map(|(name, val)| hv_register_assoc {
+                name: *name,
+                value: hv_register_value { reg64: *val },
+                ..Default::default()
+            })
+            .collect();
+        self.set_reg(&reg_assocs)
+    }
+    /// Returns the vCPU EL1 system registers
+    #[cfg(target_arch = "aarch64")]
+    pub fn get_sys_regs(&self) -> Result<aarch64::SystemRegisters> {
+        let reg_names = Self::sys_registers();
         let mut reg_assocs: Vec<hv_register_assoc> = reg_names
             .iter()
             .map(|name| hv_register_assoc {
@@ -286,129 +584,323 @@ impl VcpuFd {
             })
             .collect();
         self.get_reg(&mut reg_assocs)?;
-        let mut ret_regs = SpecialRegisters::default();
         // SAFETY: access union fields
-        unsafe {
-            ret_regs.cs = SegmentRegister::from(reg_assocs[0].value.segment);
-            ret_regs.ds = SegmentRegister::from(reg_assocs[1].value.segment);
-            ret_regs.es = SegmentRegister::from(reg_assocs[2].value.segment);
-            ret_regs.fs = SegmentRegister::from(reg_assocs[3].value.segment);
-            ret_regs.gs = SegmentRegister::from(reg_assocs[4].value.segment);
-            ret_regs.ss = SegmentRegister::from(reg_assocs[5].value.segment);
-            ret_regs.tr = SegmentRegister::from(reg_assocs[6].value.segment);
-            ret_regs.ldt = SegmentRegister::from(reg_assocs[7].value.segment);
-            ret_regs.gdt = TableRegister::from(reg_assocs[8].value.table);
-            ret_regs.idt = TableRegister::from(reg_assocs[9].value.table);
-            ret_regs.cr0 = reg_assocs[10].value.reg64;
-            ret_regs.cr2 = reg_assocs[11].value.reg64;
-            ret_regs.cr3 = reg_assocs[12].value.reg64;
-            ret_regs.cr4 = reg_assocs[13].value.reg64;
-            ret_regs.cr8 = reg_assocs[14].value.reg64;
-            ret_regs.efer = reg_assocs[15].value.reg64;
-            ret_regs.apic_base = reg_assocs[16].value.reg64;
-            let pending_reg = reg_assocs[17].value.pending_interruption.as_uint64;
-            if (pending_reg & 0x1) == 1 && // interruption pending
-            (pending_reg >> 1).trailing_zeros() >= 3
-            {
-                // interrupt type external
-                let interrupt_nr = pending_reg >> 16;
-                if interrupt_nr > 255 {
-                    panic!("Invalid interrupt vector number > 255");
-                }
-                // we have a bit array of 4 u64s, so we can split it to get the array index and the
-                // bit index
-                let bit_offset = pending_reg & 0x3F; // 6 bits = 0-63
-                let index = pending_reg >> 6;
-                ret_regs.interrupt_bitmap[index as usize] = 1 << (63 - bit_offset);
-                // shift from the left
+        let ret_regs = unsafe {
+            aarch64::SystemRegisters {
+                sctlr_el1: reg_assocs[0].value.reg64,
+                cpacr_el1: reg_assocs[1].value.reg64,
+                ttbr0_el1: reg_assocs[2].value.reg64,
+                ttbr1_el1: reg_assocs[3].value.reg64,
+                tcr_el1: reg_assocs[4].value.reg64,
+                esr_el1: reg_assocs[5].value.reg64,
+                far_el1: reg_assocs[6].value.reg64,
+                par_el1: reg_assocs[7].value.reg64,
+                mair_el1: reg_assocs[8].value.reg64,
+                vbar_el1: reg_assocs[9].value.reg64,
+                contextidr_el1: reg_assocs[10].value.reg64,
+                tpidr_el1: reg_assocs[11].value.reg64,
+                cntkctl_el1: reg_assocs[12].value.reg64,
+                tpidrro_el0: reg_assocs[13].value.reg64,
+                tpidr_el0: reg_assocs[14].value.reg64,
+                spsr_el1: reg_assocs[15].value.reg64,
+                elr_el1: reg_assocs[16].value.reg64,
             }
         };
 
         Ok(ret_regs)
     }
-    /// Sets the vCPU special registers
-    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
-    pub fn set_sregs(&self, sregs: &SpecialRegisters) -> Result<()> {
-        let reg_names: [hv_register_name; 17] = [
-            hv_register_name_HV_X64_REGISTER_CS,
-            hv_register_name_HV_X64_REGISTER_DS,
-            hv_register_name_HV_X64_REGISTER_ES,
-            hv_register_name_HV_X64_REGISTER_FS,
-            hv_register_name_HV_X64_REGISTER_GS,
-            hv_register_name_HV_X64_REGISTER_SS,
-            hv_register_name_HV_X64_REGISTER_TR,
-            hv_register_name_HV_X64_REGISTER_LDTR,
-            hv_register_name_HV_X64_REGISTER_GDTR,
-            hv_register_name_HV_X64_REGISTER_IDTR,
-            hv_register_name_HV_X64_REGISTER_CR0,
-            hv_register_name_HV_X64_REGISTER_CR2,
-            hv_register_name_HV_X64_REGISTER_CR3,
-            hv_register_name_HV_X64_REGISTER_CR4,
-            hv_register_name_HV_X64_REGISTER_CR8,
-            hv_register_name_HV_X64_REGISTER_EFER,
-            hv_register_name_HV_X64_REGISTER_APIC_BASE,
-        ];
-        let reg_values: [hv_register_value; 17] = [
-            hv_register_value {
-                segment: sregs.cs.into(),
-            },
-            hv_register_value {
-                segment: sregs.ds.into(),
-            },
-            hv_register_value {
-                segment: sregs.es.into(),
-            },
-            hv_register_value {
-                segment: sregs.fs.into(),
-            },
-            hv_register_value {
-                segment: sregs.gs.into(),
-            },
-            hv_register_value {
-                segment: sregs.ss.into(),
-            },
-            hv_register_value {
-                segment: sregs.tr.into(),
-            },
-            hv_register_value {
-                segment: sregs.ldt.into(),
-            },
-            hv_register_value {
-                table: sregs.gdt.into(),
-            },
-            hv_register_value {
-                table: sregs.idt.into(),
+    /// Returns the value of the system register with the given encoding.
+    /// Only the registers of `aarch64::SysReg` can be read; other
+    /// encodings fail with InvalidArgument.
+    #[cfg(target_arch = "aarch64")]
+    pub fn get_sys_reg(&self, encoding: aarch64::SysRegEncoding) -> Result<u64> {
+        let name = match aarch64::sys_reg_to_hv_reg_name(encoding) {
+            Ok(n) => n,
+            Err(_) => return Err(MshvError::InvalidArgument { field: "encoding" }),
+        };
+        let mut reg_assocs = [hv_register_assoc {
+            name,
+            ..Default::default()
+        }];
+        self.get_reg(&mut reg_assocs)?;
+        // SAFETY: access union fields
+        Ok(unsafe { reg_assocs[0].value.reg64 })
+    }
+    /// Sets the value of the system register with the given encoding.
+    /// Only the registers of `aarch64::SysReg` can be written; other
+    /// encodings fail with InvalidArgument.
+    #[cfg(target_arch = "aarch64")]
+    pub fn set_sys_reg(&self, encoding: aarch64::SysRegEncoding, value: u64) -> Result<()> {
+        let name = match aarch64::sys_reg_to_hv_reg_name(encoding) {
+            Ok(n) => n,
+            Err(_) => return Err(MshvError::InvalidArgument { field: "encoding" }),
+        };
+        self.set_reg(&[hv_register_assoc {
+            name,
+            value: hv_register_value { reg64: value },
+            ..Default::default()
+        }])
+    }
+    /// Puts the vCPU in its reset state with affinity `mpidr`, see
+    /// `aarch64::mpidr_for_vcpu`. The boot vCPU then gets its entry point
+    /// with `set_regs(&aarch64::StandardRegisters::boot(..))`; secondary
+    /// vCPUs are started by the guest through PSCI.
+    ///
+    /// PMU and SVE are partition wide, see
+    /// `MshvPartitionBuilder::set_vcpu_features`.
+    #[cfg(target_arch = "aarch64")]
+    pub fn vcpu_init(&self, mpidr: u64) -> Result<()> {
+        self.set_regs(&aarch64::StandardRegisters {
+            pstate: aarch64::PSTATE_RESET,
+            ..Default::default()
+        })?;
+        self.set_sys_regs(&aarch64::SystemRegisters::reset())?;
+        self.set_reg(&[hv_register_assoc {
+            name: aarch64::HV_ARM64_REGISTER_MPIDR_EL1,
+            value: hv_register_value { reg64: mpidr },
+            ..Default::default()
+        }])
+    }
+    /// Turns a hypercall intercept into the PSCI call the guest made, read
+    /// from X0 - X3. Returns None for other SMCCC calls and fails with
+    /// EINVAL for any other exit. Answer with `complete_psci_call`, except
+    /// after CPU_OFF, SYSTEM_OFF and SYSTEM_RESET which do not return.
+    #[cfg(target_arch = "aarch64")]
+    pub fn psci_exit(&self, msg: &hv_message) -> Result<Option<aarch64::PsciCall>> {
+        if msg.header.message_type != hv_message_type_HVMSG_HYPERCALL_INTERCEPT {
+            return Err(errno::Error::new(libc::EINVAL).into());
+        }
+        let mut reg_assocs: Vec<hv_register_assoc> = (0..4)
+            .map(|i| hv_register_assoc {
+                name: aarch64::HV_ARM64_REGISTER_X0 + i,
+                ..Default::default()
+            })
+            .collect();
+        self.get_reg(&mut reg_assocs)?;
+        let mut x = [0u64; 4];
+        for (x, reg) in x.iter_mut().zip(reg_assocs.iter()) {
+            // SAFETY: access union fields
+            *x = unsafe { reg.value.reg64 };
+        }
+        Ok(aarch64::PsciCall::decode(x))
+    }
+    /// Completes a PSCI call: stores `ret`, a PSCI_RET_* code or the
+    /// result of the call, in X0 and moves PC past the HVC or SMC
+    /// instruction.
+    #[cfg(target_arch = "aarch64")]
+    pub fn complete_psci_call(&self, ret: i64) -> Result<()> {
+        let mut pc = [hv_register_assoc {
+            name: aarch64::HV_ARM64_REGISTER_PC,
+            ..Default::default()
+        }];
+        self.get_reg(&mut pc)?;
+        // SAFETY: access union fields
+        let pc = unsafe { pc[0].value.reg64 };
+        self.set_reg(&[
+            hv_register_assoc {
+                name: aarch64::HV_ARM64_REGISTER_X0,
+                value: hv_register_value { reg64: ret as u64 },
+                ..Default::default()
             },
-            hv_register_value { reg64: sregs.cr0 },
-            hv_register_value { reg64: sregs.cr2 },
-            hv_register_value { reg64: sregs.cr3 },
-            hv_register_value { reg64: sregs.cr4 },
-            hv_register_value { reg64: sregs.cr8 },
-            hv_register_value { reg64: sregs.efer },
-            hv_register_value {
-                reg64: sregs.apic_base,
+            hv_register_assoc {
+                name: aarch64::HV_ARM64_REGISTER_PC,
+                value: hv_register_value { reg64: pc + 4 },
+                ..Default::default()
             },
-        ];
+        ])
+    }
+    /// Sets the guest physical address of this vCPU's GIC redistributor
+    #[cfg(target_arch = "aarch64")]
+    pub fn set_gic_redistributor_base(&self, gpa: u64) -> Result<()> {
+        self.set_reg(&[hv_register_assoc {
+            name: aarch64::HV_ARM64_REGISTER_GICR_BASE_GPA,
+            value: hv_register_value { reg64: gpa },
+            ..Default::default()
+        }])
+    }
+    /// Returns the guest physical address of this vCPU's GIC redistributor
+    #[cfg(target_arch = "aarch64")]
+    pub fn get_gic_redistributor_base(&self) -> Result<u64> {
+        let mut reg = [hv_register_assoc {
+            name: aarch64::HV_ARM64_REGISTER_GICR_BASE_GPA,
+            ..Default::default()
+        }];
+        self.get_reg(&mut reg)?;
+        // SAFETY: access union field
+        Ok(unsafe { reg[0].value.reg64 })
+    }
+    /// Returns the vCPU special registers.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn get_sregs(&self) -> Result<SpecialRegisters> {
+        let mut reg_names = SpecialRegisters::HV_REGISTER_NAMES.to_vec();
+        reg_names.push(hv_register_name_HV_REGISTER_PENDING_INTERRUPTION);
+        let mut reg_assocs: Vec<hv_register_assoc> = reg_names
+            .iter()
+            .map(|name| hv_register_assoc {
+                name: *name,
+                ..Default::default()
+            })
+            .collect();
+        self.get_reg(&mut reg_assocs)?;
+        let mut ret_regs = SpecialRegisters::from_hv_assocs(&reg_assocs)?;
+        // SAFETY: access union fields
+        let pending = unsafe { reg_assocs[17].value.pending_interruption.__bindgen_anon_1 };
+        if pending.interruption_pending() == 1
+            && pending.interruption_type() == HV_X64_PENDING_INTERRUPT
+        {
+            // Vectors are 8 bits wide, the upper bits of the field are reserved
+            ret_regs.set_pending_interrupt(Some(pending.interruption_vector() as u8));
+        }
 
-        // TODO support asserting an interrupt using interrupt_bitmap
-        // we can't do this without the vm fd which isn't available here
-        for bits in &sregs.interrupt_bitmap {
-            if *bits != 0 {
-                return Err(errno::Error::new(libc::EINVAL));
+        Ok(ret_regs)
+    }
+    /// Sets the vCPU special registers. An interrupt set in `interrupt_bitmap`
+    /// is queued for injection; at most one may be set.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn set_sregs(&self, sregs: &SpecialRegisters) -> Result<()> {
+        if self.validate_state.load(Ordering::Relaxed) {
+            sregs.validate()?;
+        }
+        let pending = sregs
+            .pending_interrupt()
+            .map_err(|_| MshvError::InvalidArgument {
+                field: "interrupt_bitmap",
+            })?;
+        let mut reg_assocs = sregs.to_hv_assocs().to_vec();
+        if let Some(vector) = pending {
+            // Queue the interrupt for injection, as KVM does on KVM_SET_SREGS
+            let mut pending_reg = hv_x64_pending_interruption_register { as_uint64: 0 };
+            // SAFETY: access union fields
+            unsafe {
+                pending_reg.__bindgen_anon_1.set_interruption_pending(1);
+                pending_reg
+                    .__bindgen_anon_1
+                    .set_interruption_type(HV_X64_PENDING_INTERRUPT);
+                pending_reg
+                    .__bindgen_anon_1
+                    .set_interruption_vector(vector as u32);
             }
+            reg_assocs.push(hv_register_assoc {
+                name: hv_register_name_HV_REGISTER_PENDING_INTERRUPTION,
+                value: hv_register_value {
+                    pending_interruption: pending_reg,
+                },
+                ..Default::default()
+            });
         }
+        self.set_reg(&reg_assocs)
+    }
+    /// Puts the vCPU in the architectural state after RESET: real mode,
+    /// executing at 0xfffffff0. The APIC base is left alone.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn set_reset_state(&self) -> Result<()> {
+        self.set_regs(&StandardRegisters {
+            rip: 0xfff0,
+            rflags: 0x2,
+            ..Default::default()
+        })?;
 
-        let reg_assocs: Vec<hv_register_assoc> = reg_names
-            .iter()
-            .zip(reg_values.iter())
-            .map(|t| hv_register_assoc {
-                name: *t.0,
-                value: *t.1,
+        let data_seg = SegmentRegister {
+            limit: 0xffff,
+            type_: 3,
+            present: 1,
+            s: 1,
+            ..Default::default()
+        };
+        let mut sregs = self.get_sregs()?;
+        sregs.cs = SegmentRegister {
+            base: 0xffff0000,
+            selector: 0xf000,
+            type_: 11,
+            ..data_seg
+        };
+        sregs.ds = data_seg;
+        sregs.es = data_seg;
+        sregs.fs = data_seg;
+        sregs.gs = data_seg;
+        sregs.ss = data_seg;
+        sregs.tr = SegmentRegister {
+            type_: 11,
+            s: 0,
+            ..data_seg
+        };
+        sregs.ldt = SegmentRegister {
+            type_: 2,
+            s: 0,
+            ..data_seg
+        };
+        sregs.gdt = TableRegister {
+            base: 0,
+            limit: 0xffff,
+        };
+        sregs.idt = sregs.gdt;
+        sregs.cr0 = 0x60000010;
+        sregs.cr2 = 0;
+        sregs.cr3 = 0;
+        sregs.cr4 = 0;
+        sregs.cr8 = 0;
+        sregs.efer = 0;
+        sregs.interrupt_bitmap = [0; 4];
+        self.set_sregs(&sregs)?;
+
+        self.set_fpu(&FloatingPointUnit {
+            fcw: 0x37f,
+            mxcsr: 0x1f80,
+            ..Default::default()
+        })?;
+        self.set_debug_regs(&DebugRegisters {
+            dr6: 0xffff0ff0,
+            dr7: 0x400,
+            ..Default::default()
+        })
+    }
+    /// Models an INIT IPI to an application processor: puts the vCPU in the
+    /// state after RESET and makes it wait for a startup IPI, see
+    /// `start_ap`. The vCPU can be run, it does not execute until then.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn init_ap(&self) -> Result<()> {
+        self.set_reset_state()?;
+        self.set_startup_suspend(true)
+    }
+    /// Models a startup IPI with `vector` to an application processor put
+    /// in wait for SIPI state by `init_ap`: the vCPU starts executing in
+    /// real mode at `vector * 0x1000`.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn start_ap(&self, vector: u8) -> Result<()> {
+        let mut sregs = self.get_sregs()?;
+        sregs.cs.selector = (vector as u16) << 8;
+        sregs.cs.base = (vector as u64) << 12;
+        self.set_sregs(&sregs)?;
+        let mut regs = self.get_regs()?;
+        regs.rip = 0;
+        self.set_regs(&regs)?;
+        self.set_startup_suspend(false)
+    }
+    /// Sets the wait for SIPI state of the vCPU. The vCPU is also taken out
+    /// of explicit suspend, the state application processors are created in.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    fn set_startup_suspend(&self, suspended: bool) -> Result<()> {
+        let mut activity = hv_internal_activity_register { as_uint64: 0 };
+        // SAFETY: access union fields
+        unsafe {
+            activity
+                .__bindgen_anon_1
+                .set_startup_suspend(suspended as u64)
+        };
+        self.set_reg(&[
+            hv_register_assoc {
+                name: hv_register_name_HV_REGISTER_INTERNAL_ACTIVITY_STATE,
+                value: hv_register_value {
+                    internal_activity: activity,
+                },
                 ..Default::default()
-            })
-            .collect();
-        self.set_reg(&reg_assocs)?;
-        Ok(())
+            },
+            hv_register_assoc {
+                name: hv_register_name_HV_REGISTER_EXPLICIT_SUSPEND,
+                value: hv_register_value { reg64: 0 },
+                ..Default::default()
+            },
+        ])
     }
 
     #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
@@ -631,63 +1123,465 @@ impl VcpuFd {
     }
     /// Returns the machine-specific registers (MSR) for this vCPU.
     pub fn get_msrs(&self, msrs: &mut Msrs) -> Result<usize> {
-        let nmsrs = msrs.as_fam_struct_ref().nmsrs as usize;
-        let mut reg_assocs: Vec<hv_register_assoc> = Vec::with_capacity(nmsrs);
+        self.get_msrs_into(msrs.as_mut_slice())
+    }
+    /// Setup the model-specific registers (MSR) for this vCPU.
+    /// Returns the number of MSR entries actually written.
+    pub fn set_msrs(&self, msrs: &Msrs) -> Result<usize> {
+        self.set_msrs_from(msrs.as_slice())
+    }
+    /// Reads the MSRs whose indices are in `msrs` into their data, without
+    /// allocating once the vCPU has handled as many MSRs in one call.
+    /// Returns the number of MSRs read.
+    pub fn get_msrs_into(&self, msrs: &mut [msr_entry]) -> Result<usize> {
+        let mut reg_assocs = self.msr_regs.regs.lock().unwrap();
+        msr_reg_assocs(msrs, &mut reg_assocs)?;
+        self.get_reg(&mut reg_assocs)?;
 
-        for i in 0..nmsrs {
-            let name = match msr_to_hv_reg_name(msrs.as_slice()[i].index) {
-                Ok(n) => n,
-                Err(_) => return Err(errno::Error::new(libc::EINVAL)),
-            };
-            reg_assocs.push(hv_register_assoc {
-                name,
-                ..Default::default()
-            });
+        for (msr, reg) in msrs.iter_mut().zip(reg_assocs.iter()) {
+            // SAFETY: access union fields requires unsafe. The values are initialized by get_reg
+            // call.
+            msr.data = unsafe { reg.value.reg64 };
+        }
+        Ok(msrs.len())
+    }
+    /// Writes the MSRs in `msrs`, without allocating once the vCPU has
+    /// handled as many MSRs in one call. Returns the number of MSRs written.
+    pub fn set_msrs_from(&self, msrs: &[msr_entry]) -> Result<usize> {
+        let mut reg_assocs = self.msr_regs.regs.lock().unwrap();
+        msr_reg_assocs(msrs, &mut reg_assocs)?;
+        for (reg, msr) in reg_assocs.iter_mut().zip(msrs.iter()) {
+            reg.value = hv_register_value { reg64: msr.data };
         }
 
+        self.set_reg(&reg_assocs)?;
+        Ok(msrs.len())
+    }
+    ///  Triggers the running of the current virtual CPU returning an exit reason.
+    ///
+    /// The call blocks the calling thread until the vCPU exits. The vCPU fd
+    /// cannot be polled for readiness and MSHV_RUN_VP has no non-blocking
+    /// mode, so this crate offers neither a pollable vCPU fd nor a run
+    /// variant returning WouldBlock: each running vCPU needs its own thread.
+    /// To get control back early, signal that thread: the call then fails
+    /// with EINTR and can be retried.
+    pub fn run(&self, mut hv_message_input: hv_message) -> Result<hv_message> {
+        let mask = *self.signal_mask.lock().unwrap();
+        let old_mask = match mask {
+            Some(mask) => Some(set_thread_sigmask(&mask)?),
+            None => None,
+        };
+        // SAFETY: we know that our file is a vCPU fd and we verify the return result.
+        let ret = unsafe { ioctl_with_mut_ref(self, MSHV_RUN_VP(), &mut hv_message_input) };
+        let err = errno::Error::last();
+        // Restoring cannot fail, pthread_sigmask only rejects an invalid
+        // `how`, and the exit must reach the caller regardless
+        if let Some(old_mask) = old_mask {
+            let _ = set_thread_sigmask(&old_mask);
+        }
+        if ret != 0 {
+            metrics::counter(
+                metrics::METRIC_VCPU_RUN_ERRORS,
+                &[("errno", err.errno() as u64)],
+                1,
+            );
+            return Err(err.into());
+        }
+        let message_type = hv_message_input.header.message_type;
+        metrics::counter(
+            metrics::METRIC_VCPU_EXITS,
+            &[("message_type", message_type as u64)],
+            1,
+        );
+        if message_type == hv_message_type_HVMSG_X64_HALT {
+            // The exit still has to reach the caller, so a failed signal is
+            // only counted
+            if let Some(fd) = self.halt_eventfd.lock().unwrap().as_ref() {
+                if let Err(e) = fd.write(1) {
+                    metrics::counter(
+                        metrics::METRIC_HALT_EVENTFD_ERRORS,
+                        &[("errno", e.raw_os_error().unwrap_or(0) as u64)],
+                        1,
+                    );
+                }
+            }
+        }
+        Ok(hv_message_input)
+    }
+    /// Pins the calling thread, which should be the one calling `run`, to
+    /// the host CPUs in `cpus`.
+    ///
+    /// With the root scheduler the VP runs on the thread that issues
+    /// MSHV_RUN_VP, so this fixes where the vCPU runs. With the hypervisor
+    /// scheduler it only places the thread, and the hypervisor remains free
+    /// to run the VP elsewhere.
+    ///
+    /// This is host thread affinity only. There is no hypervisor-side VP
+    /// affinity or ideal processor hint to go with it: neither the driver
+    /// nor the VP registers expose one, the hypervisor only reports the
+    /// ideal CPU it picked in its VP statistics.
+    pub fn set_affinity(&self, cpus: &[usize]) -> Result<()> {
+        // SAFETY: cpu_set_t is plain data, cleared by CPU_ZERO
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        // SAFETY: FFI call on a valid cpu_set_t
+        unsafe { libc::CPU_ZERO(&mut set) };
+        for &cpu in cpus {
+            if cpu >= libc::CPU_SETSIZE as usize {
+                return Err(MshvError::InvalidArgument { field: "cpus" });
+            }
+            // SAFETY: cpu is within the set
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+        // SAFETY: FFI call with a valid cpu_set_t, the result is checked
+        let ret =
+            unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+        if ret != 0 {
+            return Err(errno::Error::last().into());
+        }
+        Ok(())
+    }
+    /// Returns the host CPUs the calling thread may run on.
+    pub fn affinity(&self) -> Result<Vec<usize>> {
+        // SAFETY: cpu_set_t is plain data, filled in by sched_getaffinity
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        // SAFETY: FFI call with a valid cpu_set_t, the result is checked
+        let ret =
+            unsafe { libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) };
+        if ret != 0 {
+            return Err(errno::Error::last().into());
+        }
+        Ok((0..libc::CPU_SETSIZE as usize)
+            // SAFETY: cpu is within the set
+            .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+            .collect())
+    }
+    /// Sets the signal mask of the calling thread while `run` is in
+    /// MSHV_RUN_VP, or stops changing it with `None`. `blocked` lists the
+    /// signals blocked during the call, the equivalent of
+    /// KVM_SET_SIGNAL_MASK.
+    ///
+    /// The usual pattern keeps the kick signal blocked outside `run` and
+    /// leaves it out of `blocked`, so a kick sent before the vCPU enters the
+    /// guest stays pending until `run`.
+    ///
+    /// Unlike KVM, MSHV_RUN_VP takes no signal mask, so the switch is not
+    /// atomic with the ioctl: `run` sets the mask with pthread_sigmask right
+    /// before the ioctl and restores it right after. A kick that lands
+    /// between the mask switch and the ioctl is taken by the handler without
+    /// interrupting the run. Kickers that must not miss an exit should keep
+    /// signaling until the vCPU thread acknowledges, as `AsyncVcpuFd` does.
+    pub fn set_signal_mask(&self, blocked: Option<&[libc::c_int]>) -> Result<()> {
+        let mask = match blocked {
+            Some(signals) => {
+                // SAFETY: sigset_t is plain data, initialized by sigemptyset
+                let mut mask: libc::sigset_t = unsafe { std::mem::zeroed() };
+                // SAFETY: FFI calls on a valid sigset_t
+                unsafe { libc::sigemptyset(&mut mask) };
+                for signal in signals {
+                    // SAFETY: FFI call on a valid sigset_t, the result is checked
+                    if unsafe { libc::sigaddset(&mut mask, *signal) } != 0 {
+                        return Err(MshvError::InvalidArgument { field: "blocked" });
+                    }
+                }
+                Some(mask)
+            }
+            None => None,
+        };
+        *self.signal_mask.lock().unwrap() = mask;
+        Ok(())
+    }
+    /// Sets an eventfd that `run` signals each time the vCPU exits because
+    /// the guest halted, or clears it with `None`. IO threads can use it to
+    /// stop polling virtqueues while the vCPU idles. A duplicate of `fd` is
+    /// kept. Failing to signal it does not fail `run`, it is counted in the
+    /// `mshv_halt_eventfd_errors_total` metric.
+    pub fn set_halt_eventfd(&self, fd: Option<&EventFd>) -> Result<()> {
+        let fd = match fd {
+            Some(fd) => Some(fd.try_clone().map_err(|_| errno::Error::last())?),
+            None => None,
+        };
+        *self.halt_eventfd.lock().unwrap() = fd;
+        Ok(())
+    }
+    /// Clears the halt eventfd if it is a duplicate of `fd`, leaving one
+    /// set since in place.
+    #[cfg(feature = "event-subscribers")]
+    pub(crate) fn clear_halt_eventfd(&self, fd: &EventFd) {
+        let mut halt_eventfd = self.halt_eventfd.lock().unwrap();
+        if halt_eventfd
+            .as_ref()
+            .is_some_and(|halt_fd| same_eventfd(halt_fd, fd))
+        {
+            *halt_eventfd = None;
+        }
+    }
+    /// Returns the crash report last written by the guest to the crash MSRs
+    pub fn get_guest_crash(&self) -> Result<GuestCrash> {
+        let mut reg_assocs: Vec<hv_register_assoc> = [
+            hv_register_name_HV_REGISTER_GUEST_CRASH_P0,
+            hv_register_name_HV_REGISTER_GUEST_CRASH_P1,
+            hv_register_name_HV_REGISTER_GUEST_CRASH_P2,
+            hv_register_name_HV_REGISTER_GUEST_CRASH_P3,
+            hv_register_name_HV_REGISTER_GUEST_CRASH_P4,
+            hv_register_name_HV_REGISTER_GUEST_CRASH_CTL,
+        ]
+        .iter()
+        .map(|name| hv_register_assoc {
+            name: *name,
+            ..Default::default()
+        })
+        .collect();
+        self.get_reg(&mut reg_assocs)?;
+        let mut ret = GuestCrash::default();
+        // SAFETY: access union fields
+        unsafe {
+            for (param, reg) in ret.params.iter_mut().zip(reg_assocs.iter()) {
+                *param = reg.value.reg64;
+            }
+            ret.ctl = reg_assocs[5].value.reg64;
+        }
+        Ok(ret)
+    }
+    /// Turns an exit for a guest write to HV_X64_MSR_CRASH_CTL, see
+    /// `VmFd::install_guest_crash_intercept`, into the reported crash. Fails
+    /// with EINVAL for any other exit.
+    pub fn guest_crash_exit(&self, msg: &hv_message) -> Result<GuestCrash> {
+        let ctl = msg.to_guest_crash_ctl()?;
+        let mut ret = self.get_guest_crash()?;
+        // The intercepted write has not reached the register yet
+        ret.ctl = ctl;
+        Ok(ret)
+    }
+    /// Turns an exit that stops the guest into its reason: a triple fault,
+    /// an HvCallEnterSleepState hypercall or a crash report, see
+    /// `VmFd::install_shutdown_intercepts`. Fails with EINVAL for any other
+    /// exit. The sleep state hypercall is left pending, the VMM completes it
+    /// with `complete_hypercall` if it resumes the vCPU.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn shutdown_exit(&self, msg: &hv_message) -> Result<ShutdownReason> {
+        let message_type = msg.header.message_type;
+        if message_type == hv_message_type_HVMSG_UNRECOVERABLE_EXCEPTION {
+            return Ok(ShutdownReason::TripleFault);
+        }
+        if message_type == hv_message_type_HVMSG_X64_MSR_INTERCEPT {
+            return Ok(ShutdownReason::Crash(self.guest_crash_exit(msg)?));
+        }
+        let exit = HypercallExit::from_intercept(&msg.to_hypercall_intercept_info()?);
+        if exit.code as u32 != HVCALL_ENTER_SLEEP_STATE {
+            return Err(errno::Error::new(libc::EINVAL).into());
+        }
+        let sleep_state = if exit.fast {
+            exit.input as u32
+        } else {
+            let mut input = [0u8; 4];
+            self.access_gpa(exit.input, &mut input, false)?;
+            u32::from_le_bytes(input)
+        };
+        if sleep_state == hv_sleep_state_HV_SLEEP_STATE_S5 {
+            Ok(ShutdownReason::PowerOff)
+        } else {
+            Ok(ShutdownReason::SleepState(sleep_state))
+        }
+    }
+    /// Turns an exit after which the partition is reset or torn down into a
+    /// `PowerExit`, see `hv_message::to_reset_exit` and `shutdown_exit`.
+    /// Fails with EINVAL for any other exit.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn power_exit(&self, msg: &hv_message) -> Result<PowerExit> {
+        if let Ok(source) = msg.to_reset_exit() {
+            return Ok(PowerExit::Reset(source));
+        }
+        Ok(PowerExit::Shutdown(self.shutdown_exit(msg)?))
+    }
+    /// Shares the device regions of the VM the vCPU belongs to.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub(crate) fn set_device_regions(&mut self, regions: Arc<DeviceRegions>) {
+        self.device_regions = regions;
+    }
+    /// Tags a memory intercept in a region registered with
+    /// `VmFd::register_device_region` with the cookie of the region, so the
+    /// VMM can dispatch it without looking the GPA up. Returns None for
+    /// accesses outside device regions and fails with EINVAL for exits
+    /// that are not memory intercepts.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn device_mmio_exit(&self, msg: &hv_message) -> Result<Option<DeviceMmioExit>> {
+        let info = msg.to_memory_info()?;
+        let gpa = info.guest_physical_address;
+        Ok(self
+            .device_regions
+            .lookup(gpa)
+            .map(|region| DeviceMmioExit {
+                cookie: region.cookie,
+                gpa,
+                offset: gpa - region.gpa,
+                is_write: info.header.intercept_access_type == HV_INTERCEPT_ACCESS_WRITE as u8,
+            }))
+    }
+    /// Returns the APIC ID of the vCPU, which need not match its VP index.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn get_apic_id(&self) -> Result<u32> {
+        let mut reg_assocs = [hv_register_assoc {
+            name: hv_register_name_HV_X64_REGISTER_APIC_ID,
+            ..Default::default()
+        }];
+        self.get_reg(&mut reg_assocs)?;
+        // SAFETY: access union fields
+        Ok(unsafe { reg_assocs[0].value.reg32 })
+    }
+    /// Sets the APIC ID of the vCPU, along with the initial APIC ID reported
+    /// by CPUID. Must be done before the vCPU first runs. IDs above 0xff
+    /// need an x2APIC capable partition.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn set_apic_id(&self, apic_id: u32) -> Result<()> {
+        self.set_reg(&[
+            hv_register_assoc {
+                name: hv_register_name_HV_X64_REGISTER_INITIAL_APIC_ID,
+                value: hv_register_value {
+                    reg64: apic_id as u64,
+                },
+                ..Default::default()
+            },
+            hv_register_assoc {
+                name: hv_register_name_HV_X64_REGISTER_APIC_ID,
+                value: hv_register_value {
+                    reg64: apic_id as u64,
+                },
+                ..Default::default()
+            },
+        ])
+    }
+    /// Returns the GPA of the VP assist page, or `None` if it is disabled.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn get_vp_assist_page(&self) -> Result<Option<u64>> {
+        let mut reg_assocs = [hv_register_assoc {
+            name: hv_register_name_HV_REGISTER_VP_ASSIST_PAGE,
+            ..Default::default()
+        }];
+        self.get_reg(&mut reg_assocs)?;
+        // SAFETY: access union fields
+        let val = unsafe { reg_assocs[0].value.reg64 };
+        if val & HV_X64_MSR_VP_ASSIST_PAGE_ENABLE == 0 {
+            return Ok(None);
+        }
+        Ok(Some(val & HV_X64_MSR_VP_ASSIST_PAGE_ADDR_MASK))
+    }
+    /// Enables the VP assist page at `gpa`, or disables it if `None`.
+    ///
+    /// With the page enabled the hypervisor uses its APIC assist dword to let
+    /// the guest skip the EOI write for interrupts that don't need one (lazy
+    /// EOI), which saves an APIC access per interrupt. The partition must have
+    /// `SyntheticProcessorFeature::AccessIntrCtrlRegs` for this to work.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn set_vp_assist_page(&self, gpa: Option<u64>) -> Result<()> {
+        let val = match gpa {
+            Some(gpa) if gpa & !HV_X64_MSR_VP_ASSIST_PAGE_ADDR_MASK != 0 => {
+                return Err(MshvError::InvalidArgument { field: "gpa" });
+            }
+            Some(gpa) => gpa | HV_X64_MSR_VP_ASSIST_PAGE_ENABLE,
+            None => 0,
+        };
+        self.set_reg(&[hv_register_assoc {
+            name: hv_register_name_HV_REGISTER_VP_ASSIST_PAGE,
+            value: hv_register_value { reg64: val },
+            ..Default::default()
+        }])
+    }
+    /// Returns the guest OS ID, 0 until the guest identifies itself.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn get_guest_os_id(&self) -> Result<u64> {
+        let mut reg_assocs = [hv_register_assoc {
+            name: hv_register_name_HV_REGISTER_GUEST_OS_ID,
+            ..Default::default()
+        }];
         self.get_reg(&mut reg_assocs)?;
-
-        for (i, reg) in reg_assocs.iter().enumerate().take(nmsrs) {
-            // SAFETY: access union fields requires unsafe. The values are initialized by get_reg
-            // call.
-            unsafe {
-                msrs.as_mut_slice()[i].data = reg.value.reg64;
+        // SAFETY: access union fields
+        Ok(unsafe { reg_assocs[0].value.reg64 })
+    }
+    /// Sets the guest OS ID. Clearing it disables the hypercall page, as a
+    /// guest write of 0 to HV_X64_MSR_GUEST_OS_ID does.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn set_guest_os_id(&self, id: u64) -> Result<()> {
+        let mut reg_assocs = vec![hv_register_assoc {
+            name: hv_register_name_HV_REGISTER_GUEST_OS_ID,
+            value: hv_register_value { reg64: id },
+            ..Default::default()
+        }];
+        if id == 0 {
+            let hypercall = self.get_hypercall_msr()?;
+            if hypercall & MSR_HYPERCALL_ACTIVE != 0 {
+                reg_assocs.push(hv_register_assoc {
+                    name: hv_register_name_HV_X64_REGISTER_HYPERCALL,
+                    value: hv_register_value {
+                        reg64: hypercall & !MSR_HYPERCALL_ACTIVE,
+                    },
+                    ..Default::default()
+                });
             }
         }
-
-        Ok(nmsrs)
+        self.set_reg(&reg_assocs)
     }
-    /// Setup the model-specific registers (MSR) for this vCPU.
-    /// Returns the number of MSR entries actually written.
-    pub fn set_msrs(&self, msrs: &Msrs) -> Result<usize> {
-        let nmsrs = msrs.as_fam_struct_ref().nmsrs as usize;
-        let mut reg_assocs: Vec<hv_register_assoc> = Vec::with_capacity(nmsrs);
-
-        for i in 0..nmsrs {
-            let name = match msr_to_hv_reg_name(msrs.as_slice()[i].index) {
-                Ok(n) => n,
-                Err(_) => return Err(errno::Error::new(libc::EINVAL)),
-            };
-            reg_assocs.push(hv_register_assoc {
-                name,
-                value: hv_register_value {
-                    reg64: msrs.as_slice()[i].data,
-                },
-                ..Default::default()
-            });
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    fn get_hypercall_msr(&self) -> Result<u64> {
+        let mut reg_assocs = [hv_register_assoc {
+            name: hv_register_name_HV_X64_REGISTER_HYPERCALL,
+            ..Default::default()
+        }];
+        self.get_reg(&mut reg_assocs)?;
+        // SAFETY: access union fields
+        Ok(unsafe { reg_assocs[0].value.reg64 })
+    }
+    /// Writes `value` to HV_X64_MSR_HYPERCALL as if the guest did, so that
+    /// the hypervisor overlays its hypercall code page at the GPA in
+    /// `value` when the enable bit is set. Writes to a locked MSR are
+    /// ignored, as is the enable bit while the guest OS ID is 0. Fails with
+    /// `InvalidArgument` if reserved bits are set.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn write_hypercall_msr(&self, value: u64) -> Result<()> {
+        let current = self.get_hypercall_msr()?;
+        let new = hypercall_msr_write(current, value, self.get_guest_os_id()?)?;
+        if new == current {
+            return Ok(());
         }
-
-        self.set_reg(&reg_assocs)?;
-        Ok(0_usize)
+        self.set_reg(&[hv_register_assoc {
+            name: hv_register_name_HV_X64_REGISTER_HYPERCALL,
+            value: hv_register_value { reg64: new },
+            ..Default::default()
+        }])
     }
-    ///  Triggers the running of the current virtual CPU returning an exit reason.
-    pub fn run(&self, mut hv_message_input: hv_message) -> Result<hv_message> {
-        // SAFETY: we know that our file is a vCPU fd and we verify the return result.
-        let ret = unsafe { ioctl_with_mut_ref(self, MSHV_RUN_VP(), &mut hv_message_input) };
-        if ret != 0 {
-            return Err(errno::Error::last());
+    /// Returns the GPA of the hypercall page, or `None` if it is disabled.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn get_hypercall_page(&self) -> Result<Option<u64>> {
+        let val = self.get_hypercall_msr()?;
+        if val & MSR_HYPERCALL_ACTIVE == 0 {
+            return Ok(None);
         }
-        Ok(hv_message_input)
+        Ok(Some(val & MSR_HYPERCALL_ADDR_MASK))
+    }
+    /// Handles an exit for a guest write to HV_X64_MSR_GUEST_OS_ID or
+    /// HV_X64_MSR_HYPERCALL, for VMMs intercepting them with
+    /// `InterceptPolicy::intercept_msrs`: applies the write as
+    /// `set_guest_os_id` or `write_hypercall_msr` do and moves RIP past the
+    /// WRMSR. Fails with EINVAL for any other exit, and with
+    /// `InvalidArgument` for writes the guest should get a #GP for.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn hypercall_msr_exit(&self, msg: &hv_message) -> Result<()> {
+        let info = msg.to_msr_info()?;
+        if info.header.intercept_access_type != HV_INTERCEPT_ACCESS_WRITE as u8 {
+            return Err(errno::Error::new(libc::EINVAL).into());
+        }
+        let value = (info.rdx << 32) | (info.rax & 0xffff_ffff);
+        match info.msr_number {
+            HV_X64_MSR_GUEST_OS_ID => self.set_guest_os_id(value)?,
+            HV_X64_MSR_HYPERCALL => self.write_hypercall_msr(value)?,
+            _ => return Err(errno::Error::new(libc::EINVAL).into()),
+        }
+        self.set_reg(&[hv_register_assoc {
+            name: hv_register_name_HV_X64_REGISTER_RIP,
+            value: hv_register_value {
+                reg64: info.header.rip + info.header.instruction_length() as u64,
+            },
+            ..Default::default()
+        }])
     }
     /// Returns currently pending exceptions, interrupts, and NMIs as well as related
     /// states of the vcpu.
@@ -763,6 +1657,25 @@ impl VcpuFd {
         self.set_reg(&reg_assocs)?;
         Ok(())
     }
+    /// Queues a machine check exception for the vCPU, delivered on its next
+    /// run. The guest learns about the error by reading the MSRs `mce`
+    /// covers, see `MceInfo`. Those reads only reach the VMM if the
+    /// partition intercepts them, which
+    /// `InterceptPolicy::intercept_machine_checks` sets up. Fails with
+    /// InvalidArgument if MCi_STATUS does not hold a valid error.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn inject_machine_check(&self, mce: &MceInfo) -> Result<()> {
+        if mce.status & MCI_STATUS_VAL == 0 {
+            return Err(MshvError::InvalidArgument { field: "status" });
+        }
+        self.set_reg(&[hv_register_assoc {
+            name: hv_register_name_HV_REGISTER_PENDING_INTERRUPTION,
+            value: hv_register_value {
+                pending_interruption: mce.pending_interruption(),
+            },
+            ..Default::default()
+        }])
+    }
     /// X86 specific call that returns the vcpu's current "xcrs".
     pub fn get_xcrs(&self) -> Result<Xcrs> {
         let mut reg_assocs: [hv_register_assoc; 1] = [hv_register_assoc {
@@ -815,12 +1728,40 @@ impl VcpuFd {
             ..Default::default()
         }])
     }
+    /// Returns IA32_MTRR_DEF_TYPE and all the fixed and variable range MTRRs
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn get_mtrrs(&self) -> Result<MtrrState> {
+        let mut msrs = MtrrState::default().to_msrs();
+        let mut reg_assocs = Vec::with_capacity(msrs.len());
+        msr_reg_assocs(&msrs, &mut reg_assocs)?;
+        self.get_reg(&mut reg_assocs)?;
+        for (msr, reg) in msrs.iter_mut().zip(reg_assocs.iter()) {
+            // SAFETY: access union fields
+            msr.data = unsafe { reg.value.reg64 };
+        }
+        Ok(MtrrState::from_msrs(&msrs))
+    }
+    /// Sets all the MTRRs at once. Fails with `InvalidArgument` if `mtrrs`
+    /// does not pass `MtrrState::validate`.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn set_mtrrs(&self, mtrrs: &MtrrState) -> Result<()> {
+        mtrrs
+            .validate()
+            .map_err(|_| MshvError::InvalidArgument { field: "mtrrs" })?;
+        let msrs = mtrrs.to_msrs();
+        let mut reg_assocs = Vec::with_capacity(msrs.len());
+        msr_reg_assocs(&msrs, &mut reg_assocs)?;
+        for (reg, msr) in reg_assocs.iter_mut().zip(msrs.iter()) {
+            reg.value.reg64 = msr.data;
+        }
+        self.set_reg(&reg_assocs)
+    }
     /// Returns the VCpu state. This IOCTLs can be used to get XSave and LAPIC state.
     pub fn get_vp_state_ioctl(&self, state: &mut mshv_vp_state) -> Result<()> {
         // SAFETY: we know that our file is a vCPU fd and we verify the return result.
         let ret = unsafe { ioctl_with_mut_ref(self, MSHV_GET_VP_STATE(), state) };
         if ret != 0 {
-            return Err(errno::Error::last());
+            return Err(errno::Error::last().into());
         }
         Ok(())
     }
@@ -830,55 +1771,329 @@ impl VcpuFd {
         // SAFETY: IOCTL call with correct types
         let ret = unsafe { ioctl_with_ref(self, MSHV_SET_VP_STATE(), state) };
         if ret != 0 {
-            return Err(errno::Error::last());
+            return Err(errno::Error::last().into());
         }
         Ok(())
     }
-    /// Get the state of the LAPIC (Local Advanced Programmable Interrupt Controller).
+    /// X86 specific call that returns the vcpu's IA32_APIC_BASE.
+    pub fn get_apic_base(&self) -> Result<ApicBase> {
+        let mut reg_assocs: [hv_register_assoc; 1] = [hv_register_assoc {
+            name: hv_register_name_HV_X64_REGISTER_APIC_BASE,
+            ..Default::default()
+        }];
+        self.get_reg(&mut reg_assocs)?;
+
+        // SAFETY: access union fields
+        Ok(ApicBase::from(unsafe { reg_assocs[0].value.reg64 }))
+    }
+    /// Get the state of the LAPIC (Local Advanced Programmable Interrupt Controller),
+    /// in the layout of the APIC mode the vCPU is currently in. This reads
+    /// IA32_APIC_BASE first; use `get_lapic_in` if the mode is known.
     pub fn get_lapic(&self) -> Result<LapicState> {
-        let buffer = Buffer::new(0x1000, 0x1000)?;
+        self.get_lapic_in(self.get_apic_base()?.mode())
+    }
+    /// Get the state of the LAPIC, in the layout of APIC `mode`
+    pub fn get_lapic_in(&self, mode: ApicMode) -> Result<LapicState> {
+        let mut buffer = self.scratch(0x1000)?;
         let mut vp_state: mshv_vp_state = mshv_vp_state::default();
-        vp_state.buf.bytes = buffer.buf;
+        vp_state.buf.bytes = buffer.as_mut_ptr();
         vp_state.buf_size = buffer.size() as u64;
         vp_state.type_ =
             hv_get_set_vp_state_type_HV_GET_SET_VP_STATE_LOCAL_INTERRUPT_CONTROLLER_STATE;
 
         self.get_vp_state_ioctl(&mut vp_state)?;
-        Ok(LapicState::from(vp_state))
+        // SAFETY: the buffer is a page, larger than hv_local_interrupt_controller_state
+        let hv_state = unsafe {
+            ptr::read_unaligned(buffer.as_mut_ptr() as *const hv_local_interrupt_controller_state)
+        };
+        Ok(LapicState::from_hv_state(&hv_state, mode))
     }
     /// Sets the state of the LAPIC (Local Advanced Programmable Interrupt Controller).
+    /// IA32_APIC_BASE must already hold the value the state was saved with,
+    /// so restore the MSRs or special registers first. This reads
+    /// IA32_APIC_BASE first; use `set_lapic_in` if the mode is known.
     pub fn set_lapic(&self, lapic_state: &LapicState) -> Result<()> {
-        let mut vp_state: mshv_vp_state = mshv_vp_state::from(*lapic_state);
-        let buffer = Buffer::new(0x1000, 0x1000)?;
-        let min: usize = cmp::min(buffer.size(), vp_state.buf_size as usize);
-        // SAFETY: src and dest are valid and properly aligned
-        unsafe { ptr::copy(vp_state.buf.bytes, buffer.buf, min) };
+        self.set_lapic_in(lapic_state, self.get_apic_base()?.mode())
+    }
+    /// Sets the state of the LAPIC, laid out for APIC `mode`
+    pub fn set_lapic_in(&self, lapic_state: &LapicState, mode: ApicMode) -> Result<()> {
+        let mut buffer = self.scratch(0x1000)?;
+        buffer.zero();
+        // SAFETY: the buffer is a page, larger than hv_local_interrupt_controller_state
+        unsafe {
+            ptr::write_unaligned(
+                buffer.as_mut_ptr() as *mut hv_local_interrupt_controller_state,
+                lapic_state.to_hv_state(mode),
+            )
+        };
+        let mut vp_state: mshv_vp_state = mshv_vp_state::default();
+        vp_state.buf.bytes = buffer.as_mut_ptr();
         vp_state.buf_size = buffer.size() as u64;
-        vp_state.buf.bytes = buffer.buf;
+        vp_state.type_ =
+            hv_get_set_vp_state_type_HV_GET_SET_VP_STATE_LOCAL_INTERRUPT_CONTROLLER_STATE;
         self.set_vp_state_ioctl(&vp_state)
     }
     /// Returns the xsave data
     pub fn get_xsave(&self) -> Result<XSave> {
-        let buffer = Buffer::new(0x1000, 0x1000)?;
+        self.with_xsave(|xsave| xsave.to_xsave())
+    }
+    /// Calls `f` with a view of the xsave data borrowed from the hypercall
+    /// buffer, which saves copying the whole area to read a few fields.
+    pub fn with_xsave<R, F: FnOnce(&XSaveView<'_>) -> R>(&self, f: F) -> Result<R> {
+        let mut buffer = self.scratch(0x1000)?;
         let mut vp_state: mshv_vp_state = mshv_vp_state::default();
-        vp_state.buf.bytes = buffer.buf;
+        vp_state.buf.bytes = buffer.as_mut_ptr();
         vp_state.buf_size = buffer.size() as u64;
         vp_state.type_ = hv_get_set_vp_state_type_HV_GET_SET_VP_STATE_XSAVE;
         self.get_vp_state_ioctl(&mut vp_state)?;
-        let ret = XSave::from(vp_state);
-        Ok(ret)
+        let len = cmp::min(vp_state.buf_size as usize, buffer.size());
+        let data = &buffer.as_slice()[..len];
+        // SAFETY: access union field
+        let states = unsafe { vp_state.xsave.states.as_uint64 };
+        Ok(f(&XSaveView::new(vp_state.xsave.flags, states, data)))
     }
     /// Set the xsave data
     pub fn set_xsave(&self, data: &XSave) -> Result<()> {
         let mut vp_state: mshv_vp_state = mshv_vp_state::from(*data);
-        let buffer = Buffer::new(0x1000, 0x1000)?;
+        let mut buffer = self.scratch(0x1000)?;
+        buffer.zero();
         let min: usize = cmp::min(buffer.size(), vp_state.buf_size as usize);
         // SAFETY: src and dest are valid and properly aligned
-        unsafe { ptr::copy(data.buffer.as_ptr().offset(24) as *mut u8, buffer.buf, min) };
+        unsafe {
+            ptr::copy(
+                data.buffer.as_ptr().offset(24) as *mut u8,
+                buffer.as_mut_ptr(),
+                min,
+            )
+        };
+        vp_state.buf_size = buffer.size() as u64;
+        vp_state.buf.bytes = buffer.as_mut_ptr();
+        self.set_vp_state_ioctl(&vp_state)
+    }
+    /// Returns the state of the synthetic timers
+    pub fn get_synthetic_timers(&self) -> Result<SyntheticTimersState> {
+        let mut buffer = self.scratch(0x1000)?;
+        let mut vp_state: mshv_vp_state = mshv_vp_state::default();
+        vp_state.buf.bytes = buffer.as_mut_ptr();
+        vp_state.buf_size = buffer.size() as u64;
+        vp_state.type_ = hv_get_set_vp_state_type_HV_GET_SET_VP_STATE_SYNTHETIC_TIMERS;
+        self.get_vp_state_ioctl(&mut vp_state)?;
+        // SAFETY: the buffer is a page, larger than hv_synthetic_timers_state
+        let state =
+            unsafe { ptr::read_unaligned(buffer.as_mut_ptr() as *const hv_synthetic_timers_state) };
+        Ok(SyntheticTimersState::from(state))
+    }
+    /// Sets the state of the synthetic timers
+    pub fn set_synthetic_timers(&self, state: &SyntheticTimersState) -> Result<()> {
+        let mut buffer = self.scratch(0x1000)?;
+        buffer.zero();
+        // SAFETY: the buffer is a page, larger than hv_synthetic_timers_state
+        unsafe {
+            ptr::write_unaligned(
+                buffer.as_mut_ptr() as *mut hv_synthetic_timers_state,
+                hv_synthetic_timers_state::from(*state),
+            )
+        };
+        let mut vp_state: mshv_vp_state = mshv_vp_state::default();
+        vp_state.buf.bytes = buffer.as_mut_ptr();
+        vp_state.buf_size = buffer.size() as u64;
+        vp_state.type_ = hv_get_set_vp_state_type_HV_GET_SET_VP_STATE_SYNTHETIC_TIMERS;
+        self.set_vp_state_ioctl(&vp_state)
+    }
+    /// Retrieves every VP state component (LAPIC, XSave, SIM page, SIEF page and
+    /// synthetic timers) into `states`.
+    ///
+    /// This issues one MSHV_GET_VP_STATE per component: the driver has no
+    /// call that reads several, so the components cannot be read in a
+    /// single ioctl. They are read into the scratch buffer laid out like
+    /// `states`, which is then copied once. Fails if the kernel reports a
+    /// component larger than its slot; `save_state` keeps such components
+    /// whole.
+    pub fn get_all_vp_state_components(&self, states: &mut AllVpStateComponents) -> Result<()> {
+        self.get_vp_state_components(states, &[])
+    }
+    /// Sets every VP state component from `states`, copied once into the
+    /// scratch buffer. Like `get_all_vp_state_components`, this issues one
+    /// MSHV_SET_VP_STATE per component.
+    pub fn set_all_vp_state_components(&self, states: &mut AllVpStateComponents) -> Result<()> {
+        self.set_vp_state_components(states, &[])
+    }
+    // Reads the components of `AllVpStateComponents` into `states`, except
+    // those of a type in `skip`, whose slot is left zeroed
+    fn get_vp_state_components(
+        &self,
+        states: &mut AllVpStateComponents,
+        skip: &[hv_get_set_vp_state_type],
+    ) -> Result<()> {
+        let mut buffer = self.scratch(VP_STATE_COMPONENTS_BUFFER_SIZE)?;
+        buffer.as_mut_slice().fill(0);
+        for (i, type_) in VP_STATE_COMP_TYPES.iter().enumerate() {
+            if skip.contains(type_) {
+                continue;
+            }
+            let mut vp_state = vp_state_component(&mut buffer, i);
+            self.get_vp_state_ioctl(&mut vp_state)?;
+        }
+        states.buffer.copy_from_slice(buffer.as_slice());
+        Ok(())
+    }
+    // Sets the components of `AllVpStateComponents` from `states`, except
+    // those of a type in `skip`
+    fn set_vp_state_components(
+        &self,
+        states: &AllVpStateComponents,
+        skip: &[hv_get_set_vp_state_type],
+    ) -> Result<()> {
+        let mut buffer = self.scratch(VP_STATE_COMPONENTS_BUFFER_SIZE)?;
+        buffer.as_mut_slice().copy_from_slice(&states.buffer);
+        for (i, type_) in VP_STATE_COMP_TYPES.iter().enumerate() {
+            if skip.contains(type_) {
+                continue;
+            }
+            self.set_vp_state_ioctl(&vp_state_component(&mut buffer, i))?;
+        }
+        Ok(())
+    }
+    /// Size in bytes of the VP state component `type_`, as reported by the
+    /// kernel when MSHV_GET_VP_STATE is given an empty buffer. Fails if the
+    /// kernel does not know the component or does not report sizes.
+    pub fn get_vp_state_size(&self, type_: hv_get_set_vp_state_type) -> Result<usize> {
+        let mut vp_state = mshv_vp_state {
+            type_,
+            ..Default::default()
+        };
+        let ret = self.get_vp_state_ioctl(&mut vp_state);
+        match vp_state.buf_size {
+            0 => ret.and(Err(MshvError::NotSupported)),
+            size => Ok(size as usize),
+        }
+    }
+    /// Every VP state component of this vCPU with its size: those in
+    /// `VP_STATE_COMP_TYPES`, followed by the ones a newer kernel reports
+    /// after `HV_GET_SET_VP_STATE_SYNTHETIC_TIMERS`. Components whose size
+    /// the kernel does not report keep their `VP_STATE_COMP_SIZES` entry.
+    /// A reported size may exceed that entry, see
+    /// `get_extra_vp_state_components`.
+    pub fn get_vp_state_layout(&self) -> Result<Vec<(hv_get_set_vp_state_type, usize)>> {
+        let mut layout: Vec<(hv_get_set_vp_state_type, usize)> = VP_STATE_COMP_TYPES
+            .iter()
+            .zip(VP_STATE_COMP_SIZES.iter())
+            .map(|(type_, size)| (*type_, self.get_vp_state_size(*type_).unwrap_or(*size)))
+            .collect();
+        let mut type_ = hv_get_set_vp_state_type_HV_GET_SET_VP_STATE_SYNTHETIC_TIMERS + 1;
+        while let Ok(size) = self.get_vp_state_size(type_) {
+            layout.push((type_, size));
+            type_ += 1;
+        }
+        Ok(layout)
+    }
+    /// Retrieves the VP state components of `get_vp_state_layout` that do
+    /// not fit in `AllVpStateComponents`: the ones it has no slot for, and
+    /// the ones the kernel reports as larger than their slot. Each is read
+    /// whole, into a buffer of the reported size.
+    pub fn get_extra_vp_state_components(&self) -> Result<Vec<VpStateComponent>> {
+        self.get_vp_state_layout()?
+            .into_iter()
+            .filter(|(type_, size)| !vp_state_fits(*type_, *size))
+            .map(|(type_, size)| self.get_vp_state_component(type_, size))
+            .collect()
+    }
+    /// Sets VP state components returned by `get_extra_vp_state_components`.
+    pub fn set_extra_vp_state_components(&self, components: &[VpStateComponent]) -> Result<()> {
+        for component in components {
+            self.set_vp_state_component(component)?;
+        }
+        Ok(())
+    }
+    fn get_vp_state_component(
+        &self,
+        type_: hv_get_set_vp_state_type,
+        size: usize,
+    ) -> Result<VpStateComponent> {
+        let mut buffer = self.buffers.get(size.next_multiple_of(0x1000))?;
+        let mut vp_state: mshv_vp_state = mshv_vp_state::default();
+        vp_state.buf.bytes = buffer.as_mut_ptr();
+        vp_state.buf_size = buffer.size() as u64;
+        vp_state.type_ = type_;
+        self.get_vp_state_ioctl(&mut vp_state)?;
+        Ok(VpStateComponent {
+            type_,
+            data: buffer.as_slice()[..size].to_vec(),
+        })
+    }
+    fn set_vp_state_component(&self, component: &VpStateComponent) -> Result<()> {
+        let mut buffer = self
+            .buffers
+            .get(component.data.len().next_multiple_of(0x1000))?;
+        buffer.as_mut_slice()[..component.data.len()].copy_from_slice(&component.data);
+        let mut vp_state: mshv_vp_state = mshv_vp_state::default();
+        vp_state.buf.bytes = buffer.as_mut_ptr();
         vp_state.buf_size = buffer.size() as u64;
-        vp_state.buf.bytes = buffer.buf;
+        vp_state.type_ = component.type_;
         self.set_vp_state_ioctl(&vp_state)
     }
+    /// Saves the complete state of this vCPU, including the MSRs listed in
+    /// `msr_indices` (usually `Mshv::get_msr_index_list()`). The MTRRs are
+    /// saved in `VcpuSnapshot::mtrrs`, whether `msr_indices` lists them or
+    /// not, and left out of `VcpuSnapshot::msrs`.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn save_state(&self, msr_indices: &[u32]) -> Result<VcpuSnapshot> {
+        let entries: Vec<msr_entry> = msr_indices
+            .iter()
+            .filter(|index| !MtrrState::is_mtrr_msr(**index))
+            .map(|index| msr_entry {
+                index: *index,
+                ..Default::default()
+            })
+            .collect();
+        let mut msrs = Msrs::from_entries(&entries).map_err(|_| MshvError::InvalidArgument {
+            field: "msr_indices",
+        })?;
+        self.get_msrs(&mut msrs)?;
+
+        let mut snapshot = VcpuSnapshot {
+            regs: self.get_regs()?,
+            sregs: self.get_sregs()?,
+            fpu: self.get_fpu()?,
+            debug_regs: self.get_debug_regs()?,
+            msrs: msrs.as_slice().to_vec(),
+            vcpu_events: self.get_vcpu_events()?,
+            xcrs: self.get_xcrs()?,
+            misc: self.get_misc_regs()?,
+            mtrrs: self.get_mtrrs()?,
+            ..Default::default()
+        };
+        // Components larger than their slot are saved whole as extra ones
+        snapshot.extra_vp_states = self.get_extra_vp_state_components()?;
+        let oversized = extra_vp_state_types(&snapshot.extra_vp_states);
+        self.get_vp_state_components(&mut snapshot.vp_states, &oversized)?;
+        Ok(snapshot)
+    }
+    /// Restores the state previously returned by `save_state`. Snapshots
+    /// saved by an older version of this crate must be upgraded first.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn restore_state(&self, snapshot: &VcpuSnapshot) -> Result<()> {
+        if snapshot.version != VCPU_SNAPSHOT_VERSION {
+            return Err(MshvError::InvalidArgument { field: "version" });
+        }
+        let msrs = Msrs::from_entries(&snapshot.msrs)
+            .map_err(|_| MshvError::InvalidArgument { field: "msrs" })?;
+
+        self.set_regs(&snapshot.regs)?;
+        self.set_sregs(&snapshot.sregs)?;
+        self.set_fpu(&snapshot.fpu)?;
+        self.set_xcrs(&snapshot.xcrs)?;
+        // MSRs go before the VP state components so that the LAPIC page is
+        // applied on top of the restored APIC base.
+        self.set_msrs(&msrs)?;
+        self.set_mtrrs(&snapshot.mtrrs)?;
+        self.set_vcpu_events(&snapshot.vcpu_events)?;
+        self.set_debug_regs(&snapshot.debug_regs)?;
+        let oversized = extra_vp_state_types(&snapshot.extra_vp_states);
+        self.set_vp_state_components(&snapshot.vp_states, &oversized)?;
+        self.set_extra_vp_state_components(&snapshot.extra_vp_states)?;
+        self.set_misc_regs(&snapshot.misc)
+    }
     /// Translate guest virtual address to guest physical address
     pub fn translate_gva(&self, gva: u64, flags: u64) -> Result<(u64, hv_translate_gva_result)> {
         let gpa: u64 = 0;
@@ -893,11 +2108,23 @@ impl VcpuFd {
         // SAFETY: we know that our file is a vCPU fd, we know the kernel honours its ABI.
         let ret = unsafe { ioctl_with_mut_ref(self, MSHV_VP_TRANSLATE_GVA(), &mut args) };
         if ret != 0 {
-            return Err(errno::Error::last());
+            return Err(errno::Error::last().into());
         }
 
         Ok((gpa, result))
     }
+    /// Translates `gva` like `translate_gva`, with typed flags and result.
+    ///
+    /// A failed translation is not an error: the returned GPA is only valid
+    /// if the result is `GvaResult::Success`.
+    pub fn translate_gva_with_flags(
+        &self,
+        gva: u64,
+        flags: GvaTranslateFlags,
+    ) -> Result<(u64, GvaResult)> {
+        let (gpa, result) = self.translate_gva(gva, flags.bits())?;
+        Ok((gpa, GvaResult::from(result)))
+    }
     /// X86 specific call that returns the vcpu's current "suspend registers".
     pub fn get_suspend_regs(&self) -> Result<SuspendRegisters> {
         let reg_names: [hv_register_name; 2] = [
@@ -975,7 +2202,7 @@ impl VcpuFd {
         };
         let ret = unsafe { ioctl_with_ref(self, MSHV_VP_REGISTER_INTERCEPT_RESULT(), &args) };
         if ret != 0 {
-            return Err(errno::Error::last());
+            return Err(errno::Error::last().into());
         }
 
         Ok(())
@@ -1029,16 +2256,64 @@ impl VcpuFd {
         // correct amount of memory from our pointer, and we verify the return result.
         let ret = unsafe { ioctl_with_mut_ref(self, MSHV_GET_VP_CPUID_VALUES(), &mut parms) };
         if ret != 0 {
-            return Err(errno::Error::last());
+            return Err(errno::Error::last().into());
         }
         Ok([parms.eax, parms.ebx, parms.ecx, parms.edx])
     }
+    /// X86 specific call that retrieves the values of several CPUID leaves,
+    /// given as (eax, ecx) pairs, as observed on the virtual processor. The
+    /// results are returned in the order of `leaves`.
+    ///
+    /// This is not batched: MSHV_GET_VP_CPUID_VALUES takes a single leaf and
+    /// the driver has no pass-through for a multi-leaf
+    /// HvCallGetVpCpuidValues, so each leaf is its own ioctl and hypercall.
+    /// It stops at the first leaf that fails.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn get_cpuid_values_batch(
+        &self,
+        leaves: &[(u32, u32)],
+        xfem: u64,
+        xss: u64,
+    ) -> Result<Vec<[u32; 4]>> {
+        leaves
+            .iter()
+            .map(|&(eax, ecx)| self.get_cpuid_values(eax, ecx, xfem, xss))
+            .collect()
+    }
+    /// Builds the SNP CPUID page from the values of `leaves`, as
+    /// (EAX, ECX), that the hypervisor reports for this vCPU with XCR0
+    /// `xfem` and IA32_XSS `xss`. Fails with E2BIG above
+    /// `SNP_CPUID_COUNT_MAX` leaves.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn snp_cpuid_page(
+        &self,
+        leaves: &[(u32, u32)],
+        xfem: u64,
+        xss: u64,
+    ) -> Result<SnpCpuidPage> {
+        let mut page = SnpCpuidPage::new();
+        for &(eax_in, ecx_in) in leaves {
+            let [eax, ebx, ecx, edx] = self.get_cpuid_values(eax_in, ecx_in, xfem, xss)?;
+            page.push(SnpCpuidFunction {
+                eax_in,
+                ecx_in,
+                xcr0_in: xfem,
+                xss_in: xss,
+                eax,
+                ebx,
+                ecx,
+                edx,
+                ..Default::default()
+            })?;
+        }
+        Ok(page)
+    }
     /// Read GPA
     pub fn gpa_read(&self, input: &mut mshv_read_write_gpa) -> Result<mshv_read_write_gpa> {
         // SAFETY: we know that our file is a vCPU fd, we know the kernel honours its ABI.
         let ret = unsafe { ioctl_with_mut_ref(self, MSHV_READ_GPA(), input) };
         if ret != 0 {
-            return Err(errno::Error::last());
+            return Err(errno::Error::last().into());
         }
 
         Ok(*input)
@@ -1048,10 +2323,249 @@ impl VcpuFd {
         // SAFETY: we know that our file is a vCPU fd, we know the kernel honours its ABI.
         let ret = unsafe { ioctl_with_mut_ref(self, MSHV_WRITE_GPA(), input) };
         if ret != 0 {
-            return Err(errno::Error::last());
+            return Err(errno::Error::last().into());
+        }
+
+        Ok(*input)
+    }
+    /// Reads or writes `data` at `gpa` through MSHV_READ_GPA/MSHV_WRITE_GPA,
+    /// which move at most 16 bytes per call.
+    pub(crate) fn access_gpa(&self, gpa: u64, data: &mut [u8], write: bool) -> Result<()> {
+        if write {
+            return self.write_gpa_run(gpa, data);
+        }
+        let mut rw = mshv_read_write_gpa::default();
+        for (i, chunk) in data.chunks_mut(rw.data.len()).enumerate() {
+            rw.base_gpa = gpa + (i * rw.data.len()) as u64;
+            rw.byte_count = chunk.len() as u32;
+            self.gpa_read(&mut rw)?;
+            chunk.copy_from_slice(&rw.data[..chunk.len()]);
+        }
+        Ok(())
+    }
+    fn write_gpa_run(&self, gpa: u64, data: &[u8]) -> Result<()> {
+        let mut rw = mshv_read_write_gpa::default();
+        for (i, chunk) in data.chunks(rw.data.len()).enumerate() {
+            rw.base_gpa = gpa + (i * rw.data.len()) as u64;
+            rw.byte_count = chunk.len() as u32;
+            rw.data[..chunk.len()].copy_from_slice(chunk);
+            self.gpa_write(&mut rw)?;
+        }
+        Ok(())
+    }
+    /// Reads the guest memory `segments`, (GPA, length) pairs, into `data`
+    /// back to back. `data` must be as long as the segments together, and
+    /// no segment may wrap around the guest address space, or this fails
+    /// with InvalidArgument.
+    ///
+    /// This and `write_gpa_vectored` are also how tools outside the VMM,
+    /// holding a vCPU fd it handed over (see `FromRawFd`), reach guest
+    /// memory. The driver cannot map a partition's memory into another
+    /// process, so there is no shared mapping, only copies.
+    ///
+    /// Segments that follow each other in guest memory, as descriptor
+    /// chains often do, are read as one, so a 16 byte MSHV_READ_GPA call
+    /// can span several of them.
+    pub fn read_gpa_vectored(&self, segments: &[(u64, usize)], data: &mut [u8]) -> Result<()> {
+        let mut done = 0;
+        for (gpa, len) in gpa_runs(segments, data.len())? {
+            self.access_gpa(gpa, &mut data[done..done + len], false)?;
+            done += len;
+        }
+        Ok(())
+    }
+    /// Writes `data` to the guest memory `segments`, (GPA, length) pairs,
+    /// back to back. See `read_gpa_vectored`.
+    pub fn write_gpa_vectored(&self, segments: &[(u64, usize)], data: &[u8]) -> Result<()> {
+        let mut done = 0;
+        for (gpa, len) in gpa_runs(segments, data.len())? {
+            self.write_gpa_run(gpa, &data[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+    /// Reads or writes `data` at `gva`, translating it through the guest
+    /// page tables a page at a time. Fails with EFAULT if part of the range
+    /// does not translate.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub(crate) fn access_gva(&self, gva: u64, data: &mut [u8], write: bool) -> Result<()> {
+        let flags = if write {
+            GvaTranslateFlags::VALIDATE_WRITE
+        } else {
+            GvaTranslateFlags::VALIDATE_READ
+        };
+        let page_size = 1u64 << HV_HYP_PAGE_SHIFT;
+        let mut done = 0;
+        while done < data.len() {
+            let addr = gva.wrapping_add(done as u64);
+            let page_left = (page_size - (addr & (page_size - 1))) as usize;
+            let len = cmp::min(page_left, data.len() - done);
+            let (gpa, result) = self.translate_gva_with_flags(addr, flags)?;
+            if !result.is_success() {
+                return Err(errno::Error::new(libc::EFAULT).into());
+            }
+            self.access_gpa(gpa, &mut data[done..done + len], write)?;
+            done += len;
+        }
+        Ok(())
+    }
+    /// Completes an INS/OUTS exit without emulating the instruction.
+    ///
+    /// For OUTS each element is read from guest memory and handed to `io`,
+    /// for INS `io` fills each element which is then written to guest
+    /// memory. RSI or RDI, RCX and RIP are then updated as if the instruction
+    /// had run. Registers are left alone if an element fails, so the guest
+    /// retries the whole instruction. Fails with EINVAL if `msg` is not a
+    /// string I/O exit.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn complete_string_io<F>(
+        &self,
+        msg: &hv_x64_io_port_intercept_message,
+        mut io: F,
+    ) -> Result<()>
+    where
+        F: FnMut(&StringIo, &mut [u8]),
+    {
+        let string_io = StringIo::from_intercept(msg).ok_or(errno::Error::new(libc::EINVAL))?;
+        if !matches!(string_io.access_size, 1 | 2 | 4) {
+            return Err(errno::Error::new(libc::EINVAL).into());
+        }
+        let mut buf = [0u8; 4];
+        let data = &mut buf[..string_io.access_size as usize];
+        for n in 0..string_io.count {
+            let gva = string_io.gva(n);
+            if string_io.is_write {
+                self.access_gva(gva, data, false)?;
+                io(&string_io, data);
+            } else {
+                io(&string_io, data);
+                self.access_gva(gva, data, true)?;
+            }
+        }
+
+        let header = msg.header;
+        let index_name = if string_io.is_write {
+            hv_register_name_HV_X64_REGISTER_RSI
+        } else {
+            hv_register_name_HV_X64_REGISTER_RDI
+        };
+        let mut reg_assocs = vec![
+            hv_register_assoc {
+                name: index_name,
+                value: hv_register_value {
+                    reg64: string_io.final_index(),
+                },
+                ..Default::default()
+            },
+            hv_register_assoc {
+                name: hv_register_name_HV_X64_REGISTER_RIP,
+                value: hv_register_value {
+                    reg64: header.rip + msg.instruction_byte_count as u64,
+                },
+                ..Default::default()
+            },
+        ];
+        if string_io.rep {
+            reg_assocs.push(hv_register_assoc {
+                name: hv_register_name_HV_X64_REGISTER_RCX,
+                value: hv_register_value { reg64: 0 },
+                ..Default::default()
+            });
+        }
+        self.set_reg(&reg_assocs)
+    }
+    /// Completes a hypercall forwarded to user space: stores the result
+    /// value for `status` and `reps_completed` in RAX, or EDX:EAX outside
+    /// long mode, and moves RIP past the hypercall instruction. Output
+    /// parameters must already be written to `exit.output`.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn complete_hypercall(
+        &self,
+        exit: &HypercallExit,
+        status: u16,
+        reps_completed: u16,
+    ) -> Result<()> {
+        let result = HypercallExit::result(status, reps_completed);
+        let mut reg_assocs = vec![hv_register_assoc {
+            name: hv_register_name_HV_X64_REGISTER_RIP,
+            value: hv_register_value {
+                reg64: exit.rip + exit.instruction_length as u64,
+            },
+            ..Default::default()
+        }];
+        let (rax, rdx) = if exit.long_mode {
+            (result, None)
+        } else {
+            (result & 0xffff_ffff, Some(result >> 32))
+        };
+        reg_assocs.push(hv_register_assoc {
+            name: hv_register_name_HV_X64_REGISTER_RAX,
+            value: hv_register_value { reg64: rax },
+            ..Default::default()
+        });
+        if let Some(rdx) = rdx {
+            reg_assocs.push(hv_register_assoc {
+                name: hv_register_name_HV_X64_REGISTER_RDX,
+                value: hv_register_value { reg64: rdx },
+                ..Default::default()
+            });
+        }
+        self.set_reg(&reg_assocs)
+    }
+    /// Returns the configuration and count of synthetic timer `index`.
+    pub fn get_stimer(&self, index: usize) -> Result<(StimerConfig, u64)> {
+        let name = Self::stimer_config_register(index)?;
+        let mut reg_assocs = [
+            hv_register_assoc {
+                name,
+                ..Default::default()
+            },
+            hv_register_assoc {
+                name: name + 1,
+                ..Default::default()
+            },
+        ];
+        self.get_reg(&mut reg_assocs)?;
+        // SAFETY: access union fields
+        let (config, count) = unsafe { (reg_assocs[0].value.reg64, reg_assocs[1].value.reg64) };
+        Ok((StimerConfig::from(config), count))
+    }
+    /// Configures synthetic timer `index` and sets its count, in 100ns
+    /// units of the reference counter. The count is written last, so an
+    /// enabled timer is armed with it.
+    ///
+    /// Direct mode timers raise `config.vector` on the local APIC, which
+    /// must be a valid fixed interrupt vector. Other timers post
+    /// HVMSG_TIMER_EXPIRED to the guest SIM page, see
+    /// `hv_message::to_timer_expired_info`.
+    pub fn set_stimer(&self, index: usize, config: StimerConfig, count: u64) -> Result<()> {
+        let name = Self::stimer_config_register(index)?;
+        if config.direct_mode && config.vector < 16 {
+            return Err(MshvError::InvalidArgument { field: "vector" });
+        }
+        if config.sint as u32 >= HV_SYNIC_SINT_COUNT {
+            return Err(MshvError::InvalidArgument { field: "sint" });
+        }
+        self.set_reg(&[
+            hv_register_assoc {
+                name,
+                value: hv_register_value {
+                    reg64: config.into(),
+                },
+                ..Default::default()
+            },
+            hv_register_assoc {
+                name: name + 1,
+                value: hv_register_value { reg64: count },
+                ..Default::default()
+            },
+        ])
+    }
+    fn stimer_config_register(index: usize) -> Result<hv_register_name> {
+        if index >= HV_SYNIC_STIMER_COUNT as usize {
+            return Err(MshvError::InvalidArgument { field: "index" });
         }
-
-        Ok(*input)
+        Ok(hv_register_name_HV_REGISTER_STIMER0_CONFIG + 2 * index as hv_register_name)
     }
     /// Sets the sev control register
     pub fn set_sev_control_register(&self, reg: u64) -> Result<()> {
@@ -1131,6 +2645,20 @@ mod tests {
         assert!(g_sregs.efer == s_sregs.efer);
     }
     #[test]
+    fn test_sregs_interrupt_bitmap() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let mut s_sregs = vcpu.get_sregs().unwrap();
+        s_sregs.set_pending_interrupt(Some(0x30));
+        vcpu.set_sregs(&s_sregs).unwrap();
+        let g_sregs = vcpu.get_sregs().unwrap();
+        assert!(g_sregs.pending_interrupt().unwrap() == Some(0x30));
+
+        s_sregs.interrupt_bitmap[1] = 1;
+        assert!(vcpu.set_sregs(&s_sregs).is_err());
+    }
+    #[test]
     fn test_set_get_standardregisters() {
         let hv = Mshv::new().unwrap();
         let vm = hv.create_vm().unwrap();
@@ -1160,6 +2688,324 @@ mod tests {
         assert!(g_regs.dr6 == s_regs.dr6);
         assert!(g_regs.dr7 == s_regs.dr7);
     }
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn test_set_get_regs_aarch64() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        let mut s_regs = vcpu.get_regs().unwrap();
+        s_regs.regs[0] = 0x1;
+        s_regs.regs[30] = 0x2;
+        s_regs.pc = 0x1000;
+        vcpu.set_regs(&s_regs).unwrap();
+        let g_regs = vcpu.get_regs().unwrap();
+        assert!(g_regs == s_regs);
+
+        let s_sys_regs = vcpu.get_sys_regs().unwrap();
+        vcpu.set_sys_regs(&s_sys_regs).unwrap();
+        let g_sys_regs = vcpu.get_sys_regs().unwrap();
+        assert!(g_sys_regs == s_sys_regs);
+    }
+    #[test]
+    fn test_buffer_pool() {
+        let pool = BufferPool::default();
+        let addr = {
+            let mut buffer = pool.get(0x1000).unwrap();
+            buffer.as_mut_slice()[8] = 0xff;
+            buffer.as_ptr() as usize
+        };
+        assert!(pool.buffers.lock().unwrap().len() == 1);
+        {
+            let buffer = pool.get(0x1000).unwrap();
+            assert!(buffer.as_ptr() as usize == addr);
+            assert!(buffer.as_slice()[8] == 0);
+            let other = pool.get(0x3000).unwrap();
+            assert!(other.size() == 0x3000);
+        }
+        assert!(pool.buffers.lock().unwrap().len() == 2);
+        let held: Vec<PooledBuffer<'_>> = (0..BUFFER_POOL_MAX_BUFFERS + 2)
+            .map(|_| pool.get(0x1000).unwrap())
+            .collect();
+        drop(held);
+        assert!(pool.buffers.lock().unwrap().len() == BUFFER_POOL_MAX_BUFFERS);
+    }
+    #[test]
+    fn test_gpa_runs() {
+        assert_eq!(
+            gpa_runs(
+                &[
+                    (0x1000, 10),
+                    (0x100a, 6),
+                    (0x2000, 0),
+                    (0x3000, 16),
+                    (0x1010, 4)
+                ],
+                36
+            )
+            .unwrap(),
+            vec![(0x1000, 16), (0x3000, 16), (0x1010, 4)]
+        );
+        assert!(gpa_runs(&[], 0).unwrap().is_empty());
+        assert!(
+            gpa_runs(&[(0x1000, 16)], 8).unwrap_err()
+                == MshvError::InvalidArgument { field: "data" }
+        );
+        assert!(
+            gpa_runs(&[(u64::MAX - 4, 16)], 16).unwrap_err()
+                == MshvError::InvalidArgument { field: "segments" }
+        );
+        assert!(gpa_runs(&[(0, usize::MAX), (0x1000, 2)], 1).is_err());
+    }
+    #[test]
+    fn test_gpa_vectored() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let mem_size = 0x2000;
+        // SAFETY: FFI call, the result is checked
+        let load_addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                mem_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        assert!(load_addr != libc::MAP_FAILED);
+        let mem_region = mshv_user_mem_region {
+            flags: HV_MAP_GPA_READABLE | HV_MAP_GPA_WRITABLE,
+            guest_pfn: 0x1,
+            size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+        };
+        vm.map_user_memory(mem_region).unwrap();
+
+        let segments = [(0x1ffc, 4), (0x2000, 8), (0x1000, 3)];
+        let data: Vec<u8> = (1..=15).collect();
+        vcpu.write_gpa_vectored(&segments, &data).unwrap();
+        // SAFETY: load_addr maps mem_size bytes
+        let mem = unsafe { slice::from_raw_parts(load_addr as *const u8, mem_size) };
+        assert_eq!(&mem[0xffc..0x1008], &data[..12]);
+        assert_eq!(&mem[..3], &data[12..]);
+        let mut read = [0u8; 15];
+        vcpu.read_gpa_vectored(&segments, &mut read).unwrap();
+        assert_eq!(&read[..], &data[..]);
+        assert!(vcpu.read_gpa_vectored(&segments, &mut read[1..]).is_err());
+
+        vm.unmap_user_memory(mem_region).unwrap();
+        // SAFETY: load_addr was mapped above with mem_size
+        unsafe { libc::munmap(load_addr, mem_size) };
+    }
+    #[test]
+    fn test_vp_state_component() {
+        let pool = BufferPool::default();
+        let mut buffer = ScratchGuard::Pooled(pool.get(VP_STATE_COMPONENTS_BUFFER_SIZE).unwrap());
+        let base = buffer.as_slice().as_ptr() as usize;
+        let mut end = base;
+        for (i, type_) in VP_STATE_COMP_TYPES.iter().enumerate() {
+            let vp_state = vp_state_component(&mut buffer, i);
+            // SAFETY: bytes is the member set by vp_state_component
+            let start = unsafe { vp_state.buf.bytes } as usize;
+            assert!(start == end && start & 0xfff == 0);
+            assert!(vp_state.type_ == *type_);
+            end = start + vp_state.buf_size as usize;
+        }
+        assert!(end == base + VP_STATE_COMPONENTS_BUFFER_SIZE);
+    }
+    #[test]
+    fn test_vp_state_fits() {
+        let xsave = hv_get_set_vp_state_type_HV_GET_SET_VP_STATE_XSAVE;
+        assert!(vp_state_fits(xsave, 0x2a00));
+        assert!(vp_state_fits(xsave, 0x3000));
+        assert!(!vp_state_fits(xsave, 0x3040));
+        assert!(!vp_state_fits(
+            hv_get_set_vp_state_type_HV_GET_SET_VP_STATE_SYNTHETIC_TIMERS + 1,
+            0x10
+        ));
+    }
+    #[test]
+    fn test_scratch_buffer() {
+        // No ioctl is issued, any file will do
+        let vcpu = new_vcpu(File::open("/dev/null").unwrap());
+        let addr = {
+            let mut buffer = vcpu.scratch(0x1000).unwrap();
+            buffer.zero();
+            buffer.as_mut_slice()[8] = 0xff;
+            assert!(buffer.size() == 0x1000);
+            // Borrowed: a pooled buffer is handed out instead
+            let other = vcpu.scratch(0x1000).unwrap();
+            assert!(matches!(other, ScratchGuard::Pooled(_)));
+            buffer.as_slice().as_ptr() as usize
+        };
+        {
+            // Reused as is
+            let buffer = vcpu.scratch(0x1000).unwrap();
+            assert!(buffer.as_slice().as_ptr() as usize == addr);
+            assert!(buffer.as_slice()[8] == 0xff);
+        }
+        let buffer = vcpu.scratch(0x3000).unwrap();
+        assert!(buffer.size() == 0x3000);
+        drop(buffer);
+        assert!(vcpu.scratch(0x1000).unwrap().size() == 0x1000);
+    }
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    #[test]
+    fn test_state_validation() {
+        // Invalid state is rejected before any ioctl is issued
+        let vcpu = new_vcpu(File::open("/dev/null").unwrap());
+        let regs = StandardRegisters::default();
+        let sregs = SpecialRegisters {
+            cr0: X86_CR0_PG,
+            ..Default::default()
+        };
+        assert!(matches!(vcpu.set_regs(&regs), Err(MshvError::Ioctl(_))));
+        assert!(matches!(vcpu.set_sregs(&sregs), Err(MshvError::Ioctl(_))));
+
+        vcpu.set_state_validation(true);
+        assert!(
+            vcpu.set_regs(&regs).unwrap_err()
+                == MshvError::InvalidRegister {
+                    register: "rflags",
+                    bit: 1
+                }
+        );
+        assert!(
+            vcpu.set_sregs(&sregs).unwrap_err()
+                == MshvError::InvalidRegister {
+                    register: "cr0",
+                    bit: 0
+                }
+        );
+    }
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    #[test]
+    fn test_shutdown_exit() {
+        use mshv_test::exit_message;
+
+        // None of these exits need to read vCPU state
+        let vcpu = new_vcpu(File::open("/dev/null").unwrap());
+        let mut msg = hv_message::default();
+        msg.header.message_type = hv_message_type_HVMSG_UNRECOVERABLE_EXCEPTION;
+        assert!(vcpu.shutdown_exit(&msg) == Ok(ShutdownReason::TripleFault));
+
+        let sleep = |code: u32, state: u32| {
+            let mut info = hv_x64_hypercall_intercept_message {
+                rcx: code as u64 | HV_HYPERCALL_FAST_BIT,
+                rdx: state as u64,
+                ..Default::default()
+            };
+            // SAFETY: access union fields
+            unsafe { info.header.execution_state.__bindgen_anon_1.set_efer_lma(1) };
+            exit_message(hv_message_type_HVMSG_HYPERCALL_INTERCEPT, info)
+        };
+        assert!(
+            vcpu.power_exit(&sleep(
+                HVCALL_ENTER_SLEEP_STATE,
+                hv_sleep_state_HV_SLEEP_STATE_S5
+            )) == Ok(PowerExit::Shutdown(ShutdownReason::PowerOff))
+        );
+        assert!(
+            vcpu.shutdown_exit(&sleep(
+                HVCALL_ENTER_SLEEP_STATE,
+                hv_sleep_state_HV_SLEEP_STATE_S3
+            )) == Ok(ShutdownReason::SleepState(hv_sleep_state_HV_SLEEP_STATE_S3))
+        );
+        assert!(vcpu.shutdown_exit(&sleep(HVCALL_SIGNAL_EVENT, 0)).is_err());
+        assert!(vcpu.power_exit(&hv_message::default()).is_err());
+    }
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    #[test]
+    fn test_device_mmio_exit() {
+        use mshv_test::exit_message;
+
+        let mut vcpu = new_vcpu(File::open("/dev/null").unwrap());
+        let regions = Arc::new(DeviceRegions::default());
+        regions
+            .insert(crate::ioctls::device_region::DeviceRegion {
+                gpa: 0xd000_0000,
+                size: 0x1000,
+                cookie: 7,
+            })
+            .unwrap();
+        vcpu.set_device_regions(regions);
+
+        let unmapped = |gpa: u64, access: u32| {
+            let mut info = hv_x64_memory_intercept_message {
+                guest_physical_address: gpa,
+                ..Default::default()
+            };
+            info.header.intercept_access_type = access as u8;
+            exit_message(hv_message_type_HVMSG_UNMAPPED_GPA, info)
+        };
+        assert!(
+            vcpu.device_mmio_exit(&unmapped(0xd000_0010, HV_INTERCEPT_ACCESS_WRITE))
+                == Ok(Some(DeviceMmioExit {
+                    cookie: 7,
+                    gpa: 0xd000_0010,
+                    offset: 0x10,
+                    is_write: true,
+                }))
+        );
+        assert!(
+            vcpu.device_mmio_exit(&unmapped(0xd000_1000, HV_INTERCEPT_ACCESS_READ)) == Ok(None)
+        );
+        assert!(vcpu.device_mmio_exit(&hv_message::default()).is_err());
+    }
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    #[test]
+    fn test_inject_machine_check() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let mce = MceInfo::memory_poison(0, 0x10_0000, true);
+        assert!(
+            vcpu.inject_machine_check(&MceInfo::default()).unwrap_err()
+                == MshvError::InvalidArgument { field: "status" }
+        );
+        vcpu.inject_machine_check(&mce).unwrap();
+        let events = vcpu.get_vcpu_events().unwrap();
+        // SAFETY: access union fields
+        let pending = unsafe { mce.pending_interruption().as_uint64 };
+        assert!(events.pending_interruption == pending);
+    }
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn test_set_get_sys_reg() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let vbar = aarch64::SysReg::VbarEl1.encoding();
+        vcpu.set_sys_reg(vbar, 0x8000).unwrap();
+        assert!(vcpu.get_sys_reg(vbar).unwrap() == 0x8000);
+        let res = vcpu.get_sys_reg(aarch64::SysRegEncoding::new(3, 7, 15, 15, 7));
+        assert!(res.unwrap_err() == errno::Error::new(libc::EINVAL));
+    }
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn test_configure_gic() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let config = aarch64::GicConfig {
+            gicd_base: 0x0800_0000,
+            gicr_base: 0x080a_0000,
+            vtimer_ppi: 27,
+            pmu_ppi: 23,
+            ..Default::default()
+        };
+        vm.configure_gic(&config).unwrap();
+        vcpu.set_gic_redistributor_base(config.gicr_base_for_vcpu(0))
+            .unwrap();
+        let vcpus = [vcpu];
+        let state = vm.save_gic_state(&vcpus).unwrap();
+        assert!(state.config == config);
+        assert!(state.redistributors == [config.gicr_base_for_vcpu(0)]);
+        vm.restore_gic_state(&vcpus, &state).unwrap();
+    }
     #[cfg(target_arch = "x86_64")]
     #[test]
     fn test_set_get_fpu() {
@@ -1191,18 +3037,12 @@ mod tests {
     #[cfg(target_arch = "x86_64")]
     #[test]
     fn test_run_code() {
-        use libc::c_void;
-
-        use super::*;
-        use crate::ioctls::system::Mshv;
-        use std::io::Write;
+        use crate::ioctls::test_utils::TestVm;
 
-        let mshv = Mshv::new().unwrap();
-        let vm = mshv.create_vm().unwrap();
-        let vcpu = vm.create_vcpu(0).unwrap();
+        let mut test = TestVm::new(0x4000).unwrap();
         // This example is based on https://lwn.net/Articles/658511/
         #[rustfmt::skip]
-        let code:[u8;11] = [
+        let code:[u8;12] = [
             0xba, 0xf8, 0x03,  /* mov $0x3f8, %dx */
             0x00, 0xd8,         /* add %bl, %al */
             0x04, b'0',         /* add $'0', %al */
@@ -1210,131 +3050,30 @@ mod tests {
             /* send a 0 to indicate we're done */
             0xb0, b'\0',        /* mov $'\0', %al */
             0xee,               /* out %al, (%dx) */
+            0xf4,               /* hlt */
         ];
-
-        let mem_size = 0x4000;
-        let load_addr = unsafe {
-            libc::mmap(
-                std::ptr::null_mut(),
-                mem_size,
-                libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
-                -1,
-                0,
-            )
-        } as *mut u8;
-        let mem_region = mshv_user_mem_region {
-            flags: HV_MAP_GPA_READABLE | HV_MAP_GPA_WRITABLE | HV_MAP_GPA_EXECUTABLE,
-            guest_pfn: 0x1,
-            size: 0x1000,
-            userspace_addr: load_addr as u64,
-        };
-
-        vm.map_user_memory(mem_region).unwrap();
-
-        unsafe {
-            // Get a mutable slice of `mem_size` from `load_addr`.
-            // This is safe because we mapped it before.
-            let mut slice = slice::from_raw_parts_mut(load_addr, mem_size);
-            slice.write_all(&code).unwrap();
-        }
-
-        //Get CS Register
-        let mut cs_reg = hv_register_assoc {
-            name: hv_register_name_HV_X64_REGISTER_CS,
-            ..Default::default()
-        };
-        vcpu.get_reg(slice::from_mut(&mut cs_reg)).unwrap();
-
-        unsafe {
-            assert_ne!({ cs_reg.value.segment.base }, 0);
-            assert_ne!({ cs_reg.value.segment.selector }, 0);
-        };
-
-        cs_reg.value.segment.base = 0;
-        cs_reg.value.segment.selector = 0;
-
-        vcpu.set_reg(&[
-            cs_reg,
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_RAX,
-                value: hv_register_value { reg64: 2 },
-                ..Default::default()
-            },
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_RBX,
-                value: hv_register_value { reg64: 2 },
-                ..Default::default()
-            },
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_RIP,
-                value: hv_register_value { reg64: 0x1000 },
-                ..Default::default()
-            },
-            hv_register_assoc {
-                name: hv_register_name_HV_X64_REGISTER_RFLAGS,
-                value: hv_register_value { reg64: 0x2 },
-                ..Default::default()
-            },
-        ])
+        test.load(0x1000, &code).unwrap();
+        test.setup_real_mode(0x1000).unwrap();
+        let mut regs = test.vcpu.get_regs().unwrap();
+        regs.rax = 2;
+        regs.rbx = 2;
+        test.vcpu.set_regs(&regs).unwrap();
+
+        let mut out = Vec::new();
+        test.run_until(hv_message_type_HVMSG_X64_HALT, |test, msg| {
+            let io_message = msg.to_ioport_info()?;
+            assert!(io_message.port_number == 0x3f8);
+            // SAFETY: access_info is a bitfield over a u8
+            unsafe {
+                assert!(io_message.access_info.__bindgen_anon_1.string_op() == 0);
+                assert!(io_message.access_info.__bindgen_anon_1.access_size() == 1);
+            }
+            assert!(io_message.header.intercept_access_type == /*HV_INTERCEPT_ACCESS_WRITE*/ 1_u8);
+            out.push(io_message.rax as u8);
+            test.skip_instruction(msg)
+        })
         .unwrap();
-
-        let hv_message: hv_message = unsafe { std::mem::zeroed() };
-        let mut done = false;
-        loop {
-            let ret_hv_message: hv_message = vcpu.run(hv_message).unwrap();
-            match ret_hv_message.header.message_type {
-                hv_message_type_HVMSG_X64_HALT => {
-                    println!("VM Halted!");
-                    break;
-                }
-                hv_message_type_HVMSG_X64_IO_PORT_INTERCEPT => {
-                    let io_message = ret_hv_message.to_ioport_info().unwrap();
-
-                    if !done {
-                        assert!(io_message.rax == b'4' as u64);
-                        assert!(io_message.port_number == 0x3f8);
-                        unsafe {
-                            assert!(io_message.access_info.__bindgen_anon_1.string_op() == 0);
-                            assert!(io_message.access_info.__bindgen_anon_1.access_size() == 1);
-                        }
-                        assert!(
-                            io_message.header.intercept_access_type == /*HV_INTERCEPT_ACCESS_WRITE*/ 1_u8
-                        );
-                        done = true;
-                        /* Advance rip */
-                        vcpu.set_reg(&[hv_register_assoc {
-                            name: hv_register_name_HV_X64_REGISTER_RIP,
-                            value: hv_register_value {
-                                reg64: io_message.header.rip + 1,
-                            },
-                            ..Default::default()
-                        }])
-                        .unwrap();
-                    } else {
-                        assert!(io_message.rax == b'\0' as u64);
-                        assert!(io_message.port_number == 0x3f8);
-                        unsafe {
-                            assert!(io_message.access_info.__bindgen_anon_1.string_op() == 0);
-                            assert!(io_message.access_info.__bindgen_anon_1.access_size() == 1);
-                        }
-                        assert!(
-                            io_message.header.intercept_access_type == /*HV_INTERCEPT_ACCESS_WRITE*/ 1_u8
-                        );
-                        break;
-                    }
-                }
-                _ => {
-                    println!("Message type: 0x{:x?}", {
-                        ret_hv_message.header.message_type
-                    });
-                    panic!("Unexpected Exit Type");
-                }
-            };
-        }
-        assert!(done);
-        vm.unmap_user_memory(mem_region).unwrap();
-        unsafe { libc::munmap(load_addr as *mut c_void, mem_size) };
+        assert!(out == b"4\0");
     }
     #[test]
     fn test_set_get_msrs() {
@@ -1371,6 +3110,16 @@ mod tests {
         assert!(g_regs.as_fam_struct_ref().nmsrs == s_regs.as_fam_struct_ref().nmsrs);
         assert!(g_regs.as_slice()[0].data == s_regs.as_slice()[0].data);
         assert!(g_regs.as_slice()[1].data == s_regs.as_slice()[1].data);
+
+        let mut msrs = [msr_entry {
+            index: IA32_MSR_SYSENTER_EIP,
+            data: 0x3,
+            ..Default::default()
+        }];
+        assert_eq!(vcpu.set_msrs_from(&msrs).unwrap(), 1);
+        msrs[0].data = 0;
+        assert_eq!(vcpu.get_msrs_into(&mut msrs).unwrap(), 1);
+        assert_eq!(msrs[0].data, 0x3);
     }
     #[test]
     fn test_set_get_vcpu_events() {
@@ -1408,7 +3157,9 @@ mod tests {
 
         let state = vcpu.get_lapic().unwrap();
         vcpu.set_lapic(&state).unwrap();
-        let g_state = vcpu.get_lapic().unwrap();
+        let mode = vcpu.get_apic_base().unwrap().mode();
+        vcpu.set_lapic_in(&state, mode).unwrap();
+        let g_state = vcpu.get_lapic_in(mode).unwrap();
         for i in 0..1024 {
             assert!(state.regs[i] == g_state.regs[i]);
         }
@@ -1443,6 +3194,28 @@ mod tests {
         }
     }
     #[test]
+    fn test_reg_bundle() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let regs = RegBundle::with_values([
+            (hv_register_name_HV_X64_REGISTER_RIP, 0x1000),
+            (hv_register_name_HV_X64_REGISTER_RAX, 0x42),
+            (hv_register_name_HV_X64_REGISTER_RFLAGS, 0x2),
+        ]);
+        vcpu.set_reg(regs.as_slice()).unwrap();
+        let regs = vcpu
+            .get_reg_bundle([
+                hv_register_name_HV_X64_REGISTER_RIP,
+                hv_register_name_HV_X64_REGISTER_RAX,
+                hv_register_name_HV_X64_REGISTER_RFLAGS,
+            ])
+            .unwrap();
+        assert_eq!(regs.reg64(0), 0x1000);
+        assert_eq!(regs.reg64(1), 0x42);
+        assert_eq!(regs.reg64(2), 0x2);
+    }
+    #[test]
     fn test_get_set_xsave() {
         let hv = Mshv::new().unwrap();
         let vm = hv.create_vm().unwrap();
@@ -1451,6 +3224,242 @@ mod tests {
         let state = vcpu.get_xsave().unwrap();
 
         vcpu.set_xsave(&state).unwrap();
+        let mxcsr = vcpu.with_xsave(|xsave| xsave.mxcsr()).unwrap();
+        assert_eq!(mxcsr, XSaveView::from(&state).mxcsr());
+    }
+    #[test]
+    fn test_get_guest_crash() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let crash = vcpu.get_guest_crash().unwrap();
+        assert!(crash.ctl & HV_CRASH_CTL_CRASH_NOTIFY == 0);
+        assert!(vcpu.guest_crash_exit(&hv_message::default()).is_err());
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_get_set_vp_assist_page() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        assert!(vcpu.get_vp_assist_page().unwrap().is_none());
+        vcpu.set_vp_assist_page(Some(0x10000)).unwrap();
+        assert!(vcpu.get_vp_assist_page().unwrap() == Some(0x10000));
+        vcpu.set_vp_assist_page(None).unwrap();
+        assert!(vcpu.get_vp_assist_page().unwrap().is_none());
+        assert!(
+            vcpu.set_vp_assist_page(Some(0x10001)).unwrap_err()
+                == MshvError::InvalidArgument { field: "gpa" }
+        );
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_get_set_mtrrs() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        let mut mtrrs = vcpu.get_mtrrs().unwrap();
+        mtrrs.def_type = MTRR_DEF_TYPE_E | MTRR_TYPE_WB as u64;
+        mtrrs.variable[0] = MtrrRange {
+            base: 0xc000_0000 | MTRR_TYPE_UC as u64,
+            mask: 0xf_c000_0000 | MTRR_PHYSMASK_VALID,
+        };
+        vcpu.set_mtrrs(&mtrrs).unwrap();
+        assert!(vcpu.get_mtrrs().unwrap() == mtrrs);
+
+        mtrrs.def_type = 2;
+        assert!(
+            vcpu.set_mtrrs(&mtrrs).unwrap_err() == MshvError::InvalidArgument { field: "mtrrs" }
+        );
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_hypercall_msr_write() {
+        let page = 0x7000 | MSR_HYPERCALL_ACTIVE;
+        assert!(hypercall_msr_write(0, page, 1).unwrap() == page);
+        assert!(hypercall_msr_write(0, page, 0).unwrap() == 0x7000);
+        assert!(hypercall_msr_write(page, 0, 1).unwrap() == 0);
+        let locked = page | MSR_HYPERCALL_LOCKED;
+        assert!(hypercall_msr_write(0, locked, 1).unwrap() == locked);
+        assert!(hypercall_msr_write(locked, 0x8000, 1).unwrap() == locked);
+        assert!(
+            hypercall_msr_write(0, page | 4, 1).unwrap_err()
+                == MshvError::InvalidArgument { field: "value" }
+        );
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_hypercall_page() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        assert!(vcpu.get_hypercall_page().unwrap().is_none());
+        vcpu.write_hypercall_msr(0x7000 | MSR_HYPERCALL_ACTIVE)
+            .unwrap();
+        assert!(vcpu.get_hypercall_page().unwrap().is_none());
+        vcpu.set_guest_os_id(1 << 63).unwrap();
+        assert!(vcpu.get_guest_os_id().unwrap() == 1 << 63);
+        vcpu.write_hypercall_msr(0x7000 | MSR_HYPERCALL_ACTIVE)
+            .unwrap();
+        assert!(vcpu.get_hypercall_page().unwrap() == Some(0x7000));
+        vcpu.set_guest_os_id(0).unwrap();
+        assert!(vcpu.get_hypercall_page().unwrap().is_none());
+        assert!(vcpu.hypercall_msr_exit(&hv_message::default()).is_err());
+    }
+    #[test]
+    fn test_get_set_synthetic_timers() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let state = vcpu.get_synthetic_timers().unwrap();
+        vcpu.set_synthetic_timers(&state).unwrap();
+        assert!(vcpu.get_synthetic_timers().unwrap() == state);
+    }
+    #[test]
+    fn test_get_set_all_vp_state_components() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        let mut states = AllVpStateComponents::default();
+        vcpu.get_all_vp_state_components(&mut states).unwrap();
+        vcpu.set_all_vp_state_components(&mut states).unwrap();
+
+        let mut g_states = AllVpStateComponents::default();
+        vcpu.get_all_vp_state_components(&mut g_states).unwrap();
+        // The LAPIC component comes first and is stable across a set/get cycle.
+        assert!(states.component(0) == g_states.component(0));
+        assert!(states.synthetic_timers() == g_states.synthetic_timers());
+    }
+    #[test]
+    fn test_get_vp_state_layout() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        let layout = vcpu.get_vp_state_layout().unwrap();
+        assert!(layout.len() >= VP_STATE_COMPONENTS_COUNT);
+        assert!(layout[..VP_STATE_COMPONENTS_COUNT]
+            .iter()
+            .zip(VP_STATE_COMP_TYPES.iter())
+            .all(|((type_, size), known)| type_ == known && *size > 0));
+        let extra = vcpu.get_extra_vp_state_components().unwrap();
+        assert_eq!(
+            extra.len(),
+            layout
+                .iter()
+                .filter(|(type_, size)| !vp_state_fits(*type_, *size))
+                .count()
+        );
+        vcpu.set_extra_vp_state_components(&extra).unwrap();
+    }
+    #[test]
+    fn test_set_halt_eventfd() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let efd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        vcpu.set_halt_eventfd(Some(&efd)).unwrap();
+        assert!(vcpu.halt_eventfd.lock().unwrap().is_some());
+        vcpu.set_halt_eventfd(None).unwrap();
+        assert!(vcpu.halt_eventfd.lock().unwrap().is_none());
+    }
+    #[cfg(feature = "event-subscribers")]
+    #[test]
+    fn test_halt_subscriber_drop() {
+        use crate::ioctls::event_subscriber::EventFdSubscriber;
+        let vcpu = Arc::new(new_vcpu(File::open("/dev/null").unwrap()));
+        let subscriber = EventFdSubscriber::vcpu_halt(vcpu.clone(), |_| ()).unwrap();
+        assert!(vcpu.halt_eventfd.lock().unwrap().is_some());
+        drop(subscriber);
+        assert!(vcpu.halt_eventfd.lock().unwrap().is_none());
+
+        // An eventfd set since is kept
+        let subscriber = EventFdSubscriber::vcpu_halt(vcpu.clone(), |_| ()).unwrap();
+        let efd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        vcpu.set_halt_eventfd(Some(&efd)).unwrap();
+        drop(subscriber);
+        let halt_eventfd = vcpu.halt_eventfd.lock().unwrap();
+        assert!(same_eventfd(halt_eventfd.as_ref().unwrap(), &efd));
+    }
+    #[test]
+    fn test_set_signal_mask() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        assert!(
+            vcpu.set_signal_mask(Some(&[-1])).unwrap_err()
+                == MshvError::InvalidArgument { field: "blocked" }
+        );
+        vcpu.set_signal_mask(Some(&[libc::SIGUSR1])).unwrap();
+        assert!(vcpu.signal_mask.lock().unwrap().is_some());
+        vcpu.set_signal_mask(None).unwrap();
+        assert!(vcpu.signal_mask.lock().unwrap().is_none());
+    }
+    #[test]
+    fn test_affinity() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let cpus = vcpu.affinity().unwrap();
+        assert!(
+            vcpu.set_affinity(&[libc::CPU_SETSIZE as usize])
+                .unwrap_err()
+                == MshvError::InvalidArgument { field: "cpus" }
+        );
+        vcpu.set_affinity(&cpus[..1]).unwrap();
+        assert!(vcpu.affinity().unwrap() == cpus[..1]);
+        vcpu.set_affinity(&cpus).unwrap();
+    }
+    #[test]
+    fn test_set_get_stimer() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        assert!(
+            vcpu.set_stimer(0, StimerConfig::direct(0x2, false), 0)
+                .unwrap_err()
+                == MshvError::InvalidArgument { field: "vector" }
+        );
+        assert!(
+            vcpu.get_stimer(HV_SYNIC_STIMER_COUNT as usize).unwrap_err()
+                == MshvError::InvalidArgument { field: "index" }
+        );
+        let config = StimerConfig {
+            enabled: false,
+            ..StimerConfig::direct(0x40, true)
+        };
+        vcpu.set_stimer(1, config, 10_000).unwrap();
+        let (read, count) = vcpu.get_stimer(1).unwrap();
+        assert!(read == config && count == 10_000);
+    }
+    #[test]
+    fn test_set_thread_sigmask() {
+        // SAFETY: sigset_t is plain data, initialized by sigemptyset
+        let mut mask: libc::sigset_t = unsafe { std::mem::zeroed() };
+        // SAFETY: FFI calls on a valid sigset_t
+        unsafe {
+            libc::sigemptyset(&mut mask);
+            libc::sigaddset(&mut mask, libc::SIGUSR2);
+        }
+        let old = set_thread_sigmask(&mask).unwrap();
+        let current = set_thread_sigmask(&old).unwrap();
+        // SAFETY: FFI call on a valid sigset_t
+        assert!(unsafe { libc::sigismember(&current, libc::SIGUSR2) } == 1);
+    }
+    #[test]
+    fn test_translate_gva_with_flags() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        // Paging is off, so the GVA maps 1:1 onto a GPA with no memory behind it
+        let (_, result) = vcpu
+            .translate_gva_with_flags(0x1000, GvaTranslateFlags::VALIDATE_READ)
+            .unwrap();
+        assert!(result == GvaResult::GpaUnmapped);
     }
     #[test]
     fn test_get_suspend_regs() {
@@ -1463,6 +3472,24 @@ mod tests {
         assert!(regs.explicit_register == 0x1);
         assert!(regs.intercept_register == 0x0);
     }
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    #[test]
+    fn test_init_sipi() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let _bsp = vm.create_vcpu(0).unwrap();
+        let ap = vm.create_vcpu(1).unwrap();
+
+        ap.init_ap().unwrap();
+        assert!(ap.get_suspend_regs().unwrap().explicit_register == 0);
+        assert!(ap.get_vcpu_events().unwrap().internal_activity_state & 1 == 1);
+
+        ap.start_ap(0x9f).unwrap();
+        let sregs = ap.get_sregs().unwrap();
+        assert!(sregs.cs.selector == 0x9f00 && sregs.cs.base == 0x9_f000);
+        assert!(ap.get_regs().unwrap().rip == 0);
+        assert!(ap.get_vcpu_events().unwrap().internal_activity_state & 1 == 0);
+    }
     #[test]
     fn test_set_get_misc_regs() {
         let hv = Mshv::new().unwrap();
@@ -1482,5 +3509,12 @@ mod tests {
         let res = vcpu.get_cpuid_values(0, 0, 0, 0).unwrap();
         let max_function = res[0];
         assert!(max_function >= 1);
+
+        let batch = vcpu
+            .get_cpuid_values_batch(&[(0, 0), (1, 0), (0x40000000, 0)], 0, 0)
+            .unwrap();
+        assert!(batch.len() == 3);
+        assert!(batch[0] == res);
+        assert!(batch[2] == vcpu.get_cpuid_values(0x40000000, 0, 0, 0).unwrap());
     }
 }
@@ -3,14 +3,24 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 //
 use crate::ioctls::device::{new_device, DeviceFd};
+use crate::ioctls::device_region::{DeviceRegion, DeviceRegions};
+use crate::ioctls::eventfd_registry::same_eventfd;
+use crate::ioctls::gpa_access::{GpaAccessKind, GpaAccessRanges};
+use crate::ioctls::intercept::InterceptPolicy;
+use crate::ioctls::system::{probe_ioctl, MshvFeature};
 use crate::ioctls::vcpu::{new_vcpu, VcpuFd};
-use crate::ioctls::Result;
+use crate::ioctls::{metrics, MshvError, Result};
 use crate::mshv_ioctls::*;
 use mshv_bindings::*;
 
 use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryFrom;
 use std::fs::File;
+use std::mem;
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex, RwLock};
 
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use vmm_sys_util::errno;
@@ -18,7 +28,90 @@ use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref};
 
 /// Batch size for processing page access states
-const PAGE_ACCESS_STATES_BATCH_SIZE: u32 = 0x10000;
+pub(crate) const PAGE_ACCESS_STATES_BATCH_SIZE: u32 = 0x10000;
+
+/// CPUID leaf advertising invariant TSC to a partition with the given
+/// synthetic and processor features and TSC frequency, or NotSupported if
+/// Windows would not use the reference time sources on it.
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+fn reference_time_leaf(
+    synthetic: hv_partition_synthetic_processor_features,
+    processor: hv_partition_processor_features,
+    frequency: u64,
+) -> Result<hv_cpuid_entry> {
+    // SAFETY: access union fields
+    let supported = unsafe {
+        let features = synthetic.__bindgen_anon_1;
+        features.hypervisor_present() != 0
+            && features.hv1() != 0
+            && features.access_hypercall_regs() != 0
+            && features.access_partition_reference_counter() != 0
+            && features.access_partition_reference_tsc() != 0
+            && features.access_frequency_regs() != 0
+            && features.access_synic_regs() != 0
+            && features.access_synthetic_timer_regs() != 0
+            && processor.__bindgen_anon_1.tsc_invariant_support() != 0
+    };
+    if !supported || frequency == 0 {
+        return Err(MshvError::NotSupported);
+    }
+    Ok(hv_cpuid_entry {
+        function: CPUID_FUNCTION_ADVANCED_POWER_MANAGEMENT,
+        edx: CPUID_APM_INVARIANT_TSC,
+        ..Default::default()
+    })
+}
+
+// Older libc crates lack these, both are from Linux 5.14
+const MADV_POPULATE_READ: libc::c_int = 22;
+const MADV_POPULATE_WRITE: libc::c_int = 23;
+
+/// Faults in the `size` bytes of host memory at `addr`, for writing if
+/// `write`. Kernels without MADV_POPULATE_* fail with EINVAL.
+fn populate_memory(addr: u64, size: u64, write: bool) -> Result<()> {
+    let advice = if write {
+        MADV_POPULATE_WRITE
+    } else {
+        MADV_POPULATE_READ
+    };
+    // SAFETY: FFI call, populating does not change the memory contents
+    let ret = unsafe { libc::madvise(addr as *mut libc::c_void, size as usize, advice) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(errno::Error::last().into())
+    }
+}
+
+/// Splits `region` into the parts before, at and after the `size` bytes at
+/// `gpa`, the first and last possibly empty. `gpa` and `size` must be page
+/// aligned and lie within `region`.
+fn split_user_memory(
+    region: mshv_user_mem_region,
+    gpa: u64,
+    size: u64,
+) -> Result<[mshv_user_mem_region; 3]> {
+    let page_mask = (1u64 << HV_HYP_PAGE_SHIFT) - 1;
+    let start = region.guest_pfn << HV_HYP_PAGE_SHIFT;
+    if gpa & page_mask != 0 || gpa < start || gpa - start >= region.size {
+        return Err(MshvError::InvalidArgument { field: "gpa" });
+    }
+    let offset = gpa - start;
+    if size == 0 || size & page_mask != 0 || size > region.size - offset {
+        return Err(MshvError::InvalidArgument { field: "size" });
+    }
+    let part = |offset: u64, size: u64| mshv_user_mem_region {
+        size,
+        guest_pfn: region.guest_pfn + (offset >> HV_HYP_PAGE_SHIFT),
+        userspace_addr: region.userspace_addr + offset,
+        flags: region.flags,
+    };
+    Ok([
+        part(0, offset),
+        part(offset, size),
+        part(offset + size, region.size - offset - size),
+    ])
+}
 
 /// An address either in programmable I/O space or in memory mapped I/O space.
 ///
@@ -32,13 +125,37 @@ pub enum IoEventAddress {
     Mmio(u64),
 }
 
-/// VMType represents the type of VM.
+/// VMType represents the type of VM. More isolation types may be added.
 #[derive(Eq, PartialEq, Hash, Clone, Debug, Copy)]
+#[non_exhaustive]
 pub enum VmType {
     /// Normal VM with no support for confidential computing
     Normal,
     /// AMD's SEV-SNP
     Snp,
+    /// Intel's TDX
+    Tdx,
+}
+
+impl VmType {
+    /// All the VM types, in `TryFrom<u64>` order
+    pub const ALL: [VmType; 3] = [VmType::Normal, VmType::Snp, VmType::Tdx];
+
+    /// HV_PARTITION_ISOLATION_TYPE_* of the partitions of this type
+    pub fn isolation_type(&self) -> u64 {
+        let isolation_type = match self {
+            VmType::Normal => HV_PARTITION_ISOLATION_TYPE_NONE,
+            VmType::Snp => HV_PARTITION_ISOLATION_TYPE_SNP,
+            VmType::Tdx => HV_PARTITION_ISOLATION_TYPE_TDX,
+        };
+        isolation_type as u64
+    }
+
+    /// Whether the memory and state of the guest are protected from the
+    /// host by the processor
+    pub fn is_hardware_isolated(&self) -> bool {
+        *self != VmType::Normal
+    }
 }
 
 impl TryFrom<u64> for VmType {
@@ -48,6 +165,7 @@ impl TryFrom<u64> for VmType {
         match v {
             x if x == VmType::Normal as u64 => Ok(VmType::Normal),
             x if x == VmType::Snp as u64 => Ok(VmType::Snp),
+            x if x == VmType::Tdx as u64 => Ok(VmType::Tdx),
             _ => Err(()),
         }
     }
@@ -78,17 +196,101 @@ pub struct InterruptRequest {
     pub apic_id: u64,
     /// APIC Vector (entry of Interrupt Vector Table i.e IVT)
     pub vector: u32,
-    /// True means level triggered, false means edge triggered
+    /// True means level triggered, false means edge triggered. Only level
+    /// triggered interrupts make the guest EOI exit with HVMSG_X64_APIC_EOI,
+    /// so set this only for sources that need to see the EOI.
     pub level_triggered: bool,
     /// True means the APIC ID is logical, false means physical
     pub logical_destination_mode: bool,
     /// True means CPU is in long mode
     pub long_mode: bool,
 }
+impl InterruptRequest {
+    /// Decodes an x86 MSI into an interrupt request.
+    ///
+    /// The destination is the 8-bit APIC ID in address bits 19:12, extended
+    /// with the destination ID in address bits 11:5 and with the upper 24
+    /// bits in `address_hi`. Either extension reaches x2APIC IDs above 255;
+    /// guests use one or the other.
+    pub fn from_msi(address_lo: u32, address_hi: u32, data: u32) -> InterruptRequest {
+        let apic_id =
+            (address_lo >> 12) & 0xff | ((address_lo >> 5) & 0x7f) << 8 | address_hi & !0xff;
+        InterruptRequest {
+            interrupt_type: (data >> 8) & 0x7,
+            apic_id: apic_id as u64,
+            vector: data & 0xff,
+            level_triggered: data & (1 << 15) != 0,
+            logical_destination_mode: address_lo & (1 << 2) != 0,
+            long_mode: false,
+        }
+    }
+}
+/// Guest physical address layout enforced by `VmFd::map_user_memory`.
+///
+/// Mappings must end at or below `max_gpa`, if set, and must not overlap
+/// any of the `reserved` ranges, such as the PCI MMIO gap.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GpaLayout {
+    /// Highest guest physical address plus one, `None` means no limit
+    pub max_gpa: Option<u64>,
+    /// Reserved ranges, each given as (start GPA, size in bytes)
+    pub reserved: Vec<(u64, u64)>,
+}
+
+impl GpaLayout {
+    /// Checks that `size` bytes at `gpa` fit this layout.
+    pub fn check(&self, gpa: u64, size: u64) -> Result<()> {
+        let end = match gpa.checked_add(size) {
+            Some(end) => end,
+            None => return Err(MshvError::InvalidArgument { field: "size" }),
+        };
+        if self.max_gpa.is_some_and(|max| end > max) {
+            return Err(MshvError::InvalidArgument { field: "guest_pfn" });
+        }
+        if self
+            .reserved
+            .iter()
+            .any(|&(start, len)| gpa < start.saturating_add(len) && start < end)
+        {
+            return Err(MshvError::InvalidArgument { field: "guest_pfn" });
+        }
+        Ok(())
+    }
+}
+
+/// Handle of a doorbell registered with `VmFd::register_doorbell`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DoorbellId(u64);
+
+#[derive(Debug)]
+struct Doorbell {
+    gpa: u64,
+    datamatch: u64,
+    len: u32,
+    // HV_DOORBELL_FLAG_* it was registered with
+    doorbell_flags: u32,
+    // MSHV_IOEVENTFD flags
+    flags: u32,
+    fd: EventFd,
+}
+
+#[derive(Debug, Default)]
+struct DoorbellRegistry {
+    next_id: u64,
+    doorbells: BTreeMap<DoorbellId, Doorbell>,
+}
+
 /// Wrapper over Mshv VM ioctls.
 #[derive(Debug)]
 pub struct VmFd {
     vm: File,
+    gpa_layout: RwLock<GpaLayout>,
+    doorbells: Mutex<DoorbellRegistry>,
+    hypercall_codes: Mutex<BTreeSet<u16>>,
+    // (intercept type, parameter) -> mask installed through the policy calls
+    intercept_masks: Mutex<BTreeMap<(hv_intercept_type, u64), u32>>,
+    features: Mutex<BTreeMap<MshvFeature, bool>>,
+    device_regions: Arc<DeviceRegions>,
 }
 
 impl AsRawFd for VmFd {
@@ -97,6 +299,16 @@ impl AsRawFd for VmFd {
     }
 }
 
+impl FromRawFd for VmFd {
+    /// This function is also unsafe as the primitives currently returned have the contract that
+    /// they are the sole owner of the file descriptor they are wrapping. Usage of this function
+    /// could accidentally allow violating this contract which can cause memory unsafety in code
+    /// that relies on it being true.
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        new_vmfd(File::from_raw_fd(fd))
+    }
+}
+
 impl VmFd {
     /// Install intercept to enable some VM exits like MSR, CPUId etc
     pub fn install_intercept(&self, install_intercept_args: mshv_install_intercept) -> Result<()> {
@@ -106,8 +318,163 @@ impl VmFd {
         if ret == 0 {
             Ok(())
         } else {
-            Err(errno::Error::last())
+            Err(errno::Error::last().into())
+        }
+    }
+    /// Installs `intercept`, recording its mask for `set_intercept_policy`
+    /// to restore.
+    fn install_intercept_mask(&self, intercept: mshv_install_intercept) -> Result<()> {
+        self.install_intercept(intercept)?;
+        // SAFETY: policies build the parameter from as_uint64
+        let key = (intercept.intercept_type, unsafe {
+            intercept.intercept_parameter.as_uint64
+        });
+        let mut masks = self.intercept_masks.lock().unwrap();
+        match intercept.access_type_mask {
+            HV_INTERCEPT_ACCESS_MASK_NONE => masks.remove(&key),
+            access => masks.insert(key, access),
+        };
+        Ok(())
+    }
+    /// Returns the access mask last installed on the intercept of
+    /// `intercept_type` with `parameter` by `set_intercept_policy`, the
+    /// calls built on it or `install_io_intercept`, and
+    /// HV_INTERCEPT_ACCESS_MASK_NONE if there is none. The hypervisor cannot
+    /// be asked, intercepts installed with `install_intercept` are not
+    /// known.
+    pub fn intercept_mask(&self, intercept_type: hv_intercept_type, parameter: u64) -> u32 {
+        self.intercept_masks
+            .lock()
+            .unwrap()
+            .get(&(intercept_type, parameter))
+            .copied()
+            .unwrap_or(HV_INTERCEPT_ACCESS_MASK_NONE)
+    }
+    /// Installs every intercept of `policy`, typically once at partition
+    /// setup.
+    ///
+    /// If one fails, the intercepts of the policy already installed get
+    /// back the mask `intercept_mask` returned before the call. For those
+    /// that had none, this installs HV_INTERCEPT_ACCESS_MASK_NONE, which
+    /// the hypervisor is expected to treat as no intercept but does not
+    /// document, so the call is only all or nothing for intercepts that
+    /// previous policies installed. The hypercalls of the policy are
+    /// registered last, once every intercept is in, so a failure leaves
+    /// the forwarded hypercalls as they were.
+    pub fn set_intercept_policy(&self, policy: &InterceptPolicy) -> Result<()> {
+        let intercepts = policy.intercepts();
+        let prior: Vec<u32> = intercepts
+            .iter()
+            .map(|intercept| {
+                // SAFETY: policies build the parameter from as_uint64
+                let parameter = unsafe { intercept.intercept_parameter.as_uint64 };
+                self.intercept_mask(intercept.intercept_type, parameter)
+            })
+            .collect();
+        let mut result = Ok(());
+        let mut installed = 0;
+        for intercept in intercepts.iter() {
+            result = self.install_intercept_mask(*intercept);
+            if result.is_err() {
+                break;
+            }
+            installed += 1;
+        }
+        let hypercalls = policy.hypercalls();
+        if result.is_ok() && !hypercalls.is_empty() {
+            result = self.install_hypercall_intercept(&hypercalls);
         }
+        if result.is_err() {
+            for (intercept, access) in intercepts[..installed].iter().zip(prior) {
+                let _ = self.install_intercept_mask(mshv_install_intercept {
+                    access_type_mask: access,
+                    ..*intercept
+                });
+            }
+        }
+        result
+    }
+    /// Makes guest writes to HV_X64_MSR_CRASH_CTL exit to the VMM, so crashes
+    /// reported by the guest can be picked up with `VcpuFd::guest_crash_exit`.
+    /// The crash parameter MSRs are still handled by the hypervisor.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn install_guest_crash_intercept(&self) -> Result<()> {
+        self.set_intercept_policy(&InterceptPolicy::new().intercept_guest_crashes())
+    }
+    /// Makes the guest writes that reset the partition exit to the VMM, so
+    /// they can be told apart with `hv_message::to_reset_exit`: the i8042
+    /// command port, the reset control register and HV_X64_MSR_RESET.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn install_reset_intercepts(&self) -> Result<()> {
+        self.set_intercept_policy(&InterceptPolicy::new().intercept_resets())
+    }
+    /// Makes the ways a guest stops running exit to the VMM, so they can be
+    /// told apart with `VcpuFd::shutdown_exit`: HvCallEnterSleepState and
+    /// crash reports. Triple faults always exit.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn install_shutdown_intercepts(&self) -> Result<()> {
+        self.set_intercept_policy(&InterceptPolicy::new().intercept_shutdowns())
+    }
+    /// Installs an I/O port intercept on every port in `ports`, so that
+    /// guest accesses matching `access` (HV_INTERCEPT_ACCESS_MASK_*) exit to
+    /// user space.
+    ///
+    /// HV_INTERCEPT_TYPE_X64_IO_PORT takes a single port and there is no
+    /// range intercept type, so this costs one MSHV_INSTALL_INTERCEPT ioctl
+    /// and hypercall per port: large ranges are slow to install and are best
+    /// set up once, before the vCPUs run. Stops at the first port that
+    /// fails, leaving the ports before it installed.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn install_io_intercept(&self, ports: RangeInclusive<u16>, access: u32) -> Result<()> {
+        let policy = InterceptPolicy::new().intercept_io_ports(ports, access);
+        for intercept in policy.intercepts() {
+            self.install_intercept_mask(intercept)?;
+        }
+        Ok(())
+    }
+    /// Removes the I/O port intercepts on `ports`, by installing them with
+    /// an empty access mask, one port at a time as `install_io_intercept`.
+    ///
+    /// MSHV has no call to remove an intercept. This relies on the
+    /// hypervisor treating HV_INTERCEPT_ACCESS_MASK_NONE as no intercept,
+    /// which is not documented and not checked here: callers must still
+    /// handle exits from the removed ports.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn remove_io_intercept(&self, ports: RangeInclusive<u16>) -> Result<()> {
+        self.install_io_intercept(ports, HV_INTERCEPT_ACCESS_MASK_NONE)
+    }
+    /// Forwards the hypercalls in `codes` to user space, typically the
+    /// HvExtCall* services. They exit with HVMSG_HYPERCALL_INTERCEPT, which
+    /// `HypercallExit` decodes and `VcpuFd::complete_hypercall` completes.
+    ///
+    /// The hypervisor intercept is not per code: it is installed on the
+    /// first call and other hypercalls it does not handle exit too. Use
+    /// `forwards_hypercall` to tell them apart and fail them with
+    /// HV_STATUS_INVALID_HYPERCALL_CODE.
+    pub fn install_hypercall_intercept(&self, codes: &[u16]) -> Result<()> {
+        let mut registry = self.hypercall_codes.lock().unwrap();
+        if registry.is_empty() {
+            self.install_intercept(mshv_install_intercept {
+                access_type_mask: HV_INTERCEPT_ACCESS_MASK_EXECUTE,
+                intercept_type: hv_intercept_type_HV_INTERCEPT_TYPE_HYPERCALL,
+                intercept_parameter: hv_intercept_parameters { as_uint64: 0 },
+            })?;
+        }
+        registry.extend(codes);
+        Ok(())
+    }
+    /// Stops forwarding the hypercalls in `codes`. The intercept stays
+    /// installed, so they still exit and should be failed by the caller.
+    pub fn remove_hypercall_intercept(&self, codes: &[u16]) {
+        let mut registry = self.hypercall_codes.lock().unwrap();
+        for code in codes {
+            registry.remove(code);
+        }
+    }
+    /// Returns true if hypercall `code` was registered with
+    /// `install_hypercall_intercept`.
+    pub fn forwards_hypercall(&self, code: u16) -> bool {
+        self.hypercall_codes.lock().unwrap().contains(&code)
     }
     /// Modify host visibility for a range of GPA
     pub fn modify_gpa_host_access(
@@ -120,7 +487,7 @@ impl VmFd {
         if ret == 0 {
             Ok(())
         } else {
-            Err(errno::Error::last())
+            Err(errno::Error::last().into())
         }
     }
     /// Import the isolated pages
@@ -133,9 +500,44 @@ impl VmFd {
         if ret == 0 {
             Ok(())
         } else {
-            Err(errno::Error::last())
+            Err(errno::Error::last().into())
         }
     }
+    /// Imports the 4K pages at guest page numbers `pfns` as `page_type`,
+    /// building the `mshv_import_isolated_pages` list for
+    /// `import_isolated_pages`.
+    pub fn import_isolated_page_list(
+        &self,
+        page_type: hv_isolated_page_type,
+        pfns: &[u64],
+    ) -> Result<()> {
+        let header_len = mem::size_of::<mshv_import_isolated_pages>() / mem::size_of::<u64>();
+        let mut buf = vec![0u64; header_len + pfns.len()];
+        buf[header_len..].copy_from_slice(pfns);
+        // SAFETY: buf is u64 aligned and holds the header followed by the
+        // page numbers
+        let list = unsafe { &mut *(buf.as_mut_ptr() as *mut mshv_import_isolated_pages) };
+        list.page_type = page_type;
+        list.page_size = hv_isolated_page_size_HV_ISOLATED_PAGE_SIZE_4KB;
+        list.num_pages = pfns.len() as u64;
+        self.import_isolated_pages(list)
+    }
+    /// Imports the page at `gpa` as the SNP secrets page. The page must
+    /// hold a zeroed `SnpSecretsPage`.
+    pub fn import_snp_secrets_page(&self, gpa: u64) -> Result<()> {
+        self.import_isolated_page_list(
+            hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_SECRETS,
+            &[gpa >> HV_HYP_PAGE_SHIFT],
+        )
+    }
+    /// Imports the page at `gpa` as the SNP CPUID page. The page must
+    /// hold a `SnpCpuidPage`, see `VcpuFd::snp_cpuid_page`.
+    pub fn import_snp_cpuid_page(&self, gpa: u64) -> Result<()> {
+        self.import_isolated_page_list(
+            hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_CPUID,
+            &[gpa >> HV_HYP_PAGE_SHIFT],
+        )
+    }
     /// Mark completion of importing the isoalted pages
     pub fn complete_isolated_import(&self, data: &mshv_complete_isolated_import) -> Result<()> {
         // SAFETY: IOCTL with correct types
@@ -143,7 +545,7 @@ impl VmFd {
         if ret == 0 {
             Ok(())
         } else {
-            Err(errno::Error::last())
+            Err(errno::Error::last().into())
         }
     }
     /// Issue PSP request from guest side
@@ -153,7 +555,7 @@ impl VmFd {
         if ret == 0 {
             Ok(())
         } else {
-            Err(errno::Error::last())
+            Err(errno::Error::last().into())
         }
     }
     /// Create AP threads for SEV-SNP guest
@@ -163,38 +565,207 @@ impl VmFd {
         if ret == 0 {
             Ok(())
         } else {
-            Err(errno::Error::last())
+            Err(errno::Error::last().into())
         }
     }
+    /// Sets the highest guest physical address plus one that memory may be
+    /// mapped below. Only applies to later calls to `map_user_memory`.
+    pub fn set_max_gpa(&self, max_gpa: u64) {
+        self.gpa_layout.write().unwrap().max_gpa = Some(max_gpa);
+    }
+    /// Reserves `size` bytes at `gpa`, e.g. for a PCI MMIO gap, so that
+    /// `map_user_memory` refuses to map memory there. Only applies to later
+    /// calls to `map_user_memory`.
+    pub fn reserve_gpa_range(&self, gpa: u64, size: u64) -> Result<()> {
+        if size == 0 || gpa.checked_add(size).is_none() {
+            return Err(MshvError::InvalidArgument { field: "size" });
+        }
+        self.gpa_layout.write().unwrap().reserved.push((gpa, size));
+        Ok(())
+    }
+    /// Returns the guest physical address layout declared so far.
+    pub fn gpa_layout(&self) -> GpaLayout {
+        self.gpa_layout.read().unwrap().clone()
+    }
     /// Creates/modifies a guest physical memory.
+    ///
+    /// Fails with `InvalidArgument` if the region goes past the maximum GPA
+    /// or overlaps a reserved range, see `gpa_layout`.
+    ///
+    /// The driver pins the host pages of the region while mapping it, so
+    /// the memory cannot be filled in lazily, e.g. through userfaultfd for
+    /// post-copy migration: the map call itself would block on the missing
+    /// pages until a fault handler fills all of them in.
     pub fn map_user_memory(&self, user_memory_region: mshv_user_mem_region) -> Result<()> {
+        self.gpa_layout.read().unwrap().check(
+            user_memory_region.guest_pfn << HV_HYP_PAGE_SHIFT,
+            user_memory_region.size,
+        )?;
         // SAFETY: IOCTL with correct types
         let ret = unsafe { ioctl_with_ref(self, MSHV_MAP_GUEST_MEMORY(), &user_memory_region) };
         if ret == 0 {
+            metrics::counter(metrics::METRIC_MEMORY_OPERATIONS, &[("map", 1)], 1);
+            metrics::gauge(
+                metrics::METRIC_MAPPED_MEMORY,
+                &[],
+                user_memory_region.size as i64,
+            );
             Ok(())
         } else {
-            Err(errno::Error::last())
+            Err(errno::Error::last().into())
         }
     }
+    /// Maps guest memory like `map_user_memory`, after faulting in all of
+    /// its host pages, writable ones for writing. The guest then never
+    /// waits on first-touch faults of that memory, at the cost of
+    /// allocating all of it upfront. Memory mapped with MAP_POPULATE is
+    /// already faulted in.
+    ///
+    /// Fails with EINVAL, without mapping anything, on kernels older than
+    /// 5.14, which lack MADV_POPULATE_*. Touching the pages instead would
+    /// race with anything else writing to the memory, so there the caller
+    /// has to create it with MAP_POPULATE or mlock it.
+    pub fn map_user_memory_populated(
+        &self,
+        user_memory_region: mshv_user_mem_region,
+    ) -> Result<()> {
+        populate_memory(
+            user_memory_region.userspace_addr,
+            user_memory_region.size,
+            user_memory_region.flags & HV_MAP_GPA_WRITABLE != 0,
+        )?;
+        self.map_user_memory(user_memory_region)
+    }
     /// Unmap a guest physical memory.
     pub fn unmap_user_memory(&self, user_memory_region: mshv_user_mem_region) -> Result<()> {
         // SAFETY: IOCTL with correct types
         let ret = unsafe { ioctl_with_ref(self, MSHV_UNMAP_GUEST_MEMORY(), &user_memory_region) };
         if ret == 0 {
+            metrics::counter(metrics::METRIC_MEMORY_OPERATIONS, &[("map", 0)], 1);
+            metrics::gauge(
+                metrics::METRIC_MAPPED_MEMORY,
+                &[],
+                -(user_memory_region.size as i64),
+            );
             Ok(())
         } else {
-            Err(errno::Error::last())
+            Err(errno::Error::last().into())
         }
     }
-    /// Creates a new MSHV vCPU file descriptor
-    pub fn create_vcpu(&self, id: u8) -> Result<VcpuFd> {
-        let vp_arg = mshv_create_vp {
-            vp_index: id as __u32,
+    /// Unmaps `region` and maps `parts` in its place, putting `region` back
+    /// if one of them fails so the guest doesn't lose more than asked for.
+    /// Between the unmap and the maps the whole of `region` is unmapped.
+    fn remap_user_memory(
+        &self,
+        region: mshv_user_mem_region,
+        parts: &[mshv_user_mem_region],
+    ) -> Result<()> {
+        self.unmap_user_memory(region)?;
+        for (i, part) in parts.iter().enumerate() {
+            if let Err(e) = self.map_user_memory(*part) {
+                for mapped in &parts[..i] {
+                    let _ = self.unmap_user_memory(*mapped);
+                }
+                let _ = self.map_user_memory(region);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+    /// Punches a hole of `size` bytes at `gpa` into the mapped `region` and
+    /// releases the backing pages with MADV_DONTNEED, for balloon drivers.
+    ///
+    /// MSHV can only unmap whole regions, so `region` is unmapped and the
+    /// parts around the hole are mapped again. Returns those parts, which
+    /// replace `region` for later unmaps. Guest accesses to the hole exit as
+    /// unmapped GPA until the range is mapped again. `gpa` and `size` must be
+    /// page aligned and lie within `region`.
+    ///
+    /// All vCPUs of the partition must be paused, out of `VcpuFd::run`, for
+    /// the duration of the call: while the parts are being mapped the whole
+    /// of `region` is unmapped, and a running vCPU touching it would take an
+    /// unmapped GPA exit the VMM cannot tell from a real one. Parts are
+    /// never coalesced: each call leaves up to two regions where there was
+    /// one, each a separate driver mapping, until the caller unmaps them and
+    /// maps the whole range again.
+    ///
+    /// Once the hole is unmapped the call succeeds: if madvise then fails,
+    /// the pages stay allocated and the failure is only counted in the
+    /// `mshv_discard_errors_total` metric, so the parts are never lost.
+    pub fn discard_user_memory(
+        &self,
+        region: mshv_user_mem_region,
+        gpa: u64,
+        size: u64,
+    ) -> Result<Vec<mshv_user_mem_region>> {
+        let [before, _, after] = split_user_memory(region, gpa, size)?;
+        let parts: Vec<_> = [before, after]
+            .iter()
+            .filter(|part| part.size != 0)
+            .copied()
+            .collect();
+        self.remap_user_memory(region, &parts)?;
+        // SAFETY: FFI call, the range lies within memory the caller handed
+        // to the guest and the guest can no longer reach it.
+        let ret = unsafe {
+            libc::madvise(
+                (region.userspace_addr + before.size) as *mut libc::c_void,
+                size as usize,
+                libc::MADV_DONTNEED,
+            )
         };
+        if ret != 0 {
+            metrics::counter(
+                metrics::METRIC_DISCARD_ERRORS,
+                &[("errno", errno::Error::last().errno() as u64)],
+                1,
+            );
+        }
+        Ok(parts)
+    }
+    /// Changes the guest permissions of the `size` bytes at `gpa` of the
+    /// mapped `region` to `protection`, e.g. to make a range read-only for
+    /// ROM emulation or to trap instruction fetches for introspection.
+    ///
+    /// As for `discard_user_memory`, `region` is unmapped and mapped again
+    /// in parts, which are returned and replace `region` for later unmaps
+    /// and protection changes. `gpa` and `size` must be page aligned and lie
+    /// within `region`. Guest accesses the protection denies exit with
+    /// HVMSG_GPA_INTERCEPT, decoded by `hv_message::to_protection_violation`;
+    /// the VMM emulates or skips the instruction.
+    ///
+    /// The vCPUs must be paused, and the parts are never coalesced, as for
+    /// `discard_user_memory`: a call leaves up to three regions, and a range
+    /// protected again with the permissions of its neighbours stays a
+    /// separate part.
+    pub fn protect_user_memory(
+        &self,
+        region: mshv_user_mem_region,
+        gpa: u64,
+        size: u64,
+        protection: GpaProtection,
+    ) -> Result<Vec<mshv_user_mem_region>> {
+        let mut parts = split_user_memory(region, gpa, size)?;
+        parts[1].flags = (region.flags & !HV_MAP_GPA_PERMISSIONS_MASK) | protection.bits();
+        let parts: Vec<_> = parts
+            .iter()
+            .filter(|part| part.size != 0)
+            .copied()
+            .collect();
+        self.remap_user_memory(region, &parts)?;
+        Ok(parts)
+    }
+    /// Creates a new MSHV vCPU file descriptor for VP index `id`, at most
+    /// HV_MAX_VP_INDEX.
+    pub fn create_vcpu(&self, id: u32) -> Result<VcpuFd> {
+        if id > HV_MAX_VP_INDEX {
+            return Err(MshvError::InvalidArgument { field: "id" });
+        }
+        let vp_arg = mshv_create_vp { vp_index: id };
         // SAFETY: IOCTL with correct types
         let vcpu_fd = unsafe { ioctl_with_ref(&self.vm, MSHV_CREATE_VP(), &vp_arg) };
         if vcpu_fd < 0 {
-            return Err(errno::Error::last());
+            return Err(errno::Error::last().into());
         }
 
         // Wrap the vCPU now in case the following ? returns early. This is safe because we verified
@@ -202,7 +773,51 @@ impl VmFd {
         // SAFETY: we're sure vcpu_fd is valid.
         let vcpu = unsafe { File::from_raw_fd(vcpu_fd) };
 
-        Ok(new_vcpu(vcpu))
+        #[allow(unused_mut)]
+        let mut vcpu = new_vcpu(vcpu);
+        #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+        vcpu.set_device_regions(Arc::clone(&self.device_regions));
+        Ok(vcpu)
+    }
+    /// Registers `size` bytes from `gpa` as emulated by a device of the VMM
+    /// identified by `cookie`. Guest accesses to the range must not be
+    /// mapped: they exit as unmapped GPA, which `VcpuFd::device_mmio_exit`
+    /// tags with `cookie`. Fails with EINVAL if the range overlaps another
+    /// device region.
+    pub fn register_device_region(&self, gpa: u64, size: u64, cookie: u64) -> Result<()> {
+        self.device_regions
+            .insert(DeviceRegion { gpa, size, cookie })
+    }
+    /// Unregisters the device region starting at `gpa`, returning it.
+    pub fn unregister_device_region(&self, gpa: u64) -> Option<DeviceRegion> {
+        self.device_regions.remove(gpa)
+    }
+    /// Returns the device region `gpa` is in.
+    pub fn device_region_at(&self, gpa: u64) -> Option<DeviceRegion> {
+        self.device_regions.lookup(gpa)
+    }
+    /// Creates vCPU `id` with APIC ID `apic_id`, for topologies where the
+    /// APIC ID is not the VP index, such as when APIC IDs encode sockets or
+    /// leave holes between cores.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn create_vcpu_with_apic_id(&self, id: u32, apic_id: u32) -> Result<VcpuFd> {
+        let vcpu = self.create_vcpu(id)?;
+        vcpu.set_apic_id(apic_id)?;
+        Ok(vcpu)
+    }
+    /// Creates vCPU `id` on a partition that is already running, for CPU
+    /// hotplug. The VP index can be any one not yet in use, it need not
+    /// follow the vCPUs created at boot.
+    ///
+    /// On x86 the vCPU is put in its RESET state and in wait for SIPI state
+    /// with `VcpuFd::init_ap`, as an application processor after INIT: it
+    /// can be run, but executes nothing until the VMM delivers the startup
+    /// IPI of the guest with `VcpuFd::start_ap`.
+    pub fn hotplug_vcpu(&self, id: u32) -> Result<VcpuFd> {
+        let vcpu = self.create_vcpu(id)?;
+        #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+        vcpu.init_ap()?;
+        Ok(vcpu)
     }
     /// Inject an interrupt into the guest..
     pub fn request_virtual_interrupt(&self, request: &InterruptRequest) -> Result<()> {
@@ -229,12 +844,19 @@ impl VmFd {
         if ret == 0 {
             Ok(())
         } else {
-            Err(errno::Error::last())
+            Err(errno::Error::last().into())
         }
     }
     ///
     /// signal_event_direct: Send a sint signal event to the vp.
+    ///
+    /// Fails with `MshvError::MissingFeature` on drivers without
+    /// MSHV_SIGNAL_EVENT_DIRECT. The hypercall is not one the driver passes
+    /// through either, so there is nothing to fall back to.
     pub fn signal_event_direct(&self, vp: u32, sint: u8, flag: u16) -> Result<bool> {
+        if self.is_missing(MshvFeature::DirectSynic) {
+            return Err(MshvError::MissingFeature(MshvFeature::DirectSynic));
+        }
         let mut event_info = mshv_signal_event_direct {
             vp,
             vtl: 0,
@@ -247,12 +869,18 @@ impl VmFd {
         if ret == 0 {
             Ok(event_info.newly_signaled != 0)
         } else {
-            Err(errno::Error::last())
+            Err(self.feature_error(MshvFeature::DirectSynic))
         }
     }
     ///
     /// post_message_direct: Post a message to the vp using a given sint.
+    ///
+    /// Fails with `MshvError::MissingFeature` on drivers without
+    /// MSHV_POST_MESSAGE_DIRECT, as `signal_event_direct` does.
     pub fn post_message_direct(&self, vp: u32, sint: u8, msg: &[u8]) -> Result<()> {
+        if self.is_missing(MshvFeature::DirectSynic) {
+            return Err(MshvError::MissingFeature(MshvFeature::DirectSynic));
+        }
         let message_info = mshv_post_message_direct {
             vp,
             vtl: 0,
@@ -265,7 +893,7 @@ impl VmFd {
         if ret == 0 {
             Ok(())
         } else {
-            Err(errno::Error::last())
+            Err(self.feature_error(MshvFeature::DirectSynic))
         }
     }
     ///
@@ -288,9 +916,146 @@ impl VmFd {
         if ret == 0 {
             Ok(())
         } else {
-            Err(errno::Error::last())
+            Err(errno::Error::last().into())
         }
     }
+    /// Requests an exit as soon as the guest on `vp` can take an external
+    /// interrupt, so an interrupt controller in user space injects queued
+    /// vectors when they are deliverable. The exit is an
+    /// HVMSG_X64_INTERRUPTION_DELIVERABLE message, see
+    /// `hv_message::to_interrupt_window_exit`, and the request is dropped
+    /// once it is sent.
+    ///
+    /// This goes through `register_deliverabilty_notifications` and so
+    /// replaces whatever notifications are pending for `vp`. There is no NMI
+    /// counterpart: the driver rejects the NMI notification flag with EINVAL.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn request_interrupt_window(&self, vp: u32) -> Result<()> {
+        self.register_deliverabilty_notifications(vp, HV_DELIVERABILITY_INTERRUPT_NOTIFICATION)
+    }
+    /// Returns whether the driver implements the ioctls of `feature` for
+    /// this VM, None for vCPU features, which cannot be probed on a VM.
+    ///
+    /// The ioctl is issued once, with a NULL argument the driver fails to
+    /// copy in before acting on it, and the feature is missing if it fails
+    /// with ENOTTY rather than EFAULT. The answer is kept
+    /// for the lifetime of the VM, as are features the wrappers found
+    /// missing: their calls then fail with `MshvError::MissingFeature`.
+    pub fn supports(&self, feature: MshvFeature) -> Option<bool> {
+        if feature.is_vcpu_feature() {
+            return None;
+        }
+        let mut features = self.features.lock().unwrap();
+        if let Some(supported) = features.get(&feature) {
+            return Some(*supported);
+        }
+        let supported = match feature {
+            MshvFeature::Devices => {
+                // Only checks that the device type exists
+                let mut device = mshv_create_device {
+                    type_: mshv_device_type_MSHV_DEV_TYPE_VFIO,
+                    flags: MSHV_CREATE_DEVICE_TEST,
+                    ..Default::default()
+                };
+                // SAFETY: MSHV_CREATE_DEVICE_TEST creates no device
+                let ret = unsafe { ioctl_with_mut_ref(self, MSHV_CREATE_DEVICE(), &mut device) };
+                ret >= 0 || errno::Error::last().errno() != libc::ENOTTY
+            }
+            MshvFeature::DirectSynic => probe_ioctl(self, MSHV_SIGNAL_EVENT_DIRECT()),
+            MshvFeature::IoEventFd => probe_ioctl(self, MSHV_IOEVENTFD()),
+            MshvFeature::GpaAccessStates => probe_ioctl(self, MSHV_GET_GPA_ACCESS_STATES()),
+            _ => probe_ioctl(self, MSHV_ROOT_HVCALL()),
+        };
+        features.insert(feature, supported);
+        Some(supported)
+    }
+    /// Returns true if a call already found `feature` missing
+    fn is_missing(&self, feature: MshvFeature) -> bool {
+        self.features.lock().unwrap().get(&feature) == Some(&false)
+    }
+    /// Turns the failure of the last ioctl of `feature` into an error,
+    /// recording the feature as missing if the driver does not know it.
+    fn feature_error(&self, feature: MshvFeature) -> MshvError {
+        let e = errno::Error::last();
+        if e.errno() != libc::ENOTTY {
+            return e.into();
+        }
+        self.features.lock().unwrap().insert(feature, false);
+        MshvError::MissingFeature(feature)
+    }
+    /// Issues hypercall `code` on behalf of the partition, for hypercalls
+    /// not wrapped by this crate. `input` and `output` are copied through
+    /// page-aligned buffers and may be up to a page each. The driver picks
+    /// the calling convention, fast calls cannot be requested.
+    ///
+    /// This needs the MSHV_ROOT_HVCALL pass-through of the upstream Linux
+    /// mshv_root driver (include/uapi/linux/mshv.h). Fails with
+    /// `MshvError::Hypercall` if the hypervisor rejects the call and
+    /// `MshvError::NotSupported` if the driver has no pass-through.
+    pub fn hvcall_raw(&self, code: u16, input: &[u8], output: &mut [u8]) -> Result<()> {
+        self.hvcall(code, 0, input, output)
+    }
+    /// Issues rep hypercall `code` for `reps` elements. `input` holds the
+    /// header followed by the elements, `output` receives an element per rep
+    /// for hypercalls that return any. Same limits and errors as
+    /// `hvcall_raw`.
+    pub fn hvcall_raw_rep(
+        &self,
+        code: u16,
+        reps: u16,
+        input: &[u8],
+        output: &mut [u8],
+    ) -> Result<()> {
+        if reps == 0 {
+            return Err(MshvError::InvalidArgument { field: "reps" });
+        }
+        self.hvcall(code, reps, input, output)
+    }
+    fn hvcall(&self, code: u16, reps: u16, input: &[u8], output: &mut [u8]) -> Result<()> {
+        if self.is_missing(MshvFeature::RootHvcall) {
+            return Err(MshvError::NotSupported);
+        }
+        let page_size = 1 << HV_HYP_PAGE_SHIFT;
+        if input.len() > page_size {
+            return Err(MshvError::InvalidArgument { field: "input" });
+        }
+        if output.len() > page_size {
+            return Err(MshvError::InvalidArgument { field: "output" });
+        }
+        let mut in_page = AlignedBuffer::new(page_size, page_size)?;
+        let mut out_page = AlignedBuffer::new(page_size, page_size)?;
+        in_page.as_mut_slice()[..input.len()].copy_from_slice(input);
+        let mut args = mshv_root_hvcall {
+            code,
+            reps,
+            in_sz: input.len() as u16,
+            out_sz: output.len() as u16,
+            in_ptr: in_page.as_ptr() as u64,
+            out_ptr: out_page.as_mut_ptr() as u64,
+            ..Default::default()
+        };
+        // SAFETY: IOCTL with correct types, the pages outlive the call
+        let ret = unsafe { ioctl_with_mut_ref(self, MSHV_ROOT_HVCALL(), &mut args) };
+        if args.status as hv_status != hv_status_HV_STATUS_SUCCESS {
+            metrics::counter(
+                metrics::METRIC_HYPERCALL_FAILURES,
+                &[("code", code as u64), ("status", args.status as u64)],
+                1,
+            );
+            return Err(MshvError::Hypercall {
+                code,
+                hv_status: args.status,
+            });
+        }
+        if ret != 0 {
+            return match self.feature_error(MshvFeature::RootHvcall) {
+                MshvError::MissingFeature(_) => Err(MshvError::NotSupported),
+                e => Err(e),
+            };
+        }
+        output.copy_from_slice(&out_page.as_slice()[..output.len()]);
+        Ok(())
+    }
     /// irqfd: Passes in an eventfd which is to be used for injecting
     /// interrupts from userland.
     fn irqfd(&self, fd: RawFd, resamplefd: RawFd, gsi: u32, flags: u32) -> Result<()> {
@@ -306,7 +1071,7 @@ impl VmFd {
         if ret == 0 {
             Ok(())
         } else {
-            Err(errno::Error::last())
+            Err(errno::Error::last().into())
         }
     }
     /// Registers an event that will, when signaled, trigger the `gsi` IRQ.
@@ -427,7 +1192,7 @@ impl VmFd {
         if ret == 0 {
             Ok(())
         } else {
-            Err(errno::Error::last())
+            Err(errno::Error::last().into())
         }
     }
 
@@ -445,7 +1210,7 @@ impl VmFd {
         //
         let mmio_addr = match addr {
             IoEventAddress::Pio(_) => {
-                return Err(errno::Error::new(libc::ENOTSUP));
+                return Err(MshvError::NotSupported);
             }
             IoEventAddress::Mmio(ref m) => *m,
         };
@@ -454,10 +1219,29 @@ impl VmFd {
             flags |= 1 << mshv_ioeventfd_flag_nr_datamatch
         }
 
+        self.mmio_ioeventfd(
+            fd,
+            mmio_addr,
+            datamatch.into(),
+            std::mem::size_of::<T>() as u32,
+            flags,
+        )
+    }
+    pub(crate) fn mmio_ioeventfd(
+        &self,
+        fd: &EventFd,
+        addr: u64,
+        datamatch: u64,
+        len: u32,
+        flags: u32,
+    ) -> Result<()> {
+        if self.is_missing(MshvFeature::IoEventFd) {
+            return Err(MshvError::MissingFeature(MshvFeature::IoEventFd));
+        }
         let ioeventfd = mshv_ioeventfd {
-            datamatch: datamatch.into(),
-            len: std::mem::size_of::<T>() as u32,
-            addr: mmio_addr,
+            datamatch,
+            len,
+            addr,
             fd: fd.as_raw_fd(),
             flags,
             ..Default::default()
@@ -468,7 +1252,7 @@ impl VmFd {
         if ret == 0 {
             Ok(())
         } else {
-            Err(errno::Error::last())
+            Err(self.feature_error(MshvFeature::IoEventFd))
         }
     }
     /// Registers an event to be signaled whenever a certain address is written to.
@@ -540,6 +1324,105 @@ impl VmFd {
     ) -> Result<()> {
         self.ioeventfd(fd, addr, datamatch, 1 << mshv_ioeventfd_flag_nr_deassign)
     }
+    /// Registers a doorbell: guest writes to `gpa` signal `eventfd` without
+    /// an exit to the VMM.
+    ///
+    /// `flags` are HV_DOORBELL_FLAG_*. The trigger size selects the write
+    /// width that rings the doorbell, HV_DOORBELL_FLAG_TRIGGER_SIZE_ANY
+    /// meaning any width. Unless HV_DOORBELL_FLAG_TRIGGER_ANY_VALUE is set,
+    /// only writes of `value_match` ring it, which needs a trigger size.
+    ///
+    /// The registry keeps a duplicate of `eventfd` until the doorbell is
+    /// unregistered with the returned handle.
+    pub fn register_doorbell(
+        &self,
+        gpa: u64,
+        value_match: u64,
+        flags: u32,
+        eventfd: &EventFd,
+    ) -> Result<DoorbellId> {
+        if flags & !(HV_DOORBELL_FLAG_TRIGGER_SIZE_MASK | HV_DOORBELL_FLAG_TRIGGER_ANY_VALUE) != 0 {
+            return Err(MshvError::InvalidArgument { field: "flags" });
+        }
+        let len = match flags & HV_DOORBELL_FLAG_TRIGGER_SIZE_MASK {
+            HV_DOORBELL_FLAG_TRIGGER_SIZE_ANY => 0,
+            HV_DOORBELL_FLAG_TRIGGER_SIZE_BYTE => 1,
+            HV_DOORBELL_FLAG_TRIGGER_SIZE_WORD => 2,
+            HV_DOORBELL_FLAG_TRIGGER_SIZE_DWORD => 4,
+            HV_DOORBELL_FLAG_TRIGGER_SIZE_QWORD => 8,
+            _ => return Err(MshvError::InvalidArgument { field: "flags" }),
+        };
+        let mut ioeventfd_flags = 0;
+        if flags & HV_DOORBELL_FLAG_TRIGGER_ANY_VALUE == 0 {
+            if len == 0 {
+                return Err(MshvError::InvalidArgument { field: "flags" });
+            }
+            ioeventfd_flags |= 1 << mshv_ioeventfd_flag_nr_datamatch;
+        }
+        let doorbell = Doorbell {
+            gpa,
+            datamatch: value_match,
+            len,
+            doorbell_flags: flags,
+            flags: ioeventfd_flags,
+            fd: eventfd.try_clone().map_err(|_| errno::Error::last())?,
+        };
+
+        let mut registry = self.doorbells.lock().unwrap();
+        self.mmio_ioeventfd(&doorbell.fd, gpa, value_match, len, ioeventfd_flags)?;
+        let id = DoorbellId(registry.next_id);
+        registry.next_id += 1;
+        registry.doorbells.insert(id, doorbell);
+        Ok(id)
+    }
+    /// Unregisters the doorbell `id`. Fails with ENOENT if it is not
+    /// registered.
+    pub fn unregister_doorbell(&self, id: DoorbellId) -> Result<()> {
+        let mut registry = self.doorbells.lock().unwrap();
+        let doorbell = match registry.doorbells.get(&id) {
+            Some(doorbell) => doorbell,
+            None => return Err(errno::Error::new(libc::ENOENT).into()),
+        };
+        self.mmio_ioeventfd(
+            &doorbell.fd,
+            doorbell.gpa,
+            doorbell.datamatch,
+            doorbell.len,
+            doorbell.flags | 1 << mshv_ioeventfd_flag_nr_deassign,
+        )?;
+        registry.doorbells.remove(&id);
+        Ok(())
+    }
+    // Handle of the doorbell registered with these arguments and the same
+    // open eventfd, if any
+    pub(crate) fn find_doorbell(
+        &self,
+        gpa: u64,
+        value_match: u64,
+        flags: u32,
+        eventfd: &EventFd,
+    ) -> Option<DoorbellId> {
+        let registry = self.doorbells.lock().unwrap();
+        registry
+            .doorbells
+            .iter()
+            .find(|(_, doorbell)| {
+                doorbell.gpa == gpa
+                    && doorbell.datamatch == value_match
+                    && doorbell.doorbell_flags == flags
+                    && same_eventfd(&doorbell.fd, eventfd)
+            })
+            .map(|(id, _)| *id)
+    }
+    /// Returns the GPA of each registered doorbell along with its handle.
+    pub fn doorbells(&self) -> Vec<(DoorbellId, u64)> {
+        let registry = self.doorbells.lock().unwrap();
+        registry
+            .doorbells
+            .iter()
+            .map(|(id, doorbell)| (*id, doorbell.gpa))
+            .collect()
+    }
 
     /// Get property of the VM partition: For example , CPU Frequency, Size of the Xsave state and more.
     /// For more of the codes, please see the hv_partition_property_code type definitions in the bindings.rs
@@ -554,9 +1437,93 @@ impl VmFd {
         if ret == 0 {
             Ok(property.property_value)
         } else {
-            Err(errno::Error::last())
+            Err(errno::Error::last().into())
         }
     }
+    /// Returns the synthetic processor features the partition was created
+    /// with, as accepted by the hypervisor.
+    pub fn get_synthetic_processor_features(
+        &self,
+    ) -> Result<hv_partition_synthetic_processor_features> {
+        let val = self.get_partition_property(
+            hv_partition_property_code_HV_PARTITION_PROPERTY_SYNTHETIC_PROC_FEATURES,
+        )?;
+        Ok(hv_partition_synthetic_processor_features { as_uint64: [val] })
+    }
+    /// Returns the CPUID leaf 0x4000000A advertising nested enlightenments
+    /// to a hypervisor running in the guest, or `None` if the partition was
+    /// not created with `SyntheticProcessorFeature::EnlightenedVmcs`.
+    ///
+    /// Direct virtual flush is only advertised along with
+    /// `SyntheticProcessorFeature::TbFlushHypercalls`. Guests locate the
+    /// enlightened VMCS through the VP assist page, so the partition also
+    /// needs `SyntheticProcessorFeature::AccessIntrCtrlRegs`.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn nested_enlightenments_cpuid(&self) -> Result<Option<hv_cpuid_entry>> {
+        let features = self.get_synthetic_processor_features()?;
+        // SAFETY: access union fields
+        let (evmcs, tb_flush) = unsafe {
+            (
+                features.__bindgen_anon_1.enlightened_vmcs(),
+                features.__bindgen_anon_1.tb_flush_hypercalls(),
+            )
+        };
+        if evmcs == 0 {
+            return Ok(None);
+        }
+        let mut eax = HV_NESTED_EVMCS_VERSION | HV_NESTED_MSR_BITMAP;
+        if tb_flush != 0 {
+            eax |= HV_NESTED_DIRECT_FLUSH;
+        }
+        Ok(Some(hv_cpuid_entry {
+            function: HV_CPUID_NESTED_FEATURES,
+            eax,
+            ..Default::default()
+        }))
+    }
+    /// Returns an `HvCpuidBuilder` seeded with the partition's synthetic
+    /// processor features, including the nested features leaf if enlightened
+    /// VMCS is enabled. The VMM adds its version, recommendations and limits.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn hv_cpuid_builder(&self) -> Result<HvCpuidBuilder> {
+        let mut builder = HvCpuidBuilder::new(self.get_synthetic_processor_features()?);
+        if let Some(leaf) = self.nested_enlightenments_cpuid()? {
+            builder = builder.set_nested(leaf);
+        }
+        Ok(builder)
+    }
+    /// Checks that the host and the partition support the Hyper-V reference
+    /// time sources, as enabled by
+    /// `MshvPartitionBuilder::set_reference_time_sources`, and returns the
+    /// CPUID leaf 0x80000007 override advertising invariant TSC. Fails with
+    /// NotSupported if the host TSC is not invariant, its frequency is
+    /// unknown or the hypervisor did not grant one of the features.
+    ///
+    /// The leaf goes to `VcpuFd::register_intercept_result_cpuid_entry` on
+    /// every vCPU, along with the leaves of `hv_cpuid_builder`, which then
+    /// advertise the reference TSC page, frequency registers and synthetic
+    /// timers the partition was granted.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn reference_time_cpuid(&self) -> Result<hv_cpuid_entry> {
+        let processor = hv_partition_processor_features {
+            as_uint64: [
+                self.get_partition_property(
+                    hv_partition_property_code_HV_PARTITION_PROPERTY_PROCESSOR_FEATURES0,
+                )?,
+                self.get_partition_property(
+                    hv_partition_property_code_HV_PARTITION_PROPERTY_PROCESSOR_FEATURES1,
+                )?,
+            ],
+        };
+        let frequency = self.get_partition_property(
+            hv_partition_property_code_HV_PARTITION_PROPERTY_PROCESSOR_CLOCK_FREQUENCY,
+        )?;
+        reference_time_leaf(
+            self.get_synthetic_processor_features()?,
+            processor,
+            frequency,
+        )
+    }
     /// Sets a partion property
     pub fn set_partition_property(&self, code: u32, value: u64) -> Result<()> {
         let property: mshv_partition_property = mshv_partition_property {
@@ -568,14 +1535,21 @@ impl VmFd {
         if ret == 0 {
             Ok(())
         } else {
-            Err(errno::Error::last())
+            Err(errno::Error::last().into())
         }
     }
     /// Enable dirty page tracking by hypervisor
     /// Flags:
     ///         bit 1: Enabled
     ///         bit 2: Granularity
+    ///
+    /// Fails with `MshvError::MissingFeature` if the driver has no
+    /// MSHV_GET_GPA_ACCESS_STATES to read the dirty bits with.
     pub fn enable_dirty_page_tracking(&self) -> Result<()> {
+        // The dirty bits could not be read back
+        if self.supports(MshvFeature::GpaAccessStates) == Some(false) {
+            return Err(MshvError::MissingFeature(MshvFeature::GpaAccessStates));
+        }
         let flag: u64 = 0x1;
         self.set_partition_property(
             hv_partition_property_code_HV_PARTITION_PROPERTY_GPA_PAGE_ACCESS_TRACKING,
@@ -595,28 +1569,203 @@ impl VmFd {
             flag,
         )
     }
+    /// Applies the same register list to every vCPU in `vcpus`.
+    ///
+    /// This is a convenience loop, not a partition-wide call: the driver has
+    /// no register ioctl addressed by VP index, so it issues one
+    /// MSHV_SET_VP_REGISTERS per vCPU, each carrying the whole list. It
+    /// stops at the first vCPU that fails, leaving the vCPUs before it
+    /// updated and the ones after it untouched.
+    pub fn set_vcpus_reg(&self, vcpus: &[VcpuFd], regs: &[hv_register_assoc]) -> Result<()> {
+        for vcpu in vcpus {
+            vcpu.set_reg(regs)?;
+        }
+        Ok(())
+    }
+    /// Runs `f` with the partition time frozen, unfreezing it afterwards even
+    /// if `f` fails.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    fn with_time_frozen<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        self.set_partition_property(
+            hv_partition_property_code_HV_PARTITION_PROPERTY_TIME_FREEZE,
+            1,
+        )?;
+        let ret = f();
+        let unfreeze = self.set_partition_property(
+            hv_partition_property_code_HV_PARTITION_PROPERTY_TIME_FREEZE,
+            0,
+        );
+        let ret = ret?;
+        unfreeze?;
+        Ok(ret)
+    }
+    /// Saves the state of every vCPU in `vcpus` with the partition time
+    /// frozen. `msr_indices` lists the MSRs saved for each vCPU, usually
+    /// `Mshv::get_msr_index_list()`.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn save_state(&self, vcpus: &[VcpuFd], msr_indices: &[u32]) -> Result<VmSnapshot> {
+        self.with_time_frozen(|| {
+            let mut snapshot = VmSnapshot::default();
+            for vcpu in vcpus {
+                snapshot.vcpus.push(vcpu.save_state(msr_indices)?);
+            }
+            Ok(snapshot)
+        })
+    }
+    /// Restores a state returned by `save_state` into `vcpus`, which must be
+    /// in the same order as when the state was saved. Snapshots saved by an
+    /// older version of this crate must be upgraded first.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn restore_state(&self, vcpus: &[VcpuFd], snapshot: &VmSnapshot) -> Result<()> {
+        if snapshot.version != VM_SNAPSHOT_VERSION {
+            return Err(MshvError::InvalidArgument { field: "version" });
+        }
+        if snapshot.vcpus.len() != vcpus.len() {
+            return Err(MshvError::InvalidArgument { field: "vcpus" });
+        }
+        self.with_time_frozen(|| {
+            for (vcpu, state) in vcpus.iter().zip(snapshot.vcpus.iter()) {
+                vcpu.restore_state(state)?;
+            }
+            Ok(())
+        })
+    }
+    /// Returns the TSC state of `vcpu`
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn get_tsc_state(&self, vcpu: &VcpuFd) -> Result<TscState> {
+        let frequency = self.get_partition_property(
+            hv_partition_property_code_HV_PARTITION_PROPERTY_PROCESSOR_CLOCK_FREQUENCY,
+        )?;
+        let mut reg_assocs = [
+            hv_register_assoc {
+                name: hv_register_name_HV_X64_REGISTER_TSC,
+                ..Default::default()
+            },
+            hv_register_assoc {
+                name: hv_register_name_HV_X64_REGISTER_TSC_ADJUST,
+                ..Default::default()
+            },
+        ];
+        vcpu.get_reg(&mut reg_assocs)?;
+        // SAFETY: access union fields
+        unsafe {
+            Ok(TscState {
+                frequency,
+                tsc: reg_assocs[0].value.reg64,
+                tsc_adjust: reg_assocs[1].value.reg64,
+            })
+        }
+    }
+    /// Sets the TSC state of `vcpu`. The hypervisor derives the TSC offset
+    /// from the written value, there is no way to set the offset itself. MSHV cannot scale the guest TSC, so this fails
+    /// with EINVAL if `state.frequency` is not the partition TSC frequency.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn set_tsc_state(&self, vcpu: &VcpuFd, state: &TscState) -> Result<()> {
+        let frequency = self.get_partition_property(
+            hv_partition_property_code_HV_PARTITION_PROPERTY_PROCESSOR_CLOCK_FREQUENCY,
+        )?;
+        if state.frequency != frequency {
+            return Err(MshvError::InvalidArgument { field: "frequency" });
+        }
+        vcpu.set_reg(&[
+            hv_register_assoc {
+                name: hv_register_name_HV_X64_REGISTER_TSC,
+                value: hv_register_value { reg64: state.tsc },
+                ..Default::default()
+            },
+            hv_register_assoc {
+                name: hv_register_name_HV_X64_REGISTER_TSC_ADJUST,
+                value: hv_register_value {
+                    reg64: state.tsc_adjust,
+                },
+                ..Default::default()
+            },
+        ])
+    }
+    /// Restores TSC states saved on another host into `vcpus`, in the same
+    /// order. Time is frozen while the TSCs are written, so the offsets
+    /// against this host's TSC are recomputed at a single point in time and
+    /// the guest resumes at the saved TSC values instead of jumping.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn restore_tsc_state(&self, vcpus: &[VcpuFd], states: &[TscState]) -> Result<()> {
+        if states.len() != vcpus.len() {
+            return Err(MshvError::InvalidArgument { field: "states" });
+        }
+        self.with_time_frozen(|| {
+            for (vcpu, state) in vcpus.iter().zip(states.iter()) {
+                self.set_tsc_state(vcpu, state)?;
+            }
+            Ok(())
+        })
+    }
+    /// Programs the layout and interrupt assignment of the virtual GIC. The
+    /// redistributor of each vCPU is set separately through
+    /// `VcpuFd::set_gic_redistributor_base`.
+    #[cfg(target_arch = "aarch64")]
+    pub fn configure_gic(&self, config: &aarch64::GicConfig) -> Result<()> {
+        for (code, value) in config.partition_properties() {
+            self.set_partition_property(code, value)?;
+        }
+        Ok(())
+    }
+    /// Reads back the configuration programmed by `configure_gic`, but for
+    /// `gicr_base`, see `GicConfig::from_partition_properties`
+    #[cfg(target_arch = "aarch64")]
+    pub fn get_gic_config(&self) -> Result<aarch64::GicConfig> {
+        let props = aarch64::GIC_PARTITION_PROPERTIES
+            .iter()
+            .map(|&code| Ok((code, self.get_partition_property(code)?)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(aarch64::GicConfig::from_partition_properties(&props))
+    }
+    /// Saves the virtual GIC configuration and the redistributor base of
+    /// each vCPU in `vcpus`. See `aarch64::GicState` for what is not saved.
+    #[cfg(target_arch = "aarch64")]
+    pub fn save_gic_state(&self, vcpus: &[VcpuFd]) -> Result<aarch64::GicState> {
+        let redistributors = vcpus
+            .iter()
+            .map(|vcpu| vcpu.get_gic_redistributor_base())
+            .collect::<Result<Vec<_>>>()?;
+        let mut config = self.get_gic_config()?;
+        config.gicr_base = redistributors.first().copied().unwrap_or_default();
+        Ok(aarch64::GicState {
+            config,
+            redistributors,
+        })
+    }
+    /// Restores a state returned by `save_gic_state` into `vcpus`, which
+    /// must be in the same order as when the state was saved.
+    #[cfg(target_arch = "aarch64")]
+    pub fn restore_gic_state(&self, vcpus: &[VcpuFd], state: &aarch64::GicState) -> Result<()> {
+        if state.redistributors.len() != vcpus.len() {
+            return Err(MshvError::InvalidArgument { field: "vcpus" });
+        }
+        self.configure_gic(&state.config)?;
+        for (vcpu, &base) in vcpus.iter().zip(state.redistributors.iter()) {
+            vcpu.set_gic_redistributor_base(base)?;
+        }
+        Ok(())
+    }
     /// Get page access state
     /// The data provides each page's access state whether it is dirty or accessed
     /// Prerequisite: Need to enable page_acess_tracking
-    /// Flags:
-    ///         bit 1: ClearAccessed
-    ///         bit 2: SetAccessed
-    ///         bit 3: ClearDirty
-    ///         bit 4: SetDirty
-    ///         Number of bits reserved: 60
+    /// `flags` says what to do with the bits once read.
     pub fn get_gpa_access_state(
         &self,
         base_pfn: u64,
         nr_pfns: u32,
-        flags: u64,
+        flags: GpaAccessFlags,
     ) -> Result<mshv_get_gpa_pages_access_state> {
+        if self.is_missing(MshvFeature::GpaAccessStates) {
+            return Err(MshvError::MissingFeature(MshvFeature::GpaAccessStates));
+        }
         let mut states: Vec<hv_gpa_page_access_state> =
             vec![hv_gpa_page_access_state { as_uint8: 0 }; nr_pfns as usize];
         let mut gpa_pages_access_state: mshv_get_gpa_pages_access_state =
             mshv_get_gpa_pages_access_state {
                 count: nr_pfns,
                 hv_gpa_page_number: base_pfn,
-                flags,
+                flags: flags.bits(),
                 states: states.as_mut_ptr(),
             };
 
@@ -631,24 +1780,69 @@ impl VmFd {
         if ret == 0 {
             Ok(gpa_pages_access_state)
         } else {
-            Err(errno::Error::last())
+            Err(self.feature_error(MshvFeature::GpaAccessStates))
         }
     }
-    /// Gets the bitmap of pages dirtied since the last call of this function
+    /// Access states of `count` pages from `base_pfn`, as
+    /// `get_gpa_access_state` returns them.
+    pub(crate) fn gpa_access_states(
+        &self,
+        base_pfn: u64,
+        count: u32,
+        flags: GpaAccessFlags,
+    ) -> Result<Vec<hv_gpa_page_access_state>> {
+        if self.is_missing(MshvFeature::GpaAccessStates) {
+            return Err(MshvError::MissingFeature(MshvFeature::GpaAccessStates));
+        }
+        let mut states = vec![hv_gpa_page_access_state { as_uint8: 0 }; count as usize];
+        let mut args = mshv_get_gpa_pages_access_state {
+            count,
+            hv_gpa_page_number: base_pfn,
+            flags: flags.bits(),
+            states: states.as_mut_ptr(),
+        };
+        // SAFETY: IOCTL with correct types, `states` holds `count` entries
+        let ret = unsafe { ioctl_with_mut_ref(self, MSHV_GET_GPA_ACCESS_STATES(), &mut args) };
+        if ret == 0 {
+            states.truncate(args.count as usize);
+            Ok(states)
+        } else {
+            Err(self.feature_error(MshvFeature::GpaAccessStates))
+        }
+    }
+    /// Iterates over the runs of `kind` pages among the `nr_pfns` pages from
+    /// `base_pfn`, fetching their access states in batches with `flags`,
+    /// as for `get_gpa_access_state`. See `GpaAccessRanges`.
+    pub fn gpa_access_ranges(
+        &self,
+        base_pfn: u64,
+        nr_pfns: u64,
+        flags: GpaAccessFlags,
+        kind: GpaAccessKind,
+    ) -> GpaAccessRanges<'_> {
+        GpaAccessRanges::new(self, base_pfn, nr_pfns, flags, kind)
+    }
+    /// Gets the bitmap of pages dirtied since the dirty bits were last
+    /// cleared, usually by the previous call with
+    /// `GpaAccessFlags::CLEAR_DIRTY`.
     ///
-    /// Flags:
-    ///         bit 1: ClearAccessed
-    ///         bit 2: SetAccessed
-    ///         bit 3: ClearDirty
-    ///         bit 4: SetDirty
-    ///         Number of bits reserved: 60
-    pub fn get_dirty_log(&self, base_pfn: u64, memory_size: usize, flags: u64) -> Result<Vec<u64>> {
+    /// Pages are only known to be dirty after the guest wrote them. There
+    /// is deliberately no tracking by write protection: MSHV protects
+    /// memory by whole mapped regions, so giving one page its write access
+    /// back means remapping its region under the running vCPUs, see
+    /// `protect_user_memory`, and one mapping per dirty page.
+    pub fn get_dirty_log(
+        &self,
+        base_pfn: u64,
+        memory_size: usize,
+        flags: GpaAccessFlags,
+    ) -> Result<Vec<u64>> {
         // Compute the length of the bitmap needed for all dirty pages in one memory slot.
         // One memory page is `page_size` bytes and `KVM_GET_DIRTY_LOG` returns one dirty bit for
         // each page.
         // SAFETY: FFI call to libc
         let page_size = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
-            -1 => return Err(errno::Error::last()),
+            -1 => return Err(errno::Error::last().into()),
             ps => ps as usize,
         };
 
@@ -669,13 +1863,9 @@ impl VmFd {
 
         while remaining != 0 {
             current_size = cmp::min(PAGE_ACCESS_STATES_BATCH_SIZE, remaining);
-            let page_states =
-                self.get_gpa_access_state(base_pfn + processed as u64, current_size, flags)?;
-            // SAFETY: we're sure states and count meet the requirements for from_raw_parts
-            let slices: &[hv_gpa_page_access_state] = unsafe {
-                std::slice::from_raw_parts(page_states.states, page_states.count as usize)
-            };
-            for item in slices.iter() {
+            let states =
+                self.gpa_access_states(base_pfn + processed as u64, current_size, flags)?;
+            for item in states.iter() {
                 let bits = &mut bitmap[bitmap_index];
                 mask = 1 << bit_index;
                 // SAFETY: access union field
@@ -687,7 +1877,7 @@ impl VmFd {
                 bitmap_index = processed / 64;
                 bit_index = processed % 64;
             }
-            remaining -= page_states.count;
+            remaining -= states.len() as u32;
         }
         Ok(bitmap)
     }
@@ -695,13 +1885,16 @@ impl VmFd {
     ///
     /// See the documentation for `MSHV_CREATE_DEVICE`.
     pub fn create_device(&self, device: &mut mshv_create_device) -> Result<DeviceFd> {
+        if self.is_missing(MshvFeature::Devices) {
+            return Err(MshvError::MissingFeature(MshvFeature::Devices));
+        }
         // SAFETY: IOCTL with correct types
         let ret = unsafe { ioctl_with_ref(self, MSHV_CREATE_DEVICE(), device) };
         if ret == 0 {
             // SAFETY: fd is valid
             Ok(new_device(unsafe { File::from_raw_fd(device.fd as i32) }))
         } else {
-            Err(errno::Error::last())
+            Err(self.feature_error(MshvFeature::Devices))
         }
     }
 }
@@ -711,7 +1904,15 @@ impl VmFd {
 /// `create_vm` from `Mshv`. The function cannot be part of the `VmFd` implementation because
 /// then it would be exported with the public `VmFd` interface.
 pub fn new_vmfd(vm: File) -> VmFd {
-    VmFd { vm }
+    VmFd {
+        vm,
+        gpa_layout: RwLock::new(GpaLayout::default()),
+        doorbells: Mutex::new(DoorbellRegistry::default()),
+        hypercall_codes: Mutex::new(BTreeSet::new()),
+        intercept_masks: Mutex::new(BTreeMap::new()),
+        features: Mutex::new(BTreeMap::new()),
+        device_regions: Arc::default(),
+    }
 }
 #[cfg(test)]
 mod tests {
@@ -723,6 +1924,20 @@ mod tests {
     use std::mem;
     use vmm_sys_util::errno::Error;
 
+    #[test]
+    fn test_vm_type() {
+        for (i, vm_type) in VmType::ALL.iter().enumerate() {
+            assert_eq!(VmType::try_from(i as u64), Ok(*vm_type));
+        }
+        assert!(VmType::try_from(VmType::ALL.len() as u64).is_err());
+        assert_eq!(
+            VmType::Tdx.isolation_type(),
+            HV_PARTITION_ISOLATION_TYPE_TDX as u64
+        );
+        assert!(!VmType::Normal.is_hardware_isolated());
+        assert!(VmType::Snp.is_hardware_isolated());
+    }
+
     #[test]
     fn test_user_memory() {
         let hv = Mshv::new().unwrap();
@@ -749,11 +1964,86 @@ mod tests {
         vm.unmap_user_memory(mem).unwrap();
     }
     #[test]
+    fn test_populate_memory() {
+        // SAFETY: FFI call to libc
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let size = 16 * page_size;
+        // SAFETY: FFI call, the result is checked
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        assert!(addr != libc::MAP_FAILED);
+        let resident = || {
+            let mut pages = vec![0u8; 16];
+            // SAFETY: FFI call, pages holds a byte per page of the mapping
+            assert!(unsafe { libc::mincore(addr, size, pages.as_mut_ptr()) } == 0);
+            pages.iter().filter(|page| **page & 1 != 0).count()
+        };
+        assert!(resident() == 0);
+        populate_memory(addr as u64 + page_size as u64, 2 * page_size as u64, true).unwrap();
+        assert!(resident() == 2);
+        populate_memory(addr as u64, size as u64, true).unwrap();
+        assert!(resident() == 16);
+        // SAFETY: addr was mapped above with size
+        unsafe { libc::munmap(addr, size) };
+        assert!(populate_memory(addr as u64, size as u64, true).is_err());
+    }
+    #[test]
+    fn test_map_user_memory_populated() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let mut mem = vec![0u8; 0x2000];
+        let region = mshv_user_mem_region {
+            flags: HV_MAP_GPA_READABLE | HV_MAP_GPA_WRITABLE,
+            guest_pfn: 0x1,
+            size: 0x1000,
+            userspace_addr: (mem.as_mut_ptr() as u64 + 0xfff) & !0xfff,
+        };
+        vm.map_user_memory_populated(region).unwrap();
+        vm.unmap_user_memory(region).unwrap();
+    }
+    #[test]
     fn test_create_vcpu() {
         let hv = Mshv::new().unwrap();
         let vm = hv.create_vm().unwrap();
         let vcpu = vm.create_vcpu(0);
         assert!(vcpu.is_ok());
+        assert!(vm.create_vcpu(300).is_ok());
+        assert!(
+            vm.create_vcpu(HV_MAX_VP_INDEX + 1).unwrap_err()
+                == MshvError::InvalidArgument { field: "id" }
+        );
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_create_vcpu_with_apic_id() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        assert!(vcpu.get_apic_id().unwrap() == 0);
+        let vcpu = vm.create_vcpu_with_apic_id(1, 4).unwrap();
+        assert!(vcpu.get_apic_id().unwrap() == 4);
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_hotplug_vcpu() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let _vcpu = vm.create_vcpu(0).unwrap();
+        let vcpu = vm.hotplug_vcpu(3).unwrap();
+        let regs = vcpu.get_regs().unwrap();
+        assert!(regs.rip == 0xfff0 && regs.rflags == 0x2);
+        let sregs = vcpu.get_sregs().unwrap();
+        assert!(sregs.cs.base == 0xffff0000 && sregs.cs.selector == 0xf000);
+        assert!(sregs.cr0 == 0x60000010);
+        assert!(vm.hotplug_vcpu(3).is_err());
     }
     #[test]
     fn test_assert_virtual_interrupt() {
@@ -785,6 +2075,53 @@ mod tests {
         };
         vm.install_intercept(intercept_args).unwrap();
     }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_set_intercept_policy() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let policy = InterceptPolicy::new()
+            .intercept_cpuid(0x100)
+            .intercept_io_ports(0x3f8..=0x3ff, HV_INTERCEPT_ACCESS_MASK_WRITE)
+            .intercept_shutdowns();
+        vm.set_intercept_policy(&policy).unwrap();
+        assert!(vm.forwards_hypercall(HVCALL_ENTER_SLEEP_STATE as u16));
+        assert!(
+            vm.intercept_mask(hv_intercept_type_HV_INTERCEPT_TYPE_X64_IO_PORT, 0x3f8)
+                == HV_INTERCEPT_ACCESS_MASK_WRITE
+        );
+        // The serial port intercepts go back to WRITE
+        let policy = InterceptPolicy::new()
+            .intercept_io_ports(0x3f8..=0x3ff, HV_INTERCEPT_ACCESS_MASK_READ)
+            .intercept_exception(u16::MAX);
+        vm.set_intercept_policy(&policy).unwrap_err();
+        assert!(
+            vm.intercept_mask(hv_intercept_type_HV_INTERCEPT_TYPE_X64_IO_PORT, 0x3f8)
+                == HV_INTERCEPT_ACCESS_MASK_WRITE
+        );
+    }
+    #[test]
+    fn test_device_regions() {
+        let vm = new_vmfd(File::open("/dev/null").unwrap());
+        vm.register_device_region(0xfee0_0000, 0x1000, 1).unwrap();
+        vm.register_device_region(0xfee0_0800, 0x10, 2).unwrap_err();
+        assert!(vm.device_region_at(0xfee0_0800).unwrap().cookie == 1);
+        assert!(vm.unregister_device_region(0xfee0_0000).is_some());
+        assert!(vm.device_region_at(0xfee0_0800).is_none());
+    }
+    #[test]
+    fn test_set_intercept_policy_failure() {
+        let vm = new_vmfd(File::open("/dev/null").unwrap());
+        let policy = InterceptPolicy::new()
+            .intercept_exception(6)
+            .intercept_hypercalls(&[HV_EXT_CALL_QUERY_CAPABILITIES]);
+        vm.set_intercept_policy(&policy).unwrap_err();
+        assert!(!vm.forwards_hypercall(HV_EXT_CALL_QUERY_CAPABILITIES));
+        assert!(
+            vm.intercept_mask(hv_intercept_type_HV_INTERCEPT_TYPE_EXCEPTION, 6)
+                == HV_INTERCEPT_ACCESS_MASK_NONE
+        );
+    }
     #[test]
     fn test_setting_immutable_partition_property() {
         let hv = Mshv::new().unwrap();
@@ -831,9 +2168,12 @@ mod tests {
                 hv_partition_property_code_HV_PARTITION_PROPERTY_UNIMPLEMENTED_MSR_ACTION,
             )
             .unwrap();
+        // Not `.into()`: with guest-memory, vm-memory's PartialEq<Le64> and
+        // PartialEq<Be64> for u64 leave the target type ambiguous
         assert!(
-            val == hv_unimplemented_msr_action_HV_UNIMPLEMENTED_MSR_ACTION_IGNORE_WRITE_READ_ZERO
-                .into()
+            val == u64::from(
+                hv_unimplemented_msr_action_HV_UNIMPLEMENTED_MSR_ACTION_IGNORE_WRITE_READ_ZERO
+            )
         );
     }
     #[test]
@@ -855,12 +2195,303 @@ mod tests {
         vm.unregister_ioevent(&efd, &addr, NoDatamatch).unwrap();
     }
     #[test]
+    fn test_doorbell() {
+        let efd = EventFd::new(0).unwrap();
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        assert!(
+            vm.register_doorbell(0xe7e85000, 1, HV_DOORBELL_FLAG_TRIGGER_SIZE_ANY, &efd)
+                .unwrap_err()
+                == MshvError::InvalidArgument { field: "flags" }
+        );
+        let id = vm
+            .register_doorbell(0xe7e85000, 1, HV_DOORBELL_FLAG_TRIGGER_SIZE_DWORD, &efd)
+            .unwrap();
+        let any = vm
+            .register_doorbell(0xe7e85008, 0, HV_DOORBELL_FLAG_TRIGGER_ANY_VALUE, &efd)
+            .unwrap();
+        assert!(vm.doorbells() == vec![(id, 0xe7e85000), (any, 0xe7e85008)]);
+        vm.unregister_doorbell(id).unwrap();
+        assert!(vm.unregister_doorbell(id).unwrap_err() == Error::new(libc::ENOENT));
+        vm.unregister_doorbell(any).unwrap();
+        assert!(vm.doorbells().is_empty());
+    }
+    #[test]
+    fn test_interrupt_request_from_msi() {
+        let request = InterruptRequest::from_msi(0xfee0_1004, 0, 0x8130);
+        assert!(request.apic_id == 1);
+        assert!(request.vector == 0x30);
+        assert!(request.interrupt_type == hv_interrupt_type_HV_X64_INTERRUPT_TYPE_LOWESTPRIORITY);
+        assert!(request.level_triggered && request.logical_destination_mode);
+
+        // Extended destination ID and 32-bit destination in address_hi
+        let request = InterruptRequest::from_msi(0xfee0_2020, 0, 0x30);
+        assert!(request.apic_id == 0x102);
+        let request = InterruptRequest::from_msi(0xfee0_3000, 0x1200, 0x30);
+        assert!(request.apic_id == 0x1203);
+        assert!(!request.level_triggered && !request.logical_destination_mode);
+    }
+    #[test]
     fn test_set_msi_routing() {
         let hv = Mshv::new().unwrap();
         let vm = hv.create_vm().unwrap();
         let msi_routing = mshv_msi_routing::default();
         assert!(vm.set_msi_routing(&msi_routing).is_ok());
     }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_nested_enlightenments_cpuid() {
+        use crate::ioctls::system::{MshvPartitionBuilder, SyntheticProcessorFeature};
+
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        assert!(vm.nested_enlightenments_cpuid().unwrap().is_none());
+
+        let config = MshvPartitionBuilder::new()
+            .set_partition_creation_flag(HV_PARTITION_CREATION_FLAG_LAPIC_ENABLED as u64)
+            .set_synthetic_processor_feature(SyntheticProcessorFeature::HypervisorPresent)
+            .set_synthetic_processor_feature(SyntheticProcessorFeature::AccessIntrCtrlRegs)
+            .set_synthetic_processor_feature(SyntheticProcessorFeature::EnlightenedVmcs)
+            .build();
+        let vm = hv.create_vm_with_config(&config).unwrap();
+        let leaf = vm.nested_enlightenments_cpuid().unwrap().unwrap();
+        assert!(leaf.function == HV_CPUID_NESTED_FEATURES);
+        assert!(leaf.eax & HV_NESTED_EVMCS_VERSION_MASK == HV_NESTED_EVMCS_VERSION);
+        assert!(leaf.eax & HV_NESTED_DIRECT_FLUSH == 0);
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_hv_cpuid_builder() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let leaves = vm
+            .hv_cpuid_builder()
+            .unwrap()
+            .set_recommendations(HV_ENLIGHTENMENT_USE_APIC_MSRS)
+            .build();
+        assert!(leaves.len() == 7);
+        assert!(leaves[1].eax == HV_CPUID_INTERFACE_SIGNATURE_HV1);
+        assert!(leaves[3].eax as u64 & HV_PARTITION_PRIVILEGE_SYNIC_MSRS != 0);
+        assert!(leaves[4].eax == HV_ENLIGHTENMENT_USE_APIC_MSRS);
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_reference_time_leaf() {
+        use crate::ioctls::system::MshvPartitionBuilder;
+
+        let mut synthetic = hv_partition_synthetic_processor_features::default();
+        let mut processor = hv_partition_processor_features::default();
+        assert!(reference_time_leaf(synthetic, processor, 1_000_000_000).is_err());
+        synthetic = MshvPartitionBuilder::new()
+            .set_reference_time_sources()
+            .build()
+            .synthetic_processor_features;
+        // SAFETY: access union fields
+        unsafe {
+            processor.__bindgen_anon_1.set_tsc_invariant_support(1);
+        }
+        let leaf = reference_time_leaf(synthetic, processor, 1_000_000_000).unwrap();
+        assert!(leaf.function == CPUID_FUNCTION_ADVANCED_POWER_MANAGEMENT);
+        assert!(leaf.edx == CPUID_APM_INVARIANT_TSC);
+        assert!(reference_time_leaf(synthetic, processor, 0) == Err(MshvError::NotSupported));
+        // SAFETY: access union fields
+        unsafe {
+            synthetic
+                .__bindgen_anon_1
+                .set_access_partition_reference_tsc(0)
+        };
+        assert!(reference_time_leaf(synthetic, processor, 1_000_000_000).is_err());
+        // SAFETY: access union fields
+        unsafe {
+            synthetic
+                .__bindgen_anon_1
+                .set_access_partition_reference_tsc(1);
+            processor.__bindgen_anon_1.set_tsc_invariant_support(0);
+        }
+        assert!(reference_time_leaf(synthetic, processor, 1_000_000_000).is_err());
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_reference_time_cpuid() {
+        use crate::ioctls::system::MshvPartitionBuilder;
+
+        let hv = Mshv::new().unwrap();
+        let config = MshvPartitionBuilder::new()
+            .set_partition_creation_flag(HV_PARTITION_CREATION_FLAG_LAPIC_ENABLED as u64)
+            .set_reference_time_sources()
+            .build();
+        let vm = hv.create_vm_with_config(&config).unwrap();
+        let leaf = vm.reference_time_cpuid().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        vcpu.register_intercept_result_cpuid_entry(&leaf, None, None)
+            .unwrap();
+        let leaves = vm.hv_cpuid_builder().unwrap().build();
+        assert!(leaves[3].eax as u64 & HV_PARTITION_PRIVILEGE_ACCESS_PARTITION_REFERENCE_TSC != 0);
+        assert!(leaves[3].edx & HV_FEATURE_FREQUENCY_REGS_AVAILABLE != 0);
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_install_io_intercept() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        vm.install_io_intercept(
+            0x3f8..=0x3ff,
+            HV_INTERCEPT_ACCESS_MASK_READ | HV_INTERCEPT_ACCESS_MASK_WRITE,
+        )
+        .unwrap();
+        vm.remove_io_intercept(0x3f8..=0x3ff).unwrap();
+    }
+    #[test]
+    fn test_install_hypercall_intercept() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        vm.install_hypercall_intercept(&[HV_EXT_CALL_QUERY_CAPABILITIES])
+            .unwrap();
+        vm.install_hypercall_intercept(&[HV_EXT_CALL_MEMORY_HEAT_HINT])
+            .unwrap();
+        assert!(vm.forwards_hypercall(HV_EXT_CALL_QUERY_CAPABILITIES));
+        vm.remove_hypercall_intercept(&[HV_EXT_CALL_QUERY_CAPABILITIES]);
+        assert!(!vm.forwards_hypercall(HV_EXT_CALL_QUERY_CAPABILITIES));
+        assert!(vm.forwards_hypercall(HV_EXT_CALL_MEMORY_HEAT_HINT));
+    }
+    #[test]
+    fn test_gpa_layout_check() {
+        let layout = GpaLayout {
+            max_gpa: Some(0x1_0000_0000),
+            reserved: vec![(0xc000_0000, 0x2000_0000)],
+        };
+        assert!(layout.check(0, 0xc000_0000).is_ok());
+        assert!(layout.check(0xe000_0000, 0x2000_0000).is_ok());
+        assert!(
+            layout.check(0xbfff_f000, 0x2000).unwrap_err()
+                == MshvError::InvalidArgument { field: "guest_pfn" }
+        );
+        assert!(
+            layout.check(0xe000_0000, 0x2000_1000).unwrap_err()
+                == MshvError::InvalidArgument { field: "guest_pfn" }
+        );
+        assert!(
+            layout.check(u64::MAX, 2).unwrap_err() == MshvError::InvalidArgument { field: "size" }
+        );
+    }
+    #[test]
+    fn test_gpa_layout() {
+        let layout = GpaLayout {
+            max_gpa: Some(0x1_0000_0000),
+            reserved: vec![(0xc000_0000, 0x2000_0000)],
+        };
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        vm.set_max_gpa(0x1_0000_0000);
+        vm.reserve_gpa_range(0xc000_0000, 0x2000_0000).unwrap();
+        assert!(vm.gpa_layout() == layout);
+        let mem_region = mshv_user_mem_region {
+            flags: HV_MAP_GPA_READABLE | HV_MAP_GPA_WRITABLE,
+            guest_pfn: 0xc0000,
+            size: 0x1000,
+            userspace_addr: 0,
+        };
+        assert!(
+            vm.map_user_memory(mem_region).unwrap_err()
+                == MshvError::InvalidArgument { field: "guest_pfn" }
+        );
+    }
+    #[test]
+    fn test_discard_user_memory() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let mem_size = 0x10000;
+        let load_addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                mem_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        } as *mut u8;
+        let mem_region = mshv_user_mem_region {
+            flags: HV_MAP_GPA_READABLE | HV_MAP_GPA_WRITABLE,
+            guest_pfn: 0x100,
+            size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+        };
+        vm.map_user_memory(mem_region).unwrap();
+        unsafe { *load_addr.add(0x4000) = 0xaa };
+
+        assert!(
+            vm.discard_user_memory(mem_region, 0x100800, 0x1000)
+                .unwrap_err()
+                == MshvError::InvalidArgument { field: "gpa" }
+        );
+        let parts = vm
+            .discard_user_memory(mem_region, 0x104000, 0x2000)
+            .unwrap();
+        assert!(parts.len() == 2);
+        assert!(parts[0].guest_pfn == 0x100 && parts[0].size == 0x4000);
+        assert!(parts[1].guest_pfn == 0x106 && parts[1].size == 0xa000);
+        assert!(parts[1].userspace_addr == load_addr as u64 + 0x6000);
+        // Private anonymous pages read back as zero once discarded
+        assert!(unsafe { *load_addr.add(0x4000) } == 0);
+
+        for part in parts {
+            vm.unmap_user_memory(part).unwrap();
+        }
+        unsafe { libc::munmap(load_addr as *mut c_void, mem_size) };
+    }
+    #[test]
+    fn test_split_user_memory() {
+        let region = mshv_user_mem_region {
+            flags: HV_MAP_GPA_READABLE | HV_MAP_GPA_WRITABLE | HV_MAP_GPA_NOT_CACHED,
+            guest_pfn: 0x100,
+            size: 0x10000,
+            userspace_addr: 0x7f00_0000_0000,
+        };
+        let [before, at, after] = split_user_memory(region, 0x104000, 0x2000).unwrap();
+        assert!(before.guest_pfn == 0x100 && before.size == 0x4000);
+        assert!(at.guest_pfn == 0x104 && at.size == 0x2000);
+        assert!(at.userspace_addr == 0x7f00_0000_4000);
+        assert!(after.guest_pfn == 0x106 && after.size == 0xa000);
+        assert!(after.flags == region.flags);
+        let [before, at, after] = split_user_memory(region, 0x100000, 0x10000).unwrap();
+        assert!(before.size == 0 && at == region && after.size == 0);
+        assert!(
+            split_user_memory(region, 0x110000, 0x1000).unwrap_err()
+                == MshvError::InvalidArgument { field: "gpa" }
+        );
+        assert!(
+            split_user_memory(region, 0x10f000, 0x2000).unwrap_err()
+                == MshvError::InvalidArgument { field: "size" }
+        );
+    }
+    #[test]
+    fn test_protect_user_memory() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let mut mem = vec![0u8; 0x5000];
+        let region = mshv_user_mem_region {
+            flags: HV_MAP_GPA_READABLE | HV_MAP_GPA_WRITABLE | HV_MAP_GPA_EXECUTABLE,
+            guest_pfn: 0xf0,
+            size: 0x4000,
+            userspace_addr: (mem.as_mut_ptr() as u64 + 0xfff) & !0xfff,
+        };
+        vm.map_user_memory(region).unwrap();
+        // Make the last page a read-only ROM
+        let parts = vm
+            .protect_user_memory(
+                region,
+                0xf3000,
+                0x1000,
+                GpaProtection::READ | GpaProtection::EXECUTE,
+            )
+            .unwrap();
+        assert!(parts.len() == 2);
+        assert!(parts[1].flags == HV_MAP_GPA_READABLE | HV_MAP_GPA_EXECUTABLE);
+        for part in parts {
+            vm.unmap_user_memory(part).unwrap();
+        }
+    }
     #[test]
     fn test_get_gpa_access_states() {
         let hv = Mshv::new().unwrap();
@@ -887,14 +2518,57 @@ mod tests {
         // number of bits returned etc.
         vm.map_user_memory(mem_region).unwrap();
         vm.enable_dirty_page_tracking().unwrap();
-        let bitmaps_1: Vec<u64> = vm.get_dirty_log(0, mem_size, 0x4).unwrap();
-        let bitmaps_2: Vec<u64> = vm.get_dirty_log(0, mem_size, 0x8).unwrap();
+        let bitmaps_1: Vec<u64> = vm
+            .get_dirty_log(0, mem_size, GpaAccessFlags::CLEAR_DIRTY)
+            .unwrap();
+        let bitmaps_2: Vec<u64> = vm
+            .get_dirty_log(0, mem_size, GpaAccessFlags::SET_DIRTY)
+            .unwrap();
         vm.disable_dirty_page_tracking().unwrap();
         assert!(bitmaps_1.len() == bitmaps_2.len());
         vm.unmap_user_memory(mem_region).unwrap();
         unsafe { libc::munmap(load_addr as *mut c_void, mem_size) };
     }
     #[test]
+    fn test_missing_features() {
+        // Every ioctl fails with ENOTTY on something that isn't a VM
+        let vm = new_vmfd(File::open("/dev/null").unwrap());
+        assert_eq!(vm.supports(MshvFeature::GpaReadWrite), None);
+        assert_eq!(vm.supports(MshvFeature::Devices), Some(false));
+        assert_eq!(
+            vm.signal_event_direct(0, 0, 1).unwrap_err(),
+            MshvError::MissingFeature(MshvFeature::DirectSynic)
+        );
+        assert_eq!(vm.supports(MshvFeature::DirectSynic), Some(false));
+        assert_eq!(
+            vm.post_message_direct(0, 0, &[0u8; 16]).unwrap_err(),
+            MshvError::MissingFeature(MshvFeature::DirectSynic)
+        );
+        let efd = EventFd::new(0).unwrap();
+        for _ in 0..2 {
+            assert_eq!(
+                vm.register_ioevent(&efd, &IoEventAddress::Mmio(0x1000), NoDatamatch)
+                    .unwrap_err(),
+                MshvError::MissingFeature(MshvFeature::IoEventFd)
+            );
+        }
+        assert_eq!(
+            vm.enable_dirty_page_tracking().unwrap_err(),
+            MshvError::MissingFeature(MshvFeature::GpaAccessStates)
+        );
+        assert_eq!(
+            vm.get_dirty_log(0, 0x10000, GpaAccessFlags::empty())
+                .unwrap_err(),
+            MshvError::MissingFeature(MshvFeature::GpaAccessStates)
+        );
+        assert_eq!(
+            vm.hvcall_raw(HVCALL_GET_PARTITION_PROPERTY as u16, &[0; 16], &mut [0; 8])
+                .unwrap_err(),
+            MshvError::NotSupported
+        );
+        assert_eq!(vm.supports(MshvFeature::RootHvcall), Some(false));
+    }
+    #[test]
     #[ignore]
     fn test_signal_event_direct() {
         // TODO this is used by MSHV synic.
@@ -927,4 +2601,116 @@ mod tests {
             assert!(e == Error::new(libc::EINVAL));
         }
     }
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    #[test]
+    fn test_request_interrupt_window() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        vm.request_interrupt_window(0).unwrap();
+        let mut reg_assocs = [hv_register_assoc {
+            name: hv_register_name_HV_X64_REGISTER_DELIVERABILITY_NOTIFICATIONS,
+            ..Default::default()
+        }];
+        vcpu.get_reg(&mut reg_assocs).unwrap();
+        // SAFETY: access union fields
+        let notifications = unsafe { reg_assocs[0].value.reg64 };
+        assert!(notifications & HV_DELIVERABILITY_INTERRUPT_NOTIFICATION != 0);
+    }
+    #[test]
+    fn test_hvcall_raw() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let mut out = [0u8; 8];
+        assert!(
+            vm.hvcall_raw(HVCALL_GET_PARTITION_ID as u16, &[], &mut [0u8; 0x1001])
+                .unwrap_err()
+                == MshvError::InvalidArgument { field: "output" }
+        );
+        assert!(
+            vm.hvcall_raw_rep(HVCALL_GET_PARTITION_ID as u16, 0, &[], &mut out)
+                .unwrap_err()
+                == MshvError::InvalidArgument { field: "reps" }
+        );
+        let big = vec![0u8; 0x1001];
+        assert!(
+            vm.hvcall_raw(HVCALL_GET_PARTITION_ID as u16, &big, &mut out)
+                .unwrap_err()
+                == MshvError::InvalidArgument { field: "input" }
+        );
+        match vm.hvcall_raw(HVCALL_GET_PARTITION_ID as u16, &[], &mut out) {
+            Ok(()) => assert!(u64::from_le_bytes(out) != 0),
+            Err(e) => assert!(e == MshvError::NotSupported),
+        }
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_save_restore_state() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpus = vec![vm.create_vcpu(0).unwrap(), vm.create_vcpu(1).unwrap()];
+        let msr_list = hv.get_msr_index_list().unwrap();
+        let snapshot = vm.save_state(&vcpus, msr_list.as_slice()).unwrap();
+        assert!(snapshot.vcpus.len() == vcpus.len());
+        vm.restore_state(&vcpus, &snapshot).unwrap();
+        let res = vm.restore_state(&vcpus[..1], &snapshot);
+        assert!(res.is_err());
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_install_reset_intercepts() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        vm.install_reset_intercepts().unwrap();
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_install_shutdown_intercepts() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        vm.install_shutdown_intercepts().unwrap();
+    }
+    #[test]
+    fn test_install_guest_crash_intercept() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        vm.install_guest_crash_intercept().unwrap();
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_tsc_state() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpus = vec![vm.create_vcpu(0).unwrap(), vm.create_vcpu(1).unwrap()];
+        let states: Vec<TscState> = vcpus
+            .iter()
+            .map(|vcpu| vm.get_tsc_state(vcpu).unwrap())
+            .collect();
+        vm.restore_tsc_state(&vcpus, &states).unwrap();
+
+        let mut state = states[0];
+        state.frequency += 1;
+        assert!(vm.set_tsc_state(&vcpus[0], &state).is_err());
+    }
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_set_vcpus_reg() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpus = vec![vm.create_vcpu(0).unwrap(), vm.create_vcpu(1).unwrap()];
+        let regs = [hv_register_assoc {
+            name: hv_register_name_HV_X64_REGISTER_RAX,
+            value: hv_register_value { reg64: 0x1234 },
+            ..Default::default()
+        }];
+        vm.set_vcpus_reg(&vcpus, &regs).unwrap();
+        for vcpu in vcpus.iter() {
+            let mut get_regs = [hv_register_assoc {
+                name: hv_register_name_HV_X64_REGISTER_RAX,
+                ..Default::default()
+            }];
+            vcpu.get_reg(&mut get_regs).unwrap();
+            assert!(unsafe { get_regs[0].value.reg64 } == 0x1234);
+        }
+    }
 }
@@ -0,0 +1,392 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use crate::ioctls::gsi::GsiAllocator;
+use crate::ioctls::vm::VmFd;
+use crate::ioctls::{MshvError, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use vm_device::bus::{self, MmioAddress, MmioRange, PioAddress, PioRange};
+use vm_device::device_manager::{IoManager, MmioManager, PioManager};
+use vm_device::resources::{DeviceResources, MsiIrqType, Resource, ResourceConstraint};
+use vm_device::{DeviceMmio, DevicePio};
+use vmm_sys_util::errno;
+
+/// vm-device `IoManager` for a partition.
+///
+/// MMIO ranges are also registered as device regions of the `VmFd`, their
+/// base address as cookie, so `VcpuFd::device_mmio_exit` recognizes their
+/// exits. PIO ranges are only kept on the bus: the VMM intercepts their
+/// ports, typically with an `InterceptPolicy`.
+pub struct MshvIoManager {
+    vm: Arc<VmFd>,
+    io: IoManager,
+}
+
+impl MshvIoManager {
+    /// Creates a manager without devices for `vm`
+    pub fn new(vm: Arc<VmFd>) -> Self {
+        MshvIoManager {
+            vm,
+            io: IoManager::new(),
+        }
+    }
+    /// MMIO callback for `VcpuMmioContext`, dispatching accesses to the
+    /// registered devices. Accesses no device claims read as all ones and
+    /// writes to them are dropped.
+    pub fn mmio_handler(&self) -> impl FnMut(u64, &mut [u8], bool) + '_ {
+        move |gpa, data, write| {
+            let result = if write {
+                self.io.mmio_write(MmioAddress(gpa), data)
+            } else {
+                self.io.mmio_read(MmioAddress(gpa), data)
+            };
+            if result.is_err() && !write {
+                data.fill(0xff);
+            }
+        }
+    }
+}
+
+impl MmioManager for MshvIoManager {
+    type D = Arc<dyn DeviceMmio + Send + Sync>;
+
+    fn mmio_device(&self, addr: MmioAddress) -> Option<(&MmioRange, &Self::D)> {
+        self.io.mmio_device(addr)
+    }
+    fn mmio_read(&self, addr: MmioAddress, data: &mut [u8]) -> std::result::Result<(), bus::Error> {
+        self.io.mmio_read(addr, data)
+    }
+    fn mmio_write(&self, addr: MmioAddress, data: &[u8]) -> std::result::Result<(), bus::Error> {
+        self.io.mmio_write(addr, data)
+    }
+    fn register_mmio(
+        &mut self,
+        range: MmioRange,
+        device: Self::D,
+    ) -> std::result::Result<(), bus::Error> {
+        let base = range.base().0;
+        self.vm
+            .register_device_region(base, range.size(), base)
+            .map_err(|_| bus::Error::DeviceOverlap)?;
+        let result = self.io.register_mmio(range, device);
+        if result.is_err() {
+            self.vm.unregister_device_region(base);
+        }
+        result
+    }
+    fn deregister_mmio(&mut self, addr: MmioAddress) -> Option<(MmioRange, Self::D)> {
+        let (range, device) = self.io.deregister_mmio(addr)?;
+        self.vm.unregister_device_region(range.base().0);
+        Some((range, device))
+    }
+}
+
+impl PioManager for MshvIoManager {
+    type D = Arc<dyn DevicePio + Send + Sync>;
+
+    fn pio_device(&self, addr: PioAddress) -> Option<(&PioRange, &Self::D)> {
+        self.io.pio_device(addr)
+    }
+    fn pio_read(&self, addr: PioAddress, data: &mut [u8]) -> std::result::Result<(), bus::Error> {
+        self.io.pio_read(addr, data)
+    }
+    fn pio_write(&self, addr: PioAddress, data: &[u8]) -> std::result::Result<(), bus::Error> {
+        self.io.pio_write(addr, data)
+    }
+    fn register_pio(
+        &mut self,
+        range: PioRange,
+        device: Self::D,
+    ) -> std::result::Result<(), bus::Error> {
+        self.io.register_pio(range, device)
+    }
+    fn deregister_pio(&mut self, addr: PioAddress) -> Option<(PioRange, Self::D)> {
+        self.io.deregister_pio(addr)
+    }
+}
+
+/// First fit allocator of aligned ranges in an address window.
+#[derive(Debug)]
+struct AddressAllocator {
+    window: RangeInclusive<u64>,
+    // Base -> size of the allocated ranges
+    ranges: BTreeMap<u64, u64>,
+}
+
+impl AddressAllocator {
+    fn new(window: RangeInclusive<u64>) -> Self {
+        AddressAllocator {
+            window,
+            ranges: BTreeMap::new(),
+        }
+    }
+    fn allocate(&mut self, range: Option<(u64, u64)>, align: u64, size: u64) -> Result<u64> {
+        let (min, max) = range.unwrap_or((*self.window.start(), *self.window.end()));
+        let min = min.max(*self.window.start());
+        let max = max.min(*self.window.end());
+        if size == 0 || !align.is_power_of_two() {
+            return Err(MshvError::InvalidArgument {
+                field: "constraint",
+            });
+        }
+        let mut base = min.checked_add(align - 1).map(|b| b & !(align - 1));
+        while let Some(start) = base {
+            let last = match start.checked_add(size - 1) {
+                Some(last) if last <= max => last,
+                _ => break,
+            };
+            // Ranges overlapping [start, last], the last of them ends highest
+            match self.ranges.range(..=last).next_back() {
+                Some((b, s)) if b + (s - 1) >= start => {
+                    base = (b + (s - 1)).checked_add(align).map(|b| b & !(align - 1));
+                }
+                _ => {
+                    self.ranges.insert(start, size);
+                    return Ok(start);
+                }
+            }
+        }
+        Err(errno::Error::new(libc::ENOSPC).into())
+    }
+    fn free(&mut self, base: u64) {
+        self.ranges.remove(&base);
+    }
+}
+
+/// Allocates the resources vm-device devices ask for with
+/// `ResourceConstraint`s: PIO and MMIO ranges from the windows given at
+/// creation, legacy interrupts from the pins `GsiAllocator` reserves and
+/// MSIs from the GSIs it allocates.
+///
+/// MSHV has no memory slots, `KvmMemSlot` constraints fail with ENOTSUP.
+#[derive(Debug)]
+pub struct ResourceAllocator {
+    gsis: GsiAllocator,
+    legacy_irqs: BTreeSet<u32>,
+    mmio: AddressAllocator,
+    pio: AddressAllocator,
+}
+
+impl ResourceAllocator {
+    /// Creates an allocator of the GSIs of `gsis`, of the MMIO addresses
+    /// in `mmio` and of the ports in `pio`.
+    pub fn new(gsis: GsiAllocator, mmio: RangeInclusive<u64>, pio: RangeInclusive<u16>) -> Self {
+        ResourceAllocator {
+            gsis,
+            legacy_irqs: BTreeSet::new(),
+            mmio: AddressAllocator::new(mmio),
+            pio: AddressAllocator::new(*pio.start() as u64..=*pio.end() as u64),
+        }
+    }
+    /// GSI allocator, to route the allocated interrupts
+    pub fn gsis(&mut self) -> &mut GsiAllocator {
+        &mut self.gsis
+    }
+    fn allocate_legacy_irq(&mut self, irq: Option<u32>) -> Result<u32> {
+        let irq = match irq {
+            Some(irq) if irq < self.gsis.legacy() => irq,
+            Some(_) => return Err(MshvError::InvalidArgument { field: "irq" }),
+            None => (0..self.gsis.legacy())
+                .find(|irq| !self.legacy_irqs.contains(irq))
+                .ok_or(errno::Error::new(libc::ENOSPC))?,
+        };
+        if !self.legacy_irqs.insert(irq) {
+            return Err(errno::Error::new(libc::EBUSY).into());
+        }
+        Ok(irq)
+    }
+    // Allocates `size` consecutive GSIs
+    fn allocate_msi(&mut self, size: u32) -> Result<u32> {
+        let mut gsis: Vec<u32> = Vec::new();
+        for _ in 0..size {
+            match self.gsis.allocate() {
+                Ok(gsi) => gsis.push(gsi),
+                Err(e) => {
+                    gsis.iter().for_each(|gsi| self.gsis.free(*gsi).unwrap());
+                    return Err(e);
+                }
+            }
+        }
+        let base = *gsis
+            .first()
+            .ok_or(MshvError::InvalidArgument { field: "size" })?;
+        if gsis
+            .iter()
+            .zip(base..)
+            .any(|(gsi, expected)| *gsi != expected)
+        {
+            gsis.iter().for_each(|gsi| self.gsis.free(*gsi).unwrap());
+            return Err(errno::Error::new(libc::ENOSPC).into());
+        }
+        Ok(base)
+    }
+    fn allocate_one(&mut self, constraint: &ResourceConstraint) -> Result<Resource> {
+        Ok(match *constraint {
+            ResourceConstraint::PioAddress { range, align, size } => {
+                let range = range.map(|(min, max)| (min as u64, max as u64));
+                let base = self.pio.allocate(range, align as u64, size as u64)?;
+                Resource::PioAddressRange {
+                    base: base as u16,
+                    size,
+                }
+            }
+            ResourceConstraint::MmioAddress { range, align, size } => Resource::MmioAddressRange {
+                base: self.mmio.allocate(range, align, size)?,
+                size,
+            },
+            ResourceConstraint::LegacyIrq { irq } => {
+                Resource::LegacyIrq(self.allocate_legacy_irq(irq)?)
+            }
+            ResourceConstraint::PciMsiIrq { size } => Resource::MsiIrq {
+                ty: MsiIrqType::PciMsi,
+                base: self.allocate_msi(size)?,
+                size,
+            },
+            ResourceConstraint::PciMsixIrq { size } => Resource::MsiIrq {
+                ty: MsiIrqType::PciMsix,
+                base: self.allocate_msi(size)?,
+                size,
+            },
+            ResourceConstraint::GenericIrq { size } => Resource::MsiIrq {
+                ty: MsiIrqType::GenericMsi,
+                base: self.allocate_msi(size)?,
+                size,
+            },
+            ResourceConstraint::KvmMemSlot { .. } => return Err(MshvError::NotSupported),
+        })
+    }
+    /// Allocates a resource for each of `constraints`. Either all are
+    /// allocated or none.
+    pub fn allocate(&mut self, constraints: &[ResourceConstraint]) -> Result<DeviceResources> {
+        let mut resources = DeviceResources::new();
+        for constraint in constraints {
+            match self.allocate_one(constraint) {
+                Ok(resource) => resources.append(resource),
+                Err(e) => {
+                    self.free(&resources);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(resources)
+    }
+    fn free_one(&mut self, resource: &Resource) {
+        match *resource {
+            Resource::PioAddressRange { base, .. } => self.pio.free(base as u64),
+            Resource::MmioAddressRange { base, .. } => self.mmio.free(base),
+            Resource::LegacyIrq(irq) => {
+                self.legacy_irqs.remove(&irq);
+            }
+            Resource::MsiIrq { base, size, .. } => {
+                for gsi in base..base + size {
+                    let _ = self.gsis.free(gsi);
+                }
+            }
+            _ => (),
+        }
+    }
+    /// Frees the PIO and MMIO ranges and the interrupts of `resources`.
+    pub fn free(&mut self, resources: &DeviceResources) {
+        resources
+            .get_all_resources()
+            .iter()
+            .for_each(|r| self.free_one(r));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ioctls::gsi::IOAPIC_NUM_PINS;
+    use crate::ioctls::vm::new_vmfd;
+    use std::fs::File;
+    use std::sync::Mutex;
+    use vm_device::bus::MmioAddressOffset;
+    use vm_device::MutDeviceMmio;
+
+    #[derive(Default)]
+    struct Register(u8);
+
+    impl MutDeviceMmio for Register {
+        fn mmio_read(&mut self, _: MmioAddress, _: MmioAddressOffset, data: &mut [u8]) {
+            data.fill(self.0);
+        }
+        fn mmio_write(&mut self, _: MmioAddress, _: MmioAddressOffset, data: &[u8]) {
+            self.0 = data[0];
+        }
+    }
+
+    #[test]
+    fn test_io_manager() {
+        let vm = Arc::new(new_vmfd(File::open("/dev/null").unwrap()));
+        let mut manager = MshvIoManager::new(vm.clone());
+        let range = MmioRange::new(MmioAddress(0xd000_0000), 0x1000).unwrap();
+        manager
+            .register_mmio(range, Arc::new(Mutex::new(Register::default())))
+            .unwrap();
+        assert!(vm.device_region_at(0xd000_0010).unwrap().cookie == 0xd000_0000);
+        assert!(
+            manager.register_mmio(range, Arc::new(Mutex::new(Register::default())))
+                == Err(bus::Error::DeviceOverlap)
+        );
+
+        {
+            let mut handler = manager.mmio_handler();
+            handler(0xd000_0004, &mut [0x42], true);
+            let mut data = [0u8; 2];
+            handler(0xd000_0000, &mut data, false);
+            assert!(data == [0x42; 2]);
+            handler(0xe000_0000, &mut data, false);
+            assert!(data == [0xff; 2]);
+        }
+
+        assert!(manager.deregister_mmio(MmioAddress(0xd000_0000)).is_some());
+        assert!(vm.device_region_at(0xd000_0010).is_none());
+    }
+
+    #[test]
+    fn test_resource_allocator() {
+        let gsis = GsiAllocator::new(IOAPIC_NUM_PINS, 32).unwrap();
+        let mut allocator =
+            ResourceAllocator::new(gsis, 0xd000_0000..=0xd000_ffff, 0x1000..=0x10ff);
+        let constraints = [
+            ResourceConstraint::new_mmio(0x2000),
+            ResourceConstraint::new_pio(8),
+            ResourceConstraint::new_legacy_irq(Some(4)),
+            ResourceConstraint::GenericIrq { size: 4 },
+        ];
+        let resources = allocator.allocate(&constraints).unwrap();
+        assert!(resources.get_mmio_address_ranges() == vec![(0xd000_0000, 0x2000)]);
+        assert!(resources.get_pio_address_ranges() == vec![(0x1000, 8)]);
+        assert!(resources.get_legacy_irq() == Some(4));
+        assert!(resources.get_generic_msi_irqs() == Some((IOAPIC_NUM_PINS, 4)));
+
+        // The pin is taken, nothing else is allocated either
+        assert!(allocator.allocate(&constraints).is_err());
+        let others = allocator
+            .allocate(&[
+                ResourceConstraint::mmio_with_constraints(0x1000, None, 0x4000),
+                ResourceConstraint::new_legacy_irq(None),
+            ])
+            .unwrap();
+        assert!(others.get_mmio_address_ranges() == vec![(0xd000_4000, 0x1000)]);
+        assert!(others.get_legacy_irq() == Some(0));
+        assert!(allocator
+            .allocate(&[ResourceConstraint::new_mmio(0x10000)])
+            .is_err());
+        assert!(
+            allocator
+                .allocate(&[ResourceConstraint::new_kvm_mem_slot(1, None)])
+                .err()
+                == Some(MshvError::NotSupported)
+        );
+
+        allocator.free(&resources);
+        let again = allocator.allocate(&constraints).unwrap();
+        assert!(again.get_mmio_address_ranges() == vec![(0xd000_0000, 0x2000)]);
+        assert!(again.get_generic_msi_irqs() == Some((IOAPIC_NUM_PINS, 4)));
+    }
+}
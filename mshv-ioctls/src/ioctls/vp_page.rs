@@ -0,0 +1,159 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+use crate::ioctls::vcpu::VcpuFd;
+use crate::ioctls::{MshvError, Result};
+use mshv_bindings::*;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use vmm_sys_util::errno;
+
+/// Per-VP pages the driver can map into the VMM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VpStatePageKind {
+    /// `hv_vp_register_page`, x86 only.
+    Registers,
+    /// The `hv_message` of the last intercept.
+    InterceptMessage,
+}
+
+impl VpStatePageKind {
+    fn mmap_offset(self) -> u32 {
+        match self {
+            VpStatePageKind::Registers => MSHV_VP_MMAP_OFFSET_REGISTERS,
+            VpStatePageKind::InterceptMessage => MSHV_VP_MMAP_OFFSET_INTERCEPT_MESSAGE,
+        }
+    }
+}
+
+/// A per-VP page shared with the hypervisor, unmapped when dropped.
+///
+/// The page is only stable while the vCPU is not running: anything read
+/// from it describes the last exit and must be used before the next
+/// `VcpuFd::run`.
+#[derive(Debug)]
+pub struct VpStatePage {
+    kind: VpStatePageKind,
+    addr: *mut u8,
+}
+
+impl VpStatePage {
+    /// Which page this is.
+    pub fn kind(&self) -> VpStatePageKind {
+        self.kind
+    }
+    /// Start of the mapping.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.addr
+    }
+    /// The last intercept message, read in place. None unless this is the
+    /// intercept message page.
+    pub fn message(&self) -> Option<&hv_message> {
+        match self.kind {
+            // SAFETY: the page holds an hv_message, which has no alignment
+            // requirement, and lives as long as self
+            VpStatePageKind::InterceptMessage => {
+                Some(unsafe { &*(self.addr as *const hv_message) })
+            }
+            _ => None,
+        }
+    }
+    /// Copy of the register page. None unless this is the register page or
+    /// the hypervisor has not marked it valid.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn registers(&self) -> Option<hv_vp_register_page> {
+        if self.kind != VpStatePageKind::Registers {
+            return None;
+        }
+        // SAFETY: the page holds an hv_vp_register_page
+        let page = unsafe { ptr::read_volatile(self.addr as *const hv_vp_register_page) };
+        if page.isvalid == 0 {
+            return None;
+        }
+        Some(page)
+    }
+    /// Returns true if an event is pending injection on the next run,
+    /// which callers can check before queueing one of their own. Always
+    /// false for pages other than the register page.
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn interruption_pending(&self) -> bool {
+        if self.kind != VpStatePageKind::Registers {
+            return false;
+        }
+        // SAFETY: the page holds an hv_vp_register_page
+        let pending = unsafe {
+            let page = self.addr as *const hv_vp_register_page;
+            if ptr::read_volatile(ptr::addr_of!((*page).isvalid)) == 0 {
+                return false;
+            }
+            ptr::read_unaligned(ptr::addr_of!((*page).pending_interruption))
+        };
+        // SAFETY: access union fields
+        unsafe { pending.__bindgen_anon_1.interruption_pending() != 0 }
+    }
+}
+
+impl Drop for VpStatePage {
+    fn drop(&mut self) {
+        // SAFETY: addr was mapped by map_state_page with this size
+        unsafe { libc::munmap(self.addr as *mut libc::c_void, 1 << HV_HYP_PAGE_SHIFT) };
+    }
+}
+
+impl VcpuFd {
+    /// Maps a per-VP page read-only, so exits can be inspected without a
+    /// copy through MSHV_RUN_VP or register reads.
+    ///
+    /// Fails with `MshvError::NotSupported` if the driver does not expose
+    /// the page.
+    pub fn map_state_page(&self, kind: VpStatePageKind) -> Result<VpStatePage> {
+        let page_size = 1 << HV_HYP_PAGE_SHIFT;
+        // SAFETY: FFI call with valid arguments, the result is checked
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                page_size,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                self.as_raw_fd(),
+                kind.mmap_offset() as libc::off_t * page_size as libc::off_t,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            let e = errno::Error::last();
+            return match e.errno() {
+                libc::ENODEV | libc::EINVAL | libc::ENXIO => Err(MshvError::NotSupported),
+                _ => Err(e.into()),
+            };
+        }
+        Ok(VpStatePage {
+            kind,
+            addr: addr as *mut u8,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ioctls::system::Mshv;
+
+    #[test]
+    fn test_map_state_page() {
+        let hv = Mshv::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let page = match vcpu.map_state_page(VpStatePageKind::InterceptMessage) {
+            Ok(page) => page,
+            Err(e) => {
+                assert!(e == MshvError::NotSupported);
+                return;
+            }
+        };
+        assert!(page.kind() == VpStatePageKind::InterceptMessage);
+        let msg = page.message().unwrap();
+        let message_type = msg.header.message_type;
+        assert!(message_type == hv_message_type_HVMSG_NONE);
+    }
+}
@@ -2,7 +2,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 //
-#![cfg(target_arch = "x86_64")]
+#![cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
 #![allow(non_snake_case)]
 #![allow(non_upper_case_globals)]
 #![allow(non_camel_case_types)]
@@ -19,6 +19,7 @@
 //! # Platform support
 //!
 //! - x86_64
+//! - aarch64 (register access only)
 //!
 //! **NOTE:** The list of available ioctls is not extensive.
 //!
@@ -199,16 +200,58 @@
 //! ```
 
 mod ioctls;
+#[cfg(feature = "async")]
+pub use ioctls::async_vcpu::AsyncVcpuFd;
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+pub use ioctls::backend::{Vcpu, Vm};
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+pub use ioctls::boot;
 pub use ioctls::device::DeviceFd;
+pub use ioctls::device_interrupt::{msi_interrupt_entry, InterruptTarget};
+pub use ioctls::device_region::{DeviceMmioExit, DeviceRegion};
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+pub use ioctls::emulator::{InstructionEmulator, MmioEmulator, VcpuMmioContext};
+#[cfg(feature = "event-subscribers")]
+pub use ioctls::event_subscriber::EventFdSubscriber;
+pub use ioctls::eventfd_registry::EventFdRegistry;
+pub use ioctls::gpa_access::{GpaAccessKind, GpaAccessRanges};
+pub use ioctls::gsi::{GsiAllocator, IOAPIC_NUM_PINS};
+#[cfg(feature = "guest-memory")]
+pub use ioctls::guest_memory::GuestMemoryRegistry;
+pub use ioctls::intercept::InterceptPolicy;
+pub use ioctls::memory_hotplug::MemoryHotplug;
+pub use ioctls::metrics::{set_metrics, Metrics};
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+pub use ioctls::migration::{ChunkWriter, MigrationSource, MigrationState, MIGRATION_CHUNK_SIZE};
+pub use ioctls::port::{Connection, ConnectionKind, GuestEvent, Port, PortKind};
+pub use ioctls::seccomp::{
+    ioctl_rules, syscall_rules, IoctlFd, IoctlGroup, IoctlRule, SyscallRule,
+};
+pub use ioctls::snapshot_stream::{
+    SnapshotCompression, SnapshotReader, SnapshotSection, SnapshotWriter, SNAPSHOT_CHUNK_SIZE,
+    SNAPSHOT_MAX_SECTION_SIZE, SNAPSHOT_STREAM_MAGIC, SNAPSHOT_STREAM_VERSION,
+};
 pub use ioctls::system::Mshv;
 pub use ioctls::system::MshvPartitionBuilder;
 pub use ioctls::system::SyntheticProcessorFeature;
+pub use ioctls::system::{HypervisorVersion, MshvFeature, VersionInfo};
+#[cfg(all(
+    feature = "test-utils",
+    not(any(target_arch = "arm", target_arch = "aarch64"))
+))]
+pub use ioctls::test_utils;
 pub use ioctls::vcpu::VcpuFd;
+pub use ioctls::vm::DoorbellId;
+pub use ioctls::vm::GpaLayout;
 pub use ioctls::vm::InterruptRequest;
 pub use ioctls::vm::IoEventAddress;
 pub use ioctls::vm::NoDatamatch;
 pub use ioctls::vm::VmFd;
 pub use ioctls::vm::VmType;
+#[cfg(feature = "device-manager")]
+pub use ioctls::vm_device::{MshvIoManager, ResourceAllocator};
+pub use ioctls::vp_page::{VpStatePage, VpStatePageKind};
+pub use ioctls::MshvError;
 
 #[macro_use]
 mod mshv_ioctls;
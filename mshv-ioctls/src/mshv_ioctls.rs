@@ -130,3 +130,7 @@ ioctl_iow_nr!(
     0x34,
     mshv_sev_snp_ap_create
 );
+// Partition ioctl of the upstream Linux mshv_root driver. Its nr is the one
+// of MSHV_RUN_VP above, which is a vCPU ioctl of the driver the rest of
+// this file follows; direction and size differ, so the numbers do not.
+ioctl_iowr_nr!(MSHV_ROOT_HVCALL, MSHV_IOCTL, 0x07, mshv_root_hvcall);
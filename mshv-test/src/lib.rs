@@ -0,0 +1,587 @@
+// Copyright © 2020, Microsoft Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+#![cfg(target_arch = "x86_64")]
+#![deny(missing_docs)]
+
+//! An in-memory stand-in for the MSHV ioctl wrappers.
+//!
+//! `MockVm` and `MockVcpu` implement the `Vm` and `Vcpu` traits of
+//! `mshv-ioctls`, as `VmFd` and `VcpuFd` do, but keep all their state in
+//! memory:
+//! - registers are stored in a map, which `get_regs`, `get_sregs` and
+//!   `get_msrs` read through like the real driver does; registers never
+//!   written read as zero,
+//! - guest memory mapped with `map_user_memory` is backed by a `Vec` owned by
+//!   the mock rather than by the user's mapping,
+//! - `run` returns the exits queued with `MockVcpu::push_exit`, or a halt
+//!   message once the queue is empty.
+//!
+//! This lets VMM unit tests exercise code paths that otherwise need a
+//! Hyper-V host with `/dev/mshv`.
+
+use mshv_bindings::*;
+use mshv_ioctls::{MshvError, Vcpu, Vm};
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::ptr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use vmm_sys_util::errno;
+
+/// A specialized `Result` type matching the one returned by `mshv-ioctls`.
+pub type Result<T> = std::result::Result<T, MshvError>;
+
+const PAGE_SHIFT: u64 = 12;
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    // A panicking test thread must not take the whole mock down with it.
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn error(errno: i32) -> MshvError {
+    MshvError::Ioctl(errno::Error::new(errno))
+}
+
+/// Fake `Mshv`, the entry point used to create mock partitions.
+#[derive(Debug, Default)]
+pub struct MockMshv {}
+
+impl MockMshv {
+    /// Creates a new mock hypervisor. Never fails.
+    pub fn new() -> Result<Self> {
+        Ok(MockMshv {})
+    }
+    /// Creates a new, empty mock partition.
+    pub fn create_vm(&self) -> Result<MockVm> {
+        Ok(MockVm::default())
+    }
+}
+
+/// Builds a message of `message_type` carrying `payload`, e.g. an exit with
+/// a `hv_x64_io_port_intercept_message`, to queue with
+/// `MockVcpu::push_exit` or to decode in tests of exit handling.
+///
+/// Panics if `payload` does not fit in a message.
+pub fn exit_message<T: Copy>(message_type: hv_message_type, payload: T) -> hv_message {
+    let size = mem::size_of::<T>();
+    assert!(size <= HV_MESSAGE_PAYLOAD_QWORD_COUNT as usize * 8);
+    let mut msg = hv_message::default();
+    msg.header.message_type = message_type;
+    msg.header.payload_size = size as u8;
+    // SAFETY: the payload is large enough for `payload`, checked above
+    unsafe { ptr::write_unaligned(ptr::addr_of_mut!(msg.u.payload) as *mut T, payload) };
+    msg
+}
+
+#[derive(Debug)]
+struct MemoryRegion {
+    guest_pfn: u64,
+    flags: u32,
+    data: Vec<u8>,
+}
+
+impl MemoryRegion {
+    fn start(&self) -> u64 {
+        self.guest_pfn << PAGE_SHIFT
+    }
+    fn end(&self) -> u64 {
+        self.start() + self.data.len() as u64
+    }
+}
+
+#[derive(Debug, Default)]
+struct VmState {
+    regions: Vec<MemoryRegion>,
+    properties: HashMap<u32, u64>,
+    vcpus: Vec<u32>,
+}
+
+impl VmState {
+    fn region_mut(&mut self, gpa: u64, len: usize) -> Result<(&mut MemoryRegion, usize)> {
+        let end = gpa
+            .checked_add(len as u64)
+            .ok_or_else(|| error(libc::EFAULT))?;
+        let region = self
+            .regions
+            .iter_mut()
+            .find(|r| r.start() <= gpa && end <= r.end())
+            .ok_or_else(|| error(libc::EFAULT))?;
+        let offset = (gpa - region.start()) as usize;
+        Ok((region, offset))
+    }
+}
+
+/// Fake `VmFd` backed by in-memory state.
+#[derive(Clone, Debug, Default)]
+pub struct MockVm {
+    state: Arc<Mutex<VmState>>,
+}
+
+impl Vm for MockVm {
+    type Vcpu = MockVcpu;
+
+    /// Maps a zero filled region of guest memory. `userspace_addr` is ignored,
+    /// the memory is owned by the mock.
+    fn map_user_memory(&self, user_memory_region: mshv_user_mem_region) -> Result<()> {
+        let region = MemoryRegion {
+            guest_pfn: user_memory_region.guest_pfn,
+            flags: user_memory_region.flags,
+            data: vec![0; user_memory_region.size as usize],
+        };
+        if region.data.is_empty() || region.data.len() as u64 & ((1 << PAGE_SHIFT) - 1) != 0 {
+            return Err(MshvError::InvalidArgument { field: "size" });
+        }
+        let mut state = lock(&self.state);
+        if state
+            .regions
+            .iter()
+            .any(|r| region.start() < r.end() && r.start() < region.end())
+        {
+            return Err(error(libc::EEXIST));
+        }
+        state.regions.push(region);
+        Ok(())
+    }
+    /// Unmaps a region previously mapped with `map_user_memory`.
+    fn unmap_user_memory(&self, user_memory_region: mshv_user_mem_region) -> Result<()> {
+        let mut state = lock(&self.state);
+        let pos = state
+            .regions
+            .iter()
+            .position(|r| {
+                r.guest_pfn == user_memory_region.guest_pfn
+                    && r.data.len() as u64 == user_memory_region.size
+            })
+            .ok_or_else(|| error(libc::EINVAL))?;
+        state.regions.remove(pos);
+        Ok(())
+    }
+    /// Creates a new mock vCPU. Fails with EEXIST if `id` is already in use.
+    fn create_vcpu(&self, id: u32) -> Result<MockVcpu> {
+        let mut state = lock(&self.state);
+        if state.vcpus.contains(&id) {
+            return Err(error(libc::EEXIST));
+        }
+        state.vcpus.push(id);
+        Ok(MockVcpu {
+            vp_index: id,
+            vm: self.state.clone(),
+            regs: Mutex::new(HashMap::new()),
+            fpu: Mutex::new(FloatingPointUnit::default()),
+            lapic: Mutex::new(LapicState::default()),
+            xsave: Mutex::new(XSave::default()),
+            exits: Mutex::new(VecDeque::new()),
+        })
+    }
+    /// Returns a partition property, zero if it was never set.
+    fn get_partition_property(&self, code: u32) -> Result<u64> {
+        Ok(*lock(&self.state).properties.get(&code).unwrap_or(&0))
+    }
+    /// Sets a partition property.
+    fn set_partition_property(&self, code: u32, value: u64) -> Result<()> {
+        lock(&self.state).properties.insert(code, value);
+        Ok(())
+    }
+}
+
+impl MockVm {
+    /// Copies guest memory at `gpa` into `data`. Fails with EFAULT if the
+    /// range is not fully contained in a single mapped region.
+    pub fn read_memory(&self, gpa: u64, data: &mut [u8]) -> Result<()> {
+        let mut state = lock(&self.state);
+        let (region, offset) = state.region_mut(gpa, data.len())?;
+        data.copy_from_slice(&region.data[offset..offset + data.len()]);
+        Ok(())
+    }
+    /// Copies `data` into guest memory at `gpa`. Fails with EFAULT if the
+    /// range is not fully contained in a single mapped region, and with
+    /// EACCES if the region is not writable.
+    pub fn write_memory(&self, gpa: u64, data: &[u8]) -> Result<()> {
+        let mut state = lock(&self.state);
+        let (region, offset) = state.region_mut(gpa, data.len())?;
+        if region.flags & HV_MAP_GPA_WRITABLE == 0 {
+            return Err(error(libc::EACCES));
+        }
+        region.data[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Fake `VcpuFd` backed by in-memory state.
+pub struct MockVcpu {
+    vp_index: u32,
+    vm: Arc<Mutex<VmState>>,
+    regs: Mutex<HashMap<u32, hv_register_value>>,
+    fpu: Mutex<FloatingPointUnit>,
+    lapic: Mutex<LapicState>,
+    xsave: Mutex<XSave>,
+    exits: Mutex<VecDeque<hv_message>>,
+}
+
+impl std::fmt::Debug for MockVcpu {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MockVcpu")
+            .field("vp_index", &self.vp_index)
+            .field("regs", &lock(&self.regs).len())
+            .field("exits", &lock(&self.exits).len())
+            .finish()
+    }
+}
+
+impl MockVcpu {
+    /// Index of this vCPU within its partition.
+    pub fn vp_index(&self) -> u32 {
+        self.vp_index
+    }
+    /// Queues the message returned by the next call to `run`.
+    pub fn push_exit(&self, message: hv_message) {
+        lock(&self.exits).push_back(message);
+    }
+}
+
+impl Vcpu for MockVcpu {
+    /// Get the register values by providing an array of register names
+    fn get_reg(&self, reg_names: &mut [hv_register_assoc]) -> Result<()> {
+        let regs = lock(&self.regs);
+        for reg in reg_names.iter_mut() {
+            let name = reg.name;
+            reg.value = regs.get(&name).copied().unwrap_or_default();
+        }
+        Ok(())
+    }
+    /// Set vcpu register values by providing an array of register assocs
+    fn set_reg(&self, regs: &[hv_register_assoc]) -> Result<()> {
+        let mut stored = lock(&self.regs);
+        for reg in regs {
+            stored.insert(reg.name, reg.value);
+        }
+        Ok(())
+    }
+    /// Sets the vCPU general purpose registers
+    fn set_regs(&self, regs: &StandardRegisters) -> Result<()> {
+        self.set_reg(&regs.to_hv_assocs())
+    }
+    /// Returns the vCPU general purpose registers.
+    fn get_regs(&self) -> Result<StandardRegisters> {
+        let mut reg_assocs: Vec<hv_register_assoc> = StandardRegisters::HV_REGISTER_NAMES
+            .iter()
+            .map(|name| hv_register_assoc {
+                name: *name,
+                ..Default::default()
+            })
+            .collect();
+        self.get_reg(&mut reg_assocs)?;
+        Ok(StandardRegisters::from_hv_assocs(&reg_assocs)?)
+    }
+    fn get_sregs(&self) -> Result<SpecialRegisters> {
+        let mut reg_names = SpecialRegisters::HV_REGISTER_NAMES.to_vec();
+        reg_names.push(hv_register_name_HV_REGISTER_PENDING_INTERRUPTION);
+        let mut reg_assocs: Vec<hv_register_assoc> = reg_names
+            .iter()
+            .map(|name| hv_register_assoc {
+                name: *name,
+                ..Default::default()
+            })
+            .collect();
+        self.get_reg(&mut reg_assocs)?;
+        let mut sregs = SpecialRegisters::from_hv_assocs(&reg_assocs)?;
+        // SAFETY: access union fields
+        let pending = unsafe { reg_assocs[17].value.pending_interruption.__bindgen_anon_1 };
+        if pending.interruption_pending() == 1
+            && pending.interruption_type() == HV_X64_PENDING_INTERRUPT
+        {
+            sregs.set_pending_interrupt(Some(pending.interruption_vector() as u8));
+        }
+        Ok(sregs)
+    }
+    fn set_sregs(&self, sregs: &SpecialRegisters) -> Result<()> {
+        let pending = sregs
+            .pending_interrupt()
+            .map_err(|_| MshvError::InvalidArgument {
+                field: "interrupt_bitmap",
+            })?;
+        let mut pending_reg = hv_x64_pending_interruption_register { as_uint64: 0 };
+        if let Some(vector) = pending {
+            // SAFETY: access union fields
+            unsafe {
+                pending_reg.__bindgen_anon_1.set_interruption_pending(1);
+                pending_reg
+                    .__bindgen_anon_1
+                    .set_interruption_type(HV_X64_PENDING_INTERRUPT);
+                pending_reg
+                    .__bindgen_anon_1
+                    .set_interruption_vector(vector as u32);
+            }
+        }
+        let mut reg_assocs = sregs.to_hv_assocs().to_vec();
+        reg_assocs.push(hv_register_assoc {
+            name: hv_register_name_HV_REGISTER_PENDING_INTERRUPTION,
+            value: hv_register_value {
+                pending_interruption: pending_reg,
+            },
+            ..Default::default()
+        });
+        self.set_reg(&reg_assocs)
+    }
+    /// Returns the floating point state
+    fn get_fpu(&self) -> Result<FloatingPointUnit> {
+        Ok(*lock(&self.fpu))
+    }
+    /// Sets the floating point state
+    fn set_fpu(&self, fpu: &FloatingPointUnit) -> Result<()> {
+        *lock(&self.fpu) = *fpu;
+        Ok(())
+    }
+    /// Returns the machine-specific registers (MSR) for this vCPU.
+    fn get_msrs(&self, msrs: &mut Msrs) -> Result<usize> {
+        let regs = lock(&self.regs);
+        for entry in msrs.as_mut_slice().iter_mut() {
+            let name = msr_to_hv_reg_name(entry.index)
+                .map_err(|_| MshvError::InvalidArgument { field: "msrs" })?;
+            // SAFETY: access union fields
+            entry.data = regs.get(&name).map(|v| unsafe { v.reg64 }).unwrap_or(0);
+        }
+        Ok(msrs.as_slice().len())
+    }
+    /// Setup the model-specific registers (MSR) for this vCPU.
+    fn set_msrs(&self, msrs: &Msrs) -> Result<usize> {
+        let mut assocs = Vec::with_capacity(msrs.as_slice().len());
+        for entry in msrs.as_slice() {
+            assocs.push(hv_register_assoc {
+                name: msr_to_hv_reg_name(entry.index)
+                    .map_err(|_| MshvError::InvalidArgument { field: "msrs" })?,
+                value: hv_register_value { reg64: entry.data },
+                ..Default::default()
+            });
+        }
+        self.set_reg(&assocs)?;
+        Ok(0_usize)
+    }
+    /// Get the state of the LAPIC
+    fn get_lapic(&self) -> Result<LapicState> {
+        Ok(*lock(&self.lapic))
+    }
+    /// Sets the state of the LAPIC
+    fn set_lapic(&self, lapic_state: &LapicState) -> Result<()> {
+        *lock(&self.lapic) = *lapic_state;
+        Ok(())
+    }
+    /// Returns the xsave data
+    fn get_xsave(&self) -> Result<XSave> {
+        Ok(*lock(&self.xsave))
+    }
+    /// Set the xsave data
+    fn set_xsave(&self, data: &XSave) -> Result<()> {
+        *lock(&self.xsave) = *data;
+        Ok(())
+    }
+    /// Read GPA
+    fn gpa_read(&self, input: &mut mshv_read_write_gpa) -> Result<mshv_read_write_gpa> {
+        let len = input.byte_count as usize;
+        if len > input.data.len() {
+            return Err(MshvError::InvalidArgument {
+                field: "byte_count",
+            });
+        }
+        let mut state = lock(&self.vm);
+        let (region, offset) = state.region_mut(input.base_gpa, len)?;
+        input.data[..len].copy_from_slice(&region.data[offset..offset + len]);
+        Ok(*input)
+    }
+    /// Write GPA
+    fn gpa_write(&self, input: &mut mshv_read_write_gpa) -> Result<mshv_read_write_gpa> {
+        let len = input.byte_count as usize;
+        if len > input.data.len() {
+            return Err(MshvError::InvalidArgument {
+                field: "byte_count",
+            });
+        }
+        let mut state = lock(&self.vm);
+        let (region, offset) = state.region_mut(input.base_gpa, len)?;
+        region.data[offset..offset + len].copy_from_slice(&input.data[..len]);
+        Ok(*input)
+    }
+    /// Returns the next exit queued with `push_exit`, or a halt message if
+    /// there is none.
+    fn run(&self, mut hv_message_input: hv_message) -> Result<hv_message> {
+        match lock(&self.exits).pop_front() {
+            Some(message) => Ok(message),
+            None => {
+                hv_message_input.header.message_type = hv_message_type_HVMSG_X64_HALT;
+                Ok(hv_message_input)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registers() {
+        let vm = MockMshv::new().unwrap().create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        assert!(vm.create_vcpu(0).unwrap_err() == errno::Error::new(libc::EEXIST));
+
+        let mut regs = vcpu.get_regs().unwrap();
+        assert!(regs == StandardRegisters::default());
+        regs.rip = 0x1000;
+        regs.rax = 0x2;
+        vcpu.set_regs(&regs).unwrap();
+        assert!(vcpu.get_regs().unwrap() == regs);
+
+        let mut assocs = [hv_register_assoc {
+            name: hv_register_name_HV_X64_REGISTER_RIP,
+            ..Default::default()
+        }];
+        vcpu.get_reg(&mut assocs).unwrap();
+        // SAFETY: access union fields
+        assert!(unsafe { assocs[0].value.reg64 } == 0x1000);
+
+        vcpu.set_reg(&[hv_register_assoc {
+            name: hv_register_name_HV_X64_REGISTER_CR0,
+            value: hv_register_value { reg64: 0x11 },
+            ..Default::default()
+        }])
+        .unwrap();
+        let mut sregs = vcpu.get_sregs().unwrap();
+        assert!(sregs.cr0 == 0x11);
+        sregs.efer = 0x500;
+        sregs.set_pending_interrupt(Some(0x20));
+        vcpu.set_sregs(&sregs).unwrap();
+        let mut assocs = [hv_register_assoc {
+            name: hv_register_name_HV_X64_REGISTER_EFER,
+            ..Default::default()
+        }];
+        vcpu.get_reg(&mut assocs).unwrap();
+        // SAFETY: access union fields
+        assert!(unsafe { assocs[0].value.reg64 } == 0x500);
+        assert!(vcpu.get_sregs().unwrap().pending_interrupt().unwrap() == Some(0x20));
+
+        let msrs = Msrs::from_entries(&[msr_entry {
+            index: IA32_MSR_EFER,
+            data: 0x500,
+            ..Default::default()
+        }])
+        .unwrap();
+        vcpu.set_msrs(&msrs).unwrap();
+        let mut g_msrs = Msrs::from_entries(&[msr_entry {
+            index: IA32_MSR_EFER,
+            ..Default::default()
+        }])
+        .unwrap();
+        assert!(vcpu.get_msrs(&mut g_msrs).unwrap() == 1);
+        assert!(g_msrs.as_slice()[0].data == 0x500);
+    }
+
+    /// Maps `mem` at GPA 0x1000, copies `code` there through the vCPU and
+    /// points it at the code, the way a VMM generic over `Vm` would.
+    fn load_code<V: Vm>(vm: &V, mem: u64, code: &[u8]) -> Result<V::Vcpu> {
+        vm.map_user_memory(mshv_user_mem_region {
+            flags: HV_MAP_GPA_READABLE | HV_MAP_GPA_WRITABLE | HV_MAP_GPA_EXECUTABLE,
+            guest_pfn: 0x1,
+            size: 0x1000,
+            userspace_addr: mem,
+        })?;
+        let vcpu = vm.create_vcpu(0)?;
+        let mut input = mshv_read_write_gpa {
+            base_gpa: 0x1000,
+            byte_count: code.len() as u32,
+            ..Default::default()
+        };
+        input.data[..code.len()].copy_from_slice(code);
+        vcpu.gpa_write(&mut input)?;
+        let mut regs = vcpu.get_regs()?;
+        regs.rip = 0x1000;
+        vcpu.set_regs(&regs)?;
+        Ok(vcpu)
+    }
+
+    fn check_load_code<V: Vm>(vm: &V, mem: u64) {
+        let vcpu = load_code(vm, mem, &[0xf4]).unwrap();
+        assert!(vcpu.get_regs().unwrap().rip == 0x1000);
+        let mut input = mshv_read_write_gpa {
+            base_gpa: 0x1000,
+            byte_count: 1,
+            ..Default::default()
+        };
+        assert!(vcpu.gpa_read(&mut input).unwrap().data[0] == 0xf4);
+    }
+
+    #[test]
+    fn test_generic_mock() {
+        check_load_code(&MockMshv::new().unwrap().create_vm().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_generic_vmfd() {
+        let vm = mshv_ioctls::Mshv::new().unwrap().create_vm().unwrap();
+        // SAFETY: FFI call, the result is checked below
+        let mem = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                0x1000,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        assert!(mem != libc::MAP_FAILED);
+        check_load_code(&vm, mem as u64);
+        drop(vm);
+        // SAFETY: `mem` was mapped above with this size
+        unsafe { libc::munmap(mem, 0x1000) };
+    }
+
+    #[test]
+    fn test_memory() {
+        let vm = MockMshv::new().unwrap().create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let mem = mshv_user_mem_region {
+            flags: HV_MAP_GPA_READABLE | HV_MAP_GPA_WRITABLE,
+            guest_pfn: 0x1,
+            size: 0x2000,
+            userspace_addr: 0,
+        };
+        vm.map_user_memory(mem).unwrap();
+        assert!(vm.map_user_memory(mem).is_err());
+
+        vm.write_memory(0x1ff8, &[1, 2, 3, 4]).unwrap();
+        let mut input = mshv_read_write_gpa {
+            base_gpa: 0x1ff8,
+            byte_count: 4,
+            ..Default::default()
+        };
+        let output = vcpu.gpa_read(&mut input).unwrap();
+        assert!(output.data[..4] == [1, 2, 3, 4]);
+        assert!(vm.write_memory(0x2ffe, &[0; 4]).is_err());
+
+        vm.unmap_user_memory(mem).unwrap();
+        let mut data = [0u8; 4];
+        assert!(vm.read_memory(0x1ff8, &mut data).is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        let vm = MockMshv::new().unwrap().create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        vcpu.push_exit(exit_message(
+            hv_message_type_HVMSG_X64_IO_PORT_INTERCEPT,
+            hv_x64_io_port_intercept_message {
+                port_number: 0x3f8,
+                ..Default::default()
+            },
+        ));
+        let exit = vcpu.run(hv_message::default()).unwrap();
+        assert!(exit.header.message_type == hv_message_type_HVMSG_X64_IO_PORT_INTERCEPT);
+        assert!(exit.to_ioport_info().unwrap().port_number == 0x3f8);
+        let exit = vcpu.run(hv_message::default()).unwrap();
+        assert!(exit.header.message_type == hv_message_type_HVMSG_X64_HALT);
+        assert!(exit.to_halt_info().is_ok());
+        assert!(exit.to_halt_exit().unwrap().vp_index == 0);
+        assert!(exit.to_ioport_info().is_err());
+    }
+}